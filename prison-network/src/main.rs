@@ -3,132 +3,68 @@
 //! HTTP CONNECT proxy that intercepts HTTPS traffic and enforces allow/block rules.
 //! Designed to work with HTTP_PROXY/HTTPS_PROXY environment variables.
 
-use anyhow::Result;
-use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair, Certificate};
+use anyhow::{anyhow, bail, Result};
 use rustls::crypto::aws_lc_rs;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-use rustls::ServerConfig;
-use serde::Deserialize;
+use secure_proxy_core::*;
 use std::{
-    fs::{self, OpenOptions},
-    io::Write,
-    net::SocketAddr,
     path::Path,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::{TlsAcceptor, TlsConnector};
-use tracing::{info, error, Level};
-use tracing_subscriber::FmtSubscriber;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, error, warn, Instrument};
 
 // ============================================================================
-// Configuration
+// HTTP CONNECT Parsing
 // ============================================================================
 
-#[derive(Debug, Clone, Deserialize)]
-struct HostRule {
-    host: String,
-    #[serde(default)]
-    allowed_paths: Vec<String>,
+/// Check whether a CONNECT target should be tunneled without interception
+fn is_connect_passthrough(config: &Config, host: &str) -> bool {
+    config
+        .connect_passthrough_hosts
+        .iter()
+        .any(|pattern| host_matches(pattern, host))
 }
 
-#[derive(Debug, Clone, Deserialize)]
-struct Config {
-    #[serde(default = "default_mode")]
-    mode: String,
-    #[serde(default)]
-    allowed_rules: Vec<HostRule>,
-}
+/// How long a raw tunnel waits for the client's ClientHello before giving up on logging its
+/// SNI, so protocols where the server speaks first are barely held up
+const SNI_PEEK_WAIT: Duration = Duration::from_millis(500);
 
-fn default_mode() -> String {
-    "monitor".to_string()
+/// SNI of the ClientHello the client has sent into the tunnel. The bytes are only peeked, so
+/// they still go upstream intact.
+async fn peek_sni(client: &TcpStream, wait: Duration) -> Option<String> {
+    let hello = tokio::time::timeout(wait, peek_client_hello(client)).await.ok()?.ok()?;
+    parse_sni(&hello)
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            mode: "monitor".to_string(),
-            allowed_rules: vec![],
-        }
+/// Check the SNI of the ClientHello sent inside a tunnel against the CONNECT host
+fn check_sni_matches_connect(config: &Config, connect_host: &str, sni: Option<&str>) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
     }
-}
-
-// ============================================================================
-// Logging
-// ============================================================================
 
-fn log_traffic(action: &str, host: &str, path: &str, method: &str, mode: &str, reason: &str) {
-    let log_path = "/logs/traffic.jsonl";
-    if let Some(parent) = Path::new(log_path).parent() {
-        let _ = fs::create_dir_all(parent);
-    }
-    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(log_path) {
-        let entry = serde_json::json!({
-            "action": action,
-            "host": host,
-            "path": path,
-            "method": method,
-            "mode": mode,
-            "reason": reason
-        });
-        let _ = writeln!(file, "{}", entry);
+    let normalize = |host: &str| host.trim_end_matches('.').to_ascii_lowercase();
+    if sni.is_some_and(|sni| normalize(sni) == normalize(connect_host)) {
+        (true, "SNI Matches CONNECT".to_string())
+    } else {
+        (false, "SNI/CONNECT Mismatch".to_string())
     }
 }
 
-// ============================================================================
-// Security Check
-// ============================================================================
-
-/// Check if a host is allowed (for CONNECT-level checks, ignores path rules)
-fn check_host_allowed(config: &Config, host: &str) -> (bool, String) {
-    if config.mode != "enforce" {
-        return (true, "Monitor Mode".to_string());
-    }
-
-    let host_rule = config.allowed_rules.iter().find(|rule| {
-        host == rule.host || host.ends_with(&format!(".{}", rule.host))
-    });
-
-    match host_rule {
-        None => (false, "Host Not Allowed".to_string()),
-        Some(_) => (true, "Host Allowed".to_string()),
-    }
+/// What the client opened its connection with
+enum ConnectRequest {
+    Target(String, u16),
+    /// Not a CONNECT request we can serve; holds the reason it was refused
+    Rejected(&'static str),
 }
 
-/// Check if a request (host + path) is allowed
-fn check_request(config: &Config, host: &str, path: &str) -> (bool, String) {
-    if config.mode != "enforce" {
-        return (true, "Monitor Mode".to_string());
-    }
-
-    let host_rule = config.allowed_rules.iter().find(|rule| {
-        host == rule.host || host.ends_with(&format!(".{}", rule.host))
-    });
-
-    match host_rule {
-        None => (false, "Host Not Allowed".to_string()),
-        Some(rule) => {
-            if rule.allowed_paths.is_empty() {
-                return (true, "Host Match".to_string());
-            }
-            let path_match = rule.allowed_paths.iter().any(|p| path.starts_with(p));
-            if path_match {
-                (true, "Path Match".to_string())
-            } else {
-                (false, "Path Not Allowed".to_string())
-            }
-        }
-    }
-}
-
-// ============================================================================
-// HTTP CONNECT Parsing
-// ============================================================================
-
 /// Parse HTTP CONNECT request and return (host, port)
 /// Reads the full CONNECT request including headers
-async fn read_connect_request(client: &mut TcpStream) -> Result<Option<(String, u16)>> {
+async fn read_connect_request(client: &mut TcpStream) -> Result<ConnectRequest> {
     let mut buf = vec![0u8; 4096];
     let mut total_read = 0;
     
@@ -136,17 +72,17 @@ async fn read_connect_request(client: &mut TcpStream) -> Result<Option<(String,
     loop {
         let n = client.read(&mut buf[total_read..]).await?;
         if n == 0 {
-            return Ok(None);
+            return Ok(ConnectRequest::Rejected("Malformed CONNECT"));
         }
         total_read += n;
         
         // Check for end of headers
-        if let Some(_) = buf[..total_read].windows(4).position(|w| w == b"\r\n\r\n") {
+        if buf[..total_read].windows(4).any(|w| w == b"\r\n\r\n") {
             break;
         }
         
         if total_read >= buf.len() {
-            return Ok(None); // Headers too large
+            return Ok(ConnectRequest::Rejected("CONNECT Headers Too Large"));
         }
     }
     
@@ -155,76 +91,26 @@ async fn read_connect_request(client: &mut TcpStream) -> Result<Option<(String,
     let parts: Vec<&str> = first_line.split_whitespace().collect();
     
     if parts.len() < 3 || parts[0] != "CONNECT" {
-        return Ok(None);
+        return Ok(ConnectRequest::Rejected("Malformed CONNECT"));
     }
     
     // Parse host:port from CONNECT target
     let target = parts[1];
     let (host, port) = if let Some(colon_pos) = target.rfind(':') {
         let host = &target[..colon_pos];
-        let port: u16 = target[colon_pos + 1..].parse().unwrap_or(443);
+        let port = match target[colon_pos + 1..].parse::<u16>() {
+            Ok(port) if port != 0 => port,
+            _ => {
+                warn!("CONNECT target {:?} has an invalid port; falling back to 443", target);
+                443
+            }
+        };
         (host.to_string(), port)
     } else {
         (target.to_string(), 443)
     };
     
-    Ok(Some((host, port)))
-}
-
-// ============================================================================
-// Certificate Authority
-// ============================================================================
-
-struct CaAuthority {
-    ca_key: KeyPair,
-    ca_cert: Certificate,
-}
-
-impl CaAuthority {
-    fn new() -> Result<Self> {
-        let ca_cert_path = "/ca/certs/ca.pem";
-        let ca_key_path = "/ca/keys/ca.private.key";
-
-        fs::create_dir_all("/ca/certs")?;
-        fs::create_dir_all("/ca/keys")?;
-
-        info!("Generating CA certificate...");
-
-        let mut params = CertificateParams::default();
-        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
-        let mut dn = DistinguishedName::new();
-        dn.push(DnType::CommonName, "Secure Proxy CA");
-        dn.push(DnType::OrganizationName, "Secure Proxy");
-        params.distinguished_name = dn;
-
-        let key_pair = KeyPair::generate()?;
-        let cert = params.self_signed(&key_pair)?;
-
-        fs::write(ca_cert_path, cert.pem())?;
-        fs::write(ca_key_path, key_pair.serialize_pem())?;
-
-        info!("CA saved to {}", ca_cert_path);
-
-        Ok(Self {
-            ca_key: key_pair,
-            ca_cert: cert,
-        })
-    }
-
-    fn generate_cert_for_host(&self, hostname: &str) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
-        let mut params = CertificateParams::new(vec![hostname.to_string()])?;
-        let mut dn = DistinguishedName::new();
-        dn.push(DnType::CommonName, hostname);
-        params.distinguished_name = dn;
-
-        let key_pair = KeyPair::generate()?;
-        let cert = params.signed_by(&key_pair, &self.ca_cert, &self.ca_key)?;
-
-        let cert_der = CertificateDer::from(cert.der().to_vec());
-        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
-
-        Ok((vec![cert_der], key_der))
-    }
+    Ok(ConnectRequest::Target(host, port))
 }
 
 // ============================================================================
@@ -238,30 +124,99 @@ async fn handle_connection(
 ) -> Result<()> {
     // Parse HTTP CONNECT request
     let (hostname, port) = match read_connect_request(&mut client).await? {
-        Some((h, p)) => (h, p),
-        None => {
-            error!("Failed to parse CONNECT request");
+        ConnectRequest::Target(h, p) => (h, p),
+        ConnectRequest::Rejected(reason) => {
+            // Scanners and confused clients: worth counting, not worth a traffic log entry each
+            let peer = client.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|_| "unknown".to_string());
+            info!("{} from {}", reason, peer);
+            record_decision("BLOCK", reason);
+            note_connection(|stats| stats.close_reason = Some(reason.to_string()));
             let response = "HTTP/1.1 400 Bad Request\r\n\r\n";
             client.write_all(response.as_bytes()).await?;
             return Ok(());
         }
     };
 
+    note_connection(|stats| stats.host = Some(truncate_hostname(&hostname, config.max_hostname_len).to_string()));
+    let span = connection_span(truncate_hostname(&hostname, config.max_hostname_len));
+    serve_connection(client, ca, config, hostname, port).instrument(span).await
+}
+
+/// Everything after the target host is known, inside the connection's tracing span
+async fn serve_connection(
+    mut client: TcpStream,
+    ca: Arc<CaAuthority>,
+    config: Arc<Config>,
+    hostname: String,
+    port: u16,
+) -> Result<()> {
+
+    // Reject absurdly long names before they reach matching, logging or cert generation
+    if hostname.len() > config.max_hostname_len {
+        let logged = truncate_hostname(&hostname, config.max_hostname_len);
+        log_traffic(&config, "BLOCK", logged, "/", "CONNECT", "Hostname Too Long", &[]);
+        print_decision(&config, format_args!("⛔ [{}] CONNECT {}… -> Hostname Too Long", config.mode, logged));
+        let response = "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\n\r\nHostname too long";
+        return refuse_connect(client, &ca, &config, &hostname, "Hostname Too Long", response, false).await;
+    }
+
+    // Maintenance turns everything away before any rule is looked at
+    if in_maintenance(&config, &hostname) {
+        log_traffic(&config, "MAINTENANCE", &hostname, "/", "CONNECT", "Maintenance Mode", &[]);
+        print_decision(&config, format_args!("🚧 [{}] CONNECT {}:{} -> Maintenance Mode", config.mode, hostname, port));
+        client.write_all(maintenance_response(&config).as_bytes()).await?;
+        return Ok(());
+    }
+
     // Check if host is allowed (for CONNECT-level blocking)
-    let (host_allowed, reason) = check_host_allowed(&config, &hostname);
-    
-    if !host_allowed {
-        log_traffic("BLOCK", &hostname, "/", "CONNECT", &config.mode, &reason);
-        println!("⛔ [{}] CONNECT {}:{} -> {}", config.mode, hostname, port, reason);
+    let decision = evaluate(&config, &hostname, "/", "CONNECT");
+    let reason = &decision.reason;
+
+    if !decision.allowed() {
+        log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", reason, &[]);
+        print_decision(&config, format_args!("⛔ [{}] CONNECT {}:{} -> {}", config.mode, hostname, port, reason));
+        if blocks_as_unreachable(&config, &hostname) {
+            // A reset instead of an answer, like a host that can't be reached
+            client.set_zero_linger()?;
+            return Ok(());
+        }
         let response = "HTTP/1.1 403 Forbidden\r\nContent-Type: text/plain\r\n\r\nHost not allowed";
-        client.write_all(response.as_bytes()).await?;
-        return Ok(());
+        return refuse_connect(client, &ca, &config, &hostname, reason, response, false).await;
+    }
+
+    // Reject names we couldn't issue a certificate for
+    if !is_valid_hostname(&hostname) {
+        log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", "Invalid Hostname", &[]);
+        print_decision(&config, format_args!("⛔ [{}] CONNECT {:?} -> Invalid Hostname", config.mode, hostname));
+        let response = "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\n\r\nInvalid hostname";
+        return refuse_connect(client, &ca, &config, &hostname, "Invalid Hostname", response, false).await;
     }
 
     // Connect to upstream first to verify it's reachable
-    let upstream_addr = format!("{}:{}", hostname, port);
-    let upstream = match TcpStream::connect(&upstream_addr).await {
-        Ok(s) => s,
+    let timeouts = config.timeouts_for(&hostname);
+    let upstream_addr = upstream_dial_addr(find_host_rule(&config, &hostname), &hostname, port);
+    let serve_offline =
+        !config.audit_only
+            && !is_connect_passthrough(&config, &hostname)
+            && !never_intercepted(&config, &hostname)
+            && has_static_responses(&config, &hostname);
+    let connecting = with_deadline(timeouts.connect, connect_upstream(&config, &upstream_addr));
+    let Some(connected) = unless_client_gone(&client, &hostname, connecting).await else {
+        return Ok(());
+    };
+    let upstream = match connected {
+        Ok(s) => Some(s),
+        // Hosts with static responses can still be answered when the upstream is unreachable
+        Err(e) if serve_offline => {
+            debug!("Upstream {} unreachable ({}), serving static responses only", upstream_addr, e);
+            None
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+            let reason = "Upstream Timeout";
+            log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", reason, &[]);
+            print_decision(&config, format_args!("⛔ [{}] CONNECT {}:{} -> {}", config.mode, hostname, port, reason));
+            return refuse_connect(client, &ca, &config, &hostname, reason, gateway_timeout_response(), true).await;
+        }
         Err(e) => {
             error!("Failed to connect to upstream {}: {}", upstream_addr, e);
             let response = format!("HTTP/1.1 502 Bad Gateway\r\n\r\nFailed to connect to {}", hostname);
@@ -270,137 +225,666 @@ async fn handle_connection(
         }
     };
 
+    // Check who owns the address the upstream resolved to
+    if let Some(Ok(upstream_peer)) = upstream.as_ref().map(TcpStream::peer_addr) {
+        let (asn_allowed, reason) = check_upstream_asn(&config, upstream_peer.ip());
+        if !asn_allowed {
+            log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", &reason, &[]);
+            print_decision(&config, format_args!("⛔ [{}] CONNECT {}:{} ({}) -> {}", config.mode, hostname, port, upstream_peer.ip(), reason));
+            let response = "HTTP/1.1 403 Forbidden\r\nContent-Type: text/plain\r\n\r\nHost not allowed";
+            return refuse_connect(client, &ca, &config, &hostname, &reason, response, true).await;
+        }
+    }
+
     // Send 200 Connection Established to client
     client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
 
-    // Generate certificate for this host
-    let (certs, key) = ca.generate_cert_for_host(&hostname)?;
+    // Nested proxies and other passthrough targets get a raw tunnel, no interception
+    if is_connect_passthrough(&config, &hostname) {
+        let sni = peek_sni(&client, SNI_PEEK_WAIT).await;
+        let fields: Vec<_> = sni.as_deref().map(|sni| ("sni", sni)).into_iter().collect();
+        log_traffic(&config, "PASSTHROUGH", &hostname, "/", "CONNECT", "Connect Passthrough", &fields);
+        print_decision(&config, format_args!("↔️  [{}] CONNECT {}:{} -> Passthrough", config.mode, hostname, port));
+        let upstream = upstream.ok_or_else(|| anyhow!("No upstream connection to {}", hostname))?;
+        return tunnel(client, upstream, &config, &hostname, timeouts.idle, None, None).await;
+    }
 
-    // Create TLS config for client-facing connection
-    let server_config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)?;
-    
-    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    // The ClientHello inside the tunnel must be for the host the client asked to CONNECT to
+    if config.require_sni_matches_connect || config.debug_clienthello {
+        let hello = peek_client_hello(&client).await?;
+        if config.debug_clienthello {
+            log_client_hello(&hello);
+        }
+        if config.require_sni_matches_connect {
+            let sni = parse_sni(&hello);
+            let (sni_allowed, reason) = check_sni_matches_connect(&config, &hostname, sni.as_deref());
+            if !sni_allowed {
+                let sni = sni.as_deref().unwrap_or("");
+                log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", &reason, &[("sni", sni)]);
+                print_decision(&config, format_args!("⛔ [{}] CONNECT {}:{} (SNI {:?}) -> {}", config.mode, hostname, port, sni, reason));
+                // The 200 is already out, so all that's left is to drop the tunnel
+                return Ok(());
+            }
+        }
+    }
 
-    // Accept TLS from client
-    let mut client_tls = acceptor.accept(client).await?;
-
-    // Create TLS connection to upstream
-    let connector = TlsConnector::from(Arc::new(
-        rustls::ClientConfig::builder()
-            .with_root_certificates(rustls::RootCertStore::from_iter(
-                webpki_roots::TLS_SERVER_ROOTS.iter().cloned()
-            ))
-            .with_no_client_auth()
-    ));
-
-    let server_name = hostname.clone().try_into()?;
-    let mut upstream_tls = connector.connect(server_name, upstream).await?;
-
-    // Now we have decrypted streams. Read HTTP request.
-    let mut request_buf = vec![0u8; 8192];
-    let n = client_tls.read(&mut request_buf).await?;
-    let request_data = &request_buf[..n];
-
-    // Parse HTTP request line
-    let request_str = String::from_utf8_lossy(request_data);
-    let first_line = request_str.lines().next().unwrap_or("");
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
-    let (method, path) = if parts.len() >= 2 {
-        (parts[0], parts[1])
-    } else {
-        ("?", "/")
-    };
+    // Audit-only deployments record the host-level decision and never terminate TLS
+    if config.audit_only {
+        let sni = peek_sni(&client, SNI_PEEK_WAIT).await;
+        let mut fields = vec![("audit_only", "true")];
+        fields.extend(sni.as_deref().map(|sni| ("sni", sni)));
+        fields.extend(decision.rule_host(&config).map(|rule| ("rule", rule)));
+        log_traffic(&config, "ALLOW", &hostname, "/", "CONNECT", reason, &fields);
+        print_decision(&config, format_args!("✅ [{}] CONNECT {}:{} -> {} (audit only)", config.mode, hostname, port, reason));
+        let upstream = upstream.ok_or_else(|| anyhow!("No upstream connection to {}", hostname))?;
+        return tunnel(client, upstream, &config, &hostname, timeouts.idle, None, None).await;
+    }
 
-    // Check path-level rules
-    let (allowed, reason) = check_request(&config, &hostname, path);
-    let action = if allowed { "ALLOW" } else { "BLOCK" };
-    log_traffic(action, &hostname, path, method, &config.mode, &reason);
-
-    let icon = if allowed { "✅" } else { "⛔" };
-    println!("{} [{}] {} {}{} -> {}", icon, config.mode, method, hostname, path, reason);
-
-    if !allowed {
-        // Send 403 response
-        let response = "HTTP/1.1 403 Forbidden\r\n\
-             Content-Type: text/plain\r\n\
-             Content-Length: 24\r\n\
-             Connection: close\r\n\r\n\
-             Blocked by Secure Proxy";
-        client_tls.write_all(response.as_bytes()).await?;
-        return Ok(());
+    // Hosts on the global no-interception list are tunneled as-is once allowed
+    if never_intercepted(&config, &hostname) {
+        let sni = peek_sni(&client, SNI_PEEK_WAIT).await;
+        let mut fields = vec![("intercepted", "false")];
+        fields.extend(sni.as_deref().map(|sni| ("sni", sni)));
+        fields.extend(decision.rule_host(&config).map(|rule| ("rule", rule)));
+        log_traffic(&config, "ALLOW", &hostname, "/", "CONNECT", reason, &fields);
+        print_decision(&config, format_args!("✅ [{}] CONNECT {}:{} -> {} (not intercepted)", config.mode, hostname, port, reason));
+        let upstream = upstream.ok_or_else(|| anyhow!("No upstream connection to {}", hostname))?;
+        return tunnel(client, upstream, &config, &hostname, timeouts.idle, None, None).await;
     }
 
-    // Forward request to upstream
-    upstream_tls.write_all(request_data).await?;
+    // Generate certificate for this host and create the client-facing TLS config
+    let Some(server_config) = unless_client_gone(&client, &hostname, client_tls_config(&ca, &hostname, &config)).await else {
+        return Ok(());
+    };
+    let server_config = match server_config {
+        Ok(server_config) => server_config,
+        Err(e) if e.is::<CertGenSaturated>() => {
+            let reason = "Cert Gen Saturated";
+            log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", reason, &[]);
+            print_decision(&config, format_args!("⛔ [{}] CONNECT {}:{} -> {}", config.mode, hostname, port, reason));
+            // The 200 is already out, so all that's left is to drop the tunnel
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
 
-    // Bidirectional copy
-    let (mut client_read, mut client_write) = tokio::io::split(client_tls);
-    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream_tls);
+    // Accept TLS from client
+    let Some(client_tls) = accept_client_tls(acceptor, client, &hostname, &config, timeouts.handshake).await? else {
+        return Ok(());
+    };
 
-    let client_to_upstream = tokio::io::copy(&mut client_read, &mut upstream_write);
-    let upstream_to_client = tokio::io::copy(&mut upstream_read, &mut client_write);
+    intercept_session(client_tls, upstream, &hostname, &config, timeouts).await
+}
 
-    tokio::select! {
-        _ = client_to_upstream => {},
-        _ = upstream_to_client => {},
+/// Answer a refused CONNECT with `plain`, or with `uniform_block_response` the way every other
+/// block is answered: the tunnel opens and its request gets the block response. Refusals made
+/// before the upstream was `dialed` wait `uniform_block_delay_ms` first, so they aren't
+/// answered faster than an allowed host.
+async fn refuse_connect(
+    mut client: TcpStream,
+    ca: &Arc<CaAuthority>,
+    config: &Arc<Config>,
+    hostname: &str,
+    reason: &str,
+    plain: &str,
+    dialed: bool,
+) -> Result<()> {
+    if !config.uniform_block_response {
+        client.write_all(plain.as_bytes()).await?;
+        return Ok(());
     }
-
+    if !dialed {
+        tokio::time::sleep(config.uniform_block_delay()).await;
+    }
+    client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+    if blocks_inside_tls(config, hostname) {
+        return serve_blocked_host(client, ca, hostname, config, reason).await;
+    }
+    // No certificate can be issued for this name, so the tunnel just closes
     Ok(())
 }
 
+/// Turn away a connection that arrived while the proxy is at `max_connections`
+async fn reject_overloaded(mut client: TcpStream, config: Arc<Config>) {
+    log_traffic(&config, "BLOCK", "unknown", "/", "CONNECT", "Overloaded", &[]);
+    let response = format!(
+        "HTTP/1.1 503 Service Unavailable\r\n\
+         Retry-After: {}\r\n\
+         Content-Length: 0\r\n\
+         Connection: close\r\n\r\n",
+        config.overload_retry_after_secs.unwrap_or(5)
+    );
+    let _ = client.write_all(response.as_bytes()).await;
+}
+
 // ============================================================================
 // Main
 // ============================================================================
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .with_target(false)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
-
-    println!("🔧 Initializing Secure Proxy (Prison Network Edition)...");
-
     // Install the crypto provider globally
     aws_lc_rs::default_provider()
         .install_default()
         .expect("Failed to install crypto provider");
 
+    if let Some(path) = cli_option("--check-config") {
+        return check_config(&path);
+    }
+
     // Load config
-    let config_path = "/config/rules.json";
-    let config: Config = if Path::new(config_path).exists() {
-        let content = fs::read_to_string(config_path)?;
-        serde_json::from_str(&content)?
+    let config: Config = if Path::new(&paths().config).exists() {
+        if let Some(profile) = selected_profile() {
+            println!("[Config] Using profile {}", profile);
+        }
+        load_config(&paths().config)?
     } else {
         println!("[Config] No config found, using MONITOR mode");
         Config::default()
     };
+    // Stealth deployments skip the banners that would fingerprint the proxy
+    let banners = !config.stealth_mode;
+    if banners {
+        println!("🔧 Initializing Secure Proxy (Prison Network Edition)...");
+    }
     println!("[Config] Loaded mode: {}", config.mode.to_uppercase());
+    if config.audit_only {
+        println!("[Config] Audit-only: TLS is never intercepted, path-level rules are not applied");
+    }
+    if config.maintenance_mode {
+        println!("[Config] Starting in maintenance mode");
+        MAINTENANCE_MODE.store(true, Ordering::Relaxed);
+    }
+    config.validate()?;
+
+    if let Some(host) = cli_option("--trace-host") {
+        if !is_valid_hostname(&host) {
+            bail!("--trace-host {:?} is not a valid hostname", host);
+        }
+        println!("[Config] Tracing connections to {}", host);
+        let _ = TRACE_HOST.set(host);
+    }
+    init_logging(&config)?;
+
+    if let Some(count) = cli_option("--bench-certgen") {
+        let count = count.parse().map_err(|_| anyhow!("--bench-certgen expects a number, got {:?}", count))?;
+        return bench_certgen(&config, count);
+    }
+
+    if let Some(path) = &config.client_ca_file {
+        init_client_ca(path)?;
+        println!("[Config] Requesting client certificates issued by {}", path);
+    }
+    if let Some(path) = &config.asn_db_path {
+        init_asn_db(path)?;
+        println!("[Config] Loaded ASN database from {}", path);
+    }
+    if let Some(addr) = &config.statsd_addr {
+        init_statsd(addr)?;
+        println!("[Config] Sending metrics to StatsD at {}", addr);
+    }
     let config = Arc::new(config);
+    init_log_writer(config.clone())?;
 
     // Setup CA
-    let ca = Arc::new(CaAuthority::new()?);
-    println!("🔒 CA Certificate ready");
+    let ca = Arc::new(CaAuthority::new(&config)?);
+    if banners {
+        println!("🔒 CA Certificate ready");
+    }
 
     // Create listener
-    let addr = SocketAddr::from(([0, 0, 0, 0], 58080));
+    let addr = config.listen_addr()?;
     let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
 
-    println!("🛡️  Secure Proxy listening on 0.0.0.0:58080");
-    println!("✅ Environment Ready.");
+    if banners {
+        println!("🛡️  Secure Proxy listening on {}", addr);
+        println!("✅ Environment Ready.");
+    } else {
+        println!("Listening on {}", addr);
+    }
 
+    tokio::spawn(async {
+        if let Err(e) = dump_metrics_on_signal().await {
+            error!("Failed to install SIGUSR1 handler: {}", e);
+        }
+    });
+    tokio::spawn(async {
+        if let Err(e) = toggle_debug_on_signal().await {
+            error!("Failed to install SIGUSR2 handler: {}", e);
+        }
+    });
+    if let Some(max_idle) = config.max_idle_connections {
+        tokio::spawn(reap_idle_tunnels(config.clone(), max_idle));
+    }
+    if let Some(secs) = config.metrics_rollup_secs {
+        tokio::spawn(log_rollups(config.clone(), Duration::from_secs(secs)));
+    }
+    let live_config = Arc::new(RwLock::new(config.clone()));
+    tokio::spawn({
+        let live_config = live_config.clone();
+        async move {
+            if let Err(e) = reload_rules_on_signal(live_config).await {
+                error!("Failed to install SIGHUP handler: {}", e);
+            }
+        }
+    });
+    if config.config_watch {
+        tokio::spawn(watch_config_file(live_config.clone()));
+    }
+    if let Some(port) = config.health_port {
+        let listener = bind_health(&config, port).await?;
+        tokio::spawn(async move {
+            if let Err(e) = serve_health(listener).await {
+                error!("Health endpoint failed: {}", e);
+            }
+        });
+    }
+    if let Some(path) = config.admin_socket.clone() {
+        let live_config = live_config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_admin_socket(live_config, path).await {
+                error!("Admin socket failed: {}", e);
+            }
+        });
+    }
+    // Each connection holds a permit for its lifetime when max_connections is set
+    let connection_slots = config.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+
+    // Stop accepting on SIGTERM/SIGINT, give open connections the grace period to finish,
+    // then make sure queued log entries reach the sink
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
     loop {
-        let (client, peer_addr) = listener.accept().await?;
+        // In wait mode the slot is taken before accepting, so extra clients queue in the
+        // listen backlog instead of being turned away
+        let waited = match &connection_slots {
+            Some(slots) if config.max_connections_mode == "wait" => tokio::select! {
+                permit = slots.clone().acquire_owned() => Some(permit?),
+                _ = terminate.recv() => break,
+                _ = interrupt.recv() => break,
+            },
+            _ => None,
+        };
+        let (client, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        };
         let ca = ca.clone();
-        let config = config.clone();
+        let config = live_config.read().unwrap().clone();
+
+        let permit = match &connection_slots {
+            _ if waited.is_some() => waited,
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    warn!("Connection limit reached, turning away {}", peer_addr);
+                    tokio::spawn(reject_overloaded(client, config));
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        let conn_id = CONNECTIONS_TOTAL.fetch_add(1, Ordering::Relaxed) + 1;
+        CONNECTIONS_ACTIVE.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let started = Instant::now();
+            let stats = Arc::new(Mutex::new(ConnectionStats {
+                client_ip: Some(peer_addr.ip()),
+                ..Default::default()
+            }));
+            let handled = CONNECTION_STATS.scope(stats.clone(), handle_connection(client, ca, config.clone()));
+            if let Err(e) = handled.await {
+                let mut stats = stats.lock().unwrap();
+                log_connection_error(conn_id, peer_addr, &stats, &e);
+                stats.close_reason = Some("Error".to_string());
+            }
+            log_connection_summary(&config, conn_id, peer_addr, &stats.lock().unwrap(), started.elapsed());
+            statsd_timing("connection.duration", started.elapsed());
+            CONNECTIONS_ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    println!("Shutting down, {} connection(s) still active", CONNECTIONS_ACTIVE.load(Ordering::Relaxed));
+    // A second signal skips the rest of the grace period
+    let remaining = tokio::select! {
+        remaining = drain_connections(config.shutdown_grace()) => remaining,
+        _ = terminate.recv() => CONNECTIONS_ACTIVE.load(Ordering::Relaxed),
+        _ = interrupt.recv() => CONNECTIONS_ACTIVE.load(Ordering::Relaxed),
+    };
+    if remaining > 0 {
+        warn!("Closing {} connection(s) still active after the shutdown grace period", remaining);
+    }
+    flush_logs().await;
+    Ok(())
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use std::sync::OnceLock;
+    use tokio_rustls::TlsConnector;
+
+    /// Temp dir for the test's traffic log and rules file
+    fn test_dir() -> &'static std::path::PathBuf {
+        static DIR: OnceLock<std::path::PathBuf> = OnceLock::new();
+        DIR.get_or_init(|| {
+            let dir = std::env::temp_dir().join(format!("prison-network-tests-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            // Read once, by the first `paths()` call, so it has to be set before anything logs
+            std::env::set_var("PROXY_LOG", dir.join("traffic.jsonl"));
+            let _ = aws_lc_rs::default_provider().install_default();
+            dir
+        })
+    }
+
+    /// Traffic log lines mentioning `host`
+    fn logged_lines(host: &str) -> Vec<String> {
+        let needle = format!("\"host\":\"{}\"", host);
+        std::fs::read_to_string(&paths().log)
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| line.contains(&needle))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// An upstream on an ephemeral port that answers every TLS request with `hi`, or echoes
+    /// the body back when the request has one. An upgrade request gets `101` and everything
+    /// after it echoed. Its certificate comes from a CA of its own, so the proxy only reaches
+    /// it through `insecure_upstreams`.
+    async fn start_upstream(config: &Config) -> u16 {
+        let upstream_ca = Arc::new(CaAuthority::in_memory(config).unwrap());
+        let acceptor = TlsAcceptor::from(Arc::new(client_tls_config(&upstream_ca, "127.0.0.1", config).await.unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut tls = acceptor.accept(stream).await?;
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0u8; 1];
+                        if tls.read(&mut byte).await? == 0 {
+                            return Ok(());
+                        }
+                        head.push(byte[0]);
+                    }
+                    let head = String::from_utf8_lossy(&head).into_owned();
+                    let upgrade = head
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("upgrade"))
+                        .map(|(_, value)| value.trim().to_string());
+                    if let Some(protocol) = upgrade {
+                        let switching = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: {}\r\nConnection: Upgrade\r\n\r\n", protocol);
+                        tls.write_all(switching.as_bytes()).await?;
+                        let (mut from_client, mut to_client) = tokio::io::split(tls);
+                        tokio::io::copy(&mut from_client, &mut to_client).await?;
+                        return Ok(());
+                    }
+                    let length = head
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                        .map_or(0, |(_, value)| value.trim().parse().unwrap());
+                    let mut body = vec![0u8; length];
+                    tls.read_exact(&mut body).await?;
+                    if body.is_empty() {
+                        body = b"hi".to_vec();
+                    }
+                    let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                    tls.write_all(head.as_bytes()).await?;
+                    tls.write_all(&body).await?;
+                    tls.shutdown().await
+                });
+            }
+        });
+        port
+    }
+
+    /// The proxy on an ephemeral port, handling connections the way `main` does
+    async fn start_proxy(ca: Arc<CaAuthority>, config: Arc<Config>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(client, ca, config).await {
-                error!("Connection error from {}: {}", peer_addr, e);
+            while let Ok((client, peer_addr)) = listener.accept().await {
+                let stats = Arc::new(Mutex::new(ConnectionStats {
+                    client_ip: Some(peer_addr.ip()),
+                    ..Default::default()
+                }));
+                tokio::spawn(CONNECTION_STATS.scope(stats, handle_connection(client, ca.clone(), config.clone())));
             }
         });
+        port
+    }
+
+    /// Send a CONNECT for `target` and return the proxy's response head
+    async fn connect(proxy_port: u16, target: &str) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        stream
+            .write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target).as_bytes())
+            .await
+            .unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        (stream, String::from_utf8_lossy(&head).into_owned())
+    }
+
+    /// Load `rules` the way startup does and start an upstream and the proxy for them.
+    /// Returns the proxy's CA and the proxy and upstream ports.
+    async fn start_with_rules(rules: &str) -> (Arc<CaAuthority>, u16, u16) {
+        let path = test_dir().join(format!("rules-{}.json", std::thread::current().name().unwrap_or("test").replace("::", "-")));
+        std::fs::write(&path, rules).unwrap();
+        let config = load_config(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        config.validate().unwrap();
+        let config = Arc::new(config);
+        let ca = Arc::new(CaAuthority::in_memory(&config).unwrap());
+        let upstream_port = start_upstream(&config).await;
+        let proxy_port = start_proxy(ca.clone(), config).await;
+        (ca, proxy_port, upstream_port)
+    }
+
+    /// CONNECT to `target` and start TLS to `server_name`, trusting only the proxy's CA.
+    /// Returns the CONNECT response head and the TLS stream.
+    async fn tls_through_proxy(
+        ca: &CaAuthority,
+        proxy_port: u16,
+        target: &str,
+        server_name: &str,
+    ) -> (String, tokio_rustls::client::TlsStream<TcpStream>) {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.ca_der().clone()).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth(),
+        ));
+        let (stream, head) = connect(proxy_port, target).await;
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        (head, connector.connect(server_name, stream).await.unwrap())
+    }
+
+    /// CONNECT to `target`, then send `request` over TLS to `server_name`, trusting only the
+    /// proxy's CA. Returns the CONNECT response head and the raw response to the request.
+    async fn fetch(ca: &CaAuthority, proxy_port: u16, target: &str, server_name: &str, request: &str) -> (String, Vec<u8>) {
+        let (head, mut tls) = tls_through_proxy(ca, proxy_port, target, server_name).await;
+        tls.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        let _ = tls.read_to_end(&mut response).await;
+        (head, response)
+    }
+
+    #[tokio::test]
+    async fn connect_is_intercepted_or_blocked_by_the_rules() {
+        let (ca, proxy_port, upstream_port) = start_with_rules(
+            r#"{
+                "mode": "enforce",
+                "allowed_rules": [{ "host": "127.0.0.1", "allowed_paths": ["/allowed"] }],
+                "blocked_rules": [{ "host": "blocked.example.com" }],
+                "insecure_upstreams": ["127.0.0.1"]
+            }"#,
+        )
+        .await;
+
+        // A blocked host is refused at the CONNECT
+        let (_, head) = connect(proxy_port, "blocked.example.com:443").await;
+        assert!(head.starts_with("HTTP/1.1 403"), "{}", head);
+        let blocked = logged_lines("blocked.example.com");
+        assert!(blocked.iter().any(|line| line.contains("\"action\":\"BLOCK\"") && line.contains("\"reason\":\"Host Blocked\"")));
+
+        // An allowed host is intercepted with a leaf from the proxy's CA, then checked per request
+        let request = |path: &str| format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n", path);
+        let mut responses = Vec::new();
+        for path in ["/allowed", "/denied"] {
+            let target = format!("127.0.0.1:{}", upstream_port);
+            let (head, response) = fetch(&ca, proxy_port, &target, "127.0.0.1", &request(path)).await;
+            assert!(head.starts_with("HTTP/1.1 200 Connection Established"), "{}", head);
+            responses.push(String::from_utf8_lossy(&response).into_owned());
+        }
+        assert!(responses[0].starts_with("HTTP/1.1 200 OK") && responses[0].ends_with("hi"), "{}", responses[0]);
+        assert!(responses[1].starts_with("HTTP/1.1 403"), "{}", responses[1]);
+
+        let allowed = logged_lines("127.0.0.1");
+        let entry = |path: &str, action: &str| {
+            allowed
+                .iter()
+                .any(|line| line.contains(&format!("\"path\":\"{}\"", path)) && line.contains(&format!("\"action\":\"{}\"", action)))
+        };
+        assert!(entry("/allowed", "ALLOW"), "{:?}", allowed);
+        assert!(entry("/denied", "BLOCK"), "{:?}", allowed);
+    }
+
+    #[tokio::test]
+    async fn uniform_blocks_look_the_same_whatever_the_reason() {
+        let (ca, proxy_port, upstream_port) = start_with_rules(
+            r#"{
+                "mode": "enforce",
+                "uniform_block_response": true,
+                "uniform_block_delay_ms": 50,
+                "allowed_rules": [{ "host": "127.0.0.1", "allowed_paths": ["/allowed"], "allowed_methods": ["GET"] }],
+                "blocked_rules": [{ "host": "hidden.example.com", "block_style": "unreachable" }],
+                "insecure_upstreams": ["127.0.0.1"]
+            }"#,
+        )
+        .await;
+        let allowed_host = format!("127.0.0.1:{}", upstream_port);
+        let request = |method: &str, path: &str, host: &str| {
+            format!("{} {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n\r\n", method, path, host)
+        };
+
+        let started = std::time::Instant::now();
+        let unknown = fetch(&ca, proxy_port, "unknown.example.com:443", "unknown.example.com", &request("GET", "/", "unknown.example.com")).await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        let unreachable = fetch(&ca, proxy_port, "hidden.example.com:443", "hidden.example.com", &request("GET", "/", "hidden.example.com")).await;
+        let path = fetch(&ca, proxy_port, &allowed_host, "127.0.0.1", &request("GET", "/denied", "127.0.0.1")).await;
+        let method = fetch(&ca, proxy_port, &allowed_host, "127.0.0.1", &request("DELETE", "/allowed", "127.0.0.1")).await;
+
+        assert!(unknown.0.starts_with("HTTP/1.1 200 Connection Established"), "{}", unknown.0);
+        assert!(unknown.1.starts_with(b"HTTP/1.1 404 Not Found\r\n"), "{}", String::from_utf8_lossy(&unknown.1));
+        for other in [&unreachable, &path, &method] {
+            assert_eq!(other.0, unknown.0);
+            assert_eq!(other.1, unknown.1, "{}", String::from_utf8_lossy(&other.1));
+        }
+
+        // The log keeps the real reasons
+        let reasons = logged_lines("127.0.0.1");
+        assert!(reasons.iter().any(|line| line.contains("\"path\":\"/denied\"") && line.contains("Path Not Allowed")));
+        assert!(reasons.iter().any(|line| line.contains("\"method\":\"DELETE\"") && line.contains("Method Not Allowed")));
+        assert!(logged_lines("unknown.example.com").iter().any(|line| line.contains("Host Not Allowed")));
+        assert!(logged_lines("hidden.example.com").iter().any(|line| line.contains("Host Blocked")));
+    }
+
+    #[tokio::test]
+    async fn large_request_heads_and_bodies_are_forwarded_whole() {
+        let (ca, proxy_port, upstream_port) = start_with_rules(
+            r#"{
+                "mode": "enforce",
+                "allowed_rules": [{ "host": "127.0.0.1", "allowed_paths": ["/upload"] }],
+                "insecure_upstreams": ["127.0.0.1"]
+            }"#,
+        )
+        .await;
+        let body: String = (0..20_000).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+        let padding = "p".repeat(10_000);
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: 127.0.0.1\r\nX-Padding: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            padding,
+            body.len(),
+            body
+        );
+
+        let target = format!("127.0.0.1:{}", upstream_port);
+        let (_, response) = fetch(&ca, proxy_port, &target, "127.0.0.1", &request).await;
+        let response = String::from_utf8_lossy(&response);
+        let (head, echoed) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert_eq!(echoed.len(), body.len());
+        assert!(echoed == body, "body changed on the way through");
+    }
+
+    #[tokio::test]
+    async fn websocket_tunnels_outlive_the_plain_idle_timeout() {
+        let (ca, proxy_port, upstream_port) = start_with_rules(
+            r#"{
+                "mode": "enforce",
+                "idle_timeout_ms": 300,
+                "websocket_idle_timeout_ms": 5000,
+                "allowed_rules": [{ "host": "127.0.0.1", "allowed_paths": ["/ws", "/plain"] }],
+                "insecure_upstreams": ["127.0.0.1"]
+            }"#,
+        )
+        .await;
+        let target = format!("127.0.0.1:{}", upstream_port);
+        async fn read_head(tls: &mut tokio_rustls::client::TlsStream<TcpStream>) -> String {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8; 1];
+                if tls.read(&mut byte).await.unwrap() == 0 {
+                    break;
+                }
+                head.push(byte[0]);
+            }
+            String::from_utf8(head).unwrap()
+        }
+
+        let (_, mut ws) = tls_through_proxy(&ca, proxy_port, &target, "127.0.0.1").await;
+        ws.write_all(b"GET /ws HTTP/1.1\r\nHost: 127.0.0.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut ws).await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        for message in [b"first", b"later"] {
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            ws.write_all(message).await.unwrap();
+            let mut echoed = [0u8; 5];
+            ws.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(&echoed, message);
+        }
+
+        // The same quiet spell closes a tunnel upgraded to anything else
+        let (_, mut other) = tls_through_proxy(&ca, proxy_port, &target, "127.0.0.1").await;
+        other
+            .write_all(b"GET /plain HTTP/1.1\r\nHost: 127.0.0.1\r\nUpgrade: echo\r\nConnection: Upgrade\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_head(&mut other).await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        let started = std::time::Instant::now();
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(3), other.read_to_end(&mut rest)).await;
+        assert!(closed.is_ok(), "upgraded tunnel outlived idle_timeout_ms");
+        assert!(started.elapsed() >= Duration::from_millis(250), "closed after {:?}", started.elapsed());
     }
 }
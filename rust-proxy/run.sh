@@ -12,7 +12,7 @@ LOGS_DIR="${SCRIPT_DIR}/../logs"
 mkdir -p "$LOGS_DIR"
 
 echo "🔨 Building Secure Proxy (Rust)..."
-docker build -t $IMAGE_NAME "$SCRIPT_DIR"
+docker build -t $IMAGE_NAME -f "$SCRIPT_DIR/Dockerfile" "$SCRIPT_DIR/.."
 
 echo ""
 echo "🚀 Starting Container in Interactive Mode..."
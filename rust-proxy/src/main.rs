@@ -2,441 +2,633 @@
 //!
 //! Transparent MITM proxy that intercepts HTTPS traffic and enforces allow/block rules.
 
-use anyhow::Result;
-use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair, Certificate};
+use anyhow::{anyhow, bail, Result};
 use rustls::crypto::aws_lc_rs;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-use rustls::ServerConfig;
-use serde::Deserialize;
+use secure_proxy_core::*;
 use std::{
-    fs::{self, OpenOptions},
-    io::Write,
-    net::SocketAddr,
     path::Path,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::{TlsAcceptor, TlsConnector};
-use tracing::{info, error, Level};
-use tracing_subscriber::FmtSubscriber;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, warn, Instrument};
 
 // ============================================================================
-// Configuration
+// Connection Handler
 // ============================================================================
 
-#[derive(Debug, Clone, Deserialize)]
-struct HostRule {
-    host: String,
-    #[serde(default)]
-    allowed_paths: Vec<String>,
-}
+async fn handle_connection(
+    mut client: TcpStream,
+    ca: Arc<CaAuthority>,
+    config: Arc<Config>,
+) -> Result<()> {
+    // Peek the whole ClientHello to parse SNI
+    let hello = peek_client_hello(&client).await?;
 
-#[derive(Debug, Clone, Deserialize)]
-struct Config {
-    #[serde(default = "default_mode")]
-    mode: String,
-    #[serde(default)]
-    allowed_rules: Vec<HostRule>,
-}
+    if config.debug_clienthello {
+        log_client_hello(&hello);
+    }
+    
+    let hostname = match parse_sni(&hello) {
+        Some(h) => h,
+        // A plain HTTP client sent here by mistake gets told why instead of a bare close
+        None if is_plaintext_http(&hello) => {
+            let line = String::from_utf8_lossy(&hello);
+            let mut request_line = line.split_whitespace();
+            let method = request_line.next().unwrap_or("?");
+            let path = request_line.next().unwrap_or("/");
+            log_traffic(&config, "BLOCK", "unknown", path, method, "Plaintext HTTP", &[]);
+            print_decision(&config, format_args!("⛔ [{}] {} {} -> Plaintext HTTP", config.mode, method, path));
+            // Take the request off the socket first, or closing with it unread resets the connection
+            let _ = client.read(&mut vec![0u8; hello.len()]).await?;
+            let body = "Plaintext HTTP is not supported on this port; use HTTPS\n";
+            let response = format!(
+                "HTTP/1.1 400 Bad Request\r\n\
+                 Content-Type: text/plain\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n\
+                 {}",
+                body.len(),
+                body
+            );
+            client.write_all(response.as_bytes()).await?;
+            let _ = client.shutdown().await;
+            return Ok(());
+        }
+        None => {
+            error!("Failed to parse SNI");
+            return Ok(());
+        }
+    };
 
-fn default_mode() -> String {
-    "monitor".to_string()
+    note_connection(|stats| stats.host = Some(truncate_hostname(&hostname, config.max_hostname_len).to_string()));
+    let span = connection_span(truncate_hostname(&hostname, config.max_hostname_len));
+    serve_connection(client, ca, config, hostname).instrument(span).await
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            mode: "monitor".to_string(),
-            allowed_rules: vec![],
-        }
-    }
+/// Destination port of a connection that iptables redirected to the proxy, read with
+/// `SO_ORIGINAL_DST`. `None` when the client connected to the proxy directly.
+#[cfg(target_os = "linux")]
+fn original_dst_port(client: &TcpStream) -> Option<u16> {
+    let socket = socket2::SockRef::from(client);
+    let original = socket
+        .original_dst_v4()
+        .or_else(|_| socket.original_dst_v6())
+        .ok()?
+        .as_socket()?;
+    // Without a NAT rule, conntrack reports the proxy's own address
+    (Some(original) != client.local_addr().ok()).then_some(original.port())
 }
 
-// ============================================================================
-// Logging
-// ============================================================================
-
-fn log_traffic(action: &str, host: &str, path: &str, method: &str, mode: &str, reason: &str) {
-    let log_path = "/logs/traffic.jsonl";
-    if let Some(parent) = Path::new(log_path).parent() {
-        let _ = fs::create_dir_all(parent);
-    }
-    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(log_path) {
-        let entry = serde_json::json!({
-            "action": action,
-            "host": host,
-            "path": path,
-            "method": method,
-            "mode": mode,
-            "reason": reason
-        });
-        let _ = writeln!(file, "{}", entry);
-    }
+/// `SO_ORIGINAL_DST` is Linux-only; elsewhere `default_upstream_port` is always used
+#[cfg(not(target_os = "linux"))]
+fn original_dst_port(_client: &TcpStream) -> Option<u16> {
+    None
 }
 
-// ============================================================================
-// Security Check
-// ============================================================================
+/// Everything after the target host is known, inside the connection's tracing span
+async fn serve_connection(
+    client: TcpStream,
+    ca: Arc<CaAuthority>,
+    config: Arc<Config>,
+    hostname: String,
+) -> Result<()> {
 
-/// Check if a host is allowed (for CONNECT-level checks, ignores path rules)
-fn check_host_allowed(config: &Config, host: &str) -> (bool, String) {
-    if config.mode != "enforce" {
-        return (true, "Monitor Mode".to_string());
+    // Reject absurdly long names before they reach matching, logging or cert generation
+    if hostname.len() > config.max_hostname_len {
+        let logged = truncate_hostname(&hostname, config.max_hostname_len);
+        log_traffic(&config, "BLOCK", logged, "/", "CONNECT", "Hostname Too Long", &[]);
+        print_decision(&config, format_args!("⛔ [{}] CONNECT {}… -> Hostname Too Long", config.mode, logged));
+        return Ok(());
     }
 
-    let host_rule = config.allowed_rules.iter().find(|rule| {
-        host == rule.host || host.ends_with(&format!(".{}", rule.host))
-    });
-
-    match host_rule {
-        None => (false, "Host Not Allowed".to_string()),
-        Some(_) => (true, "Host Allowed".to_string()),
+    // The SNI goes into rule matching, cert generation and the upstream dial, so anything
+    // that isn't a plain DNS name (whitespace, a port, a path, NUL bytes) stops here
+    if !is_valid_sni(&hostname) {
+        log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", "Invalid SNI", &[]);
+        print_decision(&config, format_args!("⛔ [{}] CONNECT {:?} -> Invalid SNI", config.mode, hostname));
+        return Ok(());
     }
-}
 
-/// Check if a request (host + path) is allowed
-fn check_request(config: &Config, host: &str, path: &str) -> (bool, String) {
-    if config.mode != "enforce" {
-        return (true, "Monitor Mode".to_string());
+    // Without interception there is nowhere to put a 503, so maintenance just closes the connection
+    if (config.audit_only || never_intercepted(&config, &hostname)) && in_maintenance(&config, &hostname) {
+        log_traffic(&config, "MAINTENANCE", &hostname, "/", "CONNECT", "Maintenance Mode", &[]);
+        print_decision(&config, format_args!("🚧 [{}] CONNECT {} -> Maintenance Mode", config.mode, hostname));
+        return Ok(());
     }
 
-    let host_rule = config.allowed_rules.iter().find(|rule| {
-        host == rule.host || host.ends_with(&format!(".{}", rule.host))
-    });
-
-    match host_rule {
-        None => (false, "Host Not Allowed".to_string()),
-        Some(rule) => {
-            if rule.allowed_paths.is_empty() {
-                return (true, "Host Match".to_string());
-            }
-            let path_match = rule.allowed_paths.iter().any(|p| path.starts_with(p));
-            if path_match {
-                (true, "Path Match".to_string())
-            } else {
-                (false, "Path Not Allowed".to_string())
+    // Check if host is allowed (for CONNECT-level blocking)
+    let decision = evaluate(&config, &hostname, "/", "CONNECT");
+    let reason = &decision.reason;
+
+    if !decision.allowed() {
+        log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", reason, &[]);
+        print_decision(&config, format_args!("⛔ [{}] CONNECT {} -> {}", config.mode, hostname, reason));
+        // Finish the handshake so the client gets the block response rather than a dropped
+        // connection it can't tell from a network failure
+        if blocks_inside_tls(&config, &hostname) {
+            // Stands in for the upstream connect an allowed host waits on
+            if config.uniform_block_response {
+                tokio::time::sleep(config.uniform_block_delay()).await;
             }
+            return serve_blocked_host(client, &ca, &hostname, &config, reason).await;
         }
+        // Otherwise close the connection, with a reset for the unreachable style
+        if blocks_as_unreachable(&config, &hostname) {
+            client.set_zero_linger()?;
+        }
+        return Ok(());
     }
-}
-
-// ============================================================================
-// SNI Parsing
-// ============================================================================
-
-fn parse_sni(buf: &[u8]) -> Option<String> {
-    // TLS record: ContentType(1) + Version(2) + Length(2) + Handshake
-    if buf.len() < 5 || buf[0] != 0x16 {
-        return None; // Not a TLS handshake
-    }
-
-    let record_len = ((buf[3] as usize) << 8) | (buf[4] as usize);
-    if buf.len() < 5 + record_len {
-        return None;
-    }
-
-    let handshake = &buf[5..];
-    if handshake.is_empty() || handshake[0] != 0x01 {
-        return None; // Not ClientHello
-    }
-
-    // Skip handshake header (1 + 3 bytes length)
-    if handshake.len() < 4 {
-        return None;
-    }
-    let hello_len = ((handshake[1] as usize) << 16)
-        | ((handshake[2] as usize) << 8)
-        | (handshake[3] as usize);
-    
-    if handshake.len() < 4 + hello_len {
-        return None;
-    }
-
-    let hello = &handshake[4..];
-    
-    // Skip client version (2) + random (32) = 34 bytes
-    if hello.len() < 34 {
-        return None;
-    }
-    let mut pos = 34;
-
-    // Skip session ID
-    if pos >= hello.len() {
-        return None;
-    }
-    let session_len = hello[pos] as usize;
-    pos += 1 + session_len;
-
-    // Skip cipher suites
-    if pos + 2 > hello.len() {
-        return None;
-    }
-    let cipher_len = ((hello[pos] as usize) << 8) | (hello[pos + 1] as usize);
-    pos += 2 + cipher_len;
-
-    // Skip compression methods
-    if pos >= hello.len() {
-        return None;
-    }
-    let comp_len = hello[pos] as usize;
-    pos += 1 + comp_len;
-
-    // Extensions
-    if pos + 2 > hello.len() {
-        return None;
-    }
-    let ext_len = ((hello[pos] as usize) << 8) | (hello[pos + 1] as usize);
-    pos += 2;
 
-    let ext_end = pos + ext_len;
-    while pos + 4 <= ext_end && pos + 4 <= hello.len() {
-        let ext_type = ((hello[pos] as u16) << 8) | (hello[pos + 1] as u16);
-        let ext_data_len = ((hello[pos + 2] as usize) << 8) | (hello[pos + 3] as usize);
-        pos += 4;
+    // Connect to upstream, on the port the client was originally headed for
+    let timeouts = config.timeouts_for(&hostname);
+    let port = original_dst_port(&client).unwrap_or(config.default_upstream_port);
+    let upstream_addr = upstream_dial_addr(find_host_rule(&config, &hostname), &hostname, port);
+    let connecting = with_deadline(timeouts.connect, connect_upstream(&config, &upstream_addr));
+    let Some(connected) = unless_client_gone(&client, &hostname, connecting).await else {
+        return Ok(());
+    };
+    let upstream = match connected {
+        Ok(s) => Some(s),
+        // Hosts with static responses can still be answered when the upstream is unreachable
+        Err(e) if !config.audit_only && !never_intercepted(&config, &hostname) && has_static_responses(&config, &hostname) => {
+            debug!("Upstream {} unreachable ({}), serving static responses only", upstream_addr, e);
+            None
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+            let reason = "Upstream Timeout";
+            log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", reason, &[]);
+            print_decision(&config, format_args!("⛔ [{}] CONNECT {} -> {}", config.mode, hostname, reason));
+            return refuse_uniformly(client, &ca, &config, &hostname, reason).await;
+        }
+        Err(e) => return Err(e.into()),
+    };
 
-        if ext_type == 0 {
-            // SNI extension
-            if pos + ext_data_len > hello.len() {
-                return None;
-            }
-            let sni_data = &hello[pos..pos + ext_data_len];
-            // SNI list length (2) + type (1) + name length (2) + name
-            if sni_data.len() < 5 {
-                return None;
-            }
-            let name_len = ((sni_data[3] as usize) << 8) | (sni_data[4] as usize);
-            if sni_data.len() < 5 + name_len {
-                return None;
-            }
-            return String::from_utf8(sni_data[5..5 + name_len].to_vec()).ok();
+    // Check who owns the address the upstream resolved to
+    if let Some(Ok(upstream_peer)) = upstream.as_ref().map(TcpStream::peer_addr) {
+        let (asn_allowed, reason) = check_upstream_asn(&config, upstream_peer.ip());
+        if !asn_allowed {
+            log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", &reason, &[]);
+            print_decision(&config, format_args!("⛔ [{}] CONNECT {} ({}) -> {}", config.mode, hostname, upstream_peer.ip(), reason));
+            return refuse_uniformly(client, &ca, &config, &hostname, &reason).await;
         }
-        pos += ext_data_len;
     }
-    None
-}
-
-// ============================================================================
-// Certificate Authority
-// ============================================================================
-
-struct CaAuthority {
-    ca_key: KeyPair,
-    ca_cert: Certificate,
-}
-
-impl CaAuthority {
-    fn new() -> Result<Self> {
-        let ca_cert_path = "/ca/certs/ca.pem";
-        let ca_key_path = "/ca/keys/ca.private.key";
-
-        fs::create_dir_all("/ca/certs")?;
-        fs::create_dir_all("/ca/keys")?;
-
-        info!("Generating CA certificate...");
-
-        let mut params = CertificateParams::default();
-        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
-        let mut dn = DistinguishedName::new();
-        dn.push(DnType::CommonName, "Secure Proxy CA");
-        dn.push(DnType::OrganizationName, "Secure Proxy");
-        params.distinguished_name = dn;
-
-        let key_pair = KeyPair::generate()?;
-        let cert = params.self_signed(&key_pair)?;
 
-        fs::write(ca_cert_path, cert.pem())?;
-        fs::write(ca_key_path, key_pair.serialize_pem())?;
-
-        info!("CA saved to {}", ca_cert_path);
-
-        Ok(Self {
-            ca_key: key_pair,
-            ca_cert: cert,
-        })
+    // Audit-only deployments record the host-level decision and never terminate TLS
+    if config.audit_only {
+        let mut fields = vec![("audit_only", "true")];
+        fields.extend(decision.rule_host(&config).map(|rule| ("rule", rule)));
+        log_traffic(&config, "ALLOW", &hostname, "/", "CONNECT", reason, &fields);
+        print_decision(&config, format_args!("✅ [{}] CONNECT {} -> {} (audit only)", config.mode, hostname, reason));
+        let upstream = upstream.ok_or_else(|| anyhow!("No upstream connection to {}", hostname))?;
+        return tunnel(client, upstream, &config, &hostname, timeouts.idle, None, None).await;
     }
 
-    fn generate_cert_for_host(&self, hostname: &str) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
-        let mut params = CertificateParams::new(vec![hostname.to_string()])?;
-        let mut dn = DistinguishedName::new();
-        dn.push(DnType::CommonName, hostname);
-        params.distinguished_name = dn;
-
-        let key_pair = KeyPair::generate()?;
-        let cert = params.signed_by(&key_pair, &self.ca_cert, &self.ca_key)?;
-
-        let cert_der = CertificateDer::from(cert.der().to_vec());
-        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
-
-        Ok((vec![cert_der], key_der))
+    // Hosts on the global no-interception list are tunneled as-is once allowed
+    if never_intercepted(&config, &hostname) {
+        let mut fields = vec![("intercepted", "false")];
+        fields.extend(decision.rule_host(&config).map(|rule| ("rule", rule)));
+        log_traffic(&config, "ALLOW", &hostname, "/", "CONNECT", reason, &fields);
+        print_decision(&config, format_args!("✅ [{}] CONNECT {} -> {} (not intercepted)", config.mode, hostname, reason));
+        let upstream = upstream.ok_or_else(|| anyhow!("No upstream connection to {}", hostname))?;
+        return tunnel(client, upstream, &config, &hostname, timeouts.idle, None, None).await;
     }
-}
-
-// ============================================================================
-// Connection Handler
-// ============================================================================
 
-async fn handle_connection(
-    mut client: TcpStream,
-    ca: Arc<CaAuthority>,
-    config: Arc<Config>,
-) -> Result<()> {
-    // Read initial data to parse SNI
-    let mut buf = vec![0u8; 4096];
-    let n = client.peek(&mut buf).await?;
-    
-    let hostname = match parse_sni(&buf[..n]) {
-        Some(h) => h,
-        None => {
-            error!("Failed to parse SNI");
+    // Generate certificate for this host and create the client-facing TLS config
+    let Some(server_config) = unless_client_gone(&client, &hostname, client_tls_config(&ca, &hostname, &config)).await else {
+        return Ok(());
+    };
+    let server_config = match server_config {
+        Ok(server_config) => server_config,
+        Err(e) if e.is::<CertGenSaturated>() => {
+            let reason = "Cert Gen Saturated";
+            log_traffic(&config, "BLOCK", &hostname, "/", "CONNECT", reason, &[]);
+            print_decision(&config, format_args!("⛔ [{}] CONNECT {} -> {}", config.mode, hostname, reason));
+            // Nothing can be sent before the TLS handshake, so just close
             return Ok(());
         }
+        Err(e) => return Err(e),
     };
-
-    // Check if host is allowed (for CONNECT-level blocking)
-    let (host_allowed, reason) = check_host_allowed(&config, &hostname);
-    
-    if !host_allowed {
-        log_traffic("BLOCK", &hostname, "/", "CONNECT", &config.mode, &reason);
-        println!("⛔ [{}] CONNECT {} -> {}", config.mode, hostname, reason);
-        // Close connection immediately for blocked hosts
-        return Ok(());
-    }
-
-    // Generate certificate for this host
-    let (certs, key) = ca.generate_cert_for_host(&hostname)?;
-
-    // Create TLS config for client-facing connection
-    let server_config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)?;
-    
     let acceptor = TlsAcceptor::from(Arc::new(server_config));
 
     // Accept TLS from client
-    let mut client_tls = acceptor.accept(client).await?;
-
-    // Connect to upstream
-    let upstream_addr = format!("{}:443", hostname);
-    let upstream = TcpStream::connect(&upstream_addr).await?;
-
-    // Create TLS connection to upstream
-    let connector = TlsConnector::from(Arc::new(
-        rustls::ClientConfig::builder()
-            .with_root_certificates(rustls::RootCertStore::from_iter(
-                webpki_roots::TLS_SERVER_ROOTS.iter().cloned()
-            ))
-            .with_no_client_auth()
-    ));
-
-    let server_name = hostname.clone().try_into()?;
-    let mut upstream_tls = connector.connect(server_name, upstream).await?;
-
-    // Now we have decrypted streams. Read HTTP request.
-    let mut request_buf = vec![0u8; 8192];
-    let n = client_tls.read(&mut request_buf).await?;
-    let request_data = &request_buf[..n];
-
-    // Parse HTTP request line
-    let request_str = String::from_utf8_lossy(request_data);
-    let first_line = request_str.lines().next().unwrap_or("");
-    let parts: Vec<&str> = first_line.split_whitespace().collect();
-    let (method, path) = if parts.len() >= 2 {
-        (parts[0], parts[1])
-    } else {
-        ("?", "/")
-    };
-
-    // Check path-level rules
-    let (allowed, reason) = check_request(&config, &hostname, path);
-    let action = if allowed { "ALLOW" } else { "BLOCK" };
-    log_traffic(action, &hostname, path, method, &config.mode, &reason);
-
-    let icon = if allowed { "✅" } else { "⛔" };
-    println!("{} [{}] {} {}{} -> {}", icon, config.mode, method, hostname, path, reason);
-
-    if !allowed {
-        // Send 403 response
-        let response = format!(
-            "HTTP/1.1 403 Forbidden\r\n\
-             Content-Type: text/plain\r\n\
-             Content-Length: 24\r\n\
-             Connection: close\r\n\r\n\
-             Blocked by Secure Proxy"
-        );
-        client_tls.write_all(response.as_bytes()).await?;
+    let Some(client_tls) = accept_client_tls(acceptor, client, &hostname, &config, timeouts.handshake).await? else {
         return Ok(());
-    }
-
-    // Forward request to upstream
-    upstream_tls.write_all(request_data).await?;
-
-    // Bidirectional copy
-    let (mut client_read, mut client_write) = tokio::io::split(client_tls);
-    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream_tls);
+    };
 
-    let client_to_upstream = tokio::io::copy(&mut client_read, &mut upstream_write);
-    let upstream_to_client = tokio::io::copy(&mut upstream_read, &mut client_write);
+    intercept_session(client_tls, upstream, &hostname, &config, timeouts).await
+}
 
-    tokio::select! {
-        _ = client_to_upstream => {},
-        _ = upstream_to_client => {},
+/// Close a connection refused after its upstream was dialed, or with `uniform_block_response`
+/// answer its request inside TLS like every other block
+async fn refuse_uniformly(client: TcpStream, ca: &Arc<CaAuthority>, config: &Arc<Config>, hostname: &str, reason: &str) -> Result<()> {
+    if config.uniform_block_response && blocks_inside_tls(config, hostname) {
+        return serve_blocked_host(client, ca, hostname, config, reason).await;
     }
-
     Ok(())
 }
 
+/// Turn away a connection that arrived while the proxy is at `max_connections`.
+/// There's no way to answer before the TLS handshake, so the socket is just closed.
+async fn reject_overloaded(client: TcpStream, config: Arc<Config>) {
+    log_traffic(&config, "BLOCK", "unknown", "/", "CONNECT", "Overloaded", &[]);
+    drop(client);
+}
+
 // ============================================================================
 // Main
 // ============================================================================
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .with_target(false)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
-
-    println!("🔧 Initializing Secure Proxy (Rust Edition)...");
-
     // Install the crypto provider globally
     aws_lc_rs::default_provider()
         .install_default()
         .expect("Failed to install crypto provider");
 
+    if let Some(path) = cli_option("--check-config") {
+        return check_config(&path);
+    }
+
     // Load config
-    let config_path = "/config/rules.json";
-    let config: Config = if Path::new(config_path).exists() {
-        let content = fs::read_to_string(config_path)?;
-        serde_json::from_str(&content)?
+    let config: Config = if Path::new(&paths().config).exists() {
+        if let Some(profile) = selected_profile() {
+            println!("[Config] Using profile {}", profile);
+        }
+        load_config(&paths().config)?
     } else {
         println!("[Config] No config found, using MONITOR mode");
         Config::default()
     };
+    // Stealth deployments skip the banners that would fingerprint the proxy
+    let banners = !config.stealth_mode;
+    if banners {
+        println!("🔧 Initializing Secure Proxy (Rust Edition)...");
+    }
     println!("[Config] Loaded mode: {}", config.mode.to_uppercase());
+    if config.audit_only {
+        println!("[Config] Audit-only: TLS is never intercepted, path-level rules are not applied");
+    }
+    if config.maintenance_mode {
+        println!("[Config] Starting in maintenance mode");
+        MAINTENANCE_MODE.store(true, Ordering::Relaxed);
+    }
+    config.validate()?;
+
+    if let Some(host) = cli_option("--trace-host") {
+        if !is_valid_hostname(&host) {
+            bail!("--trace-host {:?} is not a valid hostname", host);
+        }
+        println!("[Config] Tracing connections to {}", host);
+        let _ = TRACE_HOST.set(host);
+    }
+    init_logging(&config)?;
+
+    if let Some(count) = cli_option("--bench-certgen") {
+        let count = count.parse().map_err(|_| anyhow!("--bench-certgen expects a number, got {:?}", count))?;
+        return bench_certgen(&config, count);
+    }
+
+    if !config.connect_passthrough_hosts.is_empty() {
+        warn!("[Config] connect_passthrough_hosts only applies to the CONNECT edition; ignoring");
+    }
+    if config.require_sni_matches_connect {
+        warn!("[Config] require_sni_matches_connect only applies to the CONNECT edition; ignoring");
+    }
+    if config.overload_retry_after_secs.is_some() {
+        warn!("[Config] overload_retry_after_secs only applies to the CONNECT edition; ignoring");
+    }
+    if let Some(path) = &config.client_ca_file {
+        init_client_ca(path)?;
+        println!("[Config] Requesting client certificates issued by {}", path);
+    }
+    if let Some(path) = &config.asn_db_path {
+        init_asn_db(path)?;
+        println!("[Config] Loaded ASN database from {}", path);
+    }
+    if let Some(addr) = &config.statsd_addr {
+        init_statsd(addr)?;
+        println!("[Config] Sending metrics to StatsD at {}", addr);
+    }
     let config = Arc::new(config);
+    init_log_writer(config.clone())?;
 
     // Setup CA
-    let ca = Arc::new(CaAuthority::new()?);
-    println!("🔒 CA Certificate ready");
+    let ca = Arc::new(CaAuthority::new(&config)?);
+    if banners {
+        println!("🔒 CA Certificate ready");
+    }
 
     // Create listener
-    let addr = SocketAddr::from(([0, 0, 0, 0], 58080));
+    let addr = config.listen_addr()?;
     let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
 
-    println!("🛡️  Secure Proxy listening on 0.0.0.0:58080");
-    println!("✅ Environment Ready.");
+    if banners {
+        println!("🛡️  Secure Proxy listening on {}", addr);
+        println!("✅ Environment Ready.");
+    } else {
+        println!("Listening on {}", addr);
+    }
 
+    tokio::spawn(async {
+        if let Err(e) = dump_metrics_on_signal().await {
+            error!("Failed to install SIGUSR1 handler: {}", e);
+        }
+    });
+    tokio::spawn(async {
+        if let Err(e) = toggle_debug_on_signal().await {
+            error!("Failed to install SIGUSR2 handler: {}", e);
+        }
+    });
+    if let Some(max_idle) = config.max_idle_connections {
+        tokio::spawn(reap_idle_tunnels(config.clone(), max_idle));
+    }
+    if let Some(secs) = config.metrics_rollup_secs {
+        tokio::spawn(log_rollups(config.clone(), Duration::from_secs(secs)));
+    }
+    let live_config = Arc::new(RwLock::new(config.clone()));
+    tokio::spawn({
+        let live_config = live_config.clone();
+        async move {
+            if let Err(e) = reload_rules_on_signal(live_config).await {
+                error!("Failed to install SIGHUP handler: {}", e);
+            }
+        }
+    });
+    if config.config_watch {
+        tokio::spawn(watch_config_file(live_config.clone()));
+    }
+    if let Some(port) = config.health_port {
+        let listener = bind_health(&config, port).await?;
+        tokio::spawn(async move {
+            if let Err(e) = serve_health(listener).await {
+                error!("Health endpoint failed: {}", e);
+            }
+        });
+    }
+    if let Some(path) = config.admin_socket.clone() {
+        let live_config = live_config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_admin_socket(live_config, path).await {
+                error!("Admin socket failed: {}", e);
+            }
+        });
+    }
+    // Each connection holds a permit for its lifetime when max_connections is set
+    let connection_slots = config.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+
+    // Stop accepting on SIGTERM/SIGINT, give open connections the grace period to finish,
+    // then make sure queued log entries reach the sink
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
     loop {
-        let (client, peer_addr) = listener.accept().await?;
+        // In wait mode the slot is taken before accepting, so extra clients queue in the
+        // listen backlog instead of being turned away
+        let waited = match &connection_slots {
+            Some(slots) if config.max_connections_mode == "wait" => tokio::select! {
+                permit = slots.clone().acquire_owned() => Some(permit?),
+                _ = terminate.recv() => break,
+                _ = interrupt.recv() => break,
+            },
+            _ => None,
+        };
+        let (client, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        };
         let ca = ca.clone();
-        let config = config.clone();
+        let config = live_config.read().unwrap().clone();
+
+        let permit = match &connection_slots {
+            _ if waited.is_some() => waited,
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    warn!("Connection limit reached, turning away {}", peer_addr);
+                    tokio::spawn(reject_overloaded(client, config));
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        let conn_id = CONNECTIONS_TOTAL.fetch_add(1, Ordering::Relaxed) + 1;
+        CONNECTIONS_ACTIVE.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let started = Instant::now();
+            let stats = Arc::new(Mutex::new(ConnectionStats {
+                client_ip: Some(peer_addr.ip()),
+                ..Default::default()
+            }));
+            let handled = CONNECTION_STATS.scope(stats.clone(), handle_connection(client, ca, config.clone()));
+            if let Err(e) = handled.await {
+                let mut stats = stats.lock().unwrap();
+                log_connection_error(conn_id, peer_addr, &stats, &e);
+                stats.close_reason = Some("Error".to_string());
+            }
+            log_connection_summary(&config, conn_id, peer_addr, &stats.lock().unwrap(), started.elapsed());
+            statsd_timing("connection.duration", started.elapsed());
+            CONNECTIONS_ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
 
+    println!("Shutting down, {} connection(s) still active", CONNECTIONS_ACTIVE.load(Ordering::Relaxed));
+    // A second signal skips the rest of the grace period
+    let remaining = tokio::select! {
+        remaining = drain_connections(config.shutdown_grace()) => remaining,
+        _ = terminate.recv() => CONNECTIONS_ACTIVE.load(Ordering::Relaxed),
+        _ = interrupt.recv() => CONNECTIONS_ACTIVE.load(Ordering::Relaxed),
+    };
+    if remaining > 0 {
+        warn!("Closing {} connection(s) still active after the shutdown grace period", remaining);
+    }
+    flush_logs().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use std::sync::OnceLock;
+    use tokio_rustls::TlsConnector;
+
+    /// Temp dir for the test's traffic log and rules file
+    fn test_dir() -> &'static std::path::PathBuf {
+        static DIR: OnceLock<std::path::PathBuf> = OnceLock::new();
+        DIR.get_or_init(|| {
+            let dir = std::env::temp_dir().join(format!("rust-proxy-tests-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            // Read once, by the first `paths()` call, so it has to be set before anything logs
+            std::env::set_var("PROXY_LOG", dir.join("traffic.jsonl"));
+            let _ = aws_lc_rs::default_provider().install_default();
+            dir
+        })
+    }
+
+    /// Traffic log lines mentioning `host`
+    fn logged_lines(host: &str) -> Vec<String> {
+        let needle = format!("\"host\":\"{}\"", host);
+        std::fs::read_to_string(&paths().log)
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| line.contains(&needle))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// An upstream on an ephemeral port that answers every TLS request with `hi`. Its
+    /// certificate comes from a CA of its own, so the proxy only reaches it through
+    /// `insecure_upstreams`.
+    async fn start_upstream(config: &Config) -> u16 {
+        let upstream_ca = Arc::new(CaAuthority::in_memory(config).unwrap());
+        let acceptor = TlsAcceptor::from(Arc::new(client_tls_config(&upstream_ca, "127.0.0.1", config).await.unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(client, ca, config).await {
-                error!("Connection error from {}: {}", peer_addr, e);
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut tls = acceptor.accept(stream).await?;
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0u8; 1];
+                        if tls.read(&mut byte).await? == 0 {
+                            return Ok(());
+                        }
+                        head.push(byte[0]);
+                    }
+                    tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi").await?;
+                    tls.shutdown().await
+                });
             }
         });
+        port
+    }
+
+    /// The proxy on an ephemeral port, handling connections the way `main` does. Clients
+    /// connect to it directly, so `SO_ORIGINAL_DST` finds no redirect and every upstream is
+    /// dialed on `default_upstream_port`.
+    async fn start_proxy(ca: Arc<CaAuthority>, config: Arc<Config>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((client, peer_addr)) = listener.accept().await {
+                let stats = Arc::new(Mutex::new(ConnectionStats {
+                    client_ip: Some(peer_addr.ip()),
+                    ..Default::default()
+                }));
+                tokio::spawn(CONNECTION_STATS.scope(stats, handle_connection(client, ca.clone(), config.clone())));
+            }
+        });
+        port
+    }
+
+    /// Load `rules` the way startup does, with `{upstream_port}` filled in, and start an
+    /// upstream and the proxy for them. Returns the proxy's CA and port.
+    async fn start_with_rules(rules: &str) -> (Arc<CaAuthority>, u16) {
+        let path = test_dir().join(format!("rules-{}.json", std::thread::current().name().unwrap_or("test").replace("::", "-")));
+        let upstream_port = start_upstream(&Config::default()).await;
+        std::fs::write(&path, rules.replace("{upstream_port}", &upstream_port.to_string())).unwrap();
+        let config = load_config(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        config.validate().unwrap();
+        let config = Arc::new(config);
+        let ca = Arc::new(CaAuthority::in_memory(&config).unwrap());
+        let proxy_port = start_proxy(ca.clone(), config).await;
+        (ca, proxy_port)
+    }
+
+    /// Start TLS to the proxy with `server_name` as the SNI, trusting only the proxy's CA,
+    /// then send `request` and return the raw response
+    async fn fetch(ca: &CaAuthority, proxy_port: u16, server_name: &str, request: &str) -> std::io::Result<Vec<u8>> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.ca_der().clone()).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth(),
+        ));
+        let stream = TcpStream::connect(("127.0.0.1", proxy_port)).await?;
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        let mut tls = connector.connect(server_name, stream).await?;
+        tls.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        let _ = tls.read_to_end(&mut response).await;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn sni_names_the_host_the_rules_are_checked_against() {
+        let (ca, proxy_port) = start_with_rules(
+            r#"{
+                "mode": "enforce",
+                "default_upstream_port": {upstream_port},
+                "allowed_rules": [{ "host": "allowed.test", "upstream_addr": "127.0.0.1", "allowed_paths": ["/allowed"] }],
+                "insecure_upstreams": ["allowed.test"]
+            }"#,
+        )
+        .await;
+        let request = |host: &str, path: &str| format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host);
+
+        // An allowed host is intercepted with a leaf from the proxy's CA and relayed upstream
+        let allowed = fetch(&ca, proxy_port, "allowed.test", &request("allowed.test", "/allowed")).await.unwrap();
+        let allowed = String::from_utf8_lossy(&allowed);
+        assert!(allowed.starts_with("HTTP/1.1 200 OK") && allowed.ends_with("hi"), "{}", allowed);
+        let denied = fetch(&ca, proxy_port, "allowed.test", &request("allowed.test", "/denied")).await.unwrap();
+        assert!(denied.starts_with(b"HTTP/1.1 403"), "{}", String::from_utf8_lossy(&denied));
+
+        // A host with no rule still gets a handshake, so the block response can be read
+        let unknown = fetch(&ca, proxy_port, "unknown.test", &request("unknown.test", "/")).await.unwrap();
+        assert!(unknown.starts_with(b"HTTP/1.1 403"), "{}", String::from_utf8_lossy(&unknown));
+
+        let logged = logged_lines("allowed.test");
+        let entry = |path: &str, action: &str| {
+            logged
+                .iter()
+                .any(|line| line.contains(&format!("\"path\":\"{}\"", path)) && line.contains(&format!("\"action\":\"{}\"", action)))
+        };
+        assert!(entry("/allowed", "ALLOW"), "{:?}", logged);
+        assert!(entry("/denied", "BLOCK"), "{:?}", logged);
+        assert!(logged_lines("unknown.test").iter().any(|line| line.contains("Host Not Allowed")));
+    }
+
+    #[tokio::test]
+    async fn plaintext_http_gets_a_400_naming_the_problem() {
+        let (_, proxy_port) = start_with_rules(r#"{ "mode": "enforce" }"#).await;
+        let mut client = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        client.write_all(b"GET /plaintext-probe HTTP/1.1\r\nHost: example.test\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}", response);
+        assert!(response.ends_with("use HTTPS\n"), "{}", response);
+
+        let logged = std::fs::read_to_string(&paths().log).unwrap_or_default();
+        assert!(logged
+            .lines()
+            .any(|line| line.contains("\"path\":\"/plaintext-probe\"") && line.contains("Plaintext HTTP")));
+    }
+
+    #[tokio::test]
+    async fn overlong_sni_is_refused_and_logged_truncated() {
+        let (ca, proxy_port) = start_with_rules(
+            r#"{
+                "mode": "enforce",
+                "max_hostname_len": 24,
+                "allowed_rules": [{ "host": "*.test" }]
+            }"#,
+        )
+        .await;
+        let host = "a-rather-long-name-for-the-limit.test";
+        let refused = fetch(&ca, proxy_port, host, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(refused.is_err(), "handshake completed for an overlong SNI");
+
+        let logged = logged_lines(&host[..24]);
+        assert!(logged.iter().any(|line| line.contains("Hostname Too Long")), "{:?}", logged);
     }
 }
@@ -0,0 +1,219 @@
+//! Admin socket and health endpoint
+
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::SignatureScheme;
+use std::{fs, net::SocketAddr, sync::{Arc, RwLock}, time::Duration};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::info;
+
+use crate::config::Config;
+use crate::logging::set_log_filter;
+use crate::policy::{add_runtime_exception, set_maintenance_mode};
+use crate::ca::is_valid_hostname;
+
+// ============================================================================
+// Admin Socket
+// ============================================================================
+
+/// Accept admin connections on `admin_socket`. Each line is one command and gets a one-line
+/// reply starting with `ok` or `error`.
+pub async fn serve_admin_socket(live_config: Arc<RwLock<Arc<Config>>>, path: String) -> Result<()> {
+    // A socket file left by a previous run would make the bind fail
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    info!("Admin socket listening on {}", path);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let live_config = live_config.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let config = live_config.read().unwrap().clone();
+                let reply = run_admin_command(&config, &line);
+                if writer.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Carry out one admin command and describe the outcome
+fn run_admin_command(config: &Arc<Config>, line: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["allow", host, ttl] => {
+            let host = host.to_ascii_lowercase();
+            if !is_valid_hostname(&host) {
+                return format!("error: invalid hostname {:?}", host);
+            }
+            match ttl.parse::<u64>() {
+                Ok(ttl_secs) if ttl_secs > 0 => {
+                    match add_runtime_exception(config.clone(), &host, Duration::from_secs(ttl_secs)) {
+                        Ok(()) => format!("ok: {} allowed for {}s", host, ttl_secs),
+                        Err(e) => format!("error: {}", e),
+                    }
+                }
+                _ => format!("error: ttl must be a positive number of seconds, got {:?}", ttl),
+            }
+        }
+        ["maintenance", state @ ("on" | "off")] => {
+            let on = *state == "on";
+            set_maintenance_mode(config, on, "admin_socket");
+            format!("ok: maintenance {}", state)
+        }
+        ["export", "rules"] => export_rules(config).unwrap_or_else(|e| format!("error: {}", e)),
+        ["loglevel", "reset"] => match set_log_filter(None) {
+            Ok(filter) => format!("ok: log filter {}", filter),
+            Err(e) => format!("error: {}", e),
+        },
+        ["loglevel", directives] => match set_log_filter(Some(directives)) {
+            Ok(filter) => format!("ok: log filter {}", filter),
+            Err(e) => format!("error: {}", e),
+        },
+        [] => "error: empty command".to_string(),
+        _ => "error: usage: allow <host> <ttl_seconds> | maintenance on|off | export rules | loglevel <filter>|reset"
+            .to_string(),
+    }
+}
+
+/// Signature schemes tried, in order, for the `rules_signing_key`
+const RULES_SIGNATURE_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ED25519,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+];
+
+/// Load `rules_signing_key` and pick how it signs
+pub(crate) fn rules_signer(path: &str) -> Result<Box<dyn rustls::sign::Signer>> {
+    let key = PrivateKeyDer::from_pem_file(path).map_err(|e| anyhow!("rules_signing_key {}: {}", path, e))?;
+    let provider = rustls::crypto::CryptoProvider::get_default().ok_or_else(|| anyhow!("no crypto provider installed"))?;
+    let signing_key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| anyhow!("rules_signing_key {}: {}", path, e))?;
+    signing_key
+        .choose_scheme(RULES_SIGNATURE_SCHEMES)
+        .ok_or_else(|| anyhow!("rules_signing_key {}: no supported signature scheme for this key", path))
+}
+
+/// One JSON line with the rules in force, when they were exported, and a detached signature
+/// over the exact `payload` string (hex-encoded, in the reported `scheme`)
+fn export_rules(config: &Config) -> Result<String> {
+    let key_path = config
+        .rules_signing_key
+        .as_deref()
+        .ok_or_else(|| anyhow!("rules_signing_key is not set"))?;
+    let signer = rules_signer(key_path)?;
+    let payload = serde_json::json!({
+        "exported_at": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "mode": config.mode,
+        "rules": config.loaded_rules
+    })
+    .to_string();
+    let signature = signer.sign(payload.as_bytes()).map_err(|e| anyhow!("signing failed: {}", e))?;
+    let signature: String = signature.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(serde_json::json!({
+        "payload": payload,
+        "signature": signature,
+        "scheme": format!("{:?}", signer.scheme())
+    })
+    .to_string())
+}
+
+// ============================================================================
+// Health Endpoint
+// ============================================================================
+
+/// How long a health probe gets to send its request head
+const HEALTH_READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Bind the health listener on `port` at `admin_bind_addr` (loopback by default), so the
+/// data-plane network can't reach it unless configured to.
+pub async fn bind_health(config: &Config, port: u16) -> Result<TcpListener> {
+    let listener = TcpListener::bind(SocketAddr::new(config.admin_bind_ip()?, port)).await?;
+    info!("Health endpoint listening on {}", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// Answer orchestrator probes on `listener`: `GET /healthz` gets `200 OK`, anything else
+/// `404`. Start it only once the CA is ready and the proxy port is bound, so that a probe
+/// succeeding means the proxy can serve. Probes never generate certificates or write traffic
+/// log entries.
+pub async fn serve_health(listener: TcpListener) -> Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut head = Vec::new();
+            let mut chunk = [0u8; 1024];
+            let _ = tokio::time::timeout(HEALTH_READ_TIMEOUT, async {
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 4096 {
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => head.extend_from_slice(&chunk[..n]),
+                    }
+                }
+            })
+            .await;
+            let path = head.strip_prefix(b"GET ").and_then(|rest| rest.split(|b| *b == b' ').next());
+            let response = match path {
+                Some(b"/healthz") => "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 3\r\nConnection: close\r\n\r\nok\n",
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, UdpSocket};
+    use tokio::net::TcpStream;
+    use crate::test_support::*;
+    use crate::config::Config;
+
+    /// A local address other than loopback, if this machine has a route anywhere
+    fn non_loopback_ip() -> Option<IpAddr> {
+        let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+        socket.connect("192.0.2.1:80").ok()?;
+        Some(socket.local_addr().ok()?.ip()).filter(|ip| !ip.is_loopback() && !ip.is_unspecified())
+    }
+
+    async fn probe_health(ip: IpAddr, port: u16) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(SocketAddr::new(ip, port)).await?;
+        stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: x\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn health_endpoint_binds_loopback_unless_configured() {
+        let config = Config::default();
+        assert_eq!(config.admin_bind_ip().unwrap(), IpAddr::from([127, 0, 0, 1]));
+        assert!(config_from(serde_json::json!({"admin_bind_addr": "localhost"})).admin_bind_ip().is_err());
+        let listener = bind_health(&config, 0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_health(listener));
+        let response = probe_health(IpAddr::from([127, 0, 0, 1]), port).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+        let Some(external) = non_loopback_ip() else { return };
+        assert!(probe_health(external, port).await.is_err(), "loopback health port reachable on {}", external);
+
+        let open = config_from(serde_json::json!({"admin_bind_addr": "0.0.0.0"}));
+        let listener = bind_health(&open, 0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_health(listener));
+        let response = probe_health(external, port).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+}
@@ -0,0 +1,638 @@
+//! Certificate authority and leaf issuance
+
+use anyhow::{anyhow, bail, Result};
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair, Certificate,
+    SanType, SerialNumber, SignatureAlgorithm,
+};
+use rustls::server::{ServerSessionMemoryCache, StoresServerSessions};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs,
+    net::IpAddr,
+    path::Path,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+use tracing::info;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::{Config, paths};
+use crate::metrics::{CERT_CACHE_HITS, CERT_CACHE_MISSES, CERT_CACHE_SIZE};
+
+/// Check that a hostname is something we can issue a leaf for: an IP literal, or a DNS name
+/// of at most 253 characters whose labels are 1-63 letters, digits or hyphens and don't start
+/// or end with a hyphen
+pub fn is_valid_hostname(host: &str) -> bool {
+    if host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok() {
+        return true;
+    }
+
+    let name = host.strip_suffix('.').unwrap_or(host);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// Check that a server name from (or for) a ClientHello is a DNS name: a valid hostname that
+/// isn't an IP literal, which SNI can't carry (RFC 6066 §3)
+pub fn is_valid_sni(name: &str) -> bool {
+    is_valid_hostname(name) && name.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_err()
+}
+
+/// Shorten an over-long hostname for logging, on a character boundary
+pub fn truncate_hostname(host: &str, max_len: usize) -> &str {
+    let mut end = max_len.min(host.len());
+    while !host.is_char_boundary(end) {
+        end -= 1;
+    }
+    &host[..end]
+}
+
+/// The rcgen algorithm for a `key_algorithm` name
+pub(crate) fn signature_algorithm(name: &str) -> Option<&'static SignatureAlgorithm> {
+    match name {
+        "ecdsa-p256" => Some(&rcgen::PKCS_ECDSA_P256_SHA256),
+        "ecdsa-p384" => Some(&rcgen::PKCS_ECDSA_P384_SHA384),
+        "ed25519" => Some(&rcgen::PKCS_ED25519),
+        "rsa" => Some(&rcgen::PKCS_RSA_SHA256),
+        _ => None,
+    }
+}
+
+/// Upper bound for a certificate common name (RFC 5280 `ub-common-name`)
+const MAX_COMMON_NAME_LEN: usize = 64;
+
+/// Client TLS sessions remembered for resumption
+const TLS_SESSION_CACHE_ENTRIES: usize = 4096;
+
+/// Issued leaves kept for reuse; past this many the oldest is dropped
+const LEAF_CACHE_ENTRIES: usize = 10_000;
+
+/// A signed leaf certificate chain and its private key
+type LeafCert = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/// Leaves already issued, by hostname, plus the order they were added in for eviction
+#[derive(Default)]
+struct LeafCache {
+    leaves: HashMap<String, Arc<LeafCert>>,
+    order: VecDeque<String>,
+}
+
+impl LeafCache {
+    fn insert(&mut self, hostname: &str, leaf: Arc<LeafCert>) {
+        if self.leaves.insert(hostname.to_string(), leaf).is_none() {
+            self.order.push_back(hostname.to_string());
+        }
+        while self.order.len() > LEAF_CACHE_ENTRIES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.leaves.remove(&oldest);
+            }
+        }
+        CERT_CACHE_SIZE.store(self.leaves.len() as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct CaAuthority {
+    ca_key: KeyPair,
+    /// What rcgen signs with. For a loaded CA it's rebuilt from `ca_der` with the same
+    /// subject and key, so leaves chain to the original certificate.
+    ca_cert: Certificate,
+    /// The CA certificate clients trust
+    pub(crate) ca_der: CertificateDer<'static>,
+    cn_template: String,
+    leaf_organization: Option<String>,
+    key_algorithm: &'static SignatureAlgorithm,
+    /// Leaves issued so far; later handshakes for the same host reuse them
+    issued: Mutex<LeafCache>,
+    /// Generations in progress, so concurrent handshakes for one host share a single keygen
+    in_flight: Mutex<HashMap<String, Arc<tokio::sync::OnceCell<Arc<LeafCert>>>>>,
+    /// Caps simultaneous generations when `max_concurrent_cert_gen` is set
+    cert_gen_slots: Option<Semaphore>,
+    cert_gen_wait: Duration,
+    /// Client TLS sessions, shared by every per-host server config so clients can resume
+    pub(crate) sessions: Arc<dyn StoresServerSessions>,
+}
+
+/// Error returned by `CaAuthority::leaf_for_host` when no generation slot freed up in time
+#[derive(Debug)]
+pub struct CertGenSaturated;
+
+impl fmt::Display for CertGenSaturated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cert Gen Saturated")
+    }
+}
+
+impl std::error::Error for CertGenSaturated {}
+
+impl CaAuthority {
+    /// Load the CA from `ca_pkcs12_path`, or from the certificate and key a previous run left
+    /// (see `Paths`), or generate a new one, and write its certificate out so clients can trust
+    /// it. A generated CA's key is saved too, so restarts keep the same CA; a bundled key stays
+    /// in its bundle.
+    pub fn new(config: &Config) -> Result<Self> {
+        let ca_cert_path = paths().ca_cert.as_str();
+        let ca_key_path = paths().ca_key.as_str();
+        let create_parent = |path: &str| match Path::new(path).parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        };
+
+        create_parent(ca_cert_path)?;
+
+        let ca = match &config.ca_pkcs12_path {
+            Some(path) => Self::from_pkcs12(config, path)?,
+            None if Path::new(ca_cert_path).exists() && Path::new(ca_key_path).exists() => {
+                Self::from_pem_files(config, ca_cert_path, ca_key_path)
+                    .map_err(|e| anyhow!("{} (delete both CA files to generate a new CA)", e))?
+            }
+            None => {
+                let ca = Self::in_memory(config)?;
+                create_parent(ca_key_path)?;
+                fs::write(ca_key_path, ca.ca_key.serialize_pem())?;
+                ca
+            }
+        };
+
+        fs::write(ca_cert_path, pem::encode(&pem::Pem::new("CERTIFICATE", ca.ca_der.to_vec())))?;
+
+        info!("CA saved to {}", ca_cert_path);
+
+        Ok(ca)
+    }
+
+    /// Load an existing CA certificate and key from a PKCS#12 bundle. The certificate must be
+    /// a CA and must belong to the bundled key.
+    fn from_pkcs12(config: &Config, path: &str) -> Result<Self> {
+        info!("Loading CA certificate from {}...", path);
+
+        let data = fs::read(path).map_err(|e| anyhow!("ca_pkcs12_path {}: {}", path, e))?;
+        let password = config.ca_pkcs12_password.as_deref().unwrap_or("");
+        let keystore = p12_keystore::KeyStore::from_pkcs12(&data, password)
+            .map_err(|e| anyhow!("ca_pkcs12_path {}: {}", path, e))?;
+        let Some((_, chain)) = keystore.private_key_chain() else {
+            bail!("ca_pkcs12_path {}: no private key in the bundle", path);
+        };
+        let Some(cert) = chain.chain().first() else {
+            bail!("ca_pkcs12_path {}: no certificate for the private key", path);
+        };
+
+        let key_pair = KeyPair::try_from(chain.key()).map_err(|e| anyhow!("ca_pkcs12_path {}: {}", path, e))?;
+        check_ca_certificate(cert.as_der(), &key_pair).map_err(|e| anyhow!("ca_pkcs12_path {}: {}", path, e))?;
+
+        let ca_der = CertificateDer::from(cert.as_der().to_vec());
+        let ca_cert = CertificateParams::from_ca_cert_der(&ca_der)?.self_signed(&key_pair)?;
+        Self::from_parts(config, key_pair, ca_cert, ca_der)
+    }
+
+    /// Load the CA a previous run saved: a PEM certificate and its PEM private key
+    fn from_pem_files(config: &Config, cert_path: &str, key_path: &str) -> Result<Self> {
+        info!("Loading CA certificate from {}...", cert_path);
+
+        let cert_pem = fs::read(cert_path)?;
+        let cert = pem::parse(&cert_pem).map_err(|e| anyhow!("{}: {}", cert_path, e))?;
+        if cert.tag() != "CERTIFICATE" {
+            bail!("{}: expected a CERTIFICATE, found {}", cert_path, cert.tag());
+        }
+        let key_pem = fs::read_to_string(key_path)?;
+        let key_pair = KeyPair::from_pem(&key_pem).map_err(|e| anyhow!("{}: {}", key_path, e))?;
+        check_ca_certificate(cert.contents(), &key_pair).map_err(|e| anyhow!("{}: {}", cert_path, e))?;
+
+        let ca_der = CertificateDer::from(cert.into_contents());
+        let ca_cert = CertificateParams::from_ca_cert_der(&ca_der)?.self_signed(&key_pair)?;
+        Self::from_parts(config, key_pair, ca_cert, ca_der)
+    }
+
+    /// Generate a CA without touching the filesystem (tests and ephemeral runs)
+    pub fn in_memory(config: &Config) -> Result<Self> {
+        info!("Generating CA certificate...");
+
+        if config.ca_common_name.is_empty() || config.ca_common_name.len() > MAX_COMMON_NAME_LEN {
+            bail!("ca_common_name must be 1-{} characters", MAX_COMMON_NAME_LEN);
+        }
+
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, config.ca_common_name.as_str());
+        dn.push(DnType::OrganizationName, config.ca_organization.as_str());
+        params.distinguished_name = dn;
+
+        let algorithm = signature_algorithm(&config.key_algorithm)
+            .ok_or_else(|| anyhow!("unknown key_algorithm {:?}", config.key_algorithm))?;
+        let key_pair = KeyPair::generate_for(algorithm)?;
+        let cert = params.self_signed(&key_pair)?;
+        let ca_der = cert.der().clone();
+        Self::from_parts(config, key_pair, cert, ca_der)
+    }
+
+    fn from_parts(config: &Config, ca_key: KeyPair, ca_cert: Certificate, ca_der: CertificateDer<'static>) -> Result<Self> {
+        if config.cert_cn_template.replace("{host}", "").len() > MAX_COMMON_NAME_LEN {
+            bail!("cert_cn_template is longer than {} characters before substitution", MAX_COMMON_NAME_LEN);
+        }
+
+        Ok(Self {
+            ca_key,
+            ca_cert,
+            ca_der,
+            cn_template: config.cert_cn_template.clone(),
+            leaf_organization: config.cert_organization.clone(),
+            key_algorithm: signature_algorithm(&config.key_algorithm)
+                .ok_or_else(|| anyhow!("unknown key_algorithm {:?}", config.key_algorithm))?,
+            issued: Mutex::new(LeafCache::default()),
+            in_flight: Mutex::new(HashMap::new()),
+            cert_gen_slots: config.max_concurrent_cert_gen.map(Semaphore::new),
+            cert_gen_wait: Duration::from_millis(config.cert_gen_wait_ms),
+            sessions: ServerSessionMemoryCache::new(TLS_SESSION_CACHE_ENTRIES),
+        })
+    }
+
+    /// The CA certificate clients trust, as DER
+    pub fn ca_der(&self) -> &CertificateDer<'static> {
+        &self.ca_der
+    }
+
+    /// Common name for a leaf: the configured template with `{host}` substituted, or the bare
+    /// hostname when the result would be empty or too long for a CN (the SAN still matches)
+    fn leaf_common_name(&self, hostname: &str) -> String {
+        let cn = self.cn_template.replace("{host}", hostname);
+        if cn.is_empty() || cn.len() > MAX_COMMON_NAME_LEN {
+            hostname.to_string()
+        } else {
+            cn
+        }
+    }
+
+    /// The leaf for `hostname`, issued on first use and cached after that. Issuing doesn't
+    /// block the runtime: key generation and signing run on the blocking pool, and callers
+    /// asking for the same host while that's underway wait for the same result instead of
+    /// starting their own
+    pub(crate) async fn leaf_for_host(self: &Arc<Self>, hostname: &str) -> Result<Arc<LeafCert>> {
+        if let Some(leaf) = self.issued.lock().unwrap().leaves.get(hostname) {
+            CERT_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            return Ok(leaf.clone());
+        }
+        CERT_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+
+        let generation = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(hostname.to_string())
+            .or_default()
+            .clone();
+
+        let result = generation
+            .get_or_try_init(|| {
+                let ca = self.clone();
+                let hostname = hostname.to_string();
+                async move {
+                    let _permit = match &self.cert_gen_slots {
+                        Some(slots) => match tokio::time::timeout(self.cert_gen_wait, slots.acquire()).await {
+                            Ok(permit) => Some(permit?),
+                            Err(_) => return Err(CertGenSaturated.into()),
+                        },
+                        None => None,
+                    };
+                    let leaf = tokio::task::spawn_blocking(move || ca.generate_cert_for_host(&hostname)).await??;
+                    Ok::<_, anyhow::Error>(Arc::new(leaf))
+                }
+            })
+            .await
+            .cloned();
+        if let Ok(leaf) = &result {
+            self.issued.lock().unwrap().insert(hostname, leaf.clone());
+        }
+
+        // Later handshakes start a fresh generation; a failed one is retried by whoever is waiting
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(hostname).is_some_and(|current| Arc::ptr_eq(current, &generation)) {
+            in_flight.remove(hostname);
+        }
+        result
+    }
+
+    pub(crate) fn generate_cert_for_host(&self, hostname: &str) -> Result<LeafCert> {
+        // Clients check an IP target against IP SANs only, so an address never goes in as a DNS name
+        let san = match hostname.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(hostname.try_into()?),
+        };
+        let mut params = CertificateParams::default();
+        params.subject_alt_names = vec![san];
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, self.leaf_common_name(hostname));
+        if let Some(organization) = &self.leaf_organization {
+            dn.push(DnType::OrganizationName, organization.as_str());
+        }
+        params.distinguished_name = dn;
+        params.serial_number = Some(self.serial_for_host(hostname));
+
+        let key_pair = KeyPair::generate_for(self.key_algorithm)?;
+        let cert = params.signed_by(&key_pair, &self.ca_cert, &self.ca_key)?;
+
+        let cert_der = CertificateDer::from(cert.der().to_vec());
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+
+        Ok((vec![cert_der], key_der))
+    }
+
+    /// Derive a stable leaf serial from the CA certificate and hostname.
+    ///
+    /// The same host always gets the same serial for the lifetime of this CA, which makes
+    /// pinning and debugging easier. The tradeoff is that leaves are linkable across
+    /// connections: anyone who sees two certs for a host can tell they came from this CA.
+    fn serial_for_host(&self, hostname: &str) -> SerialNumber {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.ca_der);
+        hasher.update(hostname.as_bytes());
+        let digest = hasher.finalize();
+
+        // RFC 5280 allows at most 20 bytes; clear the MSB so the integer stays positive
+        let mut serial = digest[..20].to_vec();
+        serial[0] &= 0x7f;
+        SerialNumber::from(serial)
+    }
+}
+
+/// Check that a certificate can act as a CA and belongs to `key`
+fn check_ca_certificate(der: &[u8], key: &KeyPair) -> Result<()> {
+    let (_, cert) = X509Certificate::from_der(der).map_err(|e| anyhow!("certificate doesn't parse: {}", e))?;
+    if cert.public_key().raw != key.public_key_der().as_slice() {
+        bail!("private key doesn't match the certificate");
+    }
+    let is_ca = cert
+        .basic_constraints()
+        .map_err(|e| anyhow!("bad basicConstraints: {}", e))?
+        .is_some_and(|constraints| constraints.value.ca);
+    if !is_ca {
+        bail!("certificate {} is not a CA (basicConstraints CA:TRUE is missing)", cert.subject());
+    }
+    let key_usage = cert.key_usage().map_err(|e| anyhow!("bad keyUsage: {}", e))?;
+    if key_usage.is_some_and(|usage| !usage.value.key_cert_sign()) {
+        bail!("certificate {} is not allowed to sign certificates (keyUsage lacks keyCertSign)", cert.subject());
+    }
+    Ok(())
+}
+
+/// Timings from issuing a run of leaves, for `--bench-certgen`
+#[derive(Debug, Clone, Copy)]
+pub struct CertGenStats {
+    pub count: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+impl CertGenStats {
+    /// Leaves issued per second over the whole run
+    pub fn throughput(&self) -> f64 {
+        self.count as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Issue `count` leaves one after another from a throwaway CA and time them
+pub fn measure_certgen(config: &Config, count: usize) -> Result<CertGenStats> {
+    if count == 0 {
+        bail!("--bench-certgen needs a count of at least 1");
+    }
+    let ca = CaAuthority::in_memory(config)?;
+    let mut latencies = Vec::with_capacity(count);
+    let started = Instant::now();
+    for i in 0..count {
+        let issued = Instant::now();
+        ca.generate_cert_for_host(&format!("bench-{}.example.com", i))?;
+        latencies.push(issued.elapsed());
+    }
+    let elapsed = started.elapsed();
+    latencies.sort();
+    let percentile = |p: usize| latencies[(count * p / 100).min(count - 1)];
+
+    Ok(CertGenStats {
+        count,
+        elapsed,
+        p50: percentile(50),
+        p99: percentile(99),
+    })
+}
+
+/// `--bench-certgen`: run `measure_certgen` and print the throughput and latency
+/// percentiles, for sizing a deployment
+pub fn bench_certgen(config: &Config, count: usize) -> Result<()> {
+    let stats = measure_certgen(config, count)?;
+    println!("Generated {} certificates in {:.2?}", stats.count, stats.elapsed);
+    println!("Throughput: {:.1} certs/sec", stats.throughput());
+    println!("Latency: p50 {:.2?}, p99 {:.2?}", stats.p50, stats.p99);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::client::danger::ServerCertVerifier;
+    use rustls::client::WebPkiServerVerifier;
+    use rustls::pki_types::{ServerName, UnixTime};
+    use x509_parser::prelude::GeneralName;
+    use crate::test_support::*;
+
+    /// Verify `leaf` as a server certificate for `name`, trusting only `ca_der`
+    fn verify_leaf(ca_der: &CertificateDer<'static>, leaf: &CertificateDer<'static>, name: &str) -> Result<(), rustls::Error> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca_der.clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build().unwrap();
+        let server_name = ServerName::try_from(name.to_string()).unwrap();
+        verifier.verify_server_cert(leaf, &[], &server_name, &[], UnixTime::now()).map(|_| ())
+    }
+
+    /// The subject alternative names on a leaf
+    fn leaf_sans(leaf: &CertificateDer<'static>) -> Vec<String> {
+        let (_, cert) = X509Certificate::from_der(leaf).unwrap();
+        let san = cert.subject_alternative_name().unwrap().expect("leaf has no SAN extension");
+        san.value
+            .general_names
+            .iter()
+            .map(|name| match name {
+                GeneralName::DNSName(dns) => format!("dns:{}", dns),
+                GeneralName::IPAddress(bytes) => match bytes.len() {
+                    4 => format!("ip:{}", IpAddr::from(<[u8; 4]>::try_from(*bytes).unwrap())),
+                    _ => format!("ip:{}", IpAddr::from(<[u8; 16]>::try_from(*bytes).unwrap())),
+                },
+                other => format!("{:?}", other),
+            })
+            .collect()
+    }
+
+    /// A PKCS#12 bundle of `key` (PKCS#8 DER) and `cert`, written to a temp file
+    fn pkcs12_file(name: &str, key: &[u8], cert: &[u8], password: &str) -> String {
+        let chain = p12_keystore::PrivateKeyChain::new(key, b"ca", [p12_keystore::Certificate::from_der(cert).unwrap()]);
+        let mut keystore = p12_keystore::KeyStore::new();
+        keystore.add_entry("ca", p12_keystore::KeyStoreEntry::PrivateKeyChain(chain));
+        let path = test_paths().log.replace("traffic.jsonl", &format!("{}.p12", name));
+        fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+        fs::write(&path, keystore.writer(password).write().unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn hostname_and_sni_validation() {
+        for host in ["example.com", "a-b.example.com.", "xn--bcher-kva.example", "10.0.0.1", "[::1]"] {
+            assert!(is_valid_hostname(host), "{} should be valid", host);
+        }
+        let long_label = format!("{}.com", "a".repeat(64));
+        let long_name = ["a".repeat(63).as_str(); 5].join(".");
+        for host in ["", ".", "-bad.example.com", "bad-.example.com", "a..b", "under_score.com", "sp ace.com"] {
+            assert!(!is_valid_hostname(host), "{:?} should be invalid", host);
+        }
+        assert!(!is_valid_hostname(&long_label));
+        assert!(!is_valid_hostname(&long_name));
+
+        assert!(is_valid_sni("example.com"));
+        assert!(!is_valid_sni("10.0.0.1"));
+        assert!(!is_valid_sni("[2001:db8::1]"));
+        assert!(!is_valid_sni("bad_name.example.com"));
+    }
+
+    #[test]
+    fn issued_leaf_chains_to_the_ca() {
+        let config = config_from(serde_json::json!({}));
+        let ca = CaAuthority::in_memory(&config).unwrap();
+        let (chain, _) = ca.generate_cert_for_host("chain.example.com").unwrap();
+
+        let (_, ca_cert) = X509Certificate::from_der(&ca.ca_der).unwrap();
+        let (_, leaf) = X509Certificate::from_der(&chain[0]).unwrap();
+        assert_eq!(leaf.issuer(), ca_cert.subject());
+        verify_leaf(&ca.ca_der, &chain[0], "chain.example.com").unwrap();
+
+        let other = CaAuthority::in_memory(&config).unwrap();
+        assert!(verify_leaf(&other.ca_der, &chain[0], "chain.example.com").is_err());
+        assert!(verify_leaf(&ca.ca_der, &chain[0], "other.example.com").is_err());
+    }
+
+    #[test]
+    fn leaf_san_matches_the_kind_of_host() {
+        let config = config_from(serde_json::json!({}));
+        let ca = CaAuthority::in_memory(&config).unwrap();
+        let leaf = |host: &str| ca.generate_cert_for_host(host).unwrap().0.remove(0);
+
+        let named = leaf("san.example.com");
+        assert_eq!(leaf_sans(&named), ["dns:san.example.com"]);
+
+        let v4 = leaf("192.0.2.10");
+        assert_eq!(leaf_sans(&v4), ["ip:192.0.2.10"]);
+        verify_leaf(&ca.ca_der, &v4, "192.0.2.10").unwrap();
+
+        let v6 = leaf("[2001:db8::1]");
+        assert_eq!(leaf_sans(&v6), ["ip:2001:db8::1"]);
+        verify_leaf(&ca.ca_der, &v6, "2001:db8::1").unwrap();
+    }
+
+    #[test]
+    fn certgen_benchmark_reports_throughput() {
+        let config = config_from(serde_json::json!({}));
+        let stats = measure_certgen(&config, 5).unwrap();
+        assert_eq!(stats.count, 5);
+        assert!(stats.throughput() > 0.0);
+        assert!(stats.p50 <= stats.p99 && stats.p99 <= stats.elapsed);
+        assert!(measure_certgen(&config, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn leaves_are_issued_once_per_host_and_reused() {
+        let config = config_from(serde_json::json!({}));
+        let ca = Arc::new(CaAuthority::in_memory(&config).unwrap());
+
+        let first = ca.leaf_for_host("cached.leaf.example.com").await.unwrap();
+        let again = ca.leaf_for_host("cached.leaf.example.com").await.unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+
+        let other = ca.leaf_for_host("other.leaf.example.com").await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        let (a, b) = tokio::join!(ca.leaf_for_host("racing.leaf.example.com"), ca.leaf_for_host("racing.leaf.example.com"));
+        assert!(Arc::ptr_eq(&a.unwrap(), &b.unwrap()));
+        assert_eq!(ca.issued.lock().unwrap().leaves.len(), 3);
+        assert!(ca.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn generated_ca_is_reloaded_on_restart() {
+        let config = config_from(serde_json::json!({}));
+        let paths = test_paths();
+        let _ = fs::remove_file(&paths.ca_cert);
+        let _ = fs::remove_file(&paths.ca_key);
+
+        let first = CaAuthority::new(&config).unwrap();
+        let saved_key = fs::read_to_string(&paths.ca_key).unwrap();
+        let restarted = CaAuthority::new(&config).unwrap();
+        assert_eq!(restarted.ca_der, first.ca_der);
+        assert_eq!(restarted.ca_key.serialize_pem(), first.ca_key.serialize_pem());
+        assert_eq!(fs::read_to_string(&paths.ca_key).unwrap(), saved_key);
+
+        let (chain, _) = first.generate_cert_for_host("persisted.ca.example.com").unwrap();
+        verify_leaf(&restarted.ca_der, &chain[0], "persisted.ca.example.com").unwrap();
+
+        // A key that doesn't belong to the saved certificate is refused, not silently replaced
+        let stranger = CaAuthority::in_memory(&config).unwrap();
+        fs::write(&paths.ca_key, stranger.ca_key.serialize_pem()).unwrap();
+        let mismatch = CaAuthority::new(&config).err().unwrap().to_string();
+        assert!(mismatch.contains("doesn't match"), "{}", mismatch);
+
+        fs::remove_file(&paths.ca_cert).unwrap();
+        fs::remove_file(&paths.ca_key).unwrap();
+    }
+
+    #[test]
+    fn pkcs12_ca_loads_with_the_right_password_only() {
+        let generated = CaAuthority::in_memory(&config_from(serde_json::json!({}))).unwrap();
+        let path = pkcs12_file("pkcs12-ca", &generated.ca_key.serialize_der(), &generated.ca_der, "p12-secret");
+        let with_password = |password: &str| {
+            config_from(serde_json::json!({ "ca_pkcs12_path": path, "ca_pkcs12_password": password }))
+        };
+
+        let loaded = CaAuthority::from_pkcs12(&with_password("p12-secret"), &path).unwrap();
+        assert_eq!(loaded.ca_der, generated.ca_der);
+        let (chain, _) = loaded.generate_cert_for_host("bundle.ca.example.com").unwrap();
+        verify_leaf(&generated.ca_der, &chain[0], "bundle.ca.example.com").unwrap();
+
+        let wrong = CaAuthority::from_pkcs12(&with_password("not-the-secret"), &path).err().unwrap().to_string();
+        assert!(wrong.starts_with(&format!("ca_pkcs12_path {}:", path)), "{}", wrong);
+
+        // A bundled leaf can't sign for other hosts
+        let (leaf_chain, leaf_key) = generated.generate_cert_for_host("leaf.ca.example.com").unwrap();
+        let leaf_path = pkcs12_file("pkcs12-leaf", leaf_key.secret_der(), &leaf_chain[0], "p12-secret");
+        let not_ca = CaAuthority::from_pkcs12(&with_password("p12-secret"), &leaf_path).err().unwrap().to_string();
+        assert!(not_ca.contains("is not a CA"), "{}", not_ca);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&leaf_path).unwrap();
+    }
+
+    #[test]
+    fn leaf_serials_are_stable_per_host_and_ca() {
+        let config = config_from(serde_json::json!({}));
+        let ca = CaAuthority::in_memory(&config).unwrap();
+        let serial = |ca: &CaAuthority, host: &str| {
+            let (chain, _) = ca.generate_cert_for_host(host).unwrap();
+            let (_, cert) = X509Certificate::from_der(&chain[0]).unwrap();
+            cert.raw_serial().to_vec()
+        };
+
+        let first = serial(&ca, "serial.example.com");
+        assert_eq!(serial(&ca, "serial.example.com"), first);
+        assert_ne!(serial(&ca, "other.serial.example.com"), first);
+        assert_ne!(serial(&CaAuthority::in_memory(&config).unwrap(), "serial.example.com"), first);
+        assert!(first.len() <= 20 && first[0] & 0x80 == 0, "{:02x?}", first);
+    }
+}
@@ -0,0 +1,1309 @@
+//! Rules file, profiles and hot reload
+
+use anyhow::{anyhow, bail, Result};
+use ipnet::IpNet;
+use jsonwebtoken::jwk::JwkSet;
+use rcgen::KeyPair;
+use regex_automata::meta::Regex;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, OnceLock, RwLock},
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::error;
+
+use crate::admin::rules_signer;
+use crate::policy::find_host_rule;
+use crate::intercept::{parse_upstream_addr, tls_profile};
+use crate::ca::{is_valid_hostname, is_valid_sni, signature_algorithm};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HostRule {
+    pub(crate) host: String,
+    /// Literal path prefixes, globs (`*`, `**`) or `re:` regexes; empty allows every path
+    #[serde(default)]
+    pub(crate) allowed_paths: Vec<PathPattern>,
+    /// Match `allowed_paths` against the path and its query string instead of the path alone
+    #[serde(default)]
+    pub(crate) match_query: bool,
+    /// HTTP methods allowed to this host (case-insensitive); empty allows any
+    #[serde(default)]
+    pub(crate) allowed_methods: Vec<String>,
+    /// Forward the request exactly as read from the client, with no head rewriting
+    #[serde(default)]
+    pub(crate) preserve_request_bytes: bool,
+    /// Gzip compressible request bodies with a known length before they go upstream
+    #[serde(default)]
+    pub(crate) compress_request_body: bool,
+    /// Open the upstream connection to this host (`host` or `host:port`) instead of the requested one
+    #[serde(default)]
+    pub(crate) upstream_host: Option<String>,
+    /// Dial this address (`ip` or `ip:port`) without resolving any name; TLS still uses the host's name
+    #[serde(default)]
+    pub(crate) upstream_addr: Option<String>,
+    /// SNI (and certificate name) for the upstream TLS handshake, whatever host is connected to
+    #[serde(default)]
+    pub(crate) upstream_sni: Option<String>,
+    /// With `upstream_host`, send the upstream its own name in `Host`/`:authority`
+    #[serde(default)]
+    pub(crate) rewrite_host_header: bool,
+    /// Minimum milliseconds between requests forwarded to this host; faster ones are delayed
+    #[serde(default)]
+    pub(crate) pace_requests_ms: Option<u64>,
+    /// Requests per second allowed to this host; faster ones are refused with a 429
+    #[serde(default)]
+    pub(crate) rate_limit: Option<RateLimit>,
+    /// Evaluation order: higher priorities are checked first, ties keep file order. A blocked
+    /// rule for the host only gives way to this rule when this priority is higher.
+    #[serde(default)]
+    pub(crate) priority: i32,
+    /// Redirect blocked requests for this host here instead of returning 403
+    #[serde(default)]
+    pub(crate) block_redirect_url: Option<String>,
+    /// Upstream speaks plain HTTP: terminate client TLS but don't re-encrypt upstream
+    #[serde(default)]
+    pub(crate) upstream_plaintext: bool,
+    /// Hex SHA-256 fingerprints of the upstream leaf certificate or its SPKI; any match passes
+    #[serde(default)]
+    pub(crate) upstream_pin_sha256: Vec<String>,
+    /// ALPN protocols the client may negotiate with this host (`h2`, `http/1.1`); empty allows any
+    #[serde(default)]
+    pub(crate) allowed_alpn: Vec<String>,
+    /// ALPN protocols offered to the upstream; empty mirrors what the client negotiated
+    #[serde(default)]
+    pub(crate) upstream_alpn: Vec<String>,
+    /// Substitute text in response bodies from this host before they reach the client
+    #[serde(default)]
+    pub(crate) response_rewrite: Option<ResponseRewrite>,
+    /// Response headers removed before the client sees them, e.g. `Set-Cookie` (names case-insensitive)
+    #[serde(default)]
+    pub(crate) strip_response_headers: Vec<String>,
+    /// Response trailer values to block, e.g. `{"grpc-status": ["7", "16"]}` (names case-insensitive)
+    #[serde(default)]
+    pub(crate) blocked_trailers: HashMap<String, Vec<String>>,
+    /// Canned responses served for matching paths without forwarding upstream
+    #[serde(default)]
+    pub(crate) static_responses: Vec<StaticResponse>,
+    /// gRPC methods (`/package.Service/Method` or `package.Service/*`) allowed over HTTP/2; empty allows any
+    #[serde(default)]
+    pub(crate) allowed_grpc_methods: Vec<String>,
+    /// Client-side schemes (`http`, `https`) this rule applies to; empty allows any
+    #[serde(default)]
+    pub(crate) allowed_schemes: Vec<String>,
+    /// Save decrypted traffic for this host under `capture_dir`
+    #[serde(default)]
+    pub(crate) capture: bool,
+    /// Record request/response metadata for this host as a HAR file under `capture_dir`
+    #[serde(default)]
+    pub(crate) capture_har: bool,
+    /// mTLS client identities (certificate CN or SAN) allowed to reach this host; empty allows any
+    #[serde(default)]
+    pub(crate) allowed_clients: Vec<String>,
+    /// Claims the request's `Authorization: Bearer` JWT must carry, e.g. `{"iss": "ci"}`
+    #[serde(default)]
+    pub(crate) required_jwt_claims: HashMap<String, serde_json::Value>,
+    /// Block uploads whose body starts like one of these MIME types (`image/png`, `image/*`),
+    /// whatever their `Content-Type` says
+    #[serde(default)]
+    pub(crate) blocked_upload_mimes: Vec<String>,
+    /// Log an extra `large_transfer` alert when a tunnel moves more than this many bytes
+    #[serde(default)]
+    pub(crate) alert_bytes_threshold: Option<u64>,
+    /// Close a tunnel to this host once it has moved this many bytes (overrides the global setting)
+    #[serde(default)]
+    max_connection_bytes: Option<u64>,
+    /// Throttle each direction of a tunnel to this host to this many bytes a second (overrides the global setting)
+    #[serde(default)]
+    max_bytes_per_sec: Option<u64>,
+    /// Forward this host's requests but log them with action `QUARANTINE`
+    #[serde(default)]
+    pub(crate) quarantine: bool,
+    /// Forward requests this rule would block, logging them with action `WARN` instead
+    #[serde(default)]
+    pub(crate) warn_only: bool,
+    /// Quarantine requests whose path contains any of these strings
+    #[serde(default)]
+    pub(crate) quarantine_patterns: Vec<String>,
+    /// Overrides the global `connect_timeout_ms` for this host
+    #[serde(default)]
+    connect_timeout_ms: Option<u64>,
+    /// Overrides the global `handshake_timeout_ms` for this host
+    #[serde(default)]
+    handshake_timeout_ms: Option<u64>,
+    /// Overrides the global `idle_timeout_ms` for this host
+    #[serde(default)]
+    idle_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_mode")]
+    pub mode: String,
+    /// Poll the config file and reload the rules when it changes, like SIGHUP
+    #[serde(default)]
+    pub config_watch: bool,
+    /// Address the proxy listens on (`ip:port`, default `0.0.0.0:58080`); `PROXY_LISTEN` wins
+    #[serde(default)]
+    listen: Option<String>,
+    /// Upstream port for the transparent edition when the original destination of a
+    /// redirected connection can't be read
+    #[serde(default = "default_upstream_port")]
+    pub default_upstream_port: u16,
+    /// HTTP proxy (`http://host:port`) that upstream connections are tunneled through with CONNECT
+    #[serde(default)]
+    upstream_proxy: Option<String>,
+    /// Decide on the CONNECT host/SNI only and tunnel allowed connections without interception
+    #[serde(default)]
+    pub audit_only: bool,
+    /// Unix socket path accepting admin commands such as `allow <host> <ttl_seconds>`
+    #[serde(default)]
+    pub admin_socket: Option<String>,
+    /// Port answering `GET /healthz` with `200 OK` once the proxy is ready, on `admin_bind_addr`
+    #[serde(default)]
+    pub health_port: Option<u16>,
+    /// IP address the health listener binds, `127.0.0.1` unless set
+    #[serde(default)]
+    admin_bind_addr: Option<String>,
+    /// PEM private key (PKCS#8: ECDSA, Ed25519 or RSA) that signs `export rules` artifacts
+    #[serde(default)]
+    pub(crate) rules_signing_key: Option<String>,
+    /// `allowed_rules` and `blocked_rules` as last loaded from the config file, for `export rules`
+    #[serde(skip)]
+    pub(crate) loaded_rules: serde_json::Value,
+    #[serde(default)]
+    pub(crate) allowed_rules: Vec<HostRule>,
+    /// IP ranges (e.g. `10.0.0.0/8`, `fd00::/8`) whose addresses are allowed as CONNECT targets
+    #[serde(default)]
+    pub(crate) allowed_cidrs: Vec<String>,
+    /// `allowed_cidrs`, parsed when the config is loaded
+    #[serde(skip)]
+    pub(crate) allowed_networks: Vec<IpNet>,
+    /// What a matched rule with no `allowed_paths` allows: "allow_all" (default) or "deny_all"
+    #[serde(default = "default_empty_paths_means")]
+    pub(crate) empty_paths_means: String,
+    /// Hosts (and their subdomains) blocked in enforce mode regardless of `allowed_rules`, and
+    /// the only hosts blocked in blocklist mode
+    #[serde(default)]
+    pub(crate) blocked_rules: Vec<BlockedRule>,
+    /// Most allowed plus blocked rules a config may hold; more fails startup or reload
+    #[serde(default = "default_max_rules")]
+    max_rules: usize,
+    /// Where traffic decisions are written: "file", "stdout" or "syslog"
+    #[serde(default = "default_log_sink")]
+    pub(crate) log_sink: String,
+    /// How each log entry is rendered: "jsonl" (default) or "cef" for SIEMs
+    #[serde(default = "default_log_format")]
+    pub(crate) log_format: String,
+    /// Log entries that can wait for the writer; when the queue is full new entries are dropped
+    #[serde(default = "default_log_queue_entries")]
+    pub(crate) log_queue_entries: usize,
+    /// Traffic log file rotation: "none" (default) or "daily" (local date in the file name)
+    #[serde(default = "default_log_rotation")]
+    pub(crate) log_rotation: String,
+    /// Rotate the traffic log file before it would grow past this many bytes (unset: no limit)
+    #[serde(default)]
+    pub(crate) log_max_bytes: Option<u64>,
+    /// Rotated files kept by `log_max_bytes` (`traffic.jsonl.1` is the newest)
+    #[serde(default = "default_log_max_files")]
+    pub(crate) log_max_files: u32,
+    /// While this file exists, enforcement is suspended and the proxy behaves as in monitor mode
+    #[serde(default)]
+    pub(crate) break_glass_file: Option<String>,
+    /// Start in maintenance mode: every request is answered with a 503 (toggle with the admin socket)
+    #[serde(default)]
+    pub maintenance_mode: bool,
+    /// While this file exists the proxy is in maintenance mode, whatever `maintenance_mode` says
+    #[serde(default)]
+    pub(crate) maintenance_file: Option<String>,
+    /// Body of the 503 sent while in maintenance mode
+    #[serde(default = "default_maintenance_message")]
+    pub(crate) maintenance_message: String,
+    /// Hosts (and their subdomains) that keep working during maintenance
+    #[serde(default)]
+    pub(crate) maintenance_bypass_hosts: Vec<String>,
+    /// Give up on an upstream TCP connect after this long (default 10s, `null`: OS default)
+    #[serde(default = "default_timeout_ms")]
+    connect_timeout_ms: Option<u64>,
+    /// Give up on a client or upstream TLS handshake after this long (default 10s, `null`: never)
+    #[serde(default = "default_timeout_ms")]
+    handshake_timeout_ms: Option<u64>,
+    /// Close a tunnel after this long with no traffic in either direction (unset: never)
+    #[serde(default)]
+    idle_timeout_ms: Option<u64>,
+    /// Idle timeout used instead of `idle_timeout_ms` once a WebSocket upgrade succeeds
+    #[serde(default)]
+    websocket_idle_timeout_ms: Option<u64>,
+    /// Answer `504` when the upstream sends nothing this long after a request (unset: wait)
+    #[serde(default)]
+    pub(crate) first_response_byte_timeout_ms: Option<u64>,
+    /// Redirect path-level blocks here (302) instead of returning 403; `{host}`/`{path}` are substituted
+    #[serde(default)]
+    pub(crate) block_redirect_url: Option<String>,
+    /// Status code of the response to a blocked request (default 403)
+    #[serde(default)]
+    pub(crate) block_status: Option<u16>,
+    /// Body of the response to a blocked request, e.g. a link to an internal wiki or a JSON error
+    #[serde(default)]
+    pub(crate) block_body: Option<String>,
+    /// `Content-Type` of `block_body` (default `text/plain`)
+    #[serde(default)]
+    pub(crate) block_content_type: Option<String>,
+    /// Answer every block the same way (default `404`, no per-rule redirects or `429`s), including
+    /// hosts refused before interception, so a client can't map out the policy; the log keeps the real reason
+    #[serde(default)]
+    pub uniform_block_response: bool,
+    /// With `uniform_block_response`, how long a host refused before its upstream is dialed
+    /// waits before being answered, standing in for the connect an allowed host waits on
+    #[serde(default = "default_uniform_block_delay_ms")]
+    uniform_block_delay_ms: u64,
+    /// Add a `client_type` field to request log entries based on the User-Agent
+    #[serde(default)]
+    pub(crate) classify_user_agent: bool,
+    /// Ordered User-Agent patterns used for classification (built-in defaults when empty)
+    #[serde(default)]
+    pub(crate) user_agent_patterns: Vec<UserAgentPattern>,
+    /// CONNECT targets tunneled without interception (e.g. nested proxies); CONNECT edition only
+    #[serde(default)]
+    pub connect_passthrough_hosts: Vec<String>,
+    /// Hosts (and their subdomains) never intercepted in either edition: once allowed, they
+    /// are tunneled byte-for-byte
+    #[serde(default)]
+    pub(crate) never_intercept: Vec<String>,
+    /// Hosts (and their subdomains) whose upstream certificate isn't verified, for internal
+    /// services with self-signed or private-CA certificates
+    #[serde(default)]
+    pub(crate) insecure_upstreams: Vec<String>,
+    /// Size of the per-direction buffer used when copying tunneled bytes
+    #[serde(default = "default_copy_buffer_bytes")]
+    copy_buffer_bytes: usize,
+    /// Print the emoji decision line for each connection/request to stdout
+    #[serde(default = "default_true")]
+    pub(crate) console_decisions: bool,
+    /// Offer `h2` to clients and police each HTTP/2 stream (upstream must also speak h2)
+    #[serde(default)]
+    pub(crate) intercept_http2: bool,
+    /// Maximum simultaneous connections; extra connections are turned away (unset: no limit)
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// What happens at `max_connections`: "reject" turns new connections away, "wait" stops
+    /// accepting until a slot frees up
+    #[serde(default = "default_max_connections_mode")]
+    pub max_connections_mode: String,
+    /// Close a tunnel once it has moved this many bytes, both directions combined (unset: no limit)
+    #[serde(default)]
+    max_connection_bytes: Option<u64>,
+    /// Throttle each direction of a tunnel to this many bytes a second (unset: no limit)
+    #[serde(default)]
+    max_bytes_per_sec: Option<u64>,
+    /// How long connections still open at SIGTERM/SIGINT get to finish before the proxy exits
+    #[serde(default = "default_shutdown_grace_ms")]
+    shutdown_grace_ms: u64,
+    /// Maximum leaf certificates generated at once; handshakes beyond that wait for a slot (unset: no limit)
+    #[serde(default)]
+    pub(crate) max_concurrent_cert_gen: Option<usize>,
+    /// How long a handshake waits for a certificate generation slot before it's turned away
+    #[serde(default = "default_cert_gen_wait_ms")]
+    pub(crate) cert_gen_wait_ms: u64,
+    /// `Retry-After` seconds in the 503 sent when over capacity (CONNECT edition only, default 5)
+    #[serde(default)]
+    pub overload_retry_after_secs: Option<u64>,
+    /// StatsD/DogStatsD endpoint (`host:port`) to push metrics to over UDP
+    #[serde(default)]
+    pub statsd_addr: Option<String>,
+    /// Log a per-host `rollup` entry (decisions and bytes) this often, in seconds (unset: never)
+    #[serde(default)]
+    pub metrics_rollup_secs: Option<u64>,
+    /// Maximum number of idle tunnels kept open; the longest-idle ones beyond this are closed
+    #[serde(default)]
+    pub max_idle_connections: Option<usize>,
+    /// Close an intercepted HTTP/1.1 connection after this many requests (unset: no limit)
+    #[serde(default)]
+    pub(crate) max_requests_per_connection: Option<u64>,
+    /// PKCS#12 bundle (`.p12`/`.pfx`) holding an existing CA certificate and key to sign with
+    #[serde(default)]
+    pub(crate) ca_pkcs12_path: Option<String>,
+    /// Password for `ca_pkcs12_path`
+    #[serde(default)]
+    pub(crate) ca_pkcs12_password: Option<String>,
+    /// Common name of the generated CA
+    #[serde(default = "default_ca_common_name")]
+    pub(crate) ca_common_name: String,
+    /// Organization of the generated CA
+    #[serde(default = "default_ca_organization")]
+    pub(crate) ca_organization: String,
+    /// Common name template for leaf certificates; `{host}` is replaced with the hostname
+    #[serde(default = "default_cert_cn_template")]
+    pub(crate) cert_cn_template: String,
+    /// Organization for leaf certificates (none by default)
+    #[serde(default)]
+    pub(crate) cert_organization: Option<String>,
+    /// Key type for the generated CA and leaf certificates: "ecdsa-p256" (default), "ecdsa-p384",
+    /// "ed25519" or "rsa"
+    #[serde(default = "default_key_algorithm")]
+    pub(crate) key_algorithm: String,
+    /// Cipher suite and key exchange order offered to upstreams: "default" (rustls), "chrome" or "firefox"
+    #[serde(default = "default_upstream_tls_profile")]
+    pub(crate) upstream_tls_profile: String,
+    /// Directory for decrypted traffic captures of rules with `capture` set
+    #[serde(default)]
+    pub(crate) capture_dir: Option<String>,
+    /// Maximum bytes written to a single capture file
+    #[serde(default = "default_capture_max_bytes")]
+    pub(crate) capture_max_bytes: u64,
+    /// Bytes of each request and response body kept in `capture_har` logs (0: sizes only)
+    #[serde(default)]
+    pub(crate) capture_har_body_bytes: u64,
+    /// Also capture sessions whose request was quarantined (needs `capture_dir`)
+    #[serde(default)]
+    pub(crate) capture_quarantined: bool,
+    /// Shared secret for verifying HS256/384/512 JWT signatures
+    #[serde(default)]
+    pub(crate) jwt_hmac_secret: Option<String>,
+    /// JSON Web Key Set for verifying asymmetric JWT signatures, matched by `kid`
+    #[serde(default)]
+    pub(crate) jwt_jwks: Option<JwkSet>,
+    /// Largest response body `response_rewrite` will buffer; bigger bodies pass through untouched
+    #[serde(default = "default_response_rewrite_max_bytes")]
+    pub(crate) response_rewrite_max_bytes: usize,
+    /// HTTP versions clients may use (`HTTP/1.0`, `HTTP/1.1`, `HTTP/2`); empty allows any
+    #[serde(default)]
+    pub(crate) allowed_http_versions: Vec<String>,
+    /// Request methods allowed on every host, checked before the host's own rule; empty allows any
+    #[serde(default)]
+    pub(crate) allowed_methods: Vec<String>,
+    /// Add `X-Upstream-Cert-Fingerprint` (SHA-256 of the real upstream leaf) to the first response
+    #[serde(default)]
+    pub(crate) expose_upstream_cert_header: bool,
+    /// Add `X-Forwarded-For` (the client's address) and `X-Secure-Proxy: 1` to forwarded requests
+    #[serde(default)]
+    pub(crate) inject_forwarded_headers: bool,
+    /// Answer with a 502 naming the upstream's certificate problem instead of just dropping the connection
+    #[serde(default)]
+    pub(crate) propagate_upstream_cert_errors: bool,
+    /// CONNECT edition: block tunnels whose ClientHello SNI differs from (or is missing for) the CONNECT host
+    #[serde(default)]
+    pub require_sni_matches_connect: bool,
+    /// Answer requests with both Content-Length and Transfer-Encoding, or conflicting Content-Lengths, with a 400
+    #[serde(default = "default_true")]
+    pub(crate) reject_ambiguous_framing: bool,
+    /// Answer requests with header lines continued by obsolete line folding with a 400
+    #[serde(default = "default_true")]
+    pub(crate) reject_obs_fold: bool,
+    /// Block requests whose Host header names a different registrable domain than the SNI/CONNECT host
+    #[serde(default)]
+    pub(crate) block_domain_fronting: bool,
+    /// Longest SNI/CONNECT hostname accepted; longer names are rejected before any other work
+    #[serde(default = "default_max_hostname_len")]
+    pub max_hostname_len: usize,
+    /// PEM bundle of CAs whose client certificates the proxy accepts (mTLS, optional per client)
+    #[serde(default)]
+    pub client_ca_file: Option<String>,
+    /// Hide what the proxy is: no startup banners and a generic 403 body for blocked requests
+    #[serde(default)]
+    pub stealth_mode: bool,
+    /// Log the parsed ClientHello of every connection at debug level (transparent edition only)
+    #[serde(default)]
+    pub debug_clienthello: bool,
+    /// MaxMind-format ASN database (e.g. GeoLite2-ASN.mmdb); ASN rules are inert without it
+    #[serde(default)]
+    pub asn_db_path: Option<String>,
+    /// Upstream ASNs that are always blocked
+    #[serde(default)]
+    pub(crate) blocked_asns: Vec<u32>,
+    /// When non-empty, only upstreams in these ASNs are allowed
+    #[serde(default)]
+    pub(crate) allowed_asns: Vec<u32>,
+}
+
+/// Token bucket settings for a host's `rate_limit`
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimit {
+    /// Tokens added per second
+    pub(crate) per_second: f64,
+    /// Most tokens the bucket holds, i.e. requests allowed back to back (default: `per_second`, at least 1)
+    #[serde(default)]
+    pub(crate) burst: Option<u32>,
+}
+
+impl RateLimit {
+    pub(crate) fn burst(&self) -> f64 {
+        self.burst.map_or(self.per_second.ceil().max(1.0), f64::from)
+    }
+}
+
+/// Replace every occurrence of `match` with `replace` in text response bodies
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResponseRewrite {
+    #[serde(rename = "match")]
+    pub(crate) pattern: String,
+    pub(crate) replace: String,
+    /// Path prefixes to rewrite responses for; empty rewrites every path
+    #[serde(default)]
+    pub(crate) paths: Vec<String>,
+}
+
+/// A host that is blocked, even if an allowed rule of no higher priority also matches it
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlockedRule {
+    pub(crate) host: String,
+    /// How the client sees the block: "forbidden" (default, a 403 or closed connection) or
+    /// "unreachable" (the connection is reset, as if the host couldn't be reached)
+    #[serde(default = "default_block_style")]
+    pub(crate) block_style: String,
+    /// Path prefixes to block; empty blocks the whole host
+    #[serde(default)]
+    pub(crate) paths: Vec<String>,
+    /// Ranked against the allowed rule matching the host: the block applies unless that
+    /// rule's priority is higher, so a tie goes to the block
+    #[serde(default)]
+    pub(crate) priority: i32,
+}
+
+/// One `allowed_paths` entry, compiled when the config is parsed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum PathPattern {
+    /// Plain strings allow every path they prefix
+    Prefix(String),
+    /// `*` matches within one path segment and `**` across segments; the whole target must match
+    Glob(Regex),
+    /// `re:` entries, searched for anywhere in the target unless anchored
+    Regex(Regex),
+}
+
+impl TryFrom<String> for PathPattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        if let Some(regex) = pattern.strip_prefix("re:") {
+            return Regex::new(regex)
+                .map(PathPattern::Regex)
+                .map_err(|e| format!("allowed_paths regex {:?}: {}", regex, regex_error(&e)));
+        }
+        if !pattern.contains('*') {
+            return Ok(PathPattern::Prefix(pattern));
+        }
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.next_if_eq(&'*').is_some() => regex.push_str(".*"),
+                '*' => regex.push_str("[^/]*"),
+                c => {
+                    if "\\.+?()|[]{}^$#&-~".contains(c) {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+            }
+        }
+        regex.push('$');
+        Regex::new(&regex)
+            .map(PathPattern::Glob)
+            .map_err(|e| format!("allowed_paths glob {:?}: {}", pattern, regex_error(&e)))
+    }
+}
+
+/// The parser's description of a bad pattern, which says more than the build error itself
+fn regex_error(e: &regex_automata::meta::BuildError) -> String {
+    e.syntax_error().map_or_else(|| e.to_string(), |syntax| syntax.to_string())
+}
+
+impl PathPattern {
+    /// `target` is the path alone, or the path and query for `match_query` rules
+    pub(crate) fn matches(&self, target: &str) -> bool {
+        match self {
+            PathPattern::Prefix(prefix) => target.starts_with(prefix.as_str()),
+            PathPattern::Glob(glob) => glob.is_match(target),
+            PathPattern::Regex(regex) => regex.is_match(target),
+        }
+    }
+}
+
+/// A canned response for one path, read from `body_file` each time it is served
+#[derive(Debug, Clone, Deserialize)]
+pub struct StaticResponse {
+    /// Request path to answer (query string ignored)
+    pub(crate) path: String,
+    #[serde(default = "default_static_status")]
+    pub(crate) status: u16,
+    #[serde(default = "default_static_content_type")]
+    pub(crate) content_type: String,
+    pub(crate) body_file: String,
+}
+
+/// User-Agents containing any of `contains` (case-insensitive) are labelled `client_type`
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserAgentPattern {
+    pub(crate) client_type: String,
+    pub(crate) contains: Vec<String>,
+}
+
+fn default_mode() -> String {
+    "monitor".to_string()
+}
+
+fn default_log_sink() -> String {
+    "file".to_string()
+}
+
+fn default_max_rules() -> usize {
+    100_000
+}
+
+fn default_block_style() -> String {
+    "forbidden".to_string()
+}
+
+fn default_log_format() -> String {
+    "jsonl".to_string()
+}
+
+fn default_empty_paths_means() -> String {
+    "allow_all".to_string()
+}
+
+fn default_max_connections_mode() -> String {
+    "reject".to_string()
+}
+
+fn default_log_max_files() -> u32 {
+    5
+}
+
+fn default_upstream_tls_profile() -> String {
+    "default".to_string()
+}
+
+fn default_upstream_port() -> u16 {
+    443
+}
+
+fn default_log_rotation() -> String {
+    "none".to_string()
+}
+
+fn default_maintenance_message() -> String {
+    "Service temporarily unavailable for maintenance".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_copy_buffer_bytes() -> usize {
+    8192
+}
+
+fn default_ca_common_name() -> String {
+    "Secure Proxy CA".to_string()
+}
+
+fn default_ca_organization() -> String {
+    "Secure Proxy".to_string()
+}
+
+fn default_cert_cn_template() -> String {
+    "{host}".to_string()
+}
+
+fn default_key_algorithm() -> String {
+    "ecdsa-p256".to_string()
+}
+
+fn default_capture_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_shutdown_grace_ms() -> u64 {
+    5000
+}
+
+fn default_uniform_block_delay_ms() -> u64 {
+    100
+}
+
+fn default_cert_gen_wait_ms() -> u64 {
+    5000
+}
+
+fn default_log_queue_entries() -> usize {
+    8192
+}
+
+fn default_static_status() -> u16 {
+    200
+}
+
+fn default_static_content_type() -> String {
+    "text/plain".to_string()
+}
+
+fn default_timeout_ms() -> Option<u64> {
+    Some(10_000)
+}
+
+fn default_max_hostname_len() -> usize {
+    253
+}
+
+fn default_response_rewrite_max_bytes() -> usize {
+    1024 * 1024
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: "monitor".to_string(),
+            allowed_rules: vec![],
+            empty_paths_means: default_empty_paths_means(),
+            blocked_rules: vec![],
+            max_rules: default_max_rules(),
+            log_sink: default_log_sink(),
+            log_format: default_log_format(),
+            log_queue_entries: default_log_queue_entries(),
+            log_rotation: default_log_rotation(),
+            log_max_bytes: None,
+            log_max_files: default_log_max_files(),
+            break_glass_file: None,
+            maintenance_mode: false,
+            maintenance_file: None,
+            maintenance_message: default_maintenance_message(),
+            maintenance_bypass_hosts: Vec::new(),
+            connect_timeout_ms: default_timeout_ms(),
+            handshake_timeout_ms: default_timeout_ms(),
+            idle_timeout_ms: None,
+            websocket_idle_timeout_ms: None,
+            first_response_byte_timeout_ms: None,
+            block_redirect_url: None,
+            block_status: None,
+            block_body: None,
+            block_content_type: None,
+            uniform_block_response: false,
+            uniform_block_delay_ms: default_uniform_block_delay_ms(),
+            classify_user_agent: false,
+            user_agent_patterns: vec![],
+            connect_passthrough_hosts: vec![],
+            never_intercept: vec![],
+            insecure_upstreams: vec![],
+            audit_only: false,
+            admin_socket: None,
+            health_port: None,
+            admin_bind_addr: None,
+            rules_signing_key: None,
+            loaded_rules: rules_snapshot(&serde_json::Value::Null),
+            allowed_cidrs: vec![],
+            allowed_networks: vec![],
+            config_watch: false,
+            listen: None,
+            default_upstream_port: default_upstream_port(),
+            upstream_proxy: None,
+            copy_buffer_bytes: default_copy_buffer_bytes(),
+            console_decisions: true,
+            intercept_http2: false,
+            max_connections: None,
+            max_connections_mode: default_max_connections_mode(),
+            max_connection_bytes: None,
+            max_bytes_per_sec: None,
+            shutdown_grace_ms: default_shutdown_grace_ms(),
+            max_concurrent_cert_gen: None,
+            cert_gen_wait_ms: default_cert_gen_wait_ms(),
+            overload_retry_after_secs: None,
+            statsd_addr: None,
+            metrics_rollup_secs: None,
+            max_idle_connections: None,
+            max_requests_per_connection: None,
+            ca_pkcs12_path: None,
+            ca_pkcs12_password: None,
+            ca_common_name: default_ca_common_name(),
+            ca_organization: default_ca_organization(),
+            cert_cn_template: default_cert_cn_template(),
+            cert_organization: None,
+            key_algorithm: default_key_algorithm(),
+            upstream_tls_profile: default_upstream_tls_profile(),
+            capture_dir: None,
+            capture_max_bytes: default_capture_max_bytes(),
+            capture_har_body_bytes: 0,
+            capture_quarantined: false,
+            jwt_hmac_secret: None,
+            jwt_jwks: None,
+            response_rewrite_max_bytes: default_response_rewrite_max_bytes(),
+            allowed_http_versions: Vec::new(),
+            allowed_methods: Vec::new(),
+            expose_upstream_cert_header: false,
+            inject_forwarded_headers: false,
+            propagate_upstream_cert_errors: false,
+            require_sni_matches_connect: false,
+            reject_ambiguous_framing: true,
+            reject_obs_fold: true,
+            block_domain_fronting: false,
+            max_hostname_len: default_max_hostname_len(),
+            client_ca_file: None,
+            stealth_mode: false,
+            debug_clienthello: false,
+            asn_db_path: None,
+            blocked_asns: Vec::new(),
+            allowed_asns: Vec::new(),
+        }
+    }
+}
+
+/// Timeouts for one connection, after applying the matched rule's overrides
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub handshake: Option<Duration>,
+    pub idle: Option<Duration>,
+}
+
+impl Config {
+    /// Byte ceiling for tunnels to `host`: the rule's `max_connection_bytes`, else the global one
+    pub(crate) fn max_connection_bytes_for(&self, host: &str) -> Option<u64> {
+        find_host_rule(self, host)
+            .and_then(|r| r.max_connection_bytes)
+            .or(self.max_connection_bytes)
+    }
+
+    /// Throughput cap for tunnels to `host`: the rule's `max_bytes_per_sec`, else the global one
+    pub(crate) fn max_bytes_per_sec_for(&self, host: &str) -> Option<u64> {
+        find_host_rule(self, host)
+            .and_then(|r| r.max_bytes_per_sec)
+            .or(self.max_bytes_per_sec)
+    }
+
+    /// Resolve timeouts for `host`: per-rule values win, the globals fill the gaps
+    pub fn timeouts_for(&self, host: &str) -> Timeouts {
+        let rule = find_host_rule(self, host);
+        let resolve = |per_rule: Option<u64>, global: Option<u64>| per_rule.or(global).map(Duration::from_millis);
+        Timeouts {
+            connect: resolve(rule.and_then(|r| r.connect_timeout_ms), self.connect_timeout_ms),
+            handshake: resolve(rule.and_then(|r| r.handshake_timeout_ms), self.handshake_timeout_ms),
+            idle: resolve(rule.and_then(|r| r.idle_timeout_ms), self.idle_timeout_ms),
+        }
+    }
+
+    pub(crate) fn websocket_idle_timeout(&self) -> Option<Duration> {
+        self.websocket_idle_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub(crate) fn first_response_byte_timeout(&self) -> Option<Duration> {
+        self.first_response_byte_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn uniform_block_delay(&self) -> Duration {
+        Duration::from_millis(self.uniform_block_delay_ms)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_millis(self.shutdown_grace_ms)
+    }
+
+    /// Where to listen: `PROXY_LISTEN`, else `listen`, else `0.0.0.0:58080`
+    pub fn listen_addr(&self) -> Result<SocketAddr> {
+        let env = std::env::var("PROXY_LISTEN").ok().filter(|addr| !addr.is_empty());
+        match env.as_deref().map(|addr| ("PROXY_LISTEN", addr)).or(self.listen.as_deref().map(|addr| ("listen", addr))) {
+            Some((setting, addr)) => addr
+                .parse()
+                .map_err(|_| anyhow!("{} must be ip:port (e.g. \"127.0.0.1:8080\"), got {:?}", setting, addr)),
+            None => Ok(SocketAddr::from(([0, 0, 0, 0], 58080))),
+        }
+    }
+
+    /// Where control-plane listeners bind: `admin_bind_addr`, else loopback
+    pub fn admin_bind_ip(&self) -> Result<IpAddr> {
+        match self.admin_bind_addr.as_deref() {
+            Some(addr) => addr
+                .parse()
+                .map_err(|_| anyhow!("admin_bind_addr must be an IP address (e.g. \"0.0.0.0\"), got {:?}", addr)),
+            None => Ok(IpAddr::from([127, 0, 0, 1])),
+        }
+    }
+
+    pub(crate) fn copy_buffer_bytes(&self) -> usize {
+        self.copy_buffer_bytes.max(1)
+    }
+
+    /// `host:port` of `upstream_proxy`; the port defaults to 80 as for any `http://` URL
+    pub(crate) fn upstream_proxy_addr(&self) -> Result<Option<String>> {
+        let Some(url) = &self.upstream_proxy else {
+            return Ok(None);
+        };
+        let Some(authority) = url.strip_prefix("http://").map(|rest| rest.trim_end_matches('/')) else {
+            bail!("upstream_proxy must be an http:// URL, got {:?}", url);
+        };
+        if authority.is_empty() || authority.contains(['@', '/', '?', '#']) {
+            bail!("upstream_proxy must be http://host:port without credentials or a path, got {:?}", url);
+        }
+        let has_port = match authority.rsplit_once(':') {
+            Some((_, port)) if !authority.ends_with(']') => {
+                if !matches!(port.parse::<u16>(), Ok(port) if port != 0) {
+                    bail!("upstream_proxy has an invalid port, got {:?}", url);
+                }
+                true
+            }
+            _ => false,
+        };
+        Ok(Some(if has_port { authority.to_string() } else { format!("{}:80", authority) }))
+    }
+
+    /// Reject settings that can't work before the proxy starts listening
+    pub fn validate(&self) -> Result<()> {
+        if !["monitor", "enforce", "blocklist"].contains(&self.mode.as_str()) {
+            bail!("mode must be \"monitor\", \"enforce\" or \"blocklist\", got {:?}", self.mode);
+        }
+        self.listen_addr()?;
+        if self.upstream_proxy_addr()?.is_some() && self.asn_db_path.is_some() {
+            bail!("asn_db_path can't be used with upstream_proxy: the upstream address seen is the proxy's");
+        }
+        if let Some(path) = &self.rules_signing_key {
+            rules_signer(path)?;
+        }
+        check_rule_count(self.allowed_rules.len() + self.blocked_rules.len(), self.max_rules)?;
+        for rule in &self.blocked_rules {
+            if !["forbidden", "unreachable"].contains(&rule.block_style.as_str()) {
+                bail!("blocked_rules {}: block_style must be \"forbidden\" or \"unreachable\", got {:?}", rule.host, rule.block_style);
+            }
+        }
+        if !["allow_all", "deny_all"].contains(&self.empty_paths_means.as_str()) {
+            bail!("empty_paths_means must be \"allow_all\" or \"deny_all\", got {:?}", self.empty_paths_means);
+        }
+        if !["reject", "wait"].contains(&self.max_connections_mode.as_str()) {
+            bail!("max_connections_mode must be \"reject\" or \"wait\", got {:?}", self.max_connections_mode);
+        }
+        if !["jsonl", "cef"].contains(&self.log_format.as_str()) {
+            bail!("log_format must be \"jsonl\" or \"cef\", got {:?}", self.log_format);
+        }
+        if !["none", "daily"].contains(&self.log_rotation.as_str()) {
+            bail!("log_rotation must be \"none\" or \"daily\", got {:?}", self.log_rotation);
+        }
+        if self.log_max_bytes == Some(0) {
+            bail!("log_max_bytes must be at least 1");
+        }
+        if self.log_max_files == 0 {
+            bail!("log_max_files must be at least 1");
+        }
+        if self.max_connection_bytes == Some(0) {
+            bail!("max_connection_bytes must be at least 1");
+        }
+        if let Some(rule) = self.allowed_rules.iter().find(|r| r.max_connection_bytes == Some(0)) {
+            bail!("max_connection_bytes for {} must be at least 1", rule.host);
+        }
+        if self.max_bytes_per_sec == Some(0) {
+            bail!("max_bytes_per_sec must be at least 1");
+        }
+        if let Some(rule) = self.allowed_rules.iter().find(|r| r.max_bytes_per_sec == Some(0)) {
+            bail!("max_bytes_per_sec for {} must be at least 1", rule.host);
+        }
+        if self.health_port == Some(0) {
+            bail!("health_port must be between 1 and 65535");
+        }
+        self.admin_bind_ip()?;
+        if self.default_upstream_port == 0 {
+            bail!("default_upstream_port must be between 1 and 65535");
+        }
+        let Some(algorithm) = signature_algorithm(&self.key_algorithm) else {
+            bail!(
+                "key_algorithm must be \"ecdsa-p256\", \"ecdsa-p384\", \"ed25519\" or \"rsa\", got {:?}",
+                self.key_algorithm
+            );
+        };
+        if tls_profile(&self.upstream_tls_profile).is_none() {
+            bail!(
+                "upstream_tls_profile must be \"default\", \"chrome\" or \"firefox\", got {:?}",
+                self.upstream_tls_profile
+            );
+        }
+        // A throwaway key now, rather than a failed handshake on the first connection
+        KeyPair::generate_for(algorithm).map_err(|e| {
+            anyhow!("key_algorithm {:?} is not supported by the crypto provider: {}", self.key_algorithm, e)
+        })?;
+        if self.metrics_rollup_secs == Some(0) {
+            bail!("metrics_rollup_secs must be at least 1");
+        }
+        if self.max_requests_per_connection == Some(0) {
+            bail!("max_requests_per_connection must be at least 1");
+        }
+        if let Some(addr) = &self.statsd_addr {
+            let (_, port) = addr
+                .rsplit_once(':')
+                .ok_or_else(|| anyhow!("statsd_addr {:?} must be host:port", addr))?;
+            validate_port("statsd_addr", port)?;
+        }
+        if let Some(status) = self.block_status.filter(|status| !(100..=599).contains(status)) {
+            bail!("block_status {} is not an HTTP status code", status);
+        }
+        for rule in &self.allowed_rules {
+            if let Some(bad) = rule.static_responses.iter().find(|r| !(100..=599).contains(&r.status)) {
+                bail!("static_responses for {}: status {} is not an HTTP status code", rule.host, bad.status);
+            }
+            if let Some(upstream) = &rule.upstream_host {
+                let (name, port) = match upstream.rsplit_once(':') {
+                    Some((name, port)) => (name, Some(port)),
+                    None => (upstream.as_str(), None),
+                };
+                if !is_valid_hostname(name) {
+                    bail!("upstream_host for {}: {:?} is not a valid hostname", rule.host, upstream);
+                }
+                if let Some(port) = port {
+                    validate_port(&format!("upstream_host for {}", rule.host), port)?;
+                }
+            }
+            if let Some(addr) = &rule.upstream_addr {
+                if parse_upstream_addr(addr, 443).is_none() {
+                    bail!("upstream_addr for {}: {:?} must be an IP address or ip:port", rule.host, addr);
+                }
+            }
+            if let Some(sni) = &rule.upstream_sni {
+                if !is_valid_sni(sni) {
+                    bail!("upstream_sni for {}: {:?} is not a valid DNS name", rule.host, sni);
+                }
+            }
+            if let Some(name) = rule
+                .strip_response_headers
+                .iter()
+                .find(|name| ["content-length", "transfer-encoding"].contains(&name.to_ascii_lowercase().as_str()))
+            {
+                bail!("strip_response_headers for {}: {} frames the body and can't be stripped", rule.host, name);
+            }
+            if let Some(limit) = &rule.rate_limit {
+                if !(limit.per_second.is_finite() && limit.per_second > 0.0) {
+                    bail!("rate_limit.per_second for {} must be a positive number, got {}", rule.host, limit.per_second);
+                }
+                if limit.burst == Some(0) {
+                    bail!("rate_limit.burst for {} must be at least 1", rule.host);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Refuse rule sets larger than `max_rules`, before they're used for matching
+fn check_rule_count(count: usize, max_rules: usize) -> Result<()> {
+    if count > max_rules {
+        bail!("config has {} rules but max_rules is {}; raise max_rules if this is intended", count, max_rules);
+    }
+    Ok(())
+}
+
+/// Parse a configured port, rejecting anything outside 1-65535
+fn validate_port(setting: &str, port: &str) -> Result<u16> {
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => bail!("{}: port must be a number from 1 to 65535, got {:?}", setting, port),
+    }
+}
+
+/// Filesystem locations the proxy reads and writes. Each has an environment variable so the
+/// proxy can run outside the container layout, e.g. locally or on a read-only root filesystem.
+#[derive(Debug, Clone)]
+pub struct Paths {
+    /// Settings and rules: `PROXY_CONFIG`, else `/config/rules.json`
+    pub config: String,
+    /// CA certificate clients trust: `PROXY_CA_CERT`, else `/ca/certs/ca.pem`
+    pub ca_cert: String,
+    /// Generated CA key: `PROXY_CA_KEY`, else `/ca/keys/ca.private.key`
+    pub ca_key: String,
+    /// Traffic log for the `file` sink: `PROXY_LOG`, else `/logs/traffic.jsonl`
+    pub log: String,
+}
+
+impl Paths {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: &str| {
+            std::env::var(name).ok().filter(|path| !path.is_empty()).unwrap_or_else(|| default.to_string())
+        };
+        Paths {
+            config: var("PROXY_CONFIG", "/config/rules.json"),
+            ca_cert: var("PROXY_CA_CERT", "/ca/certs/ca.pem"),
+            ca_key: var("PROXY_CA_KEY", "/ca/keys/ca.private.key"),
+            log: var("PROXY_LOG", "/logs/traffic.jsonl"),
+        }
+    }
+}
+
+pub(crate) static PATHS: OnceLock<Paths> = OnceLock::new();
+
+/// The paths for this run, read from the environment the first time they're needed
+pub fn paths() -> &'static Paths {
+    PATHS.get_or_init(Paths::from_env)
+}
+
+/// Value of a `--flag <value>` or `--flag=<value>` command-line option
+pub fn cli_option(flag: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(flag).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Profile chosen with `--profile <name>`, else `PROXY_PROFILE`
+pub fn selected_profile() -> Option<String> {
+    cli_option("--profile").or_else(|| std::env::var("PROXY_PROFILE").ok().filter(|name| !name.is_empty()))
+}
+
+/// Read the config file as JSON, with the selected profile's fields replacing the base
+/// fields of the same name, then each of `overlay_files` applied in order as a merge patch.
+/// The `profiles` map and `overlay_files` list themselves are dropped.
+fn read_config_value(path: &str) -> Result<serde_json::Value> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow!("can't read {}: {}", path, e))?;
+    let mut value: serde_json::Value =
+        serde_json::from_str(&text).map_err(|e| anyhow!("{} is not valid JSON: {}", path, e))?;
+    let profiles = value.as_object_mut().and_then(|base| base.remove("profiles"));
+    if let Some(name) = selected_profile() {
+        let profile = profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(&name))
+            .and_then(|profile| profile.as_object())
+            .ok_or_else(|| anyhow!("profile {:?} not found in {}", name, path))?;
+        if let Some(base) = value.as_object_mut() {
+            for (field, setting) in profile {
+                base.insert(field.clone(), setting.clone());
+            }
+        }
+    }
+    let overlays = value.as_object_mut().and_then(|base| base.remove("overlay_files"));
+    let overlays: Vec<String> = match overlays {
+        Some(overlays) => serde_json::from_value(overlays).map_err(|e| anyhow!("overlay_files in {}: {}", path, e))?,
+        None => Vec::new(),
+    };
+    for overlay in overlays {
+        let patch: serde_json::Value = fs::read_to_string(&overlay)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(serde_json::from_str(&text)?))
+            .map_err(|e| anyhow!("overlay {}: {}", overlay, e))?;
+        merge_patch(&mut value, &patch);
+    }
+    Ok(value)
+}
+
+/// Apply `patch` to `target` as a JSON Merge Patch (RFC 7386): objects merge field by field,
+/// `null` removes a field, and anything else (arrays included) replaces the target outright
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let Some(patch_fields) = patch.as_object() else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::json!({});
+    }
+    if let Some(fields) = target.as_object_mut() {
+        for (field, value) in patch_fields {
+            if value.is_null() {
+                fields.remove(field);
+            } else {
+                merge_patch(fields.entry(field.clone()).or_insert(serde_json::Value::Null), value);
+            }
+        }
+    }
+}
+
+/// Parse the config file (see `read_config_value`), keeping a copy of its rules
+pub fn load_config(path: &str) -> Result<Config> {
+    let value = read_config_value(path)?;
+    let mut config: Config = from_config_value(path, value.clone())?;
+    config.loaded_rules = rules_snapshot(&value);
+    config.allowed_networks = parse_cidrs(&config.allowed_cidrs)?;
+    Ok(config)
+}
+
+/// `--check-config`: load and validate a config file the way startup does, and print a
+/// summary of its rules. Nothing is bound, generated or logged.
+pub fn check_config(path: &str) -> Result<()> {
+    let config = load_config(path)?;
+    config.validate()?;
+    let paths: usize = config.allowed_rules.iter().map(|rule| rule.allowed_paths.len()).sum();
+    println!(
+        "{} is valid: mode {}, {} allowed host rule(s) with {} path pattern(s), {} blocked host rule(s)",
+        path,
+        config.mode,
+        config.allowed_rules.len(),
+        paths,
+        config.blocked_rules.len()
+    );
+    Ok(())
+}
+
+/// Deserialize a config value, naming the offending field (e.g. `allowed_rules[2].allowed_paths`)
+/// when a setting has the wrong type
+pub(crate) fn from_config_value<T: serde::de::DeserializeOwned>(path: &str, value: serde_json::Value) -> Result<T> {
+    serde_path_to_error::deserialize(value).map_err(|e| anyhow!("{}: {}: {}", path, e.path(), e.inner()))
+}
+
+/// Parse `allowed_cidrs` entries into networks
+pub(crate) fn parse_cidrs(cidrs: &[String]) -> Result<Vec<IpNet>> {
+    cidrs
+        .iter()
+        .map(|cidr| {
+            cidr.parse::<IpNet>()
+                .map_err(|_| anyhow!("allowed_cidrs entry {:?} is not a CIDR range like 10.0.0.0/8", cidr))
+        })
+        .collect()
+}
+
+/// Just the rule lists of a config value
+pub(crate) fn rules_snapshot(value: &serde_json::Value) -> serde_json::Value {
+    let rules = |field: &str| value.get(field).cloned().unwrap_or_else(|| serde_json::json!([]));
+    serde_json::json!({ "allowed_rules": rules("allowed_rules"), "blocked_rules": rules("blocked_rules") })
+}
+
+/// The part of the config file a reload picks up
+#[derive(Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    allowed_rules: Vec<HostRule>,
+    #[serde(default)]
+    blocked_rules: Vec<BlockedRule>,
+}
+
+/// Re-read `allowed_rules` and `blocked_rules` and publish them for new connections. Only the rule list changes:
+/// the CA is never regenerated and every other setting keeps its startup value, so clients'
+/// trust in the proxy is unaffected. Connections already open keep the rules they started
+/// with; a file that fails to parse or validate leaves the current rules in place.
+fn reload_rules(live_config: &RwLock<Arc<Config>>) {
+    reload_rules_from(live_config, &paths().config);
+}
+
+fn reload_rules_from(live_config: &RwLock<Arc<Config>>, path: &str) {
+    let current = live_config.read().unwrap().clone();
+    match reloaded_config(&current, path) {
+        Ok(next) => {
+            println!("[Config] Reloaded {} rules from {}", next.allowed_rules.len(), path);
+            *live_config.write().unwrap() = Arc::new(next);
+        }
+        Err(e) => error!("[Config] Reload failed, keeping current rules: {}", e),
+    }
+}
+
+/// `current` with the rules from `path` swapped in, checked the same way startup checks a config
+fn reloaded_config(current: &Config, path: &str) -> Result<Config> {
+    let value = read_config_value(path)?;
+    let rules: RulesFile = from_config_value(path, value.clone())?;
+    let mut next = current.clone();
+    next.allowed_rules = rules.allowed_rules;
+    next.blocked_rules = rules.blocked_rules;
+    next.loaded_rules = rules_snapshot(&value);
+    next.allowed_networks = parse_cidrs(&next.allowed_cidrs)?;
+    next.validate()?;
+    Ok(next)
+}
+
+pub async fn reload_rules_on_signal(live_config: Arc<RwLock<Arc<Config>>>) -> Result<()> {
+    let mut hup = signal(SignalKind::hangup())?;
+    while hup.recv().await.is_some() {
+        reload_rules(&live_config);
+    }
+    Ok(())
+}
+
+/// How often `config_watch` checks the config file's modification time
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// For `config_watch`: reload the rules when the config file's mtime changes. A change only
+/// counts once the mtime has held still for a whole interval, so an editor's burst of writes
+/// triggers one reload of the finished file.
+pub async fn watch_config_file(live_config: Arc<RwLock<Arc<Config>>>) {
+    let modified = || fs::metadata(&paths().config).and_then(|m| m.modified()).ok();
+    let mut loaded = modified();
+    let mut pending = None;
+    let mut interval = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let current = modified();
+        if current == loaded {
+            pending = None;
+        } else if pending == Some(current) {
+            reload_rules(&live_config);
+            loaded = current;
+            pending = None;
+        } else {
+            pending = Some(current);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::policy::check_host_allowed;
+    use crate::ca::CaAuthority;
+
+    #[tokio::test]
+    async fn reload_keeps_ca_and_issued_leaves() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [{ "host": "before.example.com" }]
+        }));
+        let ca = Arc::new(CaAuthority::in_memory(&config).unwrap());
+        let ca_der = ca.ca_der.clone();
+        let leaf = ca.leaf_for_host("before.example.com").await.unwrap();
+        let live_config = RwLock::new(Arc::new(config));
+
+        let path = temp_config_file(
+            "reload-keeps-ca",
+            serde_json::json!({ "allowed_rules": [{ "host": "after.example.com" }] }),
+        );
+        reload_rules_from(&live_config, &path);
+        fs::remove_file(&path).unwrap();
+
+        let reloaded = live_config.read().unwrap().clone();
+        assert!(check_host_allowed(&reloaded, "after.example.com").0);
+        assert!(!check_host_allowed(&reloaded, "before.example.com").0);
+        assert_eq!(ca.ca_der, ca_der);
+        assert!(Arc::ptr_eq(&ca.leaf_for_host("before.example.com").await.unwrap(), &leaf));
+    }
+
+    #[test]
+    fn reload_rejects_rules_startup_would_reject() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [{ "host": "kept.example.com" }]
+        }));
+        let live_config = RwLock::new(Arc::new(config));
+
+        let path = temp_config_file(
+            "reload-rejects",
+            serde_json::json!({ "allowed_rules": [{ "host": "new.example.com", "rate_limit": { "per_second": 0 } }] }),
+        );
+        reload_rules_from(&live_config, &path);
+        fs::remove_file(&path).unwrap();
+
+        let current = live_config.read().unwrap().clone();
+        assert!(check_host_allowed(&current, "kept.example.com").0);
+        assert!(!check_host_allowed(&current, "new.example.com").0);
+    }
+
+    #[test]
+    fn config_errors_say_where_the_problem_is() {
+        let path = std::env::temp_dir().join(format!("secure-proxy-malformed-{}.json", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+
+        fs::write(&path, "{\n  \"mode\": \"enforce\",\n  \"allowed_rules\": [\n    { \"host\": \"a.example.com\" },\n  ]\n}\n").unwrap();
+        let syntax = load_config(&path).err().unwrap().to_string();
+        assert!(syntax.starts_with(&format!("{} is not valid JSON:", path)), "{}", syntax);
+        assert!(syntax.contains("line 5 column 3"), "{}", syntax);
+
+        fs::write(&path, r#"{ "allowed_rules": [{ "host": "a.example.com" }, { "host": "b.example.com", "allowed_paths": "/api" }] }"#).unwrap();
+        let field = load_config(&path).err().unwrap().to_string();
+        assert!(field.starts_with(&format!("{}: allowed_rules[1].allowed_paths: ", path)), "{}", field);
+
+        fs::write(&path, r#"{ "mode": "enforcing" }"#).unwrap();
+        let mode = load_config(&path).unwrap().validate().unwrap_err().to_string();
+        assert!(mode.contains("\"enforcing\""), "{}", mode);
+
+        fs::remove_file(&path).unwrap();
+        let missing = load_config(&path).err().unwrap().to_string();
+        assert!(missing.starts_with(&format!("can't read {}:", path)), "{}", missing);
+    }
+}
@@ -0,0 +1,782 @@
+//! HTTP/1.1 message parsing and rewriting
+
+use anyhow::Result;
+use flate2::{write::GzEncoder, Compression};
+use std::{borrow::Cow, fmt, io::Write, time::Duration};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::error;
+
+use crate::config::{Config, HostRule, ResponseRewrite, StaticResponse};
+use crate::metrics::CONNECTION_STATS;
+use crate::policy::{enforcing, find_host_rule, unenforced_reason};
+use crate::tunnel::with_deadline;
+use crate::session::Capture;
+
+// ============================================================================
+// HTTP Headers
+// ============================================================================
+
+/// Find the first value of a header (case-insensitive name) in a request or response head
+pub(crate) fn header_value<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+/// Whether a request head frames its body ambiguously, the classic request smuggling setup:
+/// Content-Length together with Transfer-Encoding, or Content-Length values that disagree
+fn has_ambiguous_framing(head: &str) -> bool {
+    let fields: Vec<(&str, &str)> = head
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    let mut lengths = fields
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim);
+    let Some(first_length) = lengths.next() else {
+        return false;
+    };
+    let chunked = fields.iter().any(|(name, _)| name.eq_ignore_ascii_case("transfer-encoding"));
+    chunked || lengths.any(|length| length != first_length)
+}
+
+/// Whether a request head continues a header line onto the next one with leading whitespace
+/// (obs-fold, RFC 9112 §5.2), which parsers disagree on
+fn has_obs_fold(head: &str) -> bool {
+    head.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .any(|line| line.starts_with([' ', '\t']))
+}
+
+/// Why a request head is refused before any rule is looked at, for the request smuggling
+/// vectors `reject_ambiguous_framing` and `reject_obs_fold` guard against
+pub(crate) fn smuggling_reason(config: &Config, head: &str) -> Option<&'static str> {
+    if config.reject_ambiguous_framing && has_ambiguous_framing(head) {
+        Some("Ambiguous Request Framing")
+    } else if config.reject_obs_fold && has_obs_fold(head) {
+        Some("Obsolete Line Folding")
+    } else {
+        None
+    }
+}
+
+/// Whether a message head asks for the connection to be closed after it (`Connection: close`)
+fn wants_close(head: &str) -> bool {
+    head.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("connection"))
+        .any(|(_, value)| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("close")))
+}
+
+/// Whether a request leaves the connection open for another one: HTTP/1.1 unless it sends
+/// `Connection: close`, HTTP/1.0 only when it asks for `Connection: keep-alive`
+pub(crate) fn keeps_alive(head: &str) -> bool {
+    let version = head.lines().next().unwrap_or("").split_whitespace().nth(2).unwrap_or("");
+    match version {
+        "HTTP/1.0" => head
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("connection"))
+            .any(|(_, value)| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("keep-alive"))),
+        version if version.starts_with("HTTP/") => !wants_close(head),
+        _ => false,
+    }
+}
+
+/// Headers that only describe one connection and aren't forwarded (RFC 9110 §7.6.1).
+/// Transfer-Encoding is hop-by-hop too, but bodies are relayed with their framing intact,
+/// so it has to travel with them.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "upgrade",
+];
+
+/// Remove hop-by-hop headers, plus any header named in `Connection`, from a message head
+/// (which ends with the blank line). Protocol upgrades keep `Connection` and `Upgrade`, which
+/// they need end to end. Returns `None` when there is nothing to remove.
+fn strip_hop_by_hop(head: &str) -> Option<String> {
+    let lines: Vec<&str> = head.split("\r\n").collect();
+    let fields = || lines.iter().skip(1).filter_map(|line| line.split_once(':'));
+    let upgrade = fields().any(|(name, _)| name.trim().eq_ignore_ascii_case("upgrade"));
+
+    let mut hop_by_hop: Vec<String> = HOP_BY_HOP_HEADERS.iter().map(|h| h.to_string()).collect();
+    for (_, value) in fields().filter(|(name, _)| name.trim().eq_ignore_ascii_case("connection")) {
+        hop_by_hop.extend(value.split(',').map(|t| t.trim().to_ascii_lowercase()).filter(|t| !t.is_empty()));
+    }
+    // `Connection` can't talk us into dropping the host or the body framing
+    hop_by_hop.retain(|h| {
+        !["host", "content-length", "transfer-encoding"].contains(&h.as_str())
+            && !(upgrade && (h == "connection" || h == "upgrade"))
+    });
+
+    let is_hop_by_hop = |line: &str| {
+        line.split_once(':')
+            .is_some_and(|(name, _)| hop_by_hop.iter().any(|h| name.trim().eq_ignore_ascii_case(h)))
+    };
+    if !lines.iter().skip(1).any(|line| is_hop_by_hop(line)) {
+        return None;
+    }
+    let kept: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .filter(|(i, line)| *i == 0 || !is_hop_by_hop(line))
+        .map(|(_, line)| *line)
+        .collect();
+    Some(kept.join("\r\n"))
+}
+
+// ============================================================================
+// Request Rewriting
+// ============================================================================
+
+/// Replace the `Host` header of a request (adding one if it's missing) with `host`
+pub(crate) fn with_host_header(request: &[u8], host: &str) -> Vec<u8> {
+    let Some(head_end) = request.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4) else {
+        return request.to_vec();
+    };
+    let head = String::from_utf8_lossy(&request[..head_end]);
+    let mut lines: Vec<String> = head
+        .split("\r\n")
+        .filter(|line| !line.split_once(':').is_some_and(|(name, _)| name.trim().eq_ignore_ascii_case("host")))
+        .map(str::to_string)
+        .collect();
+    lines.insert(1, format!("Host: {}", host));
+
+    let mut rewritten = lines.join("\r\n").into_bytes();
+    rewritten.extend_from_slice(&request[head_end..]);
+    rewritten
+}
+
+/// Bytes to send upstream for a request that passed policy checks.
+///
+/// Rules with `preserve_request_bytes` always get the request exactly as it was read from
+/// the client. Everything else goes through `rewrite_request_head`, so any header
+/// stripping/injection is skipped for preserved rules.
+pub(crate) fn upstream_request_bytes<'a>(config: &Config, rule: Option<&HostRule>, request: &'a [u8]) -> Cow<'a, [u8]> {
+    if rule.is_some_and(|r| r.preserve_request_bytes) {
+        return Cow::Borrowed(request);
+    }
+    let rewritten = rewrite_request_head(request);
+    if !config.inject_forwarded_headers {
+        return rewritten;
+    }
+    Cow::Owned(with_forwarded_headers(&rewritten))
+}
+
+/// `X-Forwarded-For` for a forwarded request: the client's address appended to whatever the
+/// client already sent. `None` outside a connection, where there is no client address.
+pub(crate) fn forwarded_for(prior: Option<&str>) -> Option<String> {
+    let client_ip = CONNECTION_STATS.try_with(|stats| stats.lock().unwrap().client_ip).ok().flatten()?;
+    Some(match prior.map(str::trim).filter(|prior| !prior.is_empty()) {
+        Some(prior) => format!("{}, {}", prior, client_ip),
+        None => client_ip.to_string(),
+    })
+}
+
+/// For `inject_forwarded_headers`: set `X-Forwarded-For` and `X-Secure-Proxy: 1` on a request,
+/// replacing any `X-Secure-Proxy` the client sent
+fn with_forwarded_headers(request: &[u8]) -> Vec<u8> {
+    let Some(head_end) = request.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4) else {
+        return request.to_vec();
+    };
+    let head = String::from_utf8_lossy(&request[..head_end]);
+    let forwarded_for = forwarded_for(header_value(&head, "x-forwarded-for"));
+    let names = ["X-Forwarded-For".to_string(), "X-Secure-Proxy".to_string()];
+    let head = without_headers(&head, &names).unwrap_or_else(|| head.to_string());
+    let mut extra = vec![("X-Secure-Proxy", "1")];
+    if let Some(forwarded_for) = &forwarded_for {
+        extra.insert(0, ("X-Forwarded-For", forwarded_for.as_str()));
+    }
+
+    let mut rewritten = with_extra_headers(&head, &extra).into_bytes();
+    rewritten.extend_from_slice(&request[head_end..]);
+    rewritten
+}
+
+/// Largest request body `compress_request_body` will buffer; bigger bodies go as they are
+const MAX_COMPRESSED_REQUEST_BYTES: usize = 8 * 1024 * 1024;
+
+/// For `compress_request_body`: read the rest of the request body from the client and gzip
+/// it, fixing up Content-Length and adding `Content-Encoding: gzip`.
+///
+/// Only uncompressed, compressible bodies with a Content-Length are touched, and only when
+/// gzip actually makes them smaller. Anything read past the body is kept after it. A client
+/// that stalls mid-body for `idle_timeout` fails the session with `TimedOut`.
+pub(crate) async fn gzip_request_body<R>(client: &mut R, mut request: Vec<u8>, idle_timeout: Option<Duration>) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(head_end) = request.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4) else {
+        return Ok(request);
+    };
+    let head = String::from_utf8_lossy(&request[..head_end]).into_owned();
+    let Some(body_len) = compressible_body_len(&head).filter(|len| *len <= MAX_COMPRESSED_REQUEST_BYTES) else {
+        return Ok(request);
+    };
+
+    let mut chunk = vec![0u8; 8192];
+    while request.len() - head_end < body_len {
+        let n = with_deadline(idle_timeout, client.read(&mut chunk)).await?;
+        if n == 0 {
+            return Ok(request); // Cut short; forward what there is unchanged
+        }
+        request.extend_from_slice(&chunk[..n]);
+    }
+
+    let after = request.split_off(head_end + body_len);
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&request[head_end..])?;
+    let compressed = encoder.finish()?;
+    if compressed.len() >= body_len {
+        request.extend_from_slice(&after);
+        return Ok(request);
+    }
+
+    let head = with_extra_headers(&with_content_length(&head, compressed.len()), &[("Content-Encoding", "gzip")]);
+    let mut forwarded = head.into_bytes();
+    forwarded.extend_from_slice(&compressed);
+    forwarded.extend_from_slice(&after);
+    Ok(forwarded)
+}
+
+/// Body length of a request we can compress: a text-like body with a Content-Length and no
+/// content or transfer encoding
+fn compressible_body_len(head: &str) -> Option<usize> {
+    let content_type = header_value(head, "content-type")?;
+    if !is_text_content_type(content_type)
+        || header_value(head, "content-encoding").is_some()
+        || header_value(head, "transfer-encoding").is_some()
+    {
+        return None;
+    }
+    header_value(head, "content-length")?.trim().parse().ok().filter(|len| *len > 0)
+}
+
+/// Rewrite the request head before forwarding: hop-by-hop headers are dropped
+fn rewrite_request_head(request: &[u8]) -> Cow<'_, [u8]> {
+    let Some(head_end) = request.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4) else {
+        return Cow::Borrowed(request);
+    };
+    let Ok(head) = std::str::from_utf8(&request[..head_end]) else {
+        return Cow::Borrowed(request);
+    };
+    match strip_hop_by_hop(head) {
+        Some(head) => {
+            let mut rewritten = head.into_bytes();
+            rewritten.extend_from_slice(&request[head_end..]);
+            Cow::Owned(rewritten)
+        }
+        None => Cow::Borrowed(request),
+    }
+}
+
+// ============================================================================
+// Response Rewriting
+// ============================================================================
+
+/// Leave a response alone when its head is larger than this
+pub(crate) const MAX_RESPONSE_HEAD_BYTES: usize = 64 * 1024;
+
+/// Error returned by `read_response_for_client` when the upstream sent nothing within
+/// `first_response_byte_timeout_ms`
+#[derive(Debug)]
+pub(crate) struct SlowUpstreamResponse;
+
+impl fmt::Display for SlowUpstreamResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Slow Upstream Response")
+    }
+}
+
+impl std::error::Error for SlowUpstreamResponse {}
+
+/// The rule's `response_rewrite`, if it covers this request
+pub(crate) fn response_rewrite_for<'a>(rule: Option<&'a HostRule>, method: &str, path: &str) -> Option<&'a ResponseRewrite> {
+    if method.eq_ignore_ascii_case("HEAD") {
+        return None; // No body to rewrite
+    }
+    rule?
+        .response_rewrite
+        .as_ref()
+        .filter(|r| r.paths.is_empty() || r.paths.iter().any(|p| path.starts_with(p)))
+}
+
+/// Read the first response from upstream and prepare it for the client: drop hop-by-hop
+/// headers and those named in `strip_headers`, add `extra_headers` to its head and apply
+/// `rewrite` to its body, fixing up Content-Length.
+///
+/// Only uncompressed text bodies with a Content-Length of at most `max_bytes` are rewritten.
+/// Anything not read here flows through the tunnel untouched, as does the whole response if
+/// its head can't be found within `MAX_RESPONSE_HEAD_BYTES`. Fails with `SlowUpstreamResponse`
+/// if no byte arrives within `first_byte_timeout`.
+pub(crate) async fn read_response_for_client<R>(
+    upstream: &mut R,
+    rewrite: Option<&ResponseRewrite>,
+    strip_headers: &[String],
+    extra_headers: &[(&str, &str)],
+    max_bytes: usize,
+    first_byte_timeout: Option<Duration>,
+) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut chunk = vec![0u8; 8192];
+
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_RESPONSE_HEAD_BYTES {
+            return Ok(buf);
+        }
+        let n = match first_byte_timeout.filter(|_| buf.is_empty()) {
+            Some(limit) => tokio::time::timeout(limit, upstream.read(&mut chunk))
+                .await
+                .map_err(|_| SlowUpstreamResponse)??,
+            None => upstream.read(&mut chunk).await?,
+        };
+        if n == 0 {
+            return Ok(buf);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let mut rest = buf.split_off(head_end);
+    let mut head = String::from_utf8_lossy(&buf).into_owned();
+    let mut head_changed = false;
+    if let Some(stripped) = strip_hop_by_hop(&head) {
+        // An upstream that is about to hang up must not look reusable to the client
+        head = if wants_close(&head) { with_extra_headers(&stripped, &[("Connection", "close")]) } else { stripped };
+        head_changed = true;
+    }
+    if let Some(stripped) = without_headers(&head, strip_headers) {
+        head = stripped;
+        head_changed = true;
+    }
+    if !extra_headers.is_empty() {
+        head = with_extra_headers(&head, extra_headers);
+        head_changed = true;
+    }
+
+    let (rewrite, body_len) = match rewrite.zip(rewritable_body_len(&head)) {
+        Some((rewrite, len)) if len <= max_bytes => (rewrite, len),
+        _ => {
+            let mut response = if head_changed { head.into_bytes() } else { buf };
+            response.extend_from_slice(&rest);
+            return Ok(response);
+        }
+    };
+    while rest.len() < body_len {
+        let n = upstream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        rest.extend_from_slice(&chunk[..n]);
+    }
+
+    // Anything past the body belongs to a pipelined response and is passed on as-is
+    let after = rest.split_off(body_len.min(rest.len()));
+    let body = match std::str::from_utf8(&rest) {
+        Ok(body) if rest.len() == body_len => {
+            let body = body.replace(&rewrite.pattern, &rewrite.replace);
+            head = with_content_length(&head, body.len());
+            body.into_bytes()
+        }
+        _ => rest,
+    };
+
+    let mut response = head.into_bytes();
+    response.extend_from_slice(&body);
+    response.extend_from_slice(&after);
+    Ok(response)
+}
+
+/// Body length of a response we can rewrite: a text body with a Content-Length and no
+/// content or transfer encoding
+fn rewritable_body_len(head: &str) -> Option<usize> {
+    let status = head.lines().next()?.split_whitespace().nth(1)?;
+    if status.starts_with('1') || status == "204" || status == "304" {
+        return None;
+    }
+    let text = is_text_content_type(header_value(head, "content-type")?);
+    let encoded = header_value(head, "content-encoding").is_some_and(|v| !v.eq_ignore_ascii_case("identity"));
+    if !text || encoded || header_value(head, "transfer-encoding").is_some() {
+        return None;
+    }
+    header_value(head, "content-length")?.parse().ok()
+}
+
+/// Text, JSON, JavaScript and XML bodies: the ones safe to rewrite and worth compressing
+fn is_text_content_type(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    content_type.starts_with("text/") || ["json", "javascript", "xml"].iter().any(|t| content_type.contains(t))
+}
+
+/// A message head without the fields named in `names`, or `None` if it has none of them
+pub(crate) fn without_headers(head: &str, names: &[String]) -> Option<String> {
+    let listed = |line: &str| {
+        line.split_once(':')
+            .is_some_and(|(name, _)| names.iter().any(|n| name.trim().eq_ignore_ascii_case(n)))
+    };
+    let lines: Vec<&str> = head.split("\r\n").collect();
+    if !lines.iter().skip(1).any(|line| listed(line)) {
+        return None;
+    }
+    let kept: Vec<&str> = lines
+        .iter()
+        .enumerate()
+        .filter(|(i, line)| *i == 0 || !listed(line))
+        .map(|(_, line)| *line)
+        .collect();
+    Some(kept.join("\r\n"))
+}
+
+/// Add headers to a message head (which ends with the blank line)
+pub(crate) fn with_extra_headers(head: &str, headers: &[(&str, &str)]) -> String {
+    let mut head = head.strip_suffix("\r\n").unwrap_or(head).to_string();
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    head
+}
+
+/// Replace the Content-Length header of a message head (which ends with the blank line)
+fn with_content_length(head: &str, len: usize) -> String {
+    head.split("\r\n")
+        .map(|line| match line.split_once(':') {
+            Some((name, _)) if name.trim().eq_ignore_ascii_case("content-length") => {
+                format!("Content-Length: {}", len)
+            }
+            _ => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+// ============================================================================
+// HTTP Trailers
+// ============================================================================
+
+/// Where a chunked body parser is: in a chunk-size line, chunk data, the CRLF after the
+/// data, the trailer section that follows the last chunk, or past the end (or a chunk size
+/// that didn't parse)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    #[default]
+    Size,
+    Data(u64),
+    DataEnd(u8),
+    Trailers,
+    Done,
+    Invalid,
+}
+
+/// Follows a chunked body as it streams past, to pick out its trailer fields
+#[derive(Debug, Default)]
+pub(crate) struct ChunkedScanner {
+    state: ChunkState,
+    line: Vec<u8>,
+    pub(crate) trailers: Vec<(String, String)>,
+}
+
+impl ChunkedScanner {
+    /// Scan the next piece of the body and return how many of its leading bytes come before
+    /// the trailer section. A body that isn't valid chunked encoding ends the scan early.
+    fn feed(&mut self, buf: &[u8]) -> usize {
+        self.scan(buf, false).0
+    }
+
+    /// Scan the next piece of the body, stopping where the trailer section starts. Returns
+    /// how many leading bytes were scanned.
+    pub(crate) fn consume_to_trailers(&mut self, buf: &[u8]) -> usize {
+        self.scan(buf, true).1
+    }
+
+    /// Scan the next piece of the body and return how many of its leading bytes belong to
+    /// the body: everything up to the end of the trailer section, or all of them until then
+    pub(crate) fn consume(&mut self, buf: &[u8]) -> usize {
+        self.scan(buf, false).1
+    }
+
+    /// `feed` and `consume` together: bytes before the trailer section, and bytes up to the
+    /// end of the body (or up to the trailer section, with `stop_at_trailers`)
+    fn scan(&mut self, buf: &[u8], stop_at_trailers: bool) -> (usize, usize) {
+        let mut before_trailers = if self.in_body() { buf.len() } else { 0 };
+        let mut i = 0;
+        while i < buf.len() {
+            match self.state {
+                ChunkState::Size | ChunkState::Trailers if buf[i] != b'\n' => self.line.push(buf[i]),
+                ChunkState::Size => {
+                    let line = String::from_utf8_lossy(&self.line).into_owned();
+                    self.line.clear();
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    self.state = match u64::from_str_radix(size, 16) {
+                        Ok(0) if stop_at_trailers => {
+                            self.state = ChunkState::Trailers;
+                            return (i + 1, i + 1);
+                        }
+                        Ok(0) => {
+                            before_trailers = i + 1;
+                            ChunkState::Trailers
+                        }
+                        Ok(size) => ChunkState::Data(size),
+                        Err(_) => {
+                            self.state = ChunkState::Invalid;
+                            return (buf.len(), buf.len());
+                        }
+                    };
+                }
+                ChunkState::Data(remaining) => {
+                    let take = remaining.min((buf.len() - i) as u64);
+                    i += take as usize;
+                    self.state = if take == remaining { ChunkState::DataEnd(2) } else { ChunkState::Data(remaining - take) };
+                    continue;
+                }
+                ChunkState::DataEnd(left) => {
+                    self.state = if left == 1 { ChunkState::Size } else { ChunkState::DataEnd(left - 1) };
+                }
+                ChunkState::Trailers => {
+                    let line = String::from_utf8_lossy(&self.line).trim_end_matches('\r').to_string();
+                    self.line.clear();
+                    match line.split_once(':') {
+                        Some((name, value)) => self.trailers.push((name.trim().to_string(), value.trim().to_string())),
+                        None => self.state = ChunkState::Done,
+                    }
+                }
+                ChunkState::Done | ChunkState::Invalid => break,
+            }
+            i += 1;
+        }
+        (before_trailers, i)
+    }
+
+    fn in_body(&self) -> bool {
+        !matches!(self.state, ChunkState::Trailers | ChunkState::Done | ChunkState::Invalid)
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        matches!(self.state, ChunkState::Done | ChunkState::Invalid)
+    }
+
+    pub(crate) fn is_invalid(&self) -> bool {
+        self.state == ChunkState::Invalid
+    }
+
+    pub(crate) fn at_trailers(&self) -> bool {
+        self.state == ChunkState::Trailers
+    }
+}
+
+/// Where the body starts in a response we should follow for trailers: a chunked response
+/// to a non-HEAD request that announces them with a `Trailer` header
+pub(crate) fn trailer_body_start(response: &[u8], method: &str) -> Option<usize> {
+    if method.eq_ignore_ascii_case("HEAD") {
+        return None;
+    }
+    let head_end = response.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let head = std::str::from_utf8(&response[..head_end]).ok()?;
+    let status = head.lines().next()?.split_whitespace().nth(1)?;
+    let chunked = header_value(head, "transfer-encoding").is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    let has_body = !(status.starts_with('1') || status == "204" || status == "304");
+    (chunked && has_body && header_value(head, "trailer").is_some()).then_some(head_end)
+}
+
+/// Relay a chunked body to the client up to its trailer section. Returns the trailer fields
+/// and the bytes held back (the trailer section and anything after it) so the caller can
+/// check the trailers before they are forwarded.
+pub(crate) async fn relay_until_trailers<U, C>(
+    upstream: &mut U,
+    client: &mut C,
+    body: &[u8],
+    idle_timeout: Option<Duration>,
+    capture: Option<&Capture>,
+) -> Result<(Vec<(String, String)>, Vec<u8>)>
+where
+    U: AsyncRead + Unpin,
+    C: AsyncWrite + Unpin,
+{
+    let mut scanner = ChunkedScanner::default();
+    let mut held = Vec::new();
+    let mut buf = vec![0u8; 8192];
+    let mut piece = body.to_vec();
+    loop {
+        let forward = scanner.feed(&piece);
+        client.write_all(&piece[..forward]).await?;
+        if let Some(capture) = capture {
+            capture.record(&piece[..forward]);
+        }
+        held.extend_from_slice(&piece[forward..]);
+        if scanner.is_done() {
+            break;
+        }
+        let n = with_deadline(idle_timeout, upstream.read(&mut buf)).await?;
+        if n == 0 {
+            break;
+        }
+        piece = buf[..n].to_vec();
+    }
+    Ok((scanner.trailers, held))
+}
+
+/// Check response trailers against the host rule's `blocked_trailers`
+pub(crate) fn check_trailers(config: &Config, host: &str, trailers: &[(String, String)]) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    let Some(rule) = find_host_rule(config, host) else {
+        return (true, "No Trailer Policy".to_string());
+    };
+    let blocked = trailers.iter().any(|(name, value)| {
+        rule.blocked_trailers
+            .iter()
+            .any(|(blocked_name, values)| blocked_name.eq_ignore_ascii_case(name) && values.contains(value))
+    });
+    if blocked {
+        (false, "Trailer Not Allowed".to_string())
+    } else {
+        (true, "Trailers Allowed".to_string())
+    }
+}
+
+// ============================================================================
+// Static Responses
+// ============================================================================
+
+/// The rule's canned response for this path, if any
+pub(crate) fn static_response_for<'a>(rule: Option<&'a HostRule>, path: &str) -> Option<&'a StaticResponse> {
+    let path = path.split('?').next().unwrap_or(path);
+    rule?.static_responses.iter().find(|r| r.path == path)
+}
+
+/// Whether the host's rule has canned responses, so it's worth serving without an upstream
+pub fn has_static_responses(config: &Config, host: &str) -> bool {
+    find_host_rule(config, host).is_some_and(|r| !r.static_responses.is_empty())
+}
+
+/// Build a full HTTP/1.1 response from a canned response's status, type and body file. An
+/// unreadable body file is logged and answered with a 500.
+pub(crate) async fn render_static_response(response: &StaticResponse) -> Vec<u8> {
+    let body = match tokio::fs::read(&response.body_file).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Failed to read static response {}: {}", response.body_file, e);
+            return b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec();
+        }
+    };
+    let reason = http::StatusCode::from_u16(response.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("");
+    let mut rendered = format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        body.len()
+    )
+    .into_bytes();
+    rendered.extend_from_slice(&body);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::config::Config;
+    use crate::policy::find_host_rule;
+    use crate::intercept::host_header_rewrite;
+
+    #[test]
+    fn check_trailers_follows_the_rule() {
+        let config = grpc_config();
+        let status = |value: &str| vec![("Grpc-Status".to_string(), value.to_string())];
+        assert_eq!(check_trailers(&config, "grpc.example.com", &status("7")), (false, "Trailer Not Allowed".to_string()));
+        assert_eq!(check_trailers(&config, "grpc.example.com", &status("0")), (true, "Trailers Allowed".to_string()));
+        assert_eq!(check_trailers(&config, "other.example.com", &status("7")), (true, "No Trailer Policy".to_string()));
+
+        let monitor = config_from(serde_json::json!({
+            "mode": "monitor",
+            "allowed_rules": [{ "host": "grpc.example.com", "blocked_trailers": { "grpc-status": ["7"] } }]
+        }));
+        assert!(check_trailers(&monitor, "grpc.example.com", &status("7")).0);
+    }
+
+    #[test]
+    fn ambiguous_body_framing_is_refused() {
+        let config = Config::default();
+        let reason = |headers: &str| smuggling_reason(&config, &format!("POST /upload HTTP/1.1\r\nHost: x\r\n{}\r\n", headers));
+        let ambiguous = Some("Ambiguous Request Framing");
+
+        assert_eq!(reason("Content-Length: 5\r\nTransfer-Encoding: chunked\r\n"), ambiguous);
+        assert_eq!(reason("transfer-encoding: chunked\r\ncontent-length: 0\r\n"), ambiguous);
+        assert_eq!(reason("Content-Length: 5\r\nContent-Length: 6\r\n"), ambiguous);
+        assert_eq!(reason("Content-Length: 5, 6\r\n"), ambiguous);
+        assert_eq!(reason("Content-Length: 5\r\nContent-Length: 5\r\n"), None);
+        assert_eq!(reason("Content-Length: 5\r\n"), None);
+        assert_eq!(reason("Transfer-Encoding: chunked\r\n"), None);
+
+        let lenient = config_from(serde_json::json!({ "reject_ambiguous_framing": false }));
+        assert_eq!(smuggling_reason(&lenient, "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn folded_header_lines_are_refused() {
+        let config = Config::default();
+        let reason = |head: &str| smuggling_reason(&config, head);
+        let folded = Some("Obsolete Line Folding");
+
+        assert_eq!(reason("GET / HTTP/1.1\r\nHost: x\r\nX-Note: first\r\n second\r\n\r\n"), folded);
+        assert_eq!(reason("GET / HTTP/1.1\r\nHost: x\r\nX-Note: first\r\n\tsecond\r\n\r\n"), folded);
+        assert_eq!(reason("GET / HTTP/1.1\r\nHost: x\r\nX-Note: first second\r\n\r\n"), None);
+        assert_eq!(reason("GET / HTTP/1.1\r\nHost: x\r\n\r\n indented body line"), None);
+
+        let lenient = config_from(serde_json::json!({ "reject_obs_fold": false }));
+        assert_eq!(smuggling_reason(&lenient, "GET / HTTP/1.1\r\nX-Note: a\r\n\tb\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn preserved_requests_are_forwarded_byte_for_byte() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "inject_forwarded_headers": true,
+            "allowed_rules": [
+                { "host": "exact.preserve.example.com", "preserve_request_bytes": true, "rewrite_host_header": true, "upstream_host": "backend.example.com" },
+                { "host": "normal.preserve.example.com" }
+            ]
+        }));
+        let request: &[u8] = b"GET /a HTTP/1.1\r\nhost: exact.preserve.example.com\r\nConnection: keep-alive, X-Trace\r\nX-Trace: 1\r\nProxy-Authorization: Basic Zm9vOmJhcg==\r\nX-Secure-Proxy: spoofed\r\n\r\nbody\xff";
+        let forwarded = |host: &str| upstream_request_bytes(&config, find_host_rule(&config, host), request).into_owned();
+
+        assert_eq!(forwarded("exact.preserve.example.com"), request);
+        assert_eq!(host_header_rewrite(find_host_rule(&config, "exact.preserve.example.com")), None);
+
+        let rewritten = String::from_utf8_lossy(&forwarded("normal.preserve.example.com")).into_owned();
+        assert!(!rewritten.contains("Proxy-Authorization") && !rewritten.contains("X-Trace"), "{}", rewritten);
+        assert!(rewritten.contains("\r\nX-Secure-Proxy: 1\r\n") && !rewritten.contains("spoofed"), "{}", rewritten);
+    }
+}
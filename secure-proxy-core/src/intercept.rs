@@ -0,0 +1,1038 @@
+//! TLS interception of HTTP/1.1 and HTTP/2 sessions
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use h2::{server::SendResponse, Reason, RecvStream, SendStream};
+use http::{Request, Response};
+use rustls::client::WebPkiServerVerifier;
+use rustls::{CipherSuite, NamedGroup, HandshakeKind, ServerConfig};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::{server::TlsStream, TlsAcceptor, TlsConnector};
+use tracing::{debug, trace, warn};
+
+use crate::config::{Config, HostRule, Timeouts};
+use crate::logging::{classify_user_agent, log_response, log_traffic, print_decision};
+use crate::metrics::{
+    CONNECTION_STATS, TLS_RESUMPTIONS, current_connection_stats, note_connection,
+    record_upstream_status, statsd_count, tls_parameters,
+};
+use crate::policy::{
+    Action, CLIENT_CERT_VERIFIER, Decision, RATE_LIMITED, block_response, blocks_as_unreachable,
+    check_alpn_allowed, check_client_allowed, check_domain_fronting, check_grpc_method,
+    check_http_version, check_jwt_claims, check_rate_limit, check_scheme_allowed, check_upload_type,
+    client_cert_names, evaluate, find_host_rule, host_matches, in_maintenance, maintenance_response,
+    never_intercepted, read_upload_prefix, sniff_upload_type, too_many_requests_response,
+    upload_prefix, wait_for_pacing,
+};
+use crate::http::{
+    SlowUpstreamResponse, check_trailers, forwarded_for, gzip_request_body, header_value,
+    keeps_alive, read_response_for_client, relay_until_trailers, render_static_response,
+    response_rewrite_for, smuggling_reason, static_response_for, trailer_body_start,
+    upstream_request_bytes, with_host_header,
+};
+use crate::tunnel::{close_gracefully, is_websocket_upgrade, tunnel, with_deadline};
+use crate::session::{
+    Capture, MAX_REQUEST_HEAD_BYTES, MessageState, RequestChecks, RequestGate, first_request_split,
+    read_request_head, read_response_head, relay_request_body, request_line, scan_responses,
+};
+use crate::tls::{
+    InsecureCertVerifier, PinnedCertVerifier, hex_sha256, is_pin_mismatch, normalize_fingerprint,
+    upstream_cert_problem,
+};
+use crate::ca::{CaAuthority, is_valid_hostname};
+
+// ============================================================================
+// Interception
+// ============================================================================
+
+/// Upstream leg of an intercepted session: TLS, or plain TCP for `upstream_plaintext` rules
+trait UpstreamStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> UpstreamStream for T {}
+
+/// Build the client-facing TLS config presenting a generated leaf for `hostname`
+pub async fn client_tls_config(ca: &Arc<CaAuthority>, hostname: &str, config: &Config) -> Result<ServerConfig> {
+    let leaf = ca.leaf_for_host(hostname).await?;
+    let builder = match CLIENT_CERT_VERIFIER.get() {
+        Some(verifier) => ServerConfig::builder().with_client_cert_verifier(verifier.clone()),
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let mut server_config = builder.with_single_cert(leaf.0.clone(), leaf.1.clone_key())?;
+    server_config.session_storage = ca.sessions.clone();
+    // Only what the interception can parse is offered, so a client that also speaks h2
+    // doesn't fall back to HTTP/1.1 by guesswork
+    server_config.alpn_protocols = if config.intercept_http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    Ok(server_config)
+}
+
+/// Complete the client's TLS handshake. A client offering only protocols the proxy doesn't
+/// intercept (e.g. just `h2` without `intercept_http2`) fails it; that is logged as a block
+/// with reason `No Shared ALPN` and comes back as `None`.
+pub async fn accept_client_tls(
+    acceptor: TlsAcceptor,
+    client: TcpStream,
+    hostname: &str,
+    config: &Config,
+    timeout: Option<Duration>,
+) -> Result<Option<TlsStream<TcpStream>>> {
+    match with_deadline(timeout, acceptor.accept(client)).await {
+        Ok(client_tls) => Ok(Some(client_tls)),
+        Err(e) if e.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) == Some(&rustls::Error::NoApplicationProtocol) => {
+            let reason = "No Shared ALPN";
+            log_traffic(config, "BLOCK", hostname, "/", "CONNECT", reason, &[]);
+            print_decision(config, format_args!("⛔ [{}] TLS {} -> {} (client offered no protocol the proxy intercepts)", config.mode, hostname, reason));
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Cipher suites and key exchange groups in the order a client offers them. Empty lists
+/// keep the crypto provider's order; anything a list leaves out goes after what it names.
+pub(crate) struct TlsProfile {
+    cipher_suites: &'static [CipherSuite],
+    kx_groups: &'static [NamedGroup],
+}
+
+/// Chrome's preferences: AES-GCM ahead of ChaCha20, ECDSA ahead of RSA
+const CHROME_TLS_PROFILE: TlsProfile = TlsProfile {
+    cipher_suites: &[
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+        CipherSuite::TLS13_AES_256_GCM_SHA384,
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ],
+    kx_groups: &[NamedGroup::X25519MLKEM768, NamedGroup::X25519, NamedGroup::secp256r1, NamedGroup::secp384r1],
+};
+
+/// Firefox's preferences: ChaCha20 between the two AES-GCM sizes
+const FIREFOX_TLS_PROFILE: TlsProfile = TlsProfile {
+    cipher_suites: &[
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+        CipherSuite::TLS13_AES_256_GCM_SHA384,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    ],
+    kx_groups: &[NamedGroup::X25519MLKEM768, NamedGroup::X25519, NamedGroup::secp256r1, NamedGroup::secp384r1],
+};
+
+pub(crate) fn tls_profile(name: &str) -> Option<&'static TlsProfile> {
+    match name {
+        "default" => Some(&TlsProfile { cipher_suites: &[], kx_groups: &[] }),
+        "chrome" => Some(&CHROME_TLS_PROFILE),
+        "firefox" => Some(&FIREFOX_TLS_PROFILE),
+        _ => None,
+    }
+}
+
+/// The installed crypto provider with its cipher suites and key exchange groups reordered
+/// to `upstream_tls_profile`
+fn upstream_crypto_provider(config: &Config) -> Result<rustls::crypto::CryptoProvider> {
+    let mut provider = rustls::crypto::CryptoProvider::get_default()
+        .ok_or_else(|| anyhow!("no crypto provider installed"))?
+        .as_ref()
+        .clone();
+    // `validate` has checked the name already
+    if let Some(profile) = tls_profile(&config.upstream_tls_profile) {
+        let (suites, groups) = (profile.cipher_suites, profile.kx_groups);
+        provider
+            .cipher_suites
+            .sort_by_key(|suite| suites.iter().position(|s| *s == suite.suite()).unwrap_or(suites.len()));
+        provider
+            .kx_groups
+            .sort_by_key(|group| groups.iter().position(|g| *g == group.name()).unwrap_or(groups.len()));
+    }
+    Ok(provider)
+}
+
+/// Build the upstream-facing TLS config: webpki roots, plus certificate pinning when the
+/// matched rule lists `upstream_pin_sha256`
+fn upstream_tls_config(config: &Config, host: &str, rule: Option<&HostRule>, client_alpn: &str) -> Result<rustls::ClientConfig> {
+    let roots = Arc::new(rustls::RootCertStore::from_iter(
+        webpki_roots::TLS_SERVER_ROOTS.iter().cloned()
+    ));
+    let provider = Arc::new(upstream_crypto_provider(config)?);
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let mut client_config = match rule.filter(|r| !r.upstream_pin_sha256.is_empty()) {
+        _ if config.insecure_upstreams.iter().any(|pattern| host_matches(pattern, host)) => {
+            warn!("Not verifying the upstream certificate for {} (insecure_upstreams)", host);
+            let verifier = InsecureCertVerifier {
+                algorithms: provider.signature_verification_algorithms,
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        }
+        Some(rule) => {
+            let verifier = PinnedCertVerifier {
+                inner: WebPkiServerVerifier::builder(roots).build()?,
+                pins: rule.upstream_pin_sha256.iter().map(|p| normalize_fingerprint(p)).collect(),
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        }
+        None => builder
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    };
+
+    client_config.alpn_protocols = match rule.filter(|r| !r.upstream_alpn.is_empty()) {
+        Some(rule) => rule.upstream_alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+        None => vec![client_alpn.as_bytes().to_vec()],
+    };
+    Ok(client_config)
+}
+
+/// Where a host's upstream connection goes: its rule's `upstream_host` (with that port, or
+/// `port` when it names none), otherwise the host itself
+pub fn upstream_target(rule: Option<&HostRule>, host: &str, port: u16) -> (String, u16) {
+    match rule.and_then(|r| r.upstream_host.as_deref()) {
+        Some(upstream) => match upstream.rsplit_once(':') {
+            Some((name, upstream_port)) => (name.to_string(), upstream_port.parse().unwrap_or(port)),
+            None => (upstream.to_string(), port),
+        },
+        None => (host.to_string(), port),
+    }
+}
+
+/// `ip` or `ip:port` (`[v6]:port` for IPv6) from `upstream_addr`, with `port` filling in a
+/// missing port
+pub(crate) fn parse_upstream_addr(addr: &str, port: u16) -> Option<SocketAddr> {
+    addr.parse::<SocketAddr>()
+        .ok()
+        .filter(|addr| addr.port() != 0)
+        .or_else(|| {
+            let ip = addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok()?;
+            Some(SocketAddr::new(ip, port))
+        })
+}
+
+/// The address to dial for a host's upstream: its rule's `upstream_addr` when set, so no
+/// name is resolved, otherwise `upstream_target` as `name:port`
+pub fn upstream_dial_addr(rule: Option<&HostRule>, host: &str, port: u16) -> String {
+    let (name, port) = upstream_target(rule, host, port);
+    match rule.and_then(|r| r.upstream_addr.as_deref()).and_then(|addr| parse_upstream_addr(addr, port)) {
+        Some(addr) => addr.to_string(),
+        None => format!("{}:{}", name, port),
+    }
+}
+
+/// The name to put in forwarded `Host` headers, for rules with `rewrite_host_header`
+pub(crate) fn host_header_rewrite(rule: Option<&HostRule>) -> Option<&str> {
+    rule.filter(|r| r.rewrite_host_header && !r.preserve_request_bytes)?.upstream_host.as_deref()
+}
+
+pub fn gateway_timeout_response() -> &'static str {
+    "HTTP/1.1 504 Gateway Timeout\r\n\
+     Content-Length: 0\r\n\
+     Connection: close\r\n\r\n"
+}
+
+fn bad_gateway_response() -> &'static str {
+    "HTTP/1.1 502 Bad Gateway\r\n\
+     Content-Length: 0\r\n\
+     Connection: close\r\n\r\n"
+}
+
+/// 502 telling the client why the real upstream's certificate was refused
+fn upstream_cert_error_response(problem: &str) -> String {
+    let body = format!("Upstream certificate rejected: {}\n", problem);
+    format!(
+        "HTTP/1.1 502 Bad Gateway\r\n\
+         Content-Type: text/plain\r\n\
+         X-Upstream-Cert-Error: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        problem,
+        body.len(),
+        body
+    )
+}
+
+/// Policy decision for an HTTP/1.x request: the rules for its host and path, then its HTTP
+/// version, `Host` header and bearer token
+pub(crate) fn http1_decision(config: &Config, hostname: &str, head: &str, method: &str, path: &str, version: &str) -> Decision {
+    evaluate(config, hostname, path, method)
+        .and_check(|| check_http_version(config, version))
+        .and_check(|| check_domain_fronting(config, hostname, header_value(head, "host")))
+        .and_check(|| check_jwt_claims(config, hostname, header_value(head, "authorization")))
+        .and_check(|| check_rate_limit(config, hostname))
+}
+
+/// Whether an enforce-mode HTTP/1.1 session to this host has nothing to check per request:
+/// its rule allows the whole host and neither it nor a global setting looks at or rewrites
+/// requests or responses. Such sessions skip request parsing and are tunneled as-is.
+pub(crate) fn host_only_session(config: &Config, hostname: &str) -> bool {
+    let Some(rule) = find_host_rule(config, hostname) else {
+        return false;
+    };
+    config.mode == "enforce"
+        && rule.allowed_paths.is_empty()
+        && config.empty_paths_means == "allow_all"
+        && rule.allowed_methods.is_empty()
+        && !rule.compress_request_body
+        && host_header_rewrite(Some(rule)).is_none()
+        && rule.pace_requests_ms.is_none()
+        && rule.rate_limit.is_none()
+        && rule.response_rewrite.is_none()
+        && rule.blocked_trailers.is_empty()
+        && rule.static_responses.is_empty()
+        && !rule.capture
+        && !rule.capture_har
+        && rule.required_jwt_claims.is_empty()
+        && rule.blocked_upload_mimes.is_empty()
+        && rule.strip_response_headers.is_empty()
+        && !rule.quarantine
+        && rule.quarantine_patterns.is_empty()
+        && config.allowed_http_versions.is_empty()
+        && config.allowed_methods.is_empty()
+        && !config.block_domain_fronting
+        && !config.classify_user_agent
+        && !config.expose_upstream_cert_header
+        && !config.inject_forwarded_headers
+        && config.first_response_byte_timeout_ms.is_none()
+        && !config.blocked_rules.iter().any(|blocked| host_matches(&blocked.host, hostname))
+        && !in_maintenance(config, hostname)
+}
+
+/// Whether a host refused before interception can still be answered inside TLS; the
+/// unreachable block style and hosts whose TLS is never terminated can't be
+pub fn blocks_inside_tls(config: &Config, host: &str) -> bool {
+    !config.audit_only
+        && !never_intercepted(config, host)
+        && !blocks_as_unreachable(config, host)
+        && is_valid_hostname(host)
+}
+
+/// Terminate TLS for a host that is already refused for `reason` and answer its request with
+/// the block response, so it looks the same as a path blocked on an allowed host. The
+/// request is logged with the refusal's reason. Only HTTP/1.1 is offered, since there's no
+/// upstream to carry an HTTP/2 session.
+pub async fn serve_blocked_host(
+    client: TcpStream,
+    ca: &Arc<CaAuthority>,
+    hostname: &str,
+    config: &Arc<Config>,
+    reason: &str,
+) -> Result<()> {
+    let timeouts = config.timeouts_for(hostname);
+    let mut server_config = client_tls_config(ca, hostname, config).await?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let Some(mut client_tls) = accept_client_tls(acceptor, client, hostname, config, timeouts.handshake).await? else {
+        return Ok(());
+    };
+
+    let request = read_request_head(&mut client_tls, timeouts.idle).await?;
+    if !request.is_empty() {
+        let request = String::from_utf8_lossy(&request);
+        let (method, path, _) = request_line(&request);
+        log_traffic(config, "BLOCK", hostname, path, method, reason, &[]);
+        print_decision(config, format_args!("⛔ [{}] {} {}{} -> {}", config.mode, method, hostname, path, reason));
+        let rule = find_host_rule(config, hostname);
+        let response = block_response(config, rule, hostname, path, reason, header_value(&request, "accept"));
+        client_tls.write_all(response.as_bytes()).await?;
+    }
+    close_gracefully(&mut client_tls).await;
+    Ok(())
+}
+
+/// Serve an intercepted session once the client's TLS has been terminated: open the upstream
+/// leg, read and check the request, then forward it and tunnel the rest of the connection
+pub async fn intercept_session(
+    mut client_tls: TlsStream<TcpStream>,
+    upstream: Option<TcpStream>,
+    hostname: &str,
+    config: &Arc<Config>,
+    timeouts: Timeouts,
+) -> Result<()> {
+    let alpn = client_tls
+        .get_ref()
+        .1
+        .alpn_protocol()
+        .map(|p| String::from_utf8_lossy(p).into_owned())
+        .unwrap_or_else(|| "http/1.1".to_string());
+    trace!("Client TLS handshake complete");
+    note_connection(|stats| stats.client_tls = Some(tls_parameters(client_tls.get_ref().1)));
+    if client_tls.get_ref().1.handshake_kind() == Some(HandshakeKind::Resumed) {
+        TLS_RESUMPTIONS.fetch_add(1, Ordering::Relaxed);
+        statsd_count("tls.resumed", &[]);
+        note_connection(|stats| stats.resumed = true);
+    }
+    debug!("Client negotiated ALPN {} for {}", alpn, hostname);
+    let (alpn_allowed, reason) = check_alpn_allowed(config, hostname, &alpn);
+    if !alpn_allowed {
+        log_traffic(config, "BLOCK", hostname, "/", "CONNECT", &reason, &[]);
+        print_decision(config, format_args!("⛔ [{}] CONNECT {} ({}) -> {}", config.mode, hostname, alpn, reason));
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    }
+    let client_h2 = alpn == "h2";
+
+    // Intercepted sessions always reach us over TLS
+    let (scheme_allowed, reason) = check_scheme_allowed(config, hostname, "https");
+    if !scheme_allowed {
+        log_traffic(config, "BLOCK", hostname, "/", "CONNECT", &reason, &[]);
+        print_decision(config, format_args!("⛔ [{}] CONNECT https://{} -> {}", config.mode, hostname, reason));
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    }
+
+    // Rules with `allowed_clients` only accept the mTLS identities they list
+    let client_names = client_cert_names(&client_tls);
+    let (client_allowed, reason) = check_client_allowed(config, hostname, &client_names);
+    if !client_allowed {
+        let client_id = client_names.first().map(String::as_str).unwrap_or("anonymous");
+        log_traffic(config, "BLOCK", hostname, "/", "CONNECT", &reason, &[("client_id", client_id)]);
+        print_decision(config, format_args!("⛔ [{}] CONNECT {} (client {}) -> {}", config.mode, hostname, client_id, reason));
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    }
+
+    // Plaintext upstreams get the decrypted request as-is; everything else is re-encrypted.
+    // Without an upstream (unreachable, but the rule has static responses) only those are served.
+    let rule = find_host_rule(config, hostname);
+    let mut upstream_fingerprint = None;
+    let upstream: Option<Box<dyn UpstreamStream>> = match upstream {
+        None => None,
+        Some(upstream) if rule.is_some_and(|r| r.upstream_plaintext) => Some(Box::new(upstream)),
+        Some(upstream) => {
+            let connector = TlsConnector::from(Arc::new(upstream_tls_config(config, hostname, rule, &alpn)?));
+            let server_name = match rule.and_then(|r| r.upstream_sni.clone()) {
+                Some(sni) => sni,
+                None => upstream_target(rule, hostname, 443).0,
+            };
+            let server_name = server_name.try_into()?;
+            let upstream_tls = match with_deadline(timeouts.handshake, connector.connect(server_name, upstream)).await {
+                Ok(tls) => tls,
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    let reason = "Upstream Timeout";
+                    log_traffic(config, "BLOCK", hostname, "/", "CONNECT", reason, &[]);
+                    print_decision(config, format_args!("⛔ [{}] CONNECT {} -> {} (TLS handshake)", config.mode, hostname, reason));
+                    if !client_h2 {
+                        client_tls.write_all(gateway_timeout_response().as_bytes()).await?;
+                    }
+                    close_gracefully(&mut client_tls).await;
+                    return Ok(());
+                }
+                Err(e) if is_pin_mismatch(&e) => {
+                    let reason = "Upstream Pin Mismatch";
+                    log_traffic(config, "BLOCK", hostname, "/", "CONNECT", reason, &[]);
+                    print_decision(config, format_args!("⛔ [{}] CONNECT {} -> {}", config.mode, hostname, reason));
+                    if !client_h2 {
+                        client_tls.write_all(bad_gateway_response().as_bytes()).await?;
+                    }
+                    close_gracefully(&mut client_tls).await;
+                    return Ok(());
+                }
+                Err(e) if config.propagate_upstream_cert_errors && upstream_cert_problem(&e).is_some() => {
+                    let problem = upstream_cert_problem(&e).unwrap_or_default();
+                    let reason = "Upstream Certificate Invalid";
+                    log_traffic(config, "BLOCK", hostname, "/", "CONNECT", reason, &[("upstream_cert_error", &problem)]);
+                    print_decision(config, format_args!("⛔ [{}] CONNECT {} -> {} ({})", config.mode, hostname, reason, problem));
+                    if !client_h2 {
+                        client_tls.write_all(upstream_cert_error_response(&problem).as_bytes()).await?;
+                    }
+                    close_gracefully(&mut client_tls).await;
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            trace!("Upstream TLS handshake complete");
+            note_connection(|stats| stats.upstream_tls = Some(tls_parameters(upstream_tls.get_ref().1)));
+            let upstream_alpn = upstream_tls.get_ref().1.alpn_protocol();
+            debug!(
+                "Upstream negotiated ALPN {} for {}",
+                upstream_alpn.map(String::from_utf8_lossy).as_deref().unwrap_or("none"),
+                hostname
+            );
+            if client_h2 && upstream_alpn != Some(b"h2") {
+                bail!("Upstream {} did not negotiate HTTP/2", hostname);
+            }
+            if config.expose_upstream_cert_header {
+                upstream_fingerprint = upstream_tls
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .map(|leaf| hex_sha256(leaf));
+            }
+            Some(Box::new(upstream_tls))
+        }
+    };
+
+    if client_h2 {
+        let Some(upstream) = upstream else {
+            bail!("No upstream for HTTP/2 session to {}; static responses need HTTP/1.1", hostname);
+        };
+        return intercept_http2(client_tls, upstream, hostname, config).await;
+    }
+
+    // Nothing to check per request, so requests go straight into the tunnel. They're still
+    // followed so each response is logged and counted.
+    if upstream.is_some() && host_only_session(config, hostname) {
+        let upstream = upstream.ok_or_else(|| anyhow!("No upstream connection to {}", hostname))?;
+        let reason = "Host Only Rule";
+        let fields: Vec<_> = client_names.first().map(|client_id| ("client_id", client_id.as_str())).into_iter().collect();
+        log_traffic(config, "ALLOW", hostname, "/", "CONNECT", reason, &fields);
+        print_decision(config, format_args!("✅ [{}] CONNECT {} -> {}", config.mode, hostname, reason));
+        let gate = RequestGate::from_start(RequestChecks { config, hostname, rule, client_id: None, host_only: true });
+        return tunnel(client_tls, upstream, config, hostname, timeouts.idle, None, Some(gate)).await;
+    }
+
+    // Now we have decrypted streams. Read HTTP request.
+    let mut request_buf = read_request_head(&mut client_tls, timeouts.idle).await?;
+    // Uploads are sniffed on the first request only, so sessions that sniff end after it
+    let sniff_uploads = rule.is_some_and(|r| !r.blocked_upload_mimes.is_empty());
+    if sniff_uploads && request_buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        read_upload_prefix(&mut client_tls, &mut request_buf, timeouts.idle).await?;
+    }
+    let request_data = &request_buf[..];
+    let n = request_data.len();
+    trace!("Read {} request bytes: {:?}", n, String::from_utf8_lossy(&request_data[..n.min(1024)]));
+    if n > MAX_REQUEST_HEAD_BYTES && !request_data.windows(4).any(|w| w == b"\r\n\r\n") {
+        let reason = "Request Head Too Large";
+        log_traffic(config, "BLOCK", hostname, "/", "?", reason, &[]);
+        print_decision(config, format_args!("⛔ [{}] {} -> {}", config.mode, hostname, reason));
+        let response = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        client_tls.write_all(response.as_bytes()).await?;
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    }
+
+    // Parse HTTP request line
+    let request_str = String::from_utf8_lossy(request_data);
+    let (method, path, version) = request_line(&request_str);
+
+    // Heads the upstream might read differently are refused outright, like an invalid hostname
+    if let Some(reason) = smuggling_reason(config, &request_str) {
+        log_traffic(config, "BLOCK", hostname, path, method, reason, &[]);
+        print_decision(config, format_args!("⛔ [{}] {} {}{} -> {}", config.mode, method, hostname, path, reason));
+        let response = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        client_tls.write_all(response.as_bytes()).await?;
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    }
+
+    if in_maintenance(config, hostname) {
+        log_traffic(config, "MAINTENANCE", hostname, path, method, "Maintenance Mode", &[]);
+        print_decision(config, format_args!("🚧 [{}] {} {}{} -> Maintenance Mode", config.mode, method, hostname, path));
+        client_tls.write_all(maintenance_response(config).as_bytes()).await?;
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    }
+
+    // Check path-level rules
+    let upload_type = sniff_uploads.then(|| upload_prefix(request_data).and_then(sniff_upload_type)).flatten();
+    let decision = http1_decision(config, hostname, &request_str, method, path, version)
+        .and_check(|| check_upload_type(config, hostname, upload_type))
+        .warn_only(config, hostname);
+    let reason = &decision.reason;
+    let client_type = config
+        .classify_user_agent
+        .then(|| classify_user_agent(config, header_value(&request_str, "user-agent")));
+    let mut fields = Vec::new();
+    if let Some(client_type) = &client_type {
+        fields.push(("client_type", client_type.as_str()));
+    }
+    if let Some(client_id) = client_names.first() {
+        fields.push(("client_id", client_id.as_str()));
+    }
+    if let Some(upload_type) = upload_type {
+        fields.push(("upload_type", upload_type));
+    }
+    fields.extend(decision.rule_host(config).map(|rule| ("rule", rule)));
+    let static_response = if decision.allowed() { static_response_for(rule, path) } else { None };
+    let (action, icon) = match static_response {
+        Some(_) => ("STATIC", "📄"),
+        None => (decision.action.as_str(), decision.action.icon()),
+    };
+    log_traffic(config, action, hostname, path, method, reason, &fields);
+    print_decision(config, format_args!("{} [{}] {} {}{} -> {}", icon, config.mode, method, hostname, path, reason));
+
+    if !decision.allowed() {
+        // Send 403 (or redirect) response, or a 429 for rate-limited requests
+        let response = match decision.reason.as_str() {
+            RATE_LIMITED if !config.uniform_block_response => too_many_requests_response(rule),
+            reason => block_response(config, rule, hostname, path, reason, header_value(&request_str, "accept")),
+        };
+        client_tls.write_all(response.as_bytes()).await?;
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    }
+
+    // Canned responses are answered here and never reach the upstream
+    if let Some(static_response) = static_response {
+        client_tls.write_all(&render_static_response(static_response).await).await?;
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    }
+    let Some(mut upstream) = upstream else {
+        client_tls.write_all(bad_gateway_response().as_bytes()).await?;
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    };
+
+    if !wait_for_pacing(config, rule, hostname, path, method).await {
+        let response = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        client_tls.write_all(response.as_bytes()).await?;
+        close_gracefully(&mut client_tls).await;
+        return Ok(());
+    }
+
+    // Forward request to upstream
+    let mut forwarded = upstream_request_bytes(config, rule, request_data);
+    if let Some(upstream_host) = host_header_rewrite(rule) {
+        forwarded = Cow::Owned(with_host_header(&forwarded, upstream_host));
+    }
+    if rule.is_some_and(|r| r.compress_request_body && !r.preserve_request_bytes) {
+        forwarded = Cow::Owned(gzip_request_body(&mut client_tls, forwarded.into_owned(), timeouts.idle).await?);
+    }
+    // Only the first request goes now; anything the client pipelined behind it is checked
+    // in the tunnel like every later request
+    let (first_len, mut requests) = first_request_split(&forwarded);
+    upstream.write_all(&forwarded[..first_len]).await?;
+    let capture = Capture::start(config, rule, hostname, decision.action == Action::Quarantine);
+    if let Some(capture) = &capture {
+        capture.record(&forwarded[..first_len]);
+        capture.request(&forwarded[..first_len]);
+    }
+    let mut pending = forwarded[first_len..].to_vec();
+    // The upstream answers once it has the whole body, unless the client waits for a
+    // `100 Continue` before sending it
+    if !header_value(&request_str, "expect").is_some_and(|v| v.eq_ignore_ascii_case("100-continue")) {
+        relay_request_body(&mut client_tls, &mut upstream, &mut requests, &mut pending, timeouts.idle, capture.as_ref())
+            .await?;
+    }
+    let gate_checks = RequestChecks {
+        config,
+        hostname,
+        rule,
+        client_id: client_names.first().map(String::as_str),
+        host_only: false,
+    };
+
+    // An upgrade request's response is passed on untouched (its `Upgrade` and `Connection`
+    // headers matter). If the upstream switches protocols, the rest of the connection is a
+    // plain tunnel; if it declines, requests carry on being checked as usual.
+    let mut idle_timeout = timeouts.idle;
+    let mut gate = None;
+    if header_value(&request_str, "upgrade").is_some() {
+        let response = read_response_head(&mut upstream, timeouts.idle).await?;
+        if response.is_empty() {
+            close_gracefully(&mut client_tls).await;
+            return Ok(());
+        }
+        let mut responses = MessageState::Head(Vec::new());
+        let mut awaiting = VecDeque::from([(method.to_string(), path.to_string())]);
+        let (upgraded, _) = scan_responses(config, hostname, &mut responses, &mut awaiting, &response, capture.as_ref(), None);
+        client_tls.write_all(&response).await?;
+        if let Some(capture) = &capture {
+            capture.record(&response);
+        }
+        if upgraded {
+            debug!("{} switched protocols, tunneling the rest of the connection", hostname);
+            // Anything the client sent after the upgrade request is already the new protocol
+            upstream.write_all(&pending).await?;
+            // A WebSocket tunnel can sit quiet for long stretches, so it gets its own
+            // (usually more lenient) idle timeout
+            if is_websocket_upgrade(&request_str) {
+                idle_timeout = config.websocket_idle_timeout();
+            }
+        } else {
+            gate = Some(RequestGate {
+                checks: gate_checks,
+                requests,
+                pending,
+                responses,
+                awaiting,
+                responding: (method.to_string(), path.to_string()),
+                forwarded: 1,
+                closing: !keeps_alive(&request_str) || config.max_requests_per_connection == Some(1),
+            });
+        }
+    } else {
+        let rewrite = response_rewrite_for(rule, method, path);
+        // Only the first response's head is rewritten, so sessions stripping headers end after it
+        let strip_headers = rule.map_or(&[][..], |r| &r.strip_response_headers);
+        let single_request =
+            sniff_uploads || !strip_headers.is_empty() || config.max_requests_per_connection == Some(1);
+        let extra_headers: Vec<(&str, &str)> = upstream_fingerprint
+            .as_deref()
+            .map(|fingerprint| ("X-Upstream-Cert-Fingerprint", fingerprint))
+            .into_iter()
+            .chain(single_request.then_some(("Connection", "close")))
+            .collect();
+        let response = match read_response_for_client(
+            &mut upstream,
+            rewrite,
+            strip_headers,
+            &extra_headers,
+            config.response_rewrite_max_bytes,
+            config.first_response_byte_timeout(),
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(e) if e.is::<SlowUpstreamResponse>() => {
+                let reason = "Slow Upstream Response";
+                log_traffic(config, "BLOCK", hostname, path, method, reason, &[]);
+                print_decision(config, format_args!("⛔ [{}] {} {}{} -> {}", config.mode, method, hostname, path, reason));
+                client_tls.write_all(gateway_timeout_response().as_bytes()).await?;
+                close_gracefully(&mut client_tls).await;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let trailer_body = trailer_body_start(&response, method);
+        let body_start = trailer_body.unwrap_or(response.len());
+        client_tls.write_all(&response[..body_start]).await?;
+        if let Some(capture) = &capture {
+            capture.record(&response[..body_start]);
+        }
+
+        // Responses that announce trailers are followed to the end so the trailers can be
+        // logged and checked before the client sees them
+        if trailer_body.is_some() {
+            let (trailers, held) =
+                relay_until_trailers(&mut upstream, &mut client_tls, &response[body_start..], idle_timeout, capture.as_ref())
+                    .await?;
+            let (trailers_allowed, reason) = check_trailers(config, hostname, &trailers);
+            let trailer_fields: Vec<String> = trailers.iter().map(|(name, value)| format!("{}: {}", name, value)).collect();
+            note_connection(|stats| stats.trailers.extend(trailers));
+            if !trailers_allowed {
+                let trailer_fields = trailer_fields.join(", ");
+                log_traffic(config, "BLOCK", hostname, path, method, &reason, &[("trailers", &trailer_fields)]);
+                print_decision(config, format_args!("⛔ [{}] {} {}{} ({}) -> {}", config.mode, method, hostname, path, trailer_fields, reason));
+                // The body is out already; cutting it off before the trailers fails the response
+                return Ok(());
+            }
+            client_tls.write_all(&held).await?;
+            if let Some(capture) = &capture {
+                capture.record(&held);
+            }
+        }
+
+        // Later requests on this connection are checked one by one in the tunnel
+        let mut responses = MessageState::Head(Vec::new());
+        let mut awaiting = VecDeque::from([(method.to_string(), path.to_string())]);
+        if let Some(body_start) = trailer_body {
+            // Followed to its end already
+            awaiting.clear();
+            let head = String::from_utf8_lossy(&response[..body_start]);
+            let status = head.split_whitespace().nth(1).unwrap_or("");
+            record_upstream_status(status);
+            log_response(config, hostname, method, path, status);
+            if let Some(capture) = &capture {
+                capture.response(&head);
+            }
+        } else {
+            scan_responses(config, hostname, &mut responses, &mut awaiting, &response, capture.as_ref(), None);
+        }
+        gate = Some(RequestGate {
+            checks: gate_checks,
+            requests,
+            pending,
+            responses,
+            awaiting,
+            responding: (method.to_string(), path.to_string()),
+            forwarded: 1,
+            closing: single_request || !keeps_alive(&request_str),
+        });
+    }
+
+    // Bidirectional copy
+    trace!("Request forwarded, tunneling the rest of the connection");
+    tunnel(client_tls, upstream, config, hostname, idle_timeout, capture.as_ref(), gate).await
+}
+
+// ============================================================================
+// HTTP/2 Interception
+// ============================================================================
+
+/// Serve an HTTP/2 session: every client stream is checked against the rules on its own and
+/// forwarded over a single upstream h2 connection. Blocked streams are reset with REFUSED_STREAM.
+async fn intercept_http2(
+    client_tls: TlsStream<TcpStream>,
+    upstream: Box<dyn UpstreamStream>,
+    hostname: &str,
+    config: &Arc<Config>,
+) -> Result<()> {
+    let (send_request, connection) = h2::client::handshake(upstream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Upstream HTTP/2 connection closed: {}", e);
+        }
+    });
+
+    let mut server = h2::server::handshake(client_tls).await?;
+    while let Some(stream) = server.accept().await {
+        let (request, respond) = stream?;
+        let send_request = send_request.clone();
+        let hostname = hostname.to_string();
+        let config = config.clone();
+        let stats = current_connection_stats();
+        tokio::spawn(async move {
+            let stream = proxy_http2_stream(request, respond, send_request, &hostname, &config);
+            let result = match stats {
+                Some(stats) => CONNECTION_STATS.scope(stats, stream).await,
+                None => stream.await,
+            };
+            if let Err(e) = result {
+                debug!("HTTP/2 stream to {} failed: {}", hostname, e);
+            }
+        });
+    }
+
+    // A blocked stream doesn't end the session; the client closing it does
+    note_connection(|stats| stats.close_reason = Some("Closed".to_string()));
+    Ok(())
+}
+
+/// gRPC calls are HTTP/2 requests with an `application/grpc` content type
+fn is_grpc_request<T>(request: &Request<T>) -> bool {
+    request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+/// Trailers-only gRPC response for a blocked call: `grpc-status: 7` (PERMISSION_DENIED)
+fn grpc_permission_denied() -> Response<()> {
+    let mut response = Response::new(());
+    let headers = response.headers_mut();
+    headers.insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("application/grpc"));
+    headers.insert("grpc-status", http::HeaderValue::from_static("7"));
+    headers.insert("grpc-message", http::HeaderValue::from_static("Blocked by policy"));
+    response
+}
+
+/// Check one HTTP/2 stream using its `:method`/`:path` pseudo-headers and forward it upstream
+async fn proxy_http2_stream(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    send_request: h2::client::SendRequest<Bytes>,
+    hostname: &str,
+    config: &Config,
+) -> Result<()> {
+    let method = request.method().to_string();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "/".to_string());
+
+    let authorization = request.headers().get(http::header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let authority = request
+        .uri()
+        .authority()
+        .map(|a| a.as_str())
+        .or_else(|| request.headers().get(http::header::HOST).and_then(|v| v.to_str().ok()));
+    let grpc = is_grpc_request(&request);
+    if in_maintenance(config, hostname) {
+        log_traffic(config, "MAINTENANCE", hostname, &path, &method, "Maintenance Mode", &[]);
+        print_decision(config, format_args!("🚧 [{}] {} {}{} (h2) -> Maintenance Mode", config.mode, method, hostname, path));
+        let response = Response::builder()
+            .status(http::StatusCode::SERVICE_UNAVAILABLE)
+            .header(http::header::CONTENT_TYPE, "text/plain")
+            .body(())?;
+        let mut body = respond.send_response(response, false)?;
+        body.send_data(Bytes::from(config.maintenance_message.clone()), true)?;
+        return Ok(());
+    }
+    let decision = evaluate(config, hostname, &path, &method)
+        .and_check(|| check_http_version(config, "HTTP/2"))
+        .and_check(|| check_domain_fronting(config, hostname, authority))
+        .and_check(|| check_jwt_claims(config, hostname, authorization));
+    let decision = if grpc {
+        decision.and_check(|| check_grpc_method(config, hostname, &path))
+    } else {
+        decision
+    };
+    let decision = decision.and_check(|| check_rate_limit(config, hostname)).warn_only(config, hostname);
+    let reason = &decision.reason;
+    let fields: Vec<_> = decision.rule_host(config).map(|rule| ("rule", rule)).into_iter().collect();
+    log_traffic(config, decision.action.as_str(), hostname, &path, &method, reason, &fields);
+    let icon = decision.action.icon();
+    print_decision(config, format_args!("{} [{}] {} {}{} (h2) -> {}", icon, config.mode, method, hostname, path, reason));
+
+    if !decision.allowed() {
+        // gRPC clients expect a status in the response, not a reset stream
+        if grpc {
+            respond.send_response(grpc_permission_denied(), true)?;
+        } else if decision.reason == RATE_LIMITED && !config.uniform_block_response {
+            respond.send_response(Response::builder().status(429).body(())?, true)?;
+        } else {
+            respond.send_reset(Reason::REFUSED_STREAM);
+        }
+        return Ok(());
+    }
+    if !wait_for_pacing(config, find_host_rule(config, hostname), hostname, &path, &method).await {
+        respond.send_reset(Reason::REFUSED_STREAM);
+        return Ok(());
+    }
+
+    let (mut parts, request_body) = request.into_parts();
+    if let Some(upstream_host) = host_header_rewrite(find_host_rule(config, hostname)) {
+        let mut uri = parts.uri.into_parts();
+        uri.authority = Some(upstream_host.parse()?);
+        parts.uri = http::Uri::from_parts(uri)?;
+        if parts.headers.contains_key(http::header::HOST) {
+            parts.headers.insert(http::header::HOST, upstream_host.parse()?);
+        }
+    }
+    if config.inject_forwarded_headers {
+        let prior = parts.headers.get("x-forwarded-for").and_then(|value| value.to_str().ok());
+        if let Some(forwarded_for) = forwarded_for(prior) {
+            parts.headers.insert("x-forwarded-for", forwarded_for.parse()?);
+        }
+        parts.headers.insert("x-secure-proxy", http::HeaderValue::from_static("1"));
+    }
+    let mut send_request = send_request.ready().await?;
+    let (response, upstream_body) =
+        send_request.send_request(Request::from_parts(parts, ()), request_body.is_end_stream())?;
+    if !request_body.is_end_stream() {
+        tokio::spawn(async move {
+            if let Err(e) = forward_http2_body(request_body, upstream_body).await {
+                debug!("HTTP/2 request body forwarding failed: {}", e);
+            }
+        });
+    }
+
+    let response = match config.first_response_byte_timeout() {
+        Some(limit) => match tokio::time::timeout(limit, response).await {
+            Ok(response) => response?,
+            Err(_) => {
+                let reason = "Slow Upstream Response";
+                log_traffic(config, "BLOCK", hostname, &path, &method, reason, &[]);
+                print_decision(config, format_args!("⛔ [{}] {} {}{} (h2) -> {}", config.mode, method, hostname, path, reason));
+                let response = Response::builder().status(http::StatusCode::GATEWAY_TIMEOUT).body(())?;
+                respond.send_response(response, true)?;
+                return Ok(());
+            }
+        },
+        None => response.await?,
+    };
+    let (mut parts, response_body) = response.into_parts();
+    record_upstream_status(parts.status.as_str());
+    log_response(config, hostname, &method, &path, parts.status.as_str());
+    for name in find_host_rule(config, hostname).map_or(&[][..], |r| &r.strip_response_headers) {
+        parts.headers.remove(name.as_str());
+    }
+    let end_of_stream = response_body.is_end_stream();
+    let client_body = respond.send_response(Response::from_parts(parts, ()), end_of_stream)?;
+    if !end_of_stream {
+        forward_http2_body(response_body, client_body).await?;
+    }
+
+    Ok(())
+}
+
+/// Copy an HTTP/2 body (and trailers) between streams, respecting the receiver's flow control
+async fn forward_http2_body(mut source: RecvStream, mut sink: SendStream<Bytes>) -> Result<()> {
+    while let Some(chunk) = source.data().await {
+        let mut chunk = chunk?;
+        let _ = source.flow_control().release_capacity(chunk.len());
+
+        while !chunk.is_empty() {
+            sink.reserve_capacity(chunk.len());
+            let available = match std::future::poll_fn(|cx| sink.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                None => return Err(anyhow!("HTTP/2 stream closed while sending")),
+            };
+            let part = chunk.split_to(available.min(chunk.len()));
+            sink.send_data(part, false)?;
+        }
+    }
+
+    match source.trailers().await? {
+        Some(trailers) => sink.send_trailers(trailers)?,
+        None => sink.send_data(Bytes::new(), true)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[tokio::test]
+    async fn http2_streams_are_checked_one_by_one() {
+        let config = Arc::new(config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [{ "host": "h2.example.com", "allowed_paths": ["/allowed"] }]
+        })));
+
+        // Upstream answering every stream with an empty 200
+        let (to_upstream, upstream_side) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut upstream = h2::server::handshake(upstream_side).await.unwrap();
+            while let Some(Ok((_, mut respond))) = upstream.accept().await {
+                respond.send_response(Response::new(()), true).unwrap();
+            }
+        });
+        let (send_upstream, connection) = h2::client::handshake(to_upstream).await.unwrap();
+        tokio::spawn(connection);
+
+        // The proxy side of the client's session, one `proxy_http2_stream` per stream
+        let (client_side, proxy_side) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut server = h2::server::handshake(proxy_side).await.unwrap();
+            while let Some(Ok((request, respond))) = server.accept().await {
+                let (send_upstream, config) = (send_upstream.clone(), config.clone());
+                tokio::spawn(async move { proxy_http2_stream(request, respond, send_upstream, "h2.example.com", &config).await });
+            }
+        });
+        let (client, connection) = h2::client::handshake(client_side).await.unwrap();
+        tokio::spawn(connection);
+
+        let get = |path: &str| {
+            let client = client.clone();
+            let request = Request::get(format!("https://h2.example.com{}", path)).body(()).unwrap();
+            async move {
+                let mut client = client.ready().await.unwrap();
+                let (response, _) = client.send_request(request, true).unwrap();
+                response.await
+            }
+        };
+
+        assert_eq!(get("/allowed/item").await.unwrap().status(), 200);
+        let refused = get("/admin").await.unwrap_err();
+        assert_eq!(refused.reason(), Some(Reason::REFUSED_STREAM));
+        // The session survives a refused stream
+        assert_eq!(get("/allowed/other").await.unwrap().status(), 200);
+
+        let logged = logged_entries("h2.example.com");
+        assert!(logged.iter().any(|e| e["path"] == "/admin" && e["action"] == "BLOCK" && e["reason"] == "Path Not Allowed"));
+        assert!(logged.iter().any(|e| e["path"] == "/allowed/item" && e["action"] == "ALLOW"));
+    }
+}
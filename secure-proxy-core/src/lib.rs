@@ -0,0 +1,31 @@
+//! Secure Network Proxy - shared core
+//!
+//! Rules, security checks, logging, TLS interception and the certificate authority used by
+//! both editions of the proxy. Each binary keeps its own connection handler: `rust-proxy`
+//! takes transparently redirected TLS and reads the SNI, `prison-network` serves HTTP CONNECT.
+
+mod config;
+mod logging;
+mod metrics;
+mod admin;
+mod policy;
+mod http;
+mod tunnel;
+mod session;
+mod intercept;
+mod tls;
+mod ca;
+#[cfg(test)]
+mod test_support;
+
+pub use config::*;
+pub use logging::*;
+pub use metrics::*;
+pub use admin::*;
+pub use policy::*;
+pub use http::*;
+pub use tunnel::*;
+pub use session::*;
+pub use intercept::*;
+pub use tls::*;
+pub use ca::*;
@@ -0,0 +1,628 @@
+//! Traffic log, sinks and rotation
+
+use anyhow::{anyhow, Result};
+use chrono::{Local, NaiveDate, SecondsFormat, Utc};
+use syslog::{Facility, Formatter3164};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+use crate::config::{Config, paths};
+use crate::metrics::{
+    CONNECTION_STATS, ConnectionStats, note_connection, note_rollup, record_decision, statsd_count,
+};
+use crate::policy::break_glass_engaged;
+
+/// Write one decision to the log sink. `fields` are extra string fields for the entry,
+/// such as `client_type`.
+pub fn log_traffic(
+    config: &Config,
+    action: &str,
+    host: &str,
+    path: &str,
+    method: &str,
+    reason: &str,
+    fields: &[(&str, &str)],
+) {
+    let mut entry = serde_json::json!({
+        "action": action,
+        "host": host,
+        "path": path,
+        "method": method,
+        "mode": config.mode,
+        "reason": reason
+    });
+    if let Some(client_ip) = CONNECTION_STATS.try_with(|stats| stats.lock().unwrap().client_ip).ok().flatten() {
+        entry["client_ip"] = serde_json::json!(client_ip.to_string());
+    }
+    if break_glass_engaged(config) {
+        entry["break_glass"] = serde_json::json!(true);
+    }
+    for (name, value) in fields {
+        entry[*name] = serde_json::json!(value);
+    }
+    record_decision(action, reason);
+    if config.metrics_rollup_secs.is_some() {
+        note_rollup(host, |rollup| match action {
+            "ALLOW" => rollup.allowed += 1,
+            "BLOCK" => rollup.blocked += 1,
+            _ => {}
+        });
+    }
+    note_connection(|stats| {
+        if !method.eq_ignore_ascii_case("CONNECT") {
+            stats.requests += 1;
+        }
+        if action == "BLOCK" {
+            stats.close_reason = Some("Blocked".to_string());
+        }
+    });
+
+    write_log_entry(config, action, entry);
+}
+
+/// Log what the upstream answered a forwarded request with, as a `RESPONSE` entry that
+/// follows the request's own. Not a decision, so it isn't counted like one.
+pub(crate) fn log_response(config: &Config, host: &str, method: &str, path: &str, status: &str) {
+    let Ok(status) = status.parse::<u16>() else {
+        return;
+    };
+    let mut entry = serde_json::json!({
+        "action": "RESPONSE",
+        "host": host,
+        "path": path,
+        "method": method,
+        "mode": config.mode,
+        "status": status
+    });
+    if let Some(client_ip) = CONNECTION_STATS.try_with(|stats| stats.lock().unwrap().client_ip).ok().flatten() {
+        entry["client_ip"] = serde_json::json!(client_ip.to_string());
+    }
+    write_log_entry(config, "RESPONSE", entry);
+}
+
+/// Host picked with `--trace-host`; connections to it get trace-level output
+pub static TRACE_HOST: OnceLock<String> = OnceLock::new();
+
+/// Log filter directives: `level` everywhere, debug for `debug_clienthello` dumps, and trace
+/// inside the connection span of the `--trace-host` host
+fn log_filter(config: &Config, level: &str) -> String {
+    let mut filter = level.to_string();
+    if config.debug_clienthello {
+        filter.push_str(",clienthello=debug");
+    }
+    if let Some(host) = TRACE_HOST.get() {
+        filter.push_str(&format!(",[connection{{host={}}}]=trace", host));
+    }
+    filter
+}
+
+/// The installed log filter, swappable at runtime through the admin socket and SIGUSR2
+struct LogFilters {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// Directives the proxy started with
+    default: String,
+    /// `default` with everything raised to debug, for the SIGUSR2 toggle
+    debug: String,
+    active: Mutex<String>,
+}
+
+static LOG_FILTERS: OnceLock<LogFilters> = OnceLock::new();
+
+/// Install the global tracing subscriber with a reloadable filter
+pub fn init_logging(config: &Config) -> Result<()> {
+    let default = log_filter(config, "info");
+    let (filter, handle) = reload::Layer::new(EnvFilter::try_new(&default)?);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_target(false))
+        .try_init()?;
+    let _ = LOG_FILTERS.set(LogFilters {
+        handle,
+        debug: log_filter(config, "debug"),
+        active: Mutex::new(default.clone()),
+        default,
+    });
+    Ok(())
+}
+
+/// Replace the active log filter; `None` goes back to the one the proxy started with
+pub fn set_log_filter(directives: Option<&str>) -> Result<String> {
+    let filters = LOG_FILTERS.get().ok_or_else(|| anyhow!("logging is not initialized"))?;
+    let directives = directives.unwrap_or(&filters.default);
+    let filter = EnvFilter::try_new(directives).map_err(|e| anyhow!("invalid log filter {:?}: {}", directives, e))?;
+    filters.handle.reload(filter)?;
+    *filters.active.lock().unwrap() = directives.to_string();
+    println!("[Config] Log filter set to {}", directives);
+    Ok(directives.to_string())
+}
+
+/// Switch between debug logging and the startup filter every time the process receives SIGUSR2
+pub async fn toggle_debug_on_signal() -> Result<()> {
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    while usr2.recv().await.is_some() {
+        let Some(filters) = LOG_FILTERS.get() else {
+            continue;
+        };
+        let next = if *filters.active.lock().unwrap() == filters.default {
+            Some(filters.debug.as_str())
+        } else {
+            None
+        };
+        if let Err(e) = set_log_filter(next) {
+            error!("Failed to change log filter: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Span wrapping the handling of one connection once its target host is known. Only
+/// created when `--trace-host` is in use, so normal log lines keep their usual shape.
+pub fn connection_span(host: &str) -> tracing::Span {
+    if TRACE_HOST.get().is_some() {
+        tracing::info_span!("connection", host = %host)
+    } else {
+        tracing::Span::none()
+    }
+}
+
+/// Log the `connection_closed` summary once a client connection has been handled
+pub fn log_connection_summary(config: &Config, conn_id: u64, peer: SocketAddr, stats: &ConnectionStats, duration: Duration) {
+    let mut entry = serde_json::json!({
+        "event": "connection_closed",
+        "conn_id": conn_id,
+        "client_ip": peer.ip().to_string(),
+        "host": stats.host,
+        "mode": config.mode,
+        "requests": stats.requests,
+        "bytes_sent": stats.bytes_sent,
+        "bytes_received": stats.bytes_received,
+        "duration_ms": duration.as_millis() as u64,
+        "close_reason": stats.close_reason.as_deref().unwrap_or("Closed"),
+        "resumed": stats.resumed
+    });
+    if let Some(client_tls) = &stats.client_tls {
+        entry["client_tls"] = client_tls.clone();
+    }
+    if let Some(upstream_tls) = &stats.upstream_tls {
+        entry["upstream_tls"] = upstream_tls.clone();
+    }
+    if !stats.trailers.is_empty() {
+        entry["trailers"] = stats.trailers.iter().map(|(name, value)| (name.clone(), serde_json::json!(value))).collect();
+    }
+    write_log_entry(config, "CLOSE", entry);
+}
+
+/// Rough cause of a failed connection for the error log: `aborted` (a peer hung up
+/// mid-exchange, which is routine), `timeout`, `tls`, `upstream_unreachable` or `other`
+fn error_category(e: &anyhow::Error) -> &'static str {
+    use std::io::ErrorKind;
+    let is_tls = |cause: &(dyn std::error::Error + 'static)| cause.is::<rustls::Error>();
+    if e.chain().any(is_tls) {
+        return "tls";
+    }
+    let Some(io) = e.chain().find_map(|cause| cause.downcast_ref::<std::io::Error>()) else {
+        return "other";
+    };
+    match io.kind() {
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof => {
+            "aborted"
+        }
+        ErrorKind::TimedOut => "timeout",
+        ErrorKind::ConnectionRefused | ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => "upstream_unreachable",
+        _ if io.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()) => "tls",
+        _ => "other",
+    }
+}
+
+/// Log why a connection failed, with its ID and host. Aborts are expected, so they only
+/// go to the debug log.
+pub fn log_connection_error(conn_id: u64, peer: SocketAddr, stats: &ConnectionStats, e: &anyhow::Error) {
+    let host = stats.host.as_deref().unwrap_or("unknown");
+    match error_category(e) {
+        "aborted" => debug!("Connection {} from {} to {} aborted: {}", conn_id, peer, host, e),
+        category => error!("Connection {} from {} to {} failed ({}): {}", conn_id, peer, host, category, e),
+    }
+}
+
+/// Log an `ALERT` entry for a tunnel that moved more than its rule's `alert_bytes_threshold`
+pub(crate) fn log_transfer_alert(config: &Config, host: &str, bytes_sent: u64, bytes_received: u64, threshold: u64) {
+    let entry = serde_json::json!({
+        "action": "ALERT",
+        "alert": "large_transfer",
+        "host": host,
+        "mode": config.mode,
+        "bytes": bytes_sent + bytes_received,
+        "bytes_sent": bytes_sent,
+        "bytes_received": bytes_received,
+        "threshold": threshold
+    });
+    statsd_count("alerts", &[("alert", "large_transfer")]);
+
+    write_log_entry(config, "ALERT", entry);
+}
+
+/// Work for the log writer thread
+enum LogMessage {
+    /// A serialized entry and the action that picks its syslog severity
+    Entry(String, String),
+    /// Acknowledge once everything queued before this has been written
+    Flush(tokio::sync::oneshot::Sender<()>),
+}
+
+/// Queue feeding the log writer thread, once `init_log_writer` has run
+static LOG_QUEUE: OnceLock<mpsc::Sender<LogMessage>> = OnceLock::new();
+
+/// Entries dropped because the log queue was full
+pub(crate) static LOGS_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Start the thread that writes log entries, so file and syslog I/O never holds up a
+/// connection. The queue holds `log_queue_entries`; beyond that entries are dropped and
+/// counted in `logs_dropped` rather than making request handling wait.
+pub fn init_log_writer(config: Arc<Config>) -> Result<()> {
+    if config.log_sink == "syslog" {
+        open_syslog(&mut SYSLOG.lock().unwrap());
+    }
+    let (tx, mut rx) = mpsc::channel(config.log_queue_entries.max(1));
+    std::thread::Builder::new()
+        .name("log-writer".to_string())
+        .spawn(move || {
+            while let Some(message) = rx.blocking_recv() {
+                match message {
+                    LogMessage::Entry(action, line) => write_to_sink(&config, &action, &line),
+                    LogMessage::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        })?;
+    let _ = LOG_QUEUE.set(tx);
+    Ok(())
+}
+
+/// Wait until every queued log entry has been written (used on shutdown)
+pub async fn flush_logs() {
+    if let Some(queue) = LOG_QUEUE.get() {
+        let (done, flushed) = tokio::sync::oneshot::channel();
+        if queue.send(LogMessage::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+/// Stamp a log entry with the current time and hand it to the writer thread, or write it
+/// directly before the writer has started
+pub(crate) fn write_log_entry(config: &Config, action: &str, mut entry: serde_json::Value) {
+    entry["timestamp"] = serde_json::json!(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+    let line = match config.log_format.as_str() {
+        "cef" => format_cef(action, &entry),
+        _ => entry.to_string(),
+    };
+    let Some(queue) = LOG_QUEUE.get() else {
+        return write_to_sink(config, action, &line);
+    };
+    if queue.try_send(LogMessage::Entry(action.to_string(), line)).is_err() {
+        LOGS_DROPPED.fetch_add(1, Ordering::Relaxed);
+        statsd_count("logs.dropped", &[]);
+    }
+}
+
+/// Send a serialized log entry to the configured sink
+fn write_to_sink(config: &Config, action: &str, line: &str) {
+    match config.log_sink.as_str() {
+        "stdout" => println!("{}", line),
+        "syslog" => log_to_syslog(action, line),
+        _ => log_to_file(config, line),
+    }
+}
+
+/// Entry fields carried over to standard CEF extension keys
+const CEF_EXTENSIONS: &[(&str, &str)] = &[
+    ("client_ip", "src"),
+    ("host", "dhost"),
+    ("path", "request"),
+    ("method", "requestMethod"),
+    ("action", "act"),
+    ("reason", "reason"),
+];
+
+/// Render a log entry as a Common Event Format line. The signature ID is the action, the
+/// name is the reason (or the event/alert kind), and severity follows the action.
+fn format_cef(action: &str, entry: &serde_json::Value) -> String {
+    let field = |name: &str| entry.get(name).and_then(|v| v.as_str());
+    let name = field("reason").or(field("event")).or(field("alert")).unwrap_or(action);
+    let severity = match action {
+        "ALERT" => 8,
+        "BLOCK" => 7,
+        "QUARANTINE" | "WARN" => 5,
+        "ALLOW" | "STATIC" | "PASSTHROUGH" => 3,
+        _ => 1,
+    };
+    let header_escape = |value: &str| value.replace('\\', "\\\\").replace('|', "\\|");
+    let extension_escape = |value: &str| {
+        value
+            .replace('\\', "\\\\")
+            .replace('=', "\\=")
+            .replace('\r', "\\r")
+            .replace('\n', "\\n")
+    };
+
+    let mut extensions: Vec<String> = CEF_EXTENSIONS
+        .iter()
+        .filter_map(|(field_name, key)| field(field_name).map(|value| format!("{}={}", key, extension_escape(value))))
+        .collect();
+    if field("action").is_none() {
+        extensions.push(format!("act={}", extension_escape(action)));
+    }
+    if let Some(mode) = field("mode") {
+        extensions.push(format!("cs1Label=mode cs1={}", extension_escape(mode)));
+    }
+
+    format!(
+        "CEF:0|secure-proxy|secure-proxy|{}|{}|{}|{}|{}",
+        env!("CARGO_PKG_VERSION"),
+        header_escape(action),
+        header_escape(name),
+        severity,
+        extensions.join(" ")
+    )
+}
+
+/// Built-in User-Agent classification, checked in order
+const DEFAULT_USER_AGENT_PATTERNS: &[(&str, &[&str])] = &[
+    (
+        "ci",
+        &[
+            "github-actions", "gitlab-runner", "jenkins", "buildkite", "circleci",
+            "travis", "azure-pipelines", "teamcity", "drone",
+        ],
+    ),
+    (
+        "cli",
+        &[
+            "curl/", "wget/", "httpie", "python-requests", "go-http-client", "node-fetch",
+            "undici", "okhttp", "libwww-perl", "git/",
+        ],
+    ),
+    ("browser", &["mozilla/"]),
+];
+
+/// Classify a request's User-Agent as "ci", "browser", "cli" or "unknown"
+pub(crate) fn classify_user_agent(config: &Config, user_agent: Option<&str>) -> String {
+    let Some(user_agent) = user_agent else {
+        return "unknown".to_string();
+    };
+    let user_agent = user_agent.to_ascii_lowercase();
+
+    if config.user_agent_patterns.is_empty() {
+        DEFAULT_USER_AGENT_PATTERNS
+            .iter()
+            .find(|(_, needles)| needles.iter().any(|n| user_agent.contains(n)))
+            .map(|(client_type, _)| client_type.to_string())
+    } else {
+        config
+            .user_agent_patterns
+            .iter()
+            .find(|p| p.contains.iter().any(|n| user_agent.contains(&n.to_ascii_lowercase())))
+            .map(|p| p.client_type.clone())
+    }
+    .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Print a human-readable decision line unless `console_decisions` is off
+pub fn print_decision(config: &Config, line: fmt::Arguments) {
+    if config.console_decisions {
+        println!("{}", line);
+    }
+}
+
+/// Size of the traffic log file being appended to, so `log_max_bytes` doesn't need a stat
+/// per write
+static LOG_FILE_SIZE: Mutex<Option<(PathBuf, u64)>> = Mutex::new(None);
+
+/// Append a line to the traffic log. The file is opened per write, so daily rotation only
+/// needs the path to change, and size rotation only a rename.
+fn log_to_file(config: &Config, line: &str) {
+    append_log_line(config, &traffic_log_path(config, Local::now().date_naive()), line);
+}
+
+/// Append a line to `log_path`, rotating the file first when the line would take it past
+/// `log_max_bytes`
+fn append_log_line(config: &Config, log_path: &Path, line: &str) {
+    if let Some(parent) = log_path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    if let Some(max_bytes) = config.log_max_bytes {
+        let mut size = LOG_FILE_SIZE.lock().unwrap();
+        let mut current = match size.as_ref() {
+            Some((path, bytes)) if path == log_path => *bytes,
+            _ => fs::metadata(log_path).map(|m| m.len()).unwrap_or(0),
+        };
+        let written = line.len() as u64 + 1;
+        if current > 0 && current + written > max_bytes {
+            rotate_log_file(log_path, config.log_max_files);
+            current = 0;
+        }
+        *size = Some((log_path.to_path_buf(), current + written));
+    }
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(log_path) {
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+/// Shift `path.1` .. `path.{max_files - 1}` up by one, dropping the oldest, and move the
+/// current file to `path.1`
+fn rotate_log_file(path: &Path, max_files: u32) {
+    let numbered = |n: u32| PathBuf::from(format!("{}.{}", path.display(), n));
+    for n in (1..max_files).rev() {
+        let _ = fs::rename(numbered(n), numbered(n + 1));
+    }
+    if let Err(e) = fs::rename(path, numbered(1)) {
+        error!("Failed to rotate {}: {}", path.display(), e);
+    }
+}
+
+/// Path of the traffic log for `today`: `traffic-YYYY-MM-DD.jsonl` with daily rotation
+fn traffic_log_path(config: &Config, today: NaiveDate) -> PathBuf {
+    let path = Path::new(&paths().log);
+    match config.log_rotation.as_str() {
+        "daily" => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+            path.with_file_name(format!("{}-{}{}", stem, today.format("%Y-%m-%d"), extension))
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+type SyslogLogger = syslog::Logger<syslog::LoggerBackend, Formatter3164>;
+
+/// Longest the syslog sink waits before reconnecting after a failure
+const SYSLOG_RETRY: Duration = Duration::from_secs(5);
+
+/// The syslog connection, opened once and reused for every entry, and when to next try
+/// reopening it after it failed
+static SYSLOG: Mutex<(Option<SyslogLogger>, Option<Instant>)> = Mutex::new((None, None));
+
+/// Whether an entry goes to syslog at warning level: blocks and anything flagged for
+/// review. Everything else is info.
+fn syslog_warns(action: &str) -> bool {
+    ["BLOCK", "ALERT", "QUARANTINE", "WARN"].contains(&action)
+}
+
+/// Connect to the local syslog daemon, unless a recent failure says to wait
+fn open_syslog(sink: &mut (Option<SyslogLogger>, Option<Instant>)) {
+    if sink.0.is_some() || sink.1.is_some_and(|retry_at| Instant::now() < retry_at) {
+        return;
+    }
+    let formatter = Formatter3164 {
+        facility: Facility::LOG_DAEMON,
+        hostname: None,
+        process: "secure-proxy".to_string(),
+        pid: std::process::id(),
+    };
+    match syslog::unix(formatter) {
+        Ok(logger) => *sink = (Some(logger), None),
+        Err(e) => {
+            error!("Failed to connect to syslog: {} (retrying in {:?})", e, SYSLOG_RETRY);
+            sink.1 = Some(Instant::now() + SYSLOG_RETRY);
+        }
+    }
+}
+
+/// Send a decision to the local syslog daemon over the shared connection. A failed write
+/// drops the connection; entries are lost until it can be reopened.
+fn log_to_syslog(action: &str, line: &str) {
+    let mut sink = SYSLOG.lock().unwrap();
+    open_syslog(&mut sink);
+    let Some(logger) = sink.0.as_mut() else {
+        return;
+    };
+    let sent = if syslog_warns(action) { logger.warning(line) } else { logger.info(line) };
+    if let Err(e) = sent {
+        error!("Failed to write to syslog: {} (retrying in {:?})", e, SYSLOG_RETRY);
+        *sink = (None, Some(Instant::now() + SYSLOG_RETRY));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn syslog_severity_follows_the_action() {
+        for action in ["BLOCK", "ALERT", "QUARANTINE", "WARN"] {
+            assert!(syslog_warns(action), "{} should be a warning", action);
+        }
+        for action in ["ALLOW", "RESPONSE", "CLOSE", "STATIC", "PASSTHROUGH", "MAINTENANCE"] {
+            assert!(!syslog_warns(action), "{} should be info", action);
+        }
+    }
+
+    #[test]
+    fn cef_lines_carry_the_header_fields_and_escape_separators() {
+        let entry = serde_json::json!({
+            "client_ip": "10.0.0.5",
+            "host": "cef.example.com",
+            "path": "/a=b\\c|d\nnext",
+            "method": "GET",
+            "reason": "Path|Not\\Allowed",
+            "mode": "enforce"
+        });
+        let line = format_cef("BLOCK", &entry);
+        let header = format!("CEF:0|secure-proxy|secure-proxy|{}|BLOCK|Path\\|Not\\\\Allowed|7|", env!("CARGO_PKG_VERSION"));
+        assert!(line.starts_with(&header), "{}", line);
+
+        let extensions = &line[header.len()..];
+        assert!(extensions.contains("src=10.0.0.5 dhost=cef.example.com "), "{}", extensions);
+        assert!(extensions.contains("request=/a\\=b\\\\c|d\\nnext "), "{}", extensions);
+        assert!(extensions.contains("reason=Path|Not\\\\Allowed"), "{}", extensions);
+        assert!(extensions.ends_with("act=BLOCK cs1Label=mode cs1=enforce"), "{}", extensions);
+
+        let event = format_cef("ALERT", &serde_json::json!({ "event": "burst" }));
+        assert!(event.contains("|ALERT|burst|8|act=ALERT"), "{}", event);
+        assert!(format_cef("CLOSE", &serde_json::json!({})).contains("|CLOSE|CLOSE|1|"));
+    }
+
+    #[test]
+    fn log_rotates_before_a_line_would_pass_log_max_bytes() {
+        let config = config_from(serde_json::json!({ "log_max_bytes": 100, "log_max_files": 2 }));
+        let dir = Path::new(&test_paths().log).with_file_name("rotation");
+        let _ = fs::remove_dir_all(&dir);
+        let log = dir.join("traffic.jsonl");
+        let numbered = |n: u32| fs::read_to_string(format!("{}.{}", log.display(), n)).ok();
+        // 39 characters plus the newline: two lines fit in 100 bytes, a third doesn't
+        let line = |n: u32| format!("{{\"line\":{},\"padding\":\"{}\"}}", n, "x".repeat(16));
+
+        for n in 1..=3 {
+            append_log_line(&config, &log, &line(n));
+        }
+        assert_eq!(numbered(1).unwrap(), format!("{}\n{}\n", line(1), line(2)));
+        assert_eq!(fs::read_to_string(&log).unwrap(), format!("{}\n", line(3)));
+
+        for n in 4..=7 {
+            append_log_line(&config, &log, &line(n));
+        }
+        assert_eq!(numbered(2).unwrap(), format!("{}\n{}\n", line(3), line(4)));
+        assert_eq!(numbered(1).unwrap(), format!("{}\n{}\n", line(5), line(6)));
+        assert_eq!(fs::read_to_string(&log).unwrap(), format!("{}\n", line(7)));
+        assert_eq!(numbered(3), None);
+
+        // A line bigger than the limit still goes into a fresh file rather than rotating forever
+        let fresh = dir.join("fresh.jsonl");
+        let oversized = "y".repeat(150);
+        append_log_line(&config, &fresh, &oversized);
+        assert_eq!(fs::read_to_string(&fresh).unwrap(), format!("{}\n", oversized));
+        assert!(!Path::new(&format!("{}.1", fresh.display())).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn daily_rotation_dates_the_log_file_name() {
+        let log = Path::new(&test_paths().log);
+        let day = NaiveDate::from_ymd_opt(2026, 3, 7).unwrap();
+
+        let daily = config_from(serde_json::json!({ "log_rotation": "daily" }));
+        assert_eq!(traffic_log_path(&daily, day), log.with_file_name("traffic-2026-03-07.jsonl"));
+        assert_eq!(
+            traffic_log_path(&daily, day.succ_opt().unwrap()),
+            log.with_file_name("traffic-2026-03-08.jsonl")
+        );
+
+        let single = config_from(serde_json::json!({}));
+        assert_eq!(traffic_log_path(&single, day), log);
+    }
+}
@@ -0,0 +1,284 @@
+//! Counters, statsd and connection draining
+
+use anyhow::{anyhow, Result};
+use rustls::CommonState;
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::Config;
+use crate::logging::{LOGS_DROPPED, write_log_entry};
+
+/// Fire-and-forget StatsD client. Tags use the DogStatsD `|#key:value` extension and are
+/// limited to small fixed sets (actions, reasons, directions) to keep cardinality bounded.
+struct Statsd {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+static STATSD: OnceLock<Statsd> = OnceLock::new();
+
+/// Start pushing metrics to `addr`; until this is called every metric call is a no-op
+pub fn init_statsd(addr: &str) -> Result<()> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("statsd_addr {} did not resolve", addr))?;
+    let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.set_nonblocking(true)?;
+    let _ = STATSD.set(Statsd { socket, addr });
+    Ok(())
+}
+
+fn statsd_send(name: &str, value: u64, kind: &str, tags: &[(&str, &str)]) {
+    let Some(statsd) = STATSD.get() else {
+        return;
+    };
+
+    let mut line = format!("secure_proxy.{}:{}|{}", name, value, kind);
+    if !tags.is_empty() {
+        let tags: Vec<String> = tags
+            .iter()
+            .map(|(k, v)| format!("{}:{}", k, statsd_tag_value(v)))
+            .collect();
+        line.push_str("|#");
+        line.push_str(&tags.join(","));
+    }
+    // Never block or fail a connection over metrics
+    let _ = statsd.socket.send_to(line.as_bytes(), statsd.addr);
+}
+
+/// In-process counters, printed as JSON on SIGUSR1
+pub static CONNECTIONS_TOTAL: AtomicU64 = AtomicU64::new(0);
+pub static CONNECTIONS_ACTIVE: AtomicU64 = AtomicU64::new(0);
+static BYTES_UPSTREAM: AtomicU64 = AtomicU64::new(0);
+static BYTES_CLIENT: AtomicU64 = AtomicU64::new(0);
+pub(crate) static TLS_RESUMPTIONS: AtomicU64 = AtomicU64::new(0);
+/// Leaf lookups answered from the cache, lookups that had to issue (or wait on) a leaf, and
+/// how many leaves the cache holds
+pub(crate) static CERT_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+pub(crate) static CERT_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+pub(crate) static CERT_CACHE_SIZE: AtomicU64 = AtomicU64::new(0);
+static DECISION_COUNTS: Mutex<BTreeMap<(String, String), u64>> = Mutex::new(BTreeMap::new());
+pub(crate) static UPSTREAM_STATUS_COUNTS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// How often a shutdown checks whether the remaining connections are done
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Wait up to `grace` for open connections to finish once the listener has stopped. Returns
+/// how many were still open when the wait ended.
+pub async fn drain_connections(grace: Duration) -> u64 {
+    let deadline = Instant::now() + grace;
+    loop {
+        let active = CONNECTIONS_ACTIVE.load(Ordering::Relaxed);
+        let left = deadline.saturating_duration_since(Instant::now());
+        if active == 0 || left.is_zero() {
+            return active;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL.min(left)).await;
+    }
+}
+
+/// Count a logged decision by action and reason, locally and in StatsD
+pub fn record_decision(action: &str, reason: &str) {
+    *DECISION_COUNTS
+        .lock()
+        .unwrap()
+        .entry((action.to_string(), reason.to_string()))
+        .or_default() += 1;
+    statsd_count("decisions", &[("action", action), ("reason", reason)]);
+}
+
+/// Count a final upstream response by status class (`2xx` to `5xx`), locally and in StatsD
+pub(crate) fn record_upstream_status(status: &str) {
+    let class = match status.as_bytes() {
+        [class @ b'2'..=b'5', _, _] => format!("{}xx", *class as char),
+        _ => return,
+    };
+    statsd_count("upstream_status", &[("class", &class)]);
+    *UPSTREAM_STATUS_COUNTS.lock().unwrap().entry(class).or_default() += 1;
+}
+
+/// One host's activity since the last rollup
+#[derive(Debug, Default)]
+pub(crate) struct HostRollup {
+    pub(crate) allowed: u64,
+    pub(crate) blocked: u64,
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+}
+
+/// Per-host counters for the current `metrics_rollup_secs` interval
+static HOST_ROLLUPS: Mutex<BTreeMap<String, HostRollup>> = Mutex::new(BTreeMap::new());
+
+/// Update `host`'s counters for the current rollup interval
+pub(crate) fn note_rollup(host: &str, update: impl FnOnce(&mut HostRollup)) {
+    update(HOST_ROLLUPS.lock().unwrap().entry(host.to_string()).or_default());
+}
+
+/// Every `interval`, log a `rollup` entry for each host seen since the last one and start
+/// the next interval from zero
+pub async fn log_rollups(config: Arc<Config>, interval: Duration) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticks.tick().await;
+        let rollups = std::mem::take(&mut *HOST_ROLLUPS.lock().unwrap());
+        for (host, rollup) in rollups {
+            let entry = serde_json::json!({
+                "event": "rollup",
+                "host": host,
+                "mode": config.mode,
+                "interval_secs": interval.as_secs(),
+                "allowed": rollup.allowed,
+                "blocked": rollup.blocked,
+                "bytes_sent": rollup.bytes_sent,
+                "bytes_received": rollup.bytes_received
+            });
+            write_log_entry(&config, "ROLLUP", entry);
+        }
+    }
+}
+
+/// `{"version", "cipher"}` of a finished TLS handshake, for the connection summary
+pub(crate) fn tls_parameters(conn: &CommonState) -> serde_json::Value {
+    let version = conn.protocol_version().map(|v| v.as_str().map_or_else(|| format!("{:?}", v), str::to_string));
+    let cipher = conn
+        .negotiated_cipher_suite()
+        .map(|suite| suite.suite().as_str().map_or_else(|| format!("{:?}", suite.suite()), str::to_string));
+    serde_json::json!({ "version": version, "cipher": cipher })
+}
+
+/// Running totals for one client connection, logged as a `connection_closed` summary
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    /// Address of the client, stamped on every decision the connection logs
+    pub client_ip: Option<IpAddr>,
+    pub host: Option<String>,
+    pub requests: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub close_reason: Option<String>,
+    /// Whether the client resumed an earlier TLS session instead of doing a full handshake
+    pub resumed: bool,
+    /// Protocol version and cipher suite negotiated with the client, when TLS was terminated
+    pub client_tls: Option<serde_json::Value>,
+    /// Protocol version and cipher suite negotiated with the upstream
+    pub upstream_tls: Option<serde_json::Value>,
+    /// Trailer fields seen on chunked responses
+    pub trailers: Vec<(String, String)>,
+}
+
+tokio::task_local! {
+    /// Stats of the connection the current task is serving
+    pub static CONNECTION_STATS: Arc<Mutex<ConnectionStats>>;
+}
+
+/// Update the current connection's stats; a no-op outside a connection task
+pub fn note_connection(update: impl FnOnce(&mut ConnectionStats)) {
+    let _ = CONNECTION_STATS.try_with(|stats| update(&mut stats.lock().unwrap()));
+}
+
+/// The current connection's stats, to carry into tasks spawned on its behalf
+pub(crate) fn current_connection_stats() -> Option<Arc<Mutex<ConnectionStats>>> {
+    CONNECTION_STATS.try_with(Arc::clone).ok()
+}
+
+/// Count bytes moved through a tunnel, locally and in StatsD
+pub(crate) fn record_tunnel_bytes(sent: u64, received: u64) {
+    BYTES_UPSTREAM.fetch_add(sent, Ordering::Relaxed);
+    BYTES_CLIENT.fetch_add(received, Ordering::Relaxed);
+    statsd_send("bytes", sent, "c", &[("direction", "upstream")]);
+    statsd_send("bytes", received, "c", &[("direction", "client")]);
+}
+
+/// Point-in-time view of the in-process counters
+fn metrics_snapshot() -> serde_json::Value {
+    let mut decisions = serde_json::Map::new();
+    for ((action, reason), count) in DECISION_COUNTS.lock().unwrap().iter() {
+        let by_reason = decisions
+            .entry(action.clone())
+            .or_insert_with(|| serde_json::json!({}));
+        by_reason[reason] = serde_json::json!(count);
+    }
+
+    serde_json::json!({
+        "connections": {
+            "total": CONNECTIONS_TOTAL.load(Ordering::Relaxed),
+            "active": CONNECTIONS_ACTIVE.load(Ordering::Relaxed)
+        },
+        "decisions": decisions,
+        "upstream_status": *UPSTREAM_STATUS_COUNTS.lock().unwrap(),
+        "bytes": {
+            "upstream": BYTES_UPSTREAM.load(Ordering::Relaxed),
+            "client": BYTES_CLIENT.load(Ordering::Relaxed)
+        },
+        "tls_resumptions": TLS_RESUMPTIONS.load(Ordering::Relaxed),
+        "cert_cache": {
+            "hits": CERT_CACHE_HITS.load(Ordering::Relaxed),
+            "misses": CERT_CACHE_MISSES.load(Ordering::Relaxed),
+            "size": CERT_CACHE_SIZE.load(Ordering::Relaxed)
+        },
+        "logs_dropped": LOGS_DROPPED.load(Ordering::Relaxed)
+    })
+}
+
+/// Print `metrics_snapshot` to stdout every time the process receives SIGUSR1
+pub async fn dump_metrics_on_signal() -> Result<()> {
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    while usr1.recv().await.is_some() {
+        println!("{}", metrics_snapshot());
+    }
+    Ok(())
+}
+
+pub(crate) fn statsd_count(name: &str, tags: &[(&str, &str)]) {
+    statsd_send(name, 1, "c", tags);
+}
+
+pub fn statsd_timing(name: &str, duration: Duration) {
+    statsd_send(name, duration.as_millis() as u64, "ms", &[]);
+}
+
+/// Lowercase a tag value and replace anything that would break the line protocol
+fn statsd_tag_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '_' | '-' | '.' | '/' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use crate::ca::CaAuthority;
+
+    #[tokio::test]
+    async fn metrics_snapshot_has_counters_and_cert_cache_stats() {
+        let config = config_from(serde_json::json!({}));
+        let ca = Arc::new(CaAuthority::in_memory(&config).unwrap());
+        let before = metrics_snapshot();
+        ca.leaf_for_host("metrics.example.com").await.unwrap();
+        ca.leaf_for_host("metrics.example.com").await.unwrap();
+        let after = metrics_snapshot();
+
+        for counter in ["/connections/total", "/connections/active", "/bytes/upstream", "/bytes/client", "/tls_resumptions", "/logs_dropped"] {
+            assert!(after.pointer(counter).is_some_and(|v| v.is_u64()), "{} missing", counter);
+        }
+        assert!(after["decisions"].is_object());
+        assert!(after["upstream_status"].is_object());
+        let cache = |snapshot: &serde_json::Value, field: &str| snapshot["cert_cache"][field].as_u64().unwrap();
+        assert!(cache(&after, "hits") > cache(&before, "hits"));
+        assert!(cache(&after, "misses") > cache(&before, "misses"));
+        assert!(cache(&after, "size") >= 1);
+    }
+}
@@ -0,0 +1,1640 @@
+//! Allow and block decisions for hosts, requests and clients
+
+use anyhow::{anyhow, bail, Result};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rustls::server::{danger::ClientCertVerifier, WebPkiClientVerifier};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::Path,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, info, warn};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::config::{BlockedRule, Config, HostRule, RateLimit};
+use crate::logging::{log_traffic, print_decision, write_log_entry};
+use crate::http::header_value;
+use crate::tunnel::with_deadline;
+
+// ============================================================================
+// Security Check
+// ============================================================================
+
+/// Temporary allows added through the admin socket: host pattern and when it lapses
+static RUNTIME_EXCEPTIONS: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+/// Record a grant for `host` lasting `ttl` from `now`, replacing any earlier one, and return
+/// when it lapses. Fails when the expiry can't be represented.
+fn grant_runtime_exception(host: &str, ttl: Duration, now: Instant) -> Result<Instant> {
+    let expires = now
+        .checked_add(ttl)
+        .ok_or_else(|| anyhow!("ttl of {}s is too long", ttl.as_secs()))?;
+    let mut exceptions = RUNTIME_EXCEPTIONS.lock().unwrap();
+    exceptions.retain(|(pattern, _)| pattern != host);
+    exceptions.push((host.to_string(), expires));
+    Ok(expires)
+}
+
+/// Allow `host` (and its subdomains) for `ttl` on top of the configured rules. The grant and
+/// its expiry are both written to the traffic log.
+pub(crate) fn add_runtime_exception(config: Arc<Config>, host: &str, ttl: Duration) -> Result<()> {
+    let expires = grant_runtime_exception(host, ttl, Instant::now())?;
+    warn!("Runtime exception: {} allowed for {:?}", host, ttl);
+    let entry = serde_json::json!({
+        "event": "runtime_exception_added",
+        "host": host,
+        "mode": config.mode,
+        "ttl_secs": ttl.as_secs()
+    });
+    write_log_entry(&config, "EXCEPTION", entry);
+
+    let host = host.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep_until(expires.into()).await;
+        let expired = {
+            let mut exceptions = RUNTIME_EXCEPTIONS.lock().unwrap();
+            let before = exceptions.len();
+            // A newer grant for the same host replaced this one and has its own timer
+            exceptions.retain(|(pattern, at)| !(*pattern == host && *at == expires));
+            exceptions.len() < before
+        };
+        if expired {
+            info!("Runtime exception for {} expired", host);
+            let entry = serde_json::json!({
+                "event": "runtime_exception_expired",
+                "host": host,
+                "mode": config.mode
+            });
+            write_log_entry(&config, "EXCEPTION", entry);
+        }
+    });
+    Ok(())
+}
+
+/// Whether a runtime exception currently covers `host`
+fn runtime_exception_covers(host: &str) -> bool {
+    runtime_exception_covers_at(host, Instant::now())
+}
+
+/// Whether a runtime exception covers `host` as of `now`
+fn runtime_exception_covers_at(host: &str, now: Instant) -> bool {
+    RUNTIME_EXCEPTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(pattern, expires)| *expires > now && host_matches(pattern, host))
+}
+
+/// How long a break-glass or maintenance file check is trusted before stat-ing the file again
+const FLAG_FILE_TTL: Duration = Duration::from_secs(1);
+
+/// Last break-glass check: when it ran and whether the file was present
+static BREAK_GLASS_STATE: Mutex<Option<(Instant, bool)>> = Mutex::new(None);
+
+/// Last maintenance file check: when it ran and whether the file was present
+static MAINTENANCE_FILE_STATE: Mutex<Option<(Instant, bool)>> = Mutex::new(None);
+
+/// Maintenance mode as set by `maintenance_mode` at startup and the admin socket since
+pub static MAINTENANCE_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Check whether a flag file exists (cached for FLAG_FILE_TTL in `state`).
+/// Returns whether it exists and whether that changed since the previous check.
+fn flag_file_present(path: &str, state: &Mutex<Option<(Instant, bool)>>) -> (bool, bool) {
+    let mut state = state.lock().unwrap();
+    if let Some((checked_at, present)) = *state {
+        if checked_at.elapsed() < FLAG_FILE_TTL {
+            return (present, false);
+        }
+    }
+
+    let present = Path::new(path).exists();
+    let was_present = state.is_some_and(|(_, p)| p);
+    *state = Some((Instant::now(), present));
+    (present, present != was_present)
+}
+
+/// Check whether the break-glass override file exists (cached for FLAG_FILE_TTL)
+pub fn break_glass_engaged(config: &Config) -> bool {
+    let Some(path) = &config.break_glass_file else {
+        return false;
+    };
+
+    let (engaged, changed) = flag_file_present(path, &BREAK_GLASS_STATE);
+    if changed && engaged {
+        warn!("Break-glass file {} present, enforcement suspended", path);
+    } else if changed {
+        info!("Break-glass file {} removed, enforcement restored", path);
+    }
+    engaged
+}
+
+/// Whether policy is enforced right now: the mode is `enforce` or `blocklist` and the
+/// break-glass file isn't present
+pub fn enforcing(config: &Config) -> bool {
+    (config.mode == "enforce" || config.mode == "blocklist") && !break_glass_engaged(config)
+}
+
+/// Reason logged for a request `enforcing` lets through unchecked
+pub fn unenforced_reason(config: &Config) -> String {
+    if config.mode == "enforce" || config.mode == "blocklist" {
+        "Break Glass".to_string()
+    } else {
+        "Monitor Mode".to_string()
+    }
+}
+
+/// Switch maintenance mode on or off from `source`, logging the change
+pub(crate) fn set_maintenance_mode(config: &Config, on: bool, source: &str) {
+    if MAINTENANCE_MODE.swap(on, Ordering::Relaxed) == on {
+        return;
+    }
+    warn!("Maintenance mode {} ({})", if on { "on" } else { "off" }, source);
+    let entry = serde_json::json!({
+        "event": if on { "maintenance_started" } else { "maintenance_ended" },
+        "source": source,
+        "mode": config.mode
+    });
+    write_log_entry(config, "MAINTENANCE", entry);
+}
+
+/// Whether maintenance mode turns requests for `host` away: it is on (flag or file) and the
+/// host is not in `maintenance_bypass_hosts`
+pub fn in_maintenance(config: &Config, host: &str) -> bool {
+    let file_present = config.maintenance_file.as_deref().is_some_and(|path| {
+        let (present, changed) = flag_file_present(path, &MAINTENANCE_FILE_STATE);
+        if changed && present {
+            warn!("Maintenance file {} present, requests are turned away", path);
+        } else if changed {
+            info!("Maintenance file {} removed, maintenance over", path);
+        }
+        present
+    });
+    (MAINTENANCE_MODE.load(Ordering::Relaxed) || file_present)
+        && !config.maintenance_bypass_hosts.iter().any(|pattern| host_matches(pattern, host))
+}
+
+/// The 503 every request gets during maintenance, carrying `maintenance_message`
+pub fn maintenance_response(config: &Config) -> String {
+    format!(
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        config.maintenance_message.len(),
+        config.maintenance_message
+    )
+}
+
+/// Check whether a host matches a rule's host pattern: `example.com` covers the host itself
+/// and its subdomains, `*.example.com` only the subdomains, and `=example.com` only the host.
+/// DNS names compare case-insensitively, and a fully-qualified name's trailing dot is ignored
+/// on either side.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let (pattern, apex, subdomains) = if let Some(exact) = pattern.strip_prefix('=') {
+        (exact, true, false)
+    } else if let Some(parent) = pattern.strip_prefix("*.") {
+        (parent, false, true)
+    } else {
+        (pattern, true, true)
+    };
+    let pattern = pattern.strip_suffix('.').unwrap_or(pattern).as_bytes();
+    let host = host.strip_suffix('.').unwrap_or(host).as_bytes();
+    match host.len().checked_sub(pattern.len()) {
+        Some(0) => apex && host.eq_ignore_ascii_case(pattern),
+        Some(start) => subdomains && host[start - 1] == b'.' && host[start..].eq_ignore_ascii_case(pattern),
+        None => false,
+    }
+}
+
+/// Find the allow rule matching a host (see `host_matches` for the pattern forms).
+/// The highest-priority match wins; among equal priorities the first rule in the file wins.
+pub fn find_host_rule<'a>(config: &'a Config, host: &str) -> Option<&'a HostRule> {
+    find_host_rule_index(config, host).map(|index| &config.allowed_rules[index])
+}
+
+/// Position in `allowed_rules` of the rule `find_host_rule` picks
+pub fn find_host_rule_index(config: &Config, host: &str) -> Option<usize> {
+    config
+        .allowed_rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| host_matches(&rule.host, host))
+        .fold(None, |best: Option<(usize, &HostRule)>, (index, rule)| {
+            if best.is_none_or(|(_, b)| rule.priority > b.priority) {
+                Some((index, rule))
+            } else {
+                best
+            }
+        })
+        .map(|(index, _)| index)
+}
+
+/// Blocked rules matching a host that aren't overridden. Allow and block rules share one
+/// priority order: a block applies unless the allowed rule for the host (see
+/// `find_host_rule`) has a strictly higher `priority`, so by default blocks win.
+fn blocking_rules<'a>(config: &'a Config, host: &'a str) -> impl Iterator<Item = &'a BlockedRule> + 'a {
+    let allow_priority = find_host_rule(config, host).map(|rule| rule.priority);
+    config
+        .blocked_rules
+        .iter()
+        .filter(move |rule| host_matches(&rule.host, host) && allow_priority.is_none_or(|p| rule.priority >= p))
+}
+
+/// The blocked rule covering a whole host, if any. Rules limited to `paths` only block
+/// matching requests, so they don't count here.
+fn find_blocked_rule<'a>(config: &'a Config, host: &'a str) -> Option<&'a BlockedRule> {
+    blocking_rules(config, host).find(|rule| rule.paths.is_empty())
+}
+
+/// Whether a blocked rule covers this request, for the whole host or by path prefix
+fn request_blocked(config: &Config, host: &str, path: &str) -> bool {
+    blocking_rules(config, host).any(|rule| rule.paths.is_empty() || rule.paths.iter().any(|p| path.starts_with(p)))
+}
+
+/// Whether a block for this host should look like the host is unreachable. Never with
+/// `uniform_block_response`, where a reset would set blocked-listed hosts apart.
+pub fn blocks_as_unreachable(config: &Config, host: &str) -> bool {
+    !config.uniform_block_response
+        && find_blocked_rule(config, host).is_some_and(|rule| rule.block_style == "unreachable")
+}
+
+/// Whether TLS for this host must never be terminated, per `never_intercept`
+pub fn never_intercepted(config: &Config, host: &str) -> bool {
+    config.never_intercept.iter().any(|pattern| host_matches(pattern, host))
+}
+
+/// Outcome of a policy check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    /// Forwarded like an allow, but flagged for review
+    Quarantine,
+    /// A block that a `warn_only` rule lets through
+    Warn,
+    Block,
+}
+
+impl Action {
+    /// Name used for the `action` field in traffic logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Allow => "ALLOW",
+            Action::Quarantine => "QUARANTINE",
+            Action::Warn => "WARN",
+            Action::Block => "BLOCK",
+        }
+    }
+
+    /// Marker for the console decision line
+    pub(crate) fn icon(&self) -> &'static str {
+        match self {
+            Action::Allow => "✅",
+            Action::Quarantine => "⚠️ ",
+            Action::Warn => "🟡",
+            Action::Block => "⛔",
+        }
+    }
+}
+
+/// A policy decision, the reason behind it, and the allowed rule that matched the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub action: Action,
+    pub reason: String,
+    /// Index in `allowed_rules` of the rule matching the host, if any
+    pub rule: Option<usize>,
+}
+
+impl Decision {
+    /// Whether the request is forwarded (allowed, quarantined or warned)
+    pub fn allowed(&self) -> bool {
+        self.action != Action::Block
+    }
+
+    /// Host pattern of the matched rule, as logged in the `rule` field
+    pub fn rule_host<'a>(&self, config: &'a Config) -> Option<&'a str> {
+        self.rule.and_then(|index| config.allowed_rules.get(index)).map(|rule| rule.host.as_str())
+    }
+
+    /// Turn a block into a warning when the host's rule is `warn_only`, so the request is
+    /// forwarded and the would-be block only shows up in the logs
+    pub(crate) fn warn_only(self, config: &Config, host: &str) -> Decision {
+        match find_host_rule(config, host) {
+            Some(rule) if rule.warn_only && self.action == Action::Block => Decision {
+                action: Action::Warn,
+                reason: format!("Would Block (warn_only): {}", self.reason),
+                rule: self.rule,
+            },
+            _ => self,
+        }
+    }
+
+    /// Run a further check on an allow decision, turning it into a block if the check fails
+    pub(crate) fn and_check(self, check: impl FnOnce() -> (bool, String)) -> Decision {
+        if !self.allowed() {
+            return self;
+        }
+        match check() {
+            (true, _) => self,
+            (false, reason) => Decision { action: Action::Block, reason, rule: self.rule },
+        }
+    }
+}
+
+/// Evaluate the loaded rules for a request without running the proxy.
+///
+/// A `CONNECT` method is a tunnel request, so only the host is checked; any other method
+/// checks host and path. An allowed request the host's rule quarantines comes back as
+/// `Action::Quarantine`. The decision names the allowed rule matching the host, when one
+/// does. No I/O happens here apart from the (cached) break-glass check.
+pub fn evaluate(config: &Config, host: &str, path: &str, method: &str) -> Decision {
+    let rule = find_host_rule_index(config, host);
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let (allowed, reason) = check_host_allowed(config, host);
+        let action = if allowed { Action::Allow } else { Action::Block };
+        return Decision { action, reason, rule };
+    }
+
+    let (allowed, reason) = check_request(config, host, path, method);
+    if !allowed {
+        return Decision { action: Action::Block, reason, rule };
+    }
+    match quarantine_reason(config, host, path) {
+        Some(reason) => Decision { action: Action::Quarantine, reason, rule },
+        None => Decision { action: Action::Allow, reason, rule },
+    }
+}
+
+/// Why the host's rule quarantines this request, if it does. Quarantine never blocks, so it
+/// applies in monitor mode too.
+fn quarantine_reason(config: &Config, host: &str, path: &str) -> Option<String> {
+    let rule = find_host_rule(config, host)?;
+    if rule.quarantine {
+        return Some("Quarantined Host".to_string());
+    }
+    rule.quarantine_patterns
+        .iter()
+        .find(|pattern| path.contains(pattern.as_str()))
+        .map(|pattern| format!("Quarantine Pattern {}", pattern))
+}
+
+/// Whether `host` is an IP literal inside one of the `allowed_cidrs` ranges
+fn in_allowed_cidr(config: &Config, host: &str) -> bool {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_ok_and(|ip| config.allowed_networks.iter().any(|network| network.contains(&ip)))
+}
+
+/// Check if a host is allowed (for CONNECT-level checks, ignores path rules)
+pub fn check_host_allowed(config: &Config, host: &str) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+    if find_blocked_rule(config, host).is_some() {
+        return (false, "Host Blocked".to_string());
+    }
+    if runtime_exception_covers(host) {
+        return (true, "Runtime Exception".to_string());
+    }
+    if config.mode == "blocklist" {
+        return (true, "Host Not Blocked".to_string());
+    }
+
+    let host_rule = find_host_rule(config, host);
+
+    match host_rule {
+        None if in_allowed_cidr(config, host) => (true, "CIDR Match".to_string()),
+        None => (false, "Host Not Allowed".to_string()),
+        Some(_) => (true, "Host Allowed".to_string()),
+    }
+}
+
+/// Check if a request (host + path + method) is allowed
+pub fn check_request(config: &Config, host: &str, path: &str, method: &str) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+    if request_blocked(config, host, path) {
+        return (false, "Host Blocked".to_string());
+    }
+    // The global method list applies to every host, runtime exceptions included
+    if !config.allowed_methods.is_empty() && !config.allowed_methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+        return (false, "Method Globally Blocked".to_string());
+    }
+    if runtime_exception_covers(host) {
+        return (true, "Runtime Exception".to_string());
+    }
+    if config.mode == "blocklist" {
+        return (true, "Not Blocked".to_string());
+    }
+
+    let host_rule = find_host_rule(config, host);
+
+    match host_rule {
+        None if in_allowed_cidr(config, host) => (true, "CIDR Match".to_string()),
+        None => (false, "Host Not Allowed".to_string()),
+        Some(rule) => {
+            if !rule.allowed_methods.is_empty() && !rule.allowed_methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+                return (false, METHOD_NOT_ALLOWED.to_string());
+            }
+            if rule.allowed_paths.is_empty() {
+                if config.empty_paths_means == "deny_all" {
+                    return (false, "Path Not Allowed".to_string());
+                }
+                return (true, "Host Match".to_string());
+            }
+            let target = if rule.match_query { path } else { path.split('?').next().unwrap_or(path) };
+            let path_match = rule.allowed_paths.iter().any(|p| p.matches(target));
+            if path_match {
+                (true, "Path Match".to_string())
+            } else {
+                (false, "Path Not Allowed".to_string())
+            }
+        }
+    }
+}
+
+/// Check the protocol negotiated during the client TLS handshake against the host's rule.
+/// Clients that negotiate no ALPN speak HTTP/1.1, so they are checked as `http/1.1`.
+pub(crate) fn check_alpn_allowed(config: &Config, host: &str, alpn: &str) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    match find_host_rule(config, host) {
+        Some(rule) if !rule.allowed_alpn.is_empty() && !rule.allowed_alpn.iter().any(|a| a == alpn) => {
+            (false, "ALPN Not Allowed".to_string())
+        }
+        _ => (true, "ALPN Allowed".to_string()),
+    }
+}
+
+/// Check a gRPC call's `:path` (`/package.Service/Method`) against the host rule's
+/// `allowed_grpc_methods`. Entries may omit the leading `/`, and `package.Service/*` allows
+/// every method of a service.
+pub(crate) fn check_grpc_method(config: &Config, host: &str, path: &str) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    let Some(rule) = find_host_rule(config, host).filter(|r| !r.allowed_grpc_methods.is_empty()) else {
+        return (true, "gRPC Method Allowed".to_string());
+    };
+    let method = path.trim_start_matches('/');
+    let allowed = rule.allowed_grpc_methods.iter().any(|pattern| {
+        let pattern = pattern.trim_start_matches('/');
+        match pattern.strip_suffix("/*") {
+            Some(service) => method.split_once('/').is_some_and(|(s, _)| s == service),
+            None => method == pattern,
+        }
+    });
+    if allowed {
+        (true, "gRPC Method Allowed".to_string())
+    } else {
+        (false, "gRPC Method Not Allowed".to_string())
+    }
+}
+
+/// Check a request's HTTP version (`HTTP/1.1`, `HTTP/2`, ...) against `allowed_http_versions`
+pub(crate) fn check_http_version(config: &Config, version: &str) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    if config.allowed_http_versions.is_empty()
+        || config.allowed_http_versions.iter().any(|v| v.eq_ignore_ascii_case(version))
+    {
+        (true, "HTTP Version Allowed".to_string())
+    } else {
+        (false, "HTTP Version Not Allowed".to_string())
+    }
+}
+
+/// Registrable domain (eTLD+1) of a host, per the public suffix list; hosts the list can't
+/// place (IP literals, single labels) are compared whole
+fn registrable_domain(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    psl::domain_str(&host).map(str::to_string).unwrap_or(host)
+}
+
+/// Strip an optional `:port` (and IPv6 brackets) from a Host header value
+fn host_without_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    host.rsplit_once(':').map_or(host, |(name, _)| name)
+}
+
+/// Catch domain fronting: a Host header for another site than the one the TLS session is for
+pub(crate) fn check_domain_fronting(config: &Config, sni: &str, host_header: Option<&str>) -> (bool, String) {
+    if !config.block_domain_fronting {
+        return (true, "Domain Fronting Not Checked".to_string());
+    }
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    let Some(host_header) = host_header else {
+        return (true, "No Host Header".to_string());
+    };
+    if registrable_domain(host_without_port(host_header.trim())) == registrable_domain(sni) {
+        (true, "Host Header Matches".to_string())
+    } else {
+        (false, "Domain Fronting Detected".to_string())
+    }
+}
+
+/// Check the scheme the client connected with against the host's rule
+pub(crate) fn check_scheme_allowed(config: &Config, host: &str, scheme: &str) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    match find_host_rule(config, host) {
+        Some(rule)
+            if !rule.allowed_schemes.is_empty()
+                && !rule.allowed_schemes.iter().any(|s| s.eq_ignore_ascii_case(scheme)) =>
+        {
+            (false, "Scheme Not Allowed".to_string())
+        }
+        _ => (true, "Scheme Allowed".to_string()),
+    }
+}
+
+// ============================================================================
+// Client Certificates
+// ============================================================================
+
+pub(crate) static CLIENT_CERT_VERIFIER: OnceLock<Arc<dyn ClientCertVerifier>> = OnceLock::new();
+
+/// Ask clients for a certificate issued by one of the CAs in `path`. Clients that send none
+/// still connect, but only reach hosts whose rule has no `allowed_clients`.
+pub fn init_client_ca(path: &str) -> Result<()> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path)? {
+        roots.add(cert?)?;
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()?;
+    let _ = CLIENT_CERT_VERIFIER.set(verifier);
+    Ok(())
+}
+
+/// Names the client's verified certificate identifies as: the subject CN first, then DNS,
+/// email and URI SANs. Empty when the client sent no certificate.
+pub(crate) fn client_cert_names(tls: &TlsStream<TcpStream>) -> Vec<String> {
+    let Some(cert) = tls.get_ref().1.peer_certificates().and_then(|certs| certs.first()) else {
+        return Vec::new();
+    };
+    let Ok((_, cert)) = X509Certificate::from_der(cert) else {
+        return Vec::new();
+    };
+
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        names.extend(san.value.general_names.iter().filter_map(|name| match name {
+            GeneralName::DNSName(n) | GeneralName::RFC822Name(n) | GeneralName::URI(n) => Some(n.to_string()),
+            _ => None,
+        }));
+    }
+    names
+}
+
+/// Check the client's certificate names against the host rule's `allowed_clients`
+pub(crate) fn check_client_allowed(config: &Config, host: &str, client_names: &[String]) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    match find_host_rule(config, host) {
+        Some(rule)
+            if !rule.allowed_clients.is_empty()
+                && !client_names.iter().any(|name| rule.allowed_clients.contains(name)) =>
+        {
+            (false, "Client Not Authorized".to_string())
+        }
+        _ => (true, "Client Authorized".to_string()),
+    }
+}
+
+// ============================================================================
+// Upstream ASN Policy
+// ============================================================================
+
+static ASN_DB: OnceLock<maxminddb::Reader<Vec<u8>>> = OnceLock::new();
+
+/// Load the ASN database; until this is called ASN checks allow everything
+pub fn init_asn_db(path: &str) -> Result<()> {
+    let reader = maxminddb::Reader::open_readfile(path)
+        .map_err(|e| anyhow!("Failed to open ASN database {}: {}", path, e))?;
+    let _ = ASN_DB.set(reader);
+    Ok(())
+}
+
+fn lookup_asn(ip: IpAddr) -> Option<u32> {
+    let asn: maxminddb::geoip2::Asn = ASN_DB.get()?.lookup(ip).ok()?;
+    asn.autonomous_system_number
+}
+
+/// Check the network that owns the resolved upstream IP against `blocked_asns` and
+/// `allowed_asns`. IPs missing from the database only pass when no allow list is set.
+pub fn check_upstream_asn(config: &Config, ip: IpAddr) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+    if ASN_DB.get().is_none() {
+        return (true, "ASN Unchecked".to_string());
+    }
+
+    let asn = lookup_asn(ip);
+    let blocked = asn.is_some_and(|asn| config.blocked_asns.contains(&asn));
+    let not_allowed = !config.allowed_asns.is_empty() && !asn.is_some_and(|asn| config.allowed_asns.contains(&asn));
+    if blocked || not_allowed {
+        debug!("Upstream {} is in ASN {:?}", ip, asn);
+        (false, "ASN Not Allowed".to_string())
+    } else {
+        (true, "ASN Allowed".to_string())
+    }
+}
+
+// ============================================================================
+// JWT Claims
+// ============================================================================
+
+/// Check the bearer token against the host rule's `required_jwt_claims`.
+///
+/// Signatures are only verified when `jwt_hmac_secret` or `jwt_jwks` is configured; without
+/// either, the claims are read as-is.
+pub(crate) fn check_jwt_claims(config: &Config, host: &str, authorization: Option<&str>) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    let Some(rule) = find_host_rule(config, host).filter(|r| !r.required_jwt_claims.is_empty()) else {
+        return (true, "No Claims Required".to_string());
+    };
+
+    let token = authorization.and_then(|value| {
+        let (scheme, token) = value.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+    });
+    let Some(token) = token else {
+        return (false, "Claim Mismatch".to_string());
+    };
+    let claims = match decode_jwt_claims(config, token) {
+        Ok(claims) => claims,
+        Err(e) => {
+            debug!("Rejecting JWT for {}: {}", host, e);
+            return (false, "Invalid JWT".to_string());
+        }
+    };
+
+    let all_match = rule.required_jwt_claims.iter().all(|(name, required)| match claims.get(name) {
+        Some(serde_json::Value::Array(values)) if !required.is_array() => values.contains(required),
+        Some(value) => value == required,
+        None => false,
+    });
+    if all_match {
+        (true, "Claims Match".to_string())
+    } else {
+        (false, "Claim Mismatch".to_string())
+    }
+}
+
+/// Decode a JWT's claims, verifying the signature with the configured HMAC secret (HS*)
+/// or JWKS key (everything else) when one is set
+fn decode_jwt_claims(config: &Config, token: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let header = jsonwebtoken::decode_header(token)?;
+    let mut validation = Validation::new(header.alg);
+    validation.required_spec_claims.clear();
+    validation.validate_aud = false;
+
+    let hmac = matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512);
+    let key = match (&config.jwt_hmac_secret, &config.jwt_jwks) {
+        (None, None) => {
+            validation.insecure_disable_signature_validation();
+            DecodingKey::from_secret(&[])
+        }
+        (Some(secret), _) if hmac => DecodingKey::from_secret(secret.as_bytes()),
+        (_, Some(jwks)) if !hmac => {
+            let kid = header.kid.as_deref().ok_or_else(|| anyhow!("token has no kid"))?;
+            let jwk = jwks.find(kid).ok_or_else(|| anyhow!("no JWKS key with kid {}", kid))?;
+            DecodingKey::from_jwk(jwk)?
+        }
+        _ => bail!("no verification key configured for {:?}", header.alg),
+    };
+
+    Ok(jsonwebtoken::decode(token, &key, &validation)?.claims)
+}
+
+// ============================================================================
+// Upload Types
+// ============================================================================
+
+/// How much of a request body is read ahead of the decision to sniff its real type
+const UPLOAD_SNIFF_BYTES: usize = 64;
+
+/// Leading bytes of common file formats and the MIME type each one means
+const UPLOAD_MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"Rar!\x1a\x07", "application/vnd.rar"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"MZ", "application/vnd.microsoft.portable-executable"),
+    (b"SQLite format 3\x00", "application/vnd.sqlite3"),
+];
+
+/// The MIME type the leading bytes of an upload give away, if they match a known format
+pub(crate) fn sniff_upload_type(prefix: &[u8]) -> Option<&'static str> {
+    UPLOAD_MAGIC
+        .iter()
+        .find(|(magic, _)| prefix.starts_with(magic))
+        .map(|(_, mime)| *mime)
+}
+
+/// The first `UPLOAD_SNIFF_BYTES` of the body of the request at the start of `data`, with
+/// chunked framing undone for the first chunk. `None` while more of the body is still to be
+/// read; requests without a body (or waiting on `100 Continue`) have an empty prefix.
+pub(crate) fn upload_prefix(data: &[u8]) -> Option<&[u8]> {
+    let head_len = data.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let head = String::from_utf8_lossy(&data[..head_len]);
+    let body = &data[head_len..];
+    if header_value(&head, "expect").is_some_and(|v| v.eq_ignore_ascii_case("100-continue")) {
+        return Some(&[]);
+    }
+    if header_value(&head, "transfer-encoding").is_some_and(|te| te.to_ascii_lowercase().contains("chunked")) {
+        let Some(line_len) = body.windows(2).position(|w| w == b"\r\n") else {
+            // A chunk-size line this long isn't worth waiting for
+            return (body.len() > UPLOAD_SNIFF_BYTES).then_some(&[]);
+        };
+        let size = String::from_utf8_lossy(&body[..line_len]);
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).unwrap_or(0);
+        let chunk = &body[line_len + 2..];
+        let wanted = size.min(UPLOAD_SNIFF_BYTES);
+        return (chunk.len() >= wanted).then(|| &chunk[..wanted]);
+    }
+    let length = header_value(&head, "content-length").and_then(|v| v.parse::<usize>().ok()).unwrap_or(0);
+    let wanted = length.min(UPLOAD_SNIFF_BYTES);
+    (body.len() >= wanted).then(|| &body[..wanted])
+}
+
+/// Read on from the client until `buf`, which holds a whole request head, also has the
+/// start of the body `upload_prefix` looks at, or the client stops sending
+pub(crate) async fn read_upload_prefix<R>(client: &mut R, buf: &mut Vec<u8>, idle_timeout: Option<Duration>) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = vec![0u8; 8192];
+    while upload_prefix(buf).is_none() {
+        let n = with_deadline(idle_timeout, client.read(&mut chunk)).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(())
+}
+
+/// Check the sniffed type of an upload against the host rule's `blocked_upload_mimes`.
+/// Entries are MIME types or `type/*`; bodies that match no known format pass.
+pub(crate) fn check_upload_type(config: &Config, host: &str, sniffed: Option<&str>) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    let (Some(rule), Some(sniffed)) = (find_host_rule(config, host), sniffed) else {
+        return (true, "Upload Type Allowed".to_string());
+    };
+    let blocked = rule.blocked_upload_mimes.iter().any(|mime| match mime.strip_suffix("/*") {
+        Some(kind) => sniffed.split_once('/').is_some_and(|(k, _)| k.eq_ignore_ascii_case(kind)),
+        None => mime.eq_ignore_ascii_case(sniffed),
+    });
+    if blocked {
+        (false, "Upload Type Blocked".to_string())
+    } else {
+        (true, "Upload Type Allowed".to_string())
+    }
+}
+
+// ============================================================================
+// Request Pacing and Rate Limiting
+// ============================================================================
+
+/// Paced requests that may be waiting on one host; requests beyond this are turned away
+const MAX_PACED_WAITERS: u128 = 64;
+
+/// Per host with `pace_requests_ms`: the earliest time the next request may go upstream
+static PACED_HOSTS: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
+
+/// Hold a request until its host's pacing slot comes up. Returns false, having logged a
+/// block, when too many requests for the host are already waiting.
+pub(crate) async fn wait_for_pacing(config: &Config, rule: Option<&HostRule>, host: &str, path: &str, method: &str) -> bool {
+    let Some(interval) = rule.and_then(|r| r.pace_requests_ms).filter(|ms| *ms > 0).map(Duration::from_millis) else {
+        return true;
+    };
+    let slot = {
+        let mut paced = PACED_HOSTS.lock().unwrap();
+        let now = Instant::now();
+        let next = paced.entry(host.to_string()).or_insert(now);
+        // Every interval between now and the next free slot is held by a waiting request
+        if next.saturating_duration_since(now).as_millis() / interval.as_millis() >= MAX_PACED_WAITERS {
+            None
+        } else {
+            let slot = (*next).max(now);
+            *next = slot + interval;
+            Some(slot)
+        }
+    };
+
+    let Some(slot) = slot else {
+        let reason = "Pacing Queue Full";
+        log_traffic(config, "BLOCK", host, path, method, reason, &[]);
+        print_decision(config, format_args!("⛔ [{}] {} {}{} -> {}", config.mode, method, host, path, reason));
+        return false;
+    };
+    let delay = slot.saturating_duration_since(Instant::now());
+    if !delay.is_zero() {
+        let entry = serde_json::json!({
+            "event": "request_paced",
+            "host": host,
+            "path": path,
+            "method": method,
+            "mode": config.mode,
+            "delay_ms": delay.as_millis() as u64
+        });
+        write_log_entry(config, "PACE", entry);
+        tokio::time::sleep_until(slot.into()).await;
+    }
+    true
+}
+
+/// Reason given to requests refused by a host's `rate_limit`
+pub(crate) const RATE_LIMITED: &str = "Rate Limited";
+
+/// Block reason for a method outside the rule's `allowed_methods`, answered with a 405
+const METHOD_NOT_ALLOWED: &str = "Method Not Allowed";
+
+/// Token bucket of one host with a `rate_limit`
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Per host with a `rate_limit`: its bucket, refilled from the time elapsed whenever it is used
+static RATE_BUCKETS: Mutex<BTreeMap<String, Bucket>> = Mutex::new(BTreeMap::new());
+
+/// Take a token from the host's bucket. Buckets start full, holding `burst` tokens.
+fn take_rate_token(limit: &RateLimit, host: &str, now: Instant) -> bool {
+    let burst = limit.burst();
+    let mut buckets = RATE_BUCKETS.lock().unwrap();
+    let bucket = buckets.entry(host.to_string()).or_insert(Bucket { tokens: burst, refilled: now });
+    let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(burst);
+    bucket.refilled = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        true
+    } else {
+        false
+    }
+}
+
+/// Check a request against the host rule's `rate_limit`. Run last, so that only requests
+/// that would otherwise go through use up tokens.
+pub(crate) fn check_rate_limit(config: &Config, host: &str) -> (bool, String) {
+    if !enforcing(config) {
+        return (true, unenforced_reason(config));
+    }
+
+    match find_host_rule(config, host).and_then(|r| r.rate_limit.as_ref()) {
+        Some(limit) if !take_rate_token(limit, host, Instant::now()) => (false, RATE_LIMITED.to_string()),
+        _ => (true, "Within Rate Limit".to_string()),
+    }
+}
+
+/// 429 for a rate-limited request, with `Retry-After` set to when the next token is due
+pub(crate) fn too_many_requests_response(rule: Option<&HostRule>) -> String {
+    let retry_after = rule
+        .and_then(|r| r.rate_limit.as_ref())
+        .map_or(1.0, |limit| (1.0 / limit.per_second).ceil().max(1.0));
+    format!(
+        "HTTP/1.1 429 Too Many Requests\r\n\
+         Retry-After: {}\r\n\
+         Content-Length: 0\r\n\
+         Connection: close\r\n\r\n",
+        retry_after
+    )
+}
+
+// ============================================================================
+// Block Responses
+// ============================================================================
+
+/// Body of the 403 in `stealth_mode`; says nothing about what blocked the request
+const STEALTH_BLOCK_BODY: &str = "Forbidden";
+
+/// Body of the 403 otherwise
+const DEFAULT_BLOCK_BODY: &str = "Blocked by Secure Proxy";
+
+/// Build the response sent over TLS for a blocked request: a 302 to the rule's (or global)
+/// `block_redirect_url` when configured, otherwise `block_status` with `block_body` (a plain
+/// 403 by default, or 405 for a disallowed method). Clients that accept JSON get the block
+/// reason as a JSON body unless the response has to give nothing away.
+pub(crate) fn block_response(config: &Config, rule: Option<&HostRule>, host: &str, path: &str, reason: &str, accept: Option<&str>) -> String {
+    // A per-rule redirect would give away which hosts have rules
+    let rule = rule.filter(|_| !config.uniform_block_response);
+    let redirect_url = rule
+        .and_then(|r| r.block_redirect_url.as_ref())
+        .or(config.block_redirect_url.as_ref());
+
+    if let Some(url) = redirect_url {
+        let location = url
+            .replace("{host}", &percent_encode(host))
+            .replace("{path}", &percent_encode(path));
+        return format!(
+            "HTTP/1.1 302 Found\r\n\
+             Location: {}\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n",
+            location
+        );
+    }
+
+    let status = config.block_status.unwrap_or(match reason {
+        _ if config.uniform_block_response => 404,
+        METHOD_NOT_ALLOWED => 405,
+        _ => 403,
+    });
+    let status_text = http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("");
+    let allow = match rule {
+        Some(rule) if reason == METHOD_NOT_ALLOWED => format!("Allow: {}\r\n", rule.allowed_methods.join(", ").to_ascii_uppercase()),
+        _ => String::new(),
+    };
+    let wants_json = accept.is_some_and(|accept| accept.to_ascii_lowercase().contains("application/json"));
+    let (content_type, body) = match &config.block_body {
+        Some(body) => (config.block_content_type.as_deref().unwrap_or("text/plain"), body.clone()),
+        None if config.stealth_mode => (config.block_content_type.as_deref().unwrap_or("text/plain"), STEALTH_BLOCK_BODY.to_string()),
+        None if wants_json && !config.uniform_block_response => {
+            let body = serde_json::json!({
+                "error": DEFAULT_BLOCK_BODY,
+                "reason": reason,
+                "host": host,
+                "path": path,
+            });
+            ("application/json", body.to_string())
+        }
+        None => (config.block_content_type.as_deref().unwrap_or("text/plain"), DEFAULT_BLOCK_BODY.to_string()),
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\n\
+         {}\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n\
+         {}",
+        status,
+        status_text,
+        allow,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// Percent-encode everything except unreserved characters and `/`
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::test_support::*;
+    use crate::config::{Config, RateLimit, load_config, parse_cidrs};
+    use crate::http::header_value;
+    use crate::session::RequestChecks;
+    use crate::intercept::host_only_session;
+
+    /// A two-node IPv4 MaxMind DB: 0.0.0.0/1 is AS64500, 128.0.0.0/2 is AS64501 and
+    /// 192.0.0.0/2 isn't in it
+    fn asn_test_db() -> Vec<u8> {
+        fn field(kind: u8, payload: &[u8]) -> Vec<u8> {
+            let mut out = if kind <= 7 { vec![kind << 5 | payload.len() as u8] } else { vec![payload.len() as u8, kind - 7] };
+            out.extend_from_slice(payload);
+            out
+        }
+        fn uint(kind: u8, value: u64) -> Vec<u8> {
+            let bytes = value.to_be_bytes();
+            let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+            field(kind, &bytes[start..])
+        }
+        fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+            let mut out = vec![7 << 5 | entries.len() as u8];
+            for (key, value) in entries {
+                out.extend(field(2, key.as_bytes()));
+                out.extend_from_slice(value);
+            }
+            out
+        }
+        let asn = |number: u64| map(&[("autonomous_system_number", uint(6, number))]);
+        let (first, second) = (asn(64500), asn(64501));
+
+        let node_count = 2u32;
+        let data_pointer = |offset: usize| node_count + 16 + offset as u32;
+        let mut db = Vec::new();
+        for (left, right) in [(data_pointer(0), 1), (data_pointer(first.len()), node_count)] {
+            db.extend_from_slice(&left.to_be_bytes()[1..]);
+            db.extend_from_slice(&right.to_be_bytes()[1..]);
+        }
+        db.extend_from_slice(&[0; 16]);
+        db.extend(first);
+        db.extend(second);
+        db.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        db.extend(map(&[
+            ("binary_format_major_version", uint(5, 2)),
+            ("binary_format_minor_version", uint(5, 0)),
+            ("build_epoch", uint(9, 0)),
+            ("database_type", field(2, b"GeoLite2-ASN")),
+            ("description", map(&[])),
+            ("ip_version", uint(5, 4)),
+            ("languages", field(11, &[])),
+            ("node_count", uint(6, node_count.into())),
+            ("record_size", uint(5, 24)),
+        ]));
+        db
+    }
+
+    #[test]
+    fn runtime_exception_expires_after_its_ttl() {
+        let now = Instant::now();
+        grant_runtime_exception("expiry.example.com", Duration::from_secs(60), now).unwrap();
+        assert!(runtime_exception_covers_at("expiry.example.com", now));
+        assert!(runtime_exception_covers_at("api.expiry.example.com", now + Duration::from_secs(59)));
+        assert!(!runtime_exception_covers_at("expiry.example.com", now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn runtime_exception_rejects_overflowing_ttl() {
+        let err = grant_runtime_exception("overflow.example.com", Duration::from_secs(u64::MAX), Instant::now());
+        assert!(err.is_err());
+        assert!(!runtime_exception_covers("overflow.example.com"));
+    }
+
+    #[test]
+    fn runtime_exception_does_not_override_block() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "blocked_rules": [{ "host": "blocked-override.example.com" }]
+        }));
+        grant_runtime_exception("blocked-override.example.com", Duration::from_secs(60), Instant::now()).unwrap();
+        grant_runtime_exception("granted.example.com", Duration::from_secs(60), Instant::now()).unwrap();
+        assert_eq!(check_host_allowed(&config, "blocked-override.example.com"), (false, "Host Blocked".to_string()));
+        assert_eq!(check_host_allowed(&config, "granted.example.com"), (true, "Runtime Exception".to_string()));
+    }
+
+    #[test]
+    fn evaluate_checks_host_path_and_method() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [
+                { "host": "api.evaluate.example.com", "allowed_paths": ["/v1/"], "allowed_methods": ["GET"] },
+                { "host": "review.evaluate.example.com", "quarantine_patterns": ["/upload"] }
+            ],
+            "blocked_rules": [{ "host": "api.evaluate.example.com", "paths": ["/v1/admin"] }]
+        }));
+        let decide = |host: &str, path: &str, method: &str| {
+            let decision = evaluate(&config, host, path, method);
+            (decision.action, decision.reason)
+        };
+
+        assert_eq!(decide("api.evaluate.example.com", "/v1/items", "GET"), (Action::Allow, "Path Match".to_string()));
+        assert_eq!(decide("api.evaluate.example.com", "/v2/items", "GET"), (Action::Block, "Path Not Allowed".to_string()));
+        assert_eq!(decide("api.evaluate.example.com", "/v1/items", "POST"), (Action::Block, METHOD_NOT_ALLOWED.to_string()));
+        assert_eq!(decide("api.evaluate.example.com", "/v1/admin", "GET"), (Action::Block, "Host Blocked".to_string()));
+        assert_eq!(decide("api.evaluate.example.com", "", "CONNECT").0, Action::Allow);
+        assert_eq!(
+            decide("review.evaluate.example.com", "/upload/1", "PUT"),
+            (Action::Quarantine, "Quarantine Pattern /upload".to_string())
+        );
+        assert_eq!(decide("unknown.example.com", "/", "GET"), (Action::Block, "Host Not Allowed".to_string()));
+        assert_eq!(decide("unknown.example.com", "", "CONNECT").0, Action::Block);
+    }
+
+    #[test]
+    fn global_allowed_methods_block_trace_everywhere() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_methods": ["GET", "HEAD"],
+            "allowed_rules": [{ "host": "methods.example.com" }]
+        }));
+        grant_runtime_exception("exception.methods.example.org", Duration::from_secs(60), Instant::now()).unwrap();
+        let decide = |host: &str, method: &str| evaluate(&config, host, "/", method).reason;
+
+        assert_eq!(decide("methods.example.com", "GET"), "Host Match");
+        assert_eq!(decide("methods.example.com", "head"), "Host Match");
+        assert_eq!(decide("methods.example.com", "TRACE"), "Method Globally Blocked");
+        assert_eq!(decide("exception.methods.example.org", "GET"), "Runtime Exception");
+        assert_eq!(decide("exception.methods.example.org", "TRACE"), "Method Globally Blocked");
+        // The tunnel itself isn't subject to the list
+        assert!(evaluate(&config, "methods.example.com", "", "CONNECT").allowed());
+    }
+
+    #[test]
+    fn blocklist_mode_blocks_only_what_blocked_rules_match() {
+        let config = config_from(serde_json::json!({
+            "mode": "blocklist",
+            "blocked_rules": [
+                { "host": "bad.blocklist.example.com" },
+                { "host": "mixed.blocklist.example.com", "paths": ["/admin"] }
+            ]
+        }));
+        let decide = |host: &str, path: &str, method: &str| {
+            let decision = evaluate(&config, host, path, method);
+            (decision.action, decision.reason)
+        };
+
+        assert_eq!(decide("bad.blocklist.example.com", "/", "GET"), (Action::Block, "Host Blocked".to_string()));
+        assert_eq!(decide("api.bad.blocklist.example.com", "", "CONNECT"), (Action::Block, "Host Blocked".to_string()));
+        assert_eq!(decide("mixed.blocklist.example.com", "/admin/users", "GET"), (Action::Block, "Host Blocked".to_string()));
+        assert_eq!(decide("mixed.blocklist.example.com", "/public", "GET"), (Action::Allow, "Not Blocked".to_string()));
+        assert_eq!(decide("mixed.blocklist.example.com", "", "CONNECT").0, Action::Allow);
+        assert_eq!(decide("anything.example.org", "/", "GET"), (Action::Allow, "Not Blocked".to_string()));
+        assert_eq!(decide("anything.example.org", "", "CONNECT"), (Action::Allow, "Host Not Blocked".to_string()));
+    }
+
+    #[test]
+    fn blocklist_mode_keeps_global_checks() {
+        let config = config_from(serde_json::json!({
+            "mode": "blocklist",
+            "block_domain_fronting": true,
+            "allowed_http_versions": ["HTTP/1.1"]
+        }));
+        assert!(enforcing(&config));
+        assert_eq!(
+            check_domain_fronting(&config, "front.example.com", Some("hidden.example.org")),
+            (false, "Domain Fronting Detected".to_string())
+        );
+        assert!(!check_http_version(&config, "HTTP/1.0").0);
+        assert!(check_http_version(&config, "HTTP/1.1").0);
+
+        let monitor = config_from(serde_json::json!({ "mode": "monitor", "block_domain_fronting": true }));
+        assert!(!enforcing(&monitor));
+        assert_eq!(
+            check_domain_fronting(&monitor, "front.example.com", Some("hidden.example.org")),
+            (true, "Monitor Mode".to_string())
+        );
+    }
+
+    /// How a downstream test checks a rules file: load it as the proxy would, validate it,
+    /// then assert the decisions for the requests it cares about
+    #[test]
+    fn evaluate_a_rules_file() {
+        let path = temp_config_file(
+            "evaluate-example",
+            serde_json::json!({
+                "mode": "enforce",
+                "allowed_methods": ["GET", "HEAD", "POST"],
+                "allowed_rules": [
+                    { "host": "registry.example.com", "allowed_paths": ["/v2/*/manifests/*", "re:^/token$"] },
+                    { "host": "*.cdn.example.com" }
+                ],
+                "blocked_rules": [{ "host": "tracker.cdn.example.com" }]
+            }),
+        );
+        let config = load_config(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        config.validate().unwrap();
+
+        let allowed = |host: &str, path: &str, method: &str| evaluate(&config, host, path, method).allowed();
+        assert!(allowed("registry.example.com", "/v2/app/manifests/latest", "GET"));
+        assert!(allowed("registry.example.com", "/token?scope=pull", "GET"));
+        assert!(!allowed("registry.example.com", "/v2/app/blobs/sha256:abc", "GET"));
+        assert!(allowed("assets.cdn.example.com", "/logo.png", "HEAD"));
+        assert!(!allowed("assets.cdn.example.com", "/logo.png", "DELETE"));
+        assert!(!allowed("tracker.cdn.example.com", "/pixel", "GET"));
+        assert!(!allowed("tracker.cdn.example.com", "", "CONNECT"));
+        assert_eq!(evaluate(&config, "evil.example.com", "/", "GET").reason, "Host Not Allowed");
+    }
+
+    #[test]
+    fn higher_priority_allow_overrides_a_block() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [
+                { "host": "status.priority.example.com", "priority": 10 },
+                { "host": "tie.priority.example.com" }
+            ],
+            "blocked_rules": [
+                { "host": "priority.example.com", "priority": 5 },
+                { "host": "tie.priority.example.com" }
+            ]
+        }));
+        let decide = |host: &str, path: &str, method: &str| evaluate(&config, host, path, method).reason;
+
+        assert_eq!(decide("status.priority.example.com", "", "CONNECT"), "Host Allowed");
+        assert_eq!(decide("status.priority.example.com", "/health", "GET"), "Host Match");
+        assert_eq!(decide("other.priority.example.com", "", "CONNECT"), "Host Blocked");
+        // Equal priorities: the block wins
+        assert_eq!(decide("tie.priority.example.com", "", "CONNECT"), "Host Blocked");
+        assert_eq!(decide("tie.priority.example.com", "/", "GET"), "Host Blocked");
+    }
+
+    #[test]
+    fn decision_names_the_overlapping_rule_that_matched() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [
+                { "host": "*.overlap.example.com" },
+                { "host": "api.overlap.example.com", "priority": 5, "allowed_paths": ["/v1/"] },
+                { "host": "api.overlap.example.com", "priority": 5 },
+                { "host": "*.example.com", "priority": 1 }
+            ]
+        }));
+        let rule = |host: &str| evaluate(&config, host, "/v1/items", "GET").rule;
+        assert_eq!(rule("api.overlap.example.com"), Some(1));
+        assert_eq!(rule("www.overlap.example.com"), Some(3));
+        assert_eq!(rule("other.example.com"), Some(3));
+        assert_eq!(rule("example.org"), None);
+
+        // A block still names the rule that matched, and the logged entry carries its host
+        let blocked = evaluate(&config, "api.overlap.example.com", "/v2/items", "GET");
+        assert_eq!((blocked.action, blocked.rule), (Action::Block, Some(1)));
+        assert_eq!(blocked.rule_host(&config), Some("api.overlap.example.com"));
+
+        let checks = RequestChecks {
+            config: &config,
+            hostname: "www.overlap.example.com",
+            rule: find_host_rule(&config, "www.overlap.example.com"),
+            client_id: None,
+            host_only: false,
+        };
+        assert!(checks.check("GET /logged HTTP/1.1\r\nHost: www.overlap.example.com\r\n\r\n").is_none());
+        let entries = logged_entries("www.overlap.example.com");
+        assert_eq!(entries.last().unwrap()["rule"], "*.example.com");
+    }
+
+    #[test]
+    fn break_glass_file_suspends_enforcement_while_present() {
+        let flag = test_paths().log.replace("traffic.jsonl", "break-glass");
+        fs::create_dir_all(Path::new(&flag).parent().unwrap()).unwrap();
+        let _ = fs::remove_file(&flag);
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "break_glass_file": flag,
+            "allowed_rules": [{ "host": "allowed.breakglass.example.com" }]
+        }));
+        let decide = || {
+            // Skip the FLAG_FILE_TTL cache so each call sees the file as it is now
+            *BREAK_GLASS_STATE.lock().unwrap() = None;
+            let decision = evaluate(&config, "other.breakglass.example.com", "/", "GET");
+            (decision.action, decision.reason)
+        };
+
+        assert_eq!(decide().0, Action::Block);
+        fs::write(&flag, "").unwrap();
+        assert_eq!(decide(), (Action::Allow, "Break Glass".to_string()));
+        fs::remove_file(&flag).unwrap();
+        assert_eq!(decide().0, Action::Block);
+    }
+
+    #[test]
+    fn jwt_claims_need_a_valid_signature_and_matching_values() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "jwt_hmac_secret": "jwt-test-secret",
+            "allowed_rules": [{
+                "host": "api.jwt.example.com",
+                "required_jwt_claims": { "team": "infra", "scope": "deploy" }
+            }]
+        }));
+        let bearer = |claims: serde_json::Value, secret: &str| {
+            let header = jsonwebtoken::Header::new(Algorithm::HS256);
+            let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+            format!("Bearer {}", jsonwebtoken::encode(&header, &claims, &key).unwrap())
+        };
+        let check = |authorization: Option<&str>| check_jwt_claims(&config, "api.jwt.example.com", authorization);
+
+        let matching = bearer(serde_json::json!({ "team": "infra", "scope": "deploy" }), "jwt-test-secret");
+        assert_eq!(check(Some(&matching)), (true, "Claims Match".to_string()));
+        let listed = bearer(serde_json::json!({ "team": "infra", "scope": ["read", "deploy"] }), "jwt-test-secret");
+        assert_eq!(check(Some(&listed)), (true, "Claims Match".to_string()));
+
+        let wrong_team = bearer(serde_json::json!({ "team": "web", "scope": "deploy" }), "jwt-test-secret");
+        assert_eq!(check(Some(&wrong_team)), (false, "Claim Mismatch".to_string()));
+        let missing_claim = bearer(serde_json::json!({ "team": "infra" }), "jwt-test-secret");
+        assert_eq!(check(Some(&missing_claim)), (false, "Claim Mismatch".to_string()));
+
+        let forged = bearer(serde_json::json!({ "team": "infra", "scope": "deploy" }), "some-other-secret");
+        assert_eq!(check(Some(&forged)), (false, "Invalid JWT".to_string()));
+        assert_eq!(check(Some("Bearer not.a.jwt")), (false, "Invalid JWT".to_string()));
+
+        assert_eq!(check(None), (false, "Claim Mismatch".to_string()));
+        assert_eq!(check(Some("Basic dXNlcjpwYXNz")), (false, "Claim Mismatch".to_string()));
+        assert_eq!(check_jwt_claims(&config, "other.jwt.example.com", None), (true, "No Claims Required".to_string()));
+    }
+
+    #[test]
+    fn upstream_asn_follows_blocked_and_allowed_lists() {
+        let path = test_paths().log.replace("traffic.jsonl", "asn-test.mmdb");
+        fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+        fs::write(&path, asn_test_db()).unwrap();
+        init_asn_db(&path).unwrap();
+        let (first, second, unlisted) = ("10.0.0.1".parse().unwrap(), "130.0.0.1".parse().unwrap(), "200.0.0.1".parse().unwrap());
+        assert_eq!(lookup_asn(first), Some(64500));
+        assert_eq!(lookup_asn(second), Some(64501));
+        assert_eq!(lookup_asn(unlisted), None);
+
+        let blocked = config_from(serde_json::json!({ "mode": "enforce", "blocked_asns": [64500] }));
+        assert_eq!(check_upstream_asn(&blocked, first), (false, "ASN Not Allowed".to_string()));
+        assert_eq!(check_upstream_asn(&blocked, second), (true, "ASN Allowed".to_string()));
+        assert_eq!(check_upstream_asn(&blocked, unlisted), (true, "ASN Allowed".to_string()));
+
+        let allowed = config_from(serde_json::json!({ "mode": "enforce", "allowed_asns": [64501] }));
+        assert_eq!(check_upstream_asn(&allowed, first), (false, "ASN Not Allowed".to_string()));
+        assert_eq!(check_upstream_asn(&allowed, second), (true, "ASN Allowed".to_string()));
+        assert_eq!(check_upstream_asn(&allowed, unlisted), (false, "ASN Not Allowed".to_string()));
+
+        let monitor = config_from(serde_json::json!({ "blocked_asns": [64500] }));
+        assert_eq!(check_upstream_asn(&monitor, first), (true, "Monitor Mode".to_string()));
+    }
+
+    #[test]
+    fn allowed_clients_need_a_matching_certificate_name() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [
+                { "host": "internal.clients.example.com", "allowed_clients": ["build-agent", "spiffe://ci/runner"] },
+                { "host": "open.clients.example.com" }
+            ]
+        }));
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let check = |host: &str, client: &[&str]| check_client_allowed(&config, host, &names(client));
+
+        assert_eq!(check("internal.clients.example.com", &["build-agent"]), (true, "Client Authorized".to_string()));
+        assert_eq!(check("internal.clients.example.com", &["laptop", "spiffe://ci/runner"]), (true, "Client Authorized".to_string()));
+        assert_eq!(check("internal.clients.example.com", &["laptop"]), (false, "Client Not Authorized".to_string()));
+        assert_eq!(check("internal.clients.example.com", &["Build-Agent"]), (false, "Client Not Authorized".to_string()));
+        assert_eq!(check("internal.clients.example.com", &[]), (false, "Client Not Authorized".to_string()));
+        assert_eq!(check("open.clients.example.com", &[]), (true, "Client Authorized".to_string()));
+    }
+
+    #[test]
+    fn domain_fronting_compares_registrable_domains() {
+        let config = config_from(serde_json::json!({ "mode": "enforce", "block_domain_fronting": true }));
+        let check = |sni: &str, host: Option<&str>| check_domain_fronting(&config, sni, host);
+        let matches = (true, "Host Header Matches".to_string());
+        let fronted = (false, "Domain Fronting Detected".to_string());
+
+        assert_eq!(check("cdn.fronting.example.com", Some("evil.example.org")), fronted);
+        assert_eq!(check("cdn.fronting.example.com", Some("example.com.evil.net")), fronted);
+        assert_eq!(check("cdn.fronting.example.com", Some("api.fronting.example.com")), matches);
+        assert_eq!(check("cdn.fronting.example.com", Some("API.Fronting.Example.COM")), matches);
+        assert_eq!(check("cdn.fronting.example.com", Some("cdn.fronting.example.com.")), matches);
+        assert_eq!(check("cdn.fronting.example.com.", Some("cdn.fronting.example.com:443")), matches);
+        assert_eq!(check("10.0.0.1", Some("[::1]:8443")), fronted);
+        assert_eq!(check("cdn.fronting.example.com", None), (true, "No Host Header".to_string()));
+
+        let unchecked = config_from(serde_json::json!({ "mode": "enforce" }));
+        assert!(check_domain_fronting(&unchecked, "cdn.fronting.example.com", Some("evil.example.org")).0);
+    }
+
+    #[test]
+    fn disallowed_method_gets_405_listing_the_allowed_ones() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [{ "host": "api.methods.example.com", "allowed_methods": ["get", "HEAD"] }]
+        }));
+        let decision = evaluate(&config, "api.methods.example.com", "/items", "DELETE");
+        assert_eq!((decision.action, decision.reason.as_str()), (Action::Block, METHOD_NOT_ALLOWED));
+        assert_eq!(evaluate(&config, "api.methods.example.com", "/items", "GET").action, Action::Allow);
+
+        let rule = find_host_rule(&config, "api.methods.example.com");
+        let response = block_response(&config, rule, "api.methods.example.com", "/items", METHOD_NOT_ALLOWED, None);
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", response);
+        assert!(response.contains("\r\nAllow: GET, HEAD\r\n"), "{}", response);
+
+        let other = block_response(&config, rule, "api.methods.example.com", "/items", "Path Not Allowed", None);
+        assert!(other.starts_with("HTTP/1.1 403 Forbidden\r\n") && !other.contains("Allow:"), "{}", other);
+    }
+
+    #[test]
+    fn empty_paths_means_deny_all_needs_a_path_list() {
+        let rules = serde_json::json!([
+            { "host": "bare.paths.example.com" },
+            { "host": "listed.paths.example.com", "allowed_paths": ["/v1/*"] }
+        ]);
+        let allow_all = config_from(serde_json::json!({ "mode": "enforce", "allowed_rules": rules }));
+        let deny_all = config_from(serde_json::json!({ "mode": "enforce", "empty_paths_means": "deny_all", "allowed_rules": rules }));
+        let decide = |config: &Config, host: &str, path: &str| {
+            let decision = evaluate(config, host, path, "GET");
+            (decision.action, decision.reason)
+        };
+
+        assert_eq!(decide(&allow_all, "bare.paths.example.com", "/anything"), (Action::Allow, "Host Match".to_string()));
+        assert!(host_only_session(&allow_all, "bare.paths.example.com"));
+        assert_eq!(decide(&deny_all, "bare.paths.example.com", "/anything"), (Action::Block, "Path Not Allowed".to_string()));
+        assert!(!host_only_session(&deny_all, "bare.paths.example.com"));
+        assert_eq!(decide(&deny_all, "listed.paths.example.com", "/v1/items"), (Action::Allow, "Path Match".to_string()));
+        assert_eq!(decide(&deny_all, "listed.paths.example.com", "/v2/items"), (Action::Block, "Path Not Allowed".to_string()));
+
+        let invalid = config_from(serde_json::json!({ "empty_paths_means": "deny_some" })).validate().unwrap_err();
+        assert!(invalid.to_string().contains("empty_paths_means"), "{}", invalid);
+    }
+
+    #[test]
+    fn host_matching_ignores_case_and_a_trailing_dot() {
+        assert!(host_matches("api.github.com", "API.GitHub.COM"));
+        assert!(host_matches("API.GITHUB.COM", "api.github.com"));
+        assert!(host_matches("github.com", "Raw.GitHub.com"));
+        assert!(host_matches("api.github.com", "api.github.com."));
+        assert!(host_matches("api.github.com.", "api.github.com"));
+        assert!(host_matches("*.GitHub.com.", "api.github.com."));
+        assert!(host_matches("=api.github.com", "API.github.com."));
+        assert!(!host_matches("api.github.com", "api.github.com.."));
+        assert!(!host_matches("github.com", "notgithub.com"));
+    }
+
+    #[test]
+    fn wildcard_and_exact_patterns_keep_to_their_side_of_the_apex() {
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+
+        assert!(host_matches("=example.com", "example.com"));
+        assert!(!host_matches("=example.com", "api.example.com"));
+
+        assert!(host_matches("example.com", "example.com"));
+        assert!(host_matches("example.com", "api.example.com"));
+
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [{ "host": "*.wild.example.com" }, { "host": "=exact.example.com" }]
+        }));
+        assert_eq!(evaluate(&config, "wild.example.com", "", "CONNECT").action, Action::Block);
+        assert_eq!(evaluate(&config, "cdn.wild.example.com", "", "CONNECT").action, Action::Allow);
+        assert_eq!(evaluate(&config, "exact.example.com", "", "CONNECT").action, Action::Allow);
+        assert_eq!(evaluate(&config, "api.exact.example.com", "", "CONNECT").action, Action::Block);
+    }
+
+    #[test]
+    fn query_string_only_counts_for_match_query_rules() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_rules": [
+                { "host": "plain.query.example.com", "allowed_paths": ["/search", "/files/*.zip"] },
+                { "host": "strict.query.example.com", "allowed_paths": ["re:^/search\\?q=[a-z]+$"], "match_query": true }
+            ]
+        }));
+        let action = |host: &str, path: &str| evaluate(&config, host, path, "GET").action;
+
+        assert_eq!(action("plain.query.example.com", "/files/release.zip?token=abc"), Action::Allow);
+        assert_eq!(action("plain.query.example.com", "/files/release.zip"), Action::Allow);
+        assert_eq!(action("plain.query.example.com", "/search?q=anything&page=2"), Action::Allow);
+        assert_eq!(action("plain.query.example.com", "/other?next=/search"), Action::Block);
+
+        assert_eq!(action("strict.query.example.com", "/search?q=rust"), Action::Allow);
+        assert_eq!(action("strict.query.example.com", "/search?q=rust&debug=1"), Action::Block);
+        assert_eq!(action("strict.query.example.com", "/search"), Action::Block);
+    }
+
+    #[test]
+    fn allowed_cidrs_cover_ip_literals_only() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "allowed_cidrs": ["10.20.0.0/16", "fd00:abcd::/32"]
+        }));
+
+        assert!(in_allowed_cidr(&config, "10.20.3.4"));
+        assert!(!in_allowed_cidr(&config, "10.21.0.1"));
+        assert!(in_allowed_cidr(&config, "fd00:abcd::1"));
+        assert!(in_allowed_cidr(&config, "[fd00:abcd:1::5]"));
+        assert!(!in_allowed_cidr(&config, "[fd00:abce::1]"));
+        assert!(!in_allowed_cidr(&config, "10.20.example.com"));
+        assert!(!in_allowed_cidr(&config, "localhost"));
+
+        assert_eq!(check_host_allowed(&config, "10.20.3.4"), (true, "CIDR Match".to_string()));
+        assert!(!check_host_allowed(&config, "10.21.0.1").0);
+        assert!(parse_cidrs(&["10.0.0.0/33".to_string()]).is_err());
+    }
+
+    #[test]
+    fn rate_limit_refuses_past_the_burst_and_refills_over_time() {
+        let limit = RateLimit { per_second: 2.0, burst: Some(3) };
+        let host = "bucket.ratelimit.example.com";
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(take_rate_token(&limit, host, start));
+        }
+        assert!(!take_rate_token(&limit, host, start));
+        assert!(!take_rate_token(&limit, host, start + Duration::from_millis(400)));
+        assert!(take_rate_token(&limit, host, start + Duration::from_millis(600)));
+        assert!(!take_rate_token(&limit, host, start + Duration::from_millis(600)));
+
+        // A long pause refills no further than the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(take_rate_token(&limit, host, later));
+        }
+        assert!(!take_rate_token(&limit, host, later));
+
+        assert!(take_rate_token(&limit, "other.ratelimit.example.com", later));
+        assert!(too_many_requests_response(None).contains("\r\nRetry-After: 1\r\n"));
+    }
+
+    #[test]
+    fn block_responses_follow_the_configured_shape() {
+        let host = "blocked.response.example.com";
+        let plain = config_from(serde_json::json!({ "mode": "enforce" }));
+        let forbidden = block_response(&plain, None, host, "/x", "Host Not Allowed", None);
+        assert!(forbidden.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", forbidden);
+        assert!(forbidden.contains("\r\nContent-Type: text/plain\r\n"));
+        assert!(forbidden.ends_with("\r\n\r\nBlocked by Secure Proxy"));
+        assert!(block_response(&plain, None, host, "/x", METHOD_NOT_ALLOWED, None).starts_with("HTTP/1.1 405 "));
+
+        let json = block_response(&plain, None, host, "/x", "Host Not Allowed", Some("text/html, Application/JSON"));
+        assert!(json.contains("\r\nContent-Type: application/json\r\n"), "{}", json);
+        let body: serde_json::Value = serde_json::from_str(json.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["reason"], "Host Not Allowed");
+        assert_eq!(body["host"], host);
+        assert_eq!(body["path"], "/x");
+
+        let custom = config_from(serde_json::json!({
+            "mode": "enforce",
+            "block_status": 451,
+            "block_body": "<h1>Nope</h1>",
+            "block_content_type": "text/html"
+        }));
+        let response = block_response(&custom, None, host, "/x", "Host Not Allowed", Some("application/json"));
+        assert!(response.starts_with("HTTP/1.1 451 Unavailable For Legal Reasons\r\n"), "{}", response);
+        assert!(response.contains("\r\nContent-Type: text/html\r\nContent-Length: 13\r\n"), "{}", response);
+        assert!(response.ends_with("<h1>Nope</h1>"));
+
+        let stealth = config_from(serde_json::json!({ "mode": "enforce", "stealth_mode": true }));
+        let quiet = block_response(&stealth, None, host, "/x", "Host Not Allowed", Some("application/json"));
+        assert!(quiet.ends_with("\r\n\r\nForbidden") && !quiet.contains("Host Not Allowed"), "{}", quiet);
+
+        let redirect = config_from(serde_json::json!({ "mode": "enforce", "block_redirect_url": "https://portal.example.com/blocked" }));
+        let found = block_response(&redirect, None, host, "/x", "Host Not Allowed", None);
+        assert!(found.starts_with("HTTP/1.1 302 Found\r\nLocation: https://portal.example.com/blocked\r\n"), "{}", found);
+    }
+
+    #[test]
+    fn block_redirects_fill_in_the_encoded_host_and_path() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "block_redirect_url": "https://portal.example.com/blocked?host={host}&path={path}",
+            "allowed_rules": [{ "host": "ruled.redirect.example.com", "block_redirect_url": "https://{host}.review.example.com{path}" }]
+        }));
+        let location = |host: &str, path: &str| {
+            let response = block_response(&config, find_host_rule(&config, host), host, path, "Host Not Allowed", None);
+            assert!(response.starts_with("HTTP/1.1 302 Found\r\n"), "{}", response);
+            header_value(&response, "location").unwrap().to_string()
+        };
+
+        assert_eq!(
+            location("other.redirect.example.com", "/a b/ü?x=1&y=<2>"),
+            "https://portal.example.com/blocked?host=other.redirect.example.com&path=/a%20b/%C3%BC%3Fx%3D1%26y%3D%3C2%3E"
+        );
+        assert_eq!(
+            location("ruled.redirect.example.com", "/docs"),
+            "https://ruled.redirect.example.com.review.example.com/docs"
+        );
+        // Header injection through the path is encoded away
+        assert!(!location("other.redirect.example.com", "/x\r\nSet-Cookie: a=b").contains('\r'));
+    }
+}
@@ -0,0 +1,1357 @@
+//! Keep-alive request relaying and traffic capture
+
+use anyhow::Result;
+use base64::Engine;
+use bytes::Bytes;
+use chrono::{SecondsFormat, Utc};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicU64, Ordering}, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Notify};
+use tracing::{debug, error, trace};
+
+use crate::config::{Config, HostRule};
+use crate::logging::{classify_user_agent, log_response, log_traffic, print_decision};
+use crate::metrics::{note_connection, record_upstream_status};
+use crate::policy::{
+    RATE_LIMITED, block_response, in_maintenance, maintenance_response, too_many_requests_response,
+};
+use crate::http::{
+    ChunkedScanner, MAX_RESPONSE_HEAD_BYTES, check_trailers, header_value, keeps_alive,
+    smuggling_reason, upstream_request_bytes, with_extra_headers, with_host_header, without_headers,
+};
+use crate::tunnel::{Activity, switches_protocols, with_deadline};
+use crate::intercept::{gateway_timeout_response, host_header_rewrite, http1_decision};
+
+// ============================================================================
+// Keep-Alive Requests
+// ============================================================================
+
+/// Largest request head accepted on an intercepted HTTP/1.1 connection
+pub(crate) const MAX_REQUEST_HEAD_BYTES: usize = 64 * 1024;
+
+/// Where a stream of HTTP/1.1 messages is: in a head, in a body with a known length or
+/// chunked encoding, past the point where messages can be told apart (a protocol upgrade, or
+/// a body that runs until the connection closes), or in a chunked body that didn't parse
+#[derive(Debug)]
+pub(crate) enum MessageState {
+    Head(Vec<u8>),
+    Body(u64),
+    Chunked(ChunkedScanner),
+    Opaque,
+    Malformed,
+}
+
+impl MessageState {
+    /// Whether the stream sits between two messages
+    fn at_boundary(&self) -> bool {
+        matches!(self, MessageState::Head(head) if head.is_empty())
+    }
+
+    /// Collect head bytes from the start of `buf`. Returns how many were used and, once its
+    /// blank line has arrived, the whole head.
+    fn take_head(&mut self, buf: &[u8]) -> (usize, Option<Vec<u8>>) {
+        let MessageState::Head(head) = self else {
+            return (0, None);
+        };
+        let searched = head.len().saturating_sub(3);
+        head.extend_from_slice(buf);
+        match head[searched..].windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => {
+                let end = searched + pos + 4;
+                let used = buf.len() - (head.len() - end);
+                head.truncate(end);
+                (used, Some(std::mem::take(head)))
+            }
+            None => (buf.len(), None),
+        }
+    }
+
+    /// Pass over body bytes at the start of `buf`, returning how many belong to the current
+    /// body. Moves on to the next head once the body ends.
+    fn skip_body(&mut self, buf: &[u8]) -> usize {
+        match self {
+            MessageState::Body(remaining) => {
+                let used = (*remaining).min(buf.len() as u64);
+                *remaining -= used;
+                if *remaining == 0 {
+                    *self = MessageState::Head(Vec::new());
+                }
+                used as usize
+            }
+            MessageState::Chunked(scanner) => {
+                let used = scanner.consume(buf);
+                if scanner.is_invalid() {
+                    *self = MessageState::Malformed;
+                } else if scanner.is_done() {
+                    *self = MessageState::Head(Vec::new());
+                }
+                used
+            }
+            MessageState::Head(_) => 0,
+            MessageState::Opaque | MessageState::Malformed => buf.len(),
+        }
+    }
+
+    /// `skip_body`, except that a chunked body stops where its trailer section starts
+    fn skip_to_trailers(&mut self, buf: &[u8]) -> usize {
+        let MessageState::Chunked(scanner) = self else {
+            return self.skip_body(buf);
+        };
+        let used = scanner.consume_to_trailers(buf);
+        if scanner.is_invalid() {
+            *self = MessageState::Malformed;
+        }
+        used
+    }
+
+    /// Whether a chunked body has reached its trailer section
+    fn in_trailers(&self) -> bool {
+        matches!(self, MessageState::Chunked(scanner) if scanner.at_trailers())
+    }
+
+    /// Collect a chunked body's trailer section from the start of `buf`. Returns how many
+    /// bytes belong to it and, once it has ended, its fields.
+    fn take_trailers(&mut self, buf: &[u8]) -> (usize, Option<Vec<(String, String)>>) {
+        let MessageState::Chunked(scanner) = self else {
+            return (0, None);
+        };
+        let used = scanner.consume(buf);
+        if !scanner.is_done() {
+            return (used, None);
+        }
+        let trailers = std::mem::take(&mut scanner.trailers);
+        *self = MessageState::Head(Vec::new());
+        (used, Some(trailers))
+    }
+}
+
+/// State after a message head: its body, framed by Transfer-Encoding or Content-Length.
+/// Without either, requests have no body and responses run until the connection closes.
+fn body_state(head: &str, is_response: bool) -> MessageState {
+    let chunked = header_value(head, "transfer-encoding").is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    if chunked {
+        return MessageState::Chunked(ChunkedScanner::default());
+    }
+    match header_value(head, "content-length").and_then(|len| len.parse::<u64>().ok()) {
+        Some(0) => MessageState::Head(Vec::new()),
+        Some(len) => MessageState::Body(len),
+        None if is_response => MessageState::Opaque,
+        None => MessageState::Head(Vec::new()),
+    }
+}
+
+/// Method, path and version from a request head's start line
+pub(crate) fn request_line(head: &str) -> (&str, &str, &str) {
+    let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), version) => (method, path, version.unwrap_or("HTTP/0.9")),
+        _ => ("?", "/", "HTTP/0.9"),
+    }
+}
+
+/// Read from the client until a whole request head has arrived, however many reads that
+/// takes. Returns everything read, which can run past the head into the body; stops early
+/// at end of stream or once more than `MAX_REQUEST_HEAD_BYTES` came without a blank line.
+pub(crate) async fn read_request_head<R>(client: &mut R, idle_timeout: Option<Duration>) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut chunk = vec![0u8; 8192];
+    loop {
+        let n = with_deadline(idle_timeout, client.read(&mut chunk)).await?;
+        if n == 0 {
+            return Ok(buf);
+        }
+        let searched = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+        if buf[searched..].windows(4).any(|w| w == b"\r\n\r\n") || buf.len() > MAX_REQUEST_HEAD_BYTES {
+            return Ok(buf);
+        }
+    }
+}
+
+/// Read from the upstream until a whole response head has arrived, however many reads that
+/// takes. Whatever came in after the head is returned with it.
+pub(crate) async fn read_response_head<R>(upstream: &mut R, idle_timeout: Option<Duration>) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut chunk = vec![0u8; 8192];
+    loop {
+        let n = with_deadline(idle_timeout, upstream.read(&mut chunk)).await?;
+        if n == 0 {
+            return Ok(buf);
+        }
+        let searched = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+        if buf[searched..].windows(4).any(|w| w == b"\r\n\r\n") || buf.len() > MAX_RESPONSE_HEAD_BYTES {
+            return Ok(buf);
+        }
+    }
+}
+
+/// Split the bytes read for the first request of a connection into the request and what
+/// follows it: returns the request's length and the client stream's state after it
+pub(crate) fn first_request_split(data: &[u8]) -> (usize, MessageState) {
+    let mut state = MessageState::Head(Vec::new());
+    let (used, head) = state.take_head(data);
+    let Some(head) = head else {
+        // The client stopped before finishing the head; nothing after it to tell apart
+        return (data.len(), MessageState::Opaque);
+    };
+    state = body_state(&String::from_utf8_lossy(&head), false);
+    let used = used + state.skip_body(&data[used..]);
+    (used, state)
+}
+
+/// Relay what is left of a request body as the client sends it. Bytes read past the end of
+/// the body (a pipelined request) are added to `pending`.
+pub(crate) async fn relay_request_body<R, W>(
+    client: &mut R,
+    upstream: &mut W,
+    state: &mut MessageState,
+    pending: &mut Vec<u8>,
+    idle_timeout: Option<Duration>,
+    capture: Option<&Capture>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; 8192];
+    while matches!(state, MessageState::Body(_) | MessageState::Chunked(_)) {
+        let n = with_deadline(idle_timeout, client.read(&mut buf)).await?;
+        if n == 0 {
+            break;
+        }
+        let used = state.skip_body(&buf[..n]);
+        upstream.write_all(&buf[..used]).await?;
+        if let Some(capture) = capture {
+            capture.record(&buf[..used]);
+            capture.request_body(&buf[..used]);
+        }
+        pending.extend_from_slice(&buf[used..n]);
+    }
+    Ok(())
+}
+
+/// Follow responses as they stream past, a head at a time however the reads split it. Each
+/// final response takes the oldest request from `awaiting`, whose method tells whether it has
+/// a body, and is logged against it. With `responding`, that request is also kept there and
+/// the scan stops at the end of each response and where a chunked body's trailer section
+/// starts, so the trailers can be checked before they are sent. Returns whether a response switched protocols, after which
+/// the stream is no longer followed, and how many bytes were scanned.
+pub(crate) fn scan_responses(
+    config: &Config,
+    host: &str,
+    state: &mut MessageState,
+    awaiting: &mut VecDeque<(String, String)>,
+    mut buf: &[u8],
+    capture: Option<&Capture>,
+    mut responding: Option<&mut (String, String)>,
+) -> (bool, usize) {
+    let total = buf.len();
+    while !buf.is_empty() {
+        let used = match state.take_head(buf) {
+            (used, Some(head)) => {
+                let head = String::from_utf8_lossy(&head);
+                let status = head.split_whitespace().nth(1).unwrap_or("");
+                if switches_protocols(&head) {
+                    let (method, path) = awaiting.pop_front().unwrap_or_default();
+                    log_response(config, host, &method, &path, status);
+                    if let Some(capture) = capture {
+                        capture.response(&head);
+                    }
+                    *state = MessageState::Opaque;
+                    return (true, total);
+                }
+                *state = if status.starts_with('1') {
+                    MessageState::Head(Vec::new())
+                } else {
+                    record_upstream_status(status);
+                    if let Some(capture) = capture {
+                        capture.response(&head);
+                    }
+                    let (method, path) = awaiting.pop_front().unwrap_or_default();
+                    log_response(config, host, &method, &path, status);
+                    let no_body = method.eq_ignore_ascii_case("HEAD") || status == "204" || status == "304";
+                    if let Some(responding) = responding.as_deref_mut() {
+                        *responding = (method, path);
+                    }
+                    if no_body {
+                        MessageState::Head(Vec::new())
+                    } else {
+                        body_state(&head, true)
+                    }
+                };
+                used
+            }
+            (used, None) if matches!(state, MessageState::Head(_)) => {
+                if matches!(state, MessageState::Head(head) if head.len() > MAX_RESPONSE_HEAD_BYTES) {
+                    *state = MessageState::Opaque;
+                }
+                used
+            }
+            _ => {
+                let in_body = matches!(state, MessageState::Body(_) | MessageState::Chunked(_));
+                let used = if responding.is_some() { state.skip_to_trailers(buf) } else { state.skip_body(buf) };
+                if let Some(capture) = capture.filter(|_| in_body) {
+                    capture.response_body(&buf[..used]);
+                }
+                if state.in_trailers() {
+                    return (false, total - buf.len() + used);
+                }
+                used
+            }
+        };
+        buf = &buf[used..];
+        if responding.is_some() && state.at_boundary() {
+            // One response at a time, so the caller can act between them
+            return (false, total - buf.len());
+        }
+    }
+    (false, total)
+}
+
+/// The checks a request after the first one on a kept-alive HTTP/1.1 connection goes
+/// through: the same ones as the first request, minus static responses and pacing
+pub(crate) struct RequestChecks<'a> {
+    pub(crate) config: &'a Config,
+    pub(crate) hostname: &'a str,
+    pub(crate) rule: Option<&'a HostRule>,
+    pub(crate) client_id: Option<&'a str>,
+    /// A `host_only_session`: requests are only followed, for their responses' sake, and go
+    /// upstream unchecked, unlogged and as sent
+    pub(crate) host_only: bool,
+}
+
+impl RequestChecks<'_> {
+    /// Check and log one request head. Returns the response to send instead of forwarding
+    /// it, when it is refused.
+    pub(crate) fn check(&self, head: &str) -> Option<Vec<u8>> {
+        if self.host_only {
+            // Unchecked, but still counted for the connection summary
+            note_connection(|stats| stats.requests += 1);
+            return None;
+        }
+        let (config, hostname) = (self.config, self.hostname);
+        let (method, path, version) = request_line(head);
+
+        if let Some(reason) = smuggling_reason(config, head) {
+            log_traffic(config, "BLOCK", hostname, path, method, reason, &[]);
+            print_decision(config, format_args!("⛔ [{}] {} {}{} -> {}", config.mode, method, hostname, path, reason));
+            return Some(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec());
+        }
+        if in_maintenance(config, hostname) {
+            log_traffic(config, "MAINTENANCE", hostname, path, method, "Maintenance Mode", &[]);
+            print_decision(config, format_args!("🚧 [{}] {} {}{} -> Maintenance Mode", config.mode, method, hostname, path));
+            return Some(maintenance_response(config).into_bytes());
+        }
+
+        let decision = http1_decision(config, hostname, head, method, path, version).warn_only(config, hostname);
+        let client_type = config
+            .classify_user_agent
+            .then(|| classify_user_agent(config, header_value(head, "user-agent")));
+        let mut fields = Vec::new();
+        if let Some(client_type) = &client_type {
+            fields.push(("client_type", client_type.as_str()));
+        }
+        if let Some(client_id) = self.client_id {
+            fields.push(("client_id", client_id));
+        }
+        fields.extend(decision.rule_host(config).map(|rule| ("rule", rule)));
+        log_traffic(config, decision.action.as_str(), hostname, path, method, &decision.reason, &fields);
+        let icon = decision.action.icon();
+        print_decision(config, format_args!("{} [{}] {} {}{} -> {}", icon, config.mode, method, hostname, path, decision.reason));
+
+        (!decision.allowed()).then(|| match decision.reason.as_str() {
+            RATE_LIMITED if !config.uniform_block_response => too_many_requests_response(self.rule).into_bytes(),
+            reason => block_response(config, self.rule, hostname, path, reason, header_value(head, "accept")).into_bytes(),
+        })
+    }
+
+    /// Refuse the rest of the stream because it can't be parsed into requests
+    fn refuse_malformed(&self, reason: &str) -> Vec<u8> {
+        let config = self.config;
+        log_traffic(config, "BLOCK", self.hostname, "/", "?", reason, &[]);
+        print_decision(config, format_args!("⛔ [{}] {} -> {}", config.mode, self.hostname, reason));
+        b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+    }
+
+    /// A checked request head as it goes upstream
+    fn forward<'h>(&self, head: &'h [u8]) -> Cow<'h, [u8]> {
+        if self.host_only {
+            return Cow::Borrowed(head);
+        }
+        let forwarded = upstream_request_bytes(self.config, self.rule, head);
+        match host_header_rewrite(self.rule) {
+            Some(upstream_host) => Cow::Owned(with_host_header(&forwarded, upstream_host)),
+            None => forwarded,
+        }
+    }
+}
+
+/// What `tunnel` needs to check every request on a kept-alive HTTP/1.1 connection, not just
+/// the first: the checks, and where both streams are once the first exchange is through
+pub struct RequestGate<'a> {
+    pub(crate) checks: RequestChecks<'a>,
+    /// Client stream state after the first request, and bytes already read past it
+    pub(crate) requests: MessageState,
+    pub(crate) pending: Vec<u8>,
+    /// Upstream stream state after what has been sent of the first response, and the
+    /// method and path of the first request while its response head is still to come
+    pub(crate) responses: MessageState,
+    pub(crate) awaiting: VecDeque<(String, String)>,
+    /// Method and path of the first request, whose response may still be streaming
+    pub(crate) responding: (String, String),
+    /// Requests already forwarded before the gate took over
+    pub(crate) forwarded: u64,
+    /// The first request was the connection's last (see `keeps_alive`)
+    pub(crate) closing: bool,
+}
+
+impl<'a> RequestGate<'a> {
+    /// A gate for a connection none of whose requests have been read yet
+    pub(crate) fn from_start(checks: RequestChecks<'a>) -> Self {
+        RequestGate {
+            checks,
+            requests: MessageState::Head(Vec::new()),
+            pending: Vec::new(),
+            responses: MessageState::Head(Vec::new()),
+            awaiting: VecDeque::new(),
+            responding: Default::default(),
+            forwarded: 0,
+            closing: false,
+        }
+    }
+}
+
+/// Shared by the two directions of a gated tunnel
+#[derive(Default)]
+pub(crate) struct GateState {
+    /// Method and path of forwarded requests whose responses haven't started, oldest first
+    pub(crate) awaiting: VecDeque<(String, String)>,
+    /// Answer for a refused request, sent once the responses before it are through
+    refusal: Option<Vec<u8>>,
+    /// A forwarded request didn't keep the connection alive, so its response is the last
+    pub(crate) closing: bool,
+    /// The upstream switched protocols, so the client stream is no longer HTTP/1.1
+    upgraded: bool,
+    /// When the upstream must have started the next response, under `first_response_byte_timeout_ms`
+    response_due: Option<tokio::time::Instant>,
+    /// Requests forwarded on this connection so far
+    pub(crate) forwarded: u64,
+    /// The last forwarded request reached `max_requests_per_connection`, so its response gets
+    /// `Connection: close`
+    at_request_limit: bool,
+}
+
+/// Relay requests from the client, checking each head. A refused request and everything
+/// after it stay with the proxy; its answer is left in `shared` for `relay_responses`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn relay_requests<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    checks: &RequestChecks<'_>,
+    mut state: MessageState,
+    mut data: Vec<u8>,
+    shared: &Mutex<GateState>,
+    changed: &Notify,
+    buffer_bytes: usize,
+    activity: &Activity,
+    copied: &AtomicU64,
+    capture: Option<&Capture>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; buffer_bytes];
+    loop {
+        let mut at = 0;
+        loop {
+            let (closing, upgraded) = {
+                let shared = shared.lock().unwrap();
+                (shared.closing, shared.upgraded)
+            };
+            if upgraded && state.at_boundary() {
+                // Everything from here on belongs to the new protocol
+                state = MessageState::Opaque;
+            }
+            if closing && state.at_boundary() {
+                // The last request is through; whatever else the client sends is ignored
+                changed.notify_one();
+                return std::future::pending().await;
+            }
+            if at == data.len() {
+                break;
+            }
+            let mut forwarded = Cow::Borrowed(&[][..]);
+            let mut refusal = None;
+            let used = if matches!(state, MessageState::Head(_)) {
+                let (used, head) = state.take_head(&data[at..]);
+                match head {
+                    Some(head) => {
+                        // Blank lines between requests are tolerated (RFC 9112 §2.2)
+                        let start = head.iter().position(|b| !b"\r\n".contains(b)).unwrap_or(head.len());
+                        let head = &head[start..];
+                        if !head.is_empty() {
+                            let text = String::from_utf8_lossy(head);
+                            refusal = checks.check(&text);
+                            if refusal.is_none() {
+                                let mut shared = shared.lock().unwrap();
+                                let (method, path, _) = request_line(&text);
+                                let start_clock = shared.awaiting.is_empty() && shared.response_due.is_none();
+                                if let Some(limit) = checks.config.first_response_byte_timeout().filter(|_| start_clock) {
+                                    shared.response_due = Some(tokio::time::Instant::now() + limit);
+                                    // The response side picks the deadline up on its next wait
+                                    changed.notify_one();
+                                }
+                                shared.awaiting.push_back((method.to_string(), path.to_string()));
+                                shared.forwarded += 1;
+                                shared.at_request_limit =
+                                    checks.config.max_requests_per_connection.is_some_and(|limit| shared.forwarded >= limit);
+                                shared.closing = !keeps_alive(&text) || shared.at_request_limit;
+                                drop(shared);
+                                // An upgrade only takes over the stream once the response accepts it
+                                state = body_state(&text, false);
+                                forwarded = Cow::Owned(checks.forward(head).into_owned());
+                                if let Some(capture) = capture {
+                                    capture.request(&forwarded);
+                                }
+                            }
+                        }
+                    }
+                    None if matches!(&state, MessageState::Head(head) if head.len() > MAX_REQUEST_HEAD_BYTES) => {
+                        if checks.host_only {
+                            // Nothing to check, so the stream just stops being followed
+                            if let MessageState::Head(head) = std::mem::replace(&mut state, MessageState::Opaque) {
+                                forwarded = Cow::Owned(head);
+                            }
+                        } else {
+                            refusal = Some(checks.refuse_malformed("Request Head Too Large"));
+                        }
+                    }
+                    None => {}
+                }
+                used
+            } else if matches!(state, MessageState::Malformed) {
+                if checks.host_only {
+                    state = MessageState::Opaque;
+                } else {
+                    refusal = Some(checks.refuse_malformed("Malformed Request"));
+                }
+                0
+            } else {
+                let in_body = matches!(state, MessageState::Body(_) | MessageState::Chunked(_));
+                let used = state.skip_body(&data[at..]);
+                forwarded = Cow::Borrowed(&data[at..at + used]);
+                if let Some(capture) = capture.filter(|_| in_body) {
+                    capture.request_body(&forwarded);
+                }
+                used
+            };
+            if let Some(refusal) = refusal {
+                shared.lock().unwrap().refusal = Some(refusal);
+                changed.notify_one();
+                // Nothing more from this client goes upstream; the tunnel closes once the
+                // refusal has been answered
+                return std::future::pending().await;
+            }
+            if !forwarded.is_empty() {
+                writer.write_all(&forwarded).await?;
+                copied.fetch_add(forwarded.len() as u64, Ordering::Relaxed);
+                if let Some(capture) = capture {
+                    capture.record(&forwarded);
+                }
+            }
+            at += used;
+        }
+
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        activity.touch();
+        trace!("Relaying {} request bytes: {:?}", n, String::from_utf8_lossy(&buf[..n.min(256)]));
+        data = buf[..n].to_vec();
+    }
+}
+
+/// A final response's head marked as the connection's last. Interim responses pass unchanged.
+fn last_response_head(head: &str) -> String {
+    if head.split_whitespace().nth(1).is_some_and(|status| status.starts_with('1')) {
+        return head.to_string();
+    }
+    let head = without_headers(head, &["Connection".to_string()]).unwrap_or_else(|| head.to_string());
+    with_extra_headers(&head, &[("Connection", "close")])
+}
+
+/// Relay responses to the client, following them so that a refused request is answered in
+/// its place in the sequence. The trailer section of a chunked response is held back until
+/// it has been checked against `blocked_trailers`. Returns whether a refusal was sent or the
+/// connection was cut off for its trailers.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn relay_responses<R, W>(
+    config: &Config,
+    host: &str,
+    reader: &mut R,
+    writer: &mut W,
+    mut state: MessageState,
+    mut responding: (String, String),
+    shared: &Mutex<GateState>,
+    changed: &Notify,
+    buffer_bytes: usize,
+    activity: &Activity,
+    copied: &AtomicU64,
+    capture: Option<&Capture>,
+) -> Result<bool>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; buffer_bytes];
+    // The trailer section of the response being relayed, while it is checked
+    let mut held = Vec::new();
+    // The head of the last response under `max_requests_per_connection`, until it is complete
+    let mut final_head = Vec::new();
+    loop {
+        let (refusal, done) = {
+            let mut shared = shared.lock().unwrap();
+            if shared.awaiting.is_empty() && state.at_boundary() {
+                (shared.refusal.take(), shared.closing)
+            } else {
+                (None, false)
+            }
+        };
+        if let Some(refusal) = refusal {
+            writer.write_all(&refusal).await?;
+            if let Some(capture) = capture {
+                capture.record(&refusal);
+            }
+            return Ok(true);
+        }
+        if done {
+            // The last response is out; closing is how the client knows
+            return Ok(false);
+        }
+
+        let due = shared.lock().unwrap().response_due;
+        let n = tokio::select! {
+            n = reader.read(&mut buf) => n?,
+            _ = changed.notified() => continue,
+            _ = async {
+                match due {
+                    Some(due) => tokio::time::sleep_until(due).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                if !state.at_boundary() {
+                    // The previous response is still streaming; its end restarts the clock
+                    shared.lock().unwrap().response_due = None;
+                    continue;
+                }
+                let (method, path) = shared.lock().unwrap().awaiting.front().cloned().unwrap_or_default();
+                let reason = "Slow Upstream Response";
+                log_traffic(config, "BLOCK", host, &path, &method, reason, &[]);
+                print_decision(config, format_args!("⛔ [{}] {} {}{} -> {}", config.mode, method, host, path, reason));
+                writer.write_all(gateway_timeout_response().as_bytes()).await?;
+                return Ok(true);
+            }
+        };
+        if n == 0 {
+            // The upstream is done; a refused request still gets its answer
+            let refusal = shared.lock().unwrap().refusal.take();
+            if let Some(refusal) = &refusal {
+                writer.write_all(refusal).await?;
+            }
+            return Ok(refusal.is_some());
+        }
+        activity.touch();
+        trace!("Relaying {} response bytes: {:?}", n, String::from_utf8_lossy(&buf[..n.min(256)]));
+        let mut incoming = Cow::Borrowed(&buf[..n]);
+        let mut at = 0;
+        let mut head_released = false;
+        while at < incoming.len() {
+            let data = &incoming[at..];
+            let hold_head = !head_released && state.at_boundary() && {
+                let shared = shared.lock().unwrap();
+                shared.at_request_limit && shared.awaiting.len() == 1
+            };
+            if hold_head {
+                // The last response's head is held until it has been marked as the last
+                final_head.extend_from_slice(data);
+                let head_end = match final_head.windows(4).position(|w| w == b"\r\n\r\n") {
+                    Some(pos) => pos + 4,
+                    None if final_head.len() > MAX_RESPONSE_HEAD_BYTES => final_head.len(),
+                    None => {
+                        at = incoming.len();
+                        continue;
+                    }
+                };
+                let rest = final_head.split_off(head_end);
+                let head = last_response_head(&String::from_utf8_lossy(&final_head));
+                final_head.clear();
+                incoming = Cow::Owned([head.into_bytes(), rest].concat());
+                at = 0;
+                head_released = true;
+                continue;
+            }
+            head_released = false;
+            if state.in_trailers() {
+                let (used, trailers) = state.take_trailers(data);
+                held.extend_from_slice(&data[..used]);
+                at += used;
+                let Some(trailers) = trailers else {
+                    continue;
+                };
+                let (method, path) = (responding.0.as_str(), responding.1.as_str());
+                let (trailers_allowed, reason) = check_trailers(config, host, &trailers);
+                let trailer_fields: Vec<String> = trailers.iter().map(|(name, value)| format!("{}: {}", name, value)).collect();
+                note_connection(|stats| stats.trailers.extend(trailers));
+                if !trailers_allowed {
+                    let trailer_fields = trailer_fields.join(", ");
+                    log_traffic(config, "BLOCK", host, path, method, &reason, &[("trailers", &trailer_fields)]);
+                    print_decision(config, format_args!("⛔ [{}] {} {}{} ({}) -> {}", config.mode, method, host, path, trailer_fields, reason));
+                    // The body is out already; cutting it off before the trailers fails the response
+                    return Ok(true);
+                }
+                writer.write_all(&held).await?;
+                copied.fetch_add(held.len() as u64, Ordering::Relaxed);
+                if let Some(capture) = capture {
+                    capture.record(&held);
+                }
+                held.clear();
+                continue;
+            }
+            // Followed before it is sent, so the request side knows about an upgrade before the
+            // client can answer it with bytes of the new protocol
+            let used = {
+                let mut shared = shared.lock().unwrap();
+                let (upgraded, used) =
+                    scan_responses(config, host, &mut state, &mut shared.awaiting, data, capture, Some(&mut responding));
+                shared.upgraded |= upgraded;
+                // The next response's clock starts once this one is through
+                shared.response_due = config
+                    .first_response_byte_timeout()
+                    .filter(|_| !shared.awaiting.is_empty() && state.at_boundary())
+                    .map(|limit| tokio::time::Instant::now() + limit);
+                used
+            };
+            writer.write_all(&data[..used]).await?;
+            copied.fetch_add(used as u64, Ordering::Relaxed);
+            if let Some(capture) = capture {
+                capture.record(&data[..used]);
+            }
+            at += used;
+        }
+    }
+}
+
+// ============================================================================
+// Traffic Capture
+// ============================================================================
+
+/// Chunks queued for a capture file before new ones are dropped
+const CAPTURE_QUEUE_CHUNKS: usize = 256;
+
+static NEXT_CAPTURE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Tees decrypted bytes of one connection to `capture_dir/<host>/<conn_id>.http`, and/or
+/// keeps a HAR log of its requests in `<conn_id>.har`.
+///
+/// Writes happen on a separate task fed by a bounded channel; if the disk falls behind,
+/// chunks are dropped rather than slowing down forwarding. At most `capture_max_bytes`
+/// are written per connection.
+pub struct Capture {
+    tx: Option<mpsc::Sender<Bytes>>,
+    remaining: AtomicU64,
+    har: Option<Mutex<Har>>,
+}
+
+impl Capture {
+    /// Start a capture when `capture_dir` is set and the host's rule asks for one, or the
+    /// request was quarantined and `capture_quarantined` is on
+    pub(crate) fn start(config: &Config, rule: Option<&HostRule>, host: &str, quarantined: bool) -> Option<Self> {
+        let capture_dir = config.capture_dir.as_ref()?;
+        let wanted = rule.is_some_and(|r| r.capture) || (quarantined && config.capture_quarantined);
+        let har_wanted = rule.is_some_and(|r| r.capture_har);
+        if !wanted && !har_wanted {
+            return None;
+        }
+
+        let started_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let conn_id = format!("{}-{}", started_ms, NEXT_CAPTURE_ID.fetch_add(1, Ordering::Relaxed));
+        let dir = Path::new(capture_dir).join(host);
+        let har_path = dir.join(format!("{}.har", conn_id));
+        let har = har_wanted.then(|| Mutex::new(Har::new(har_path, host, config.capture_har_body_bytes as usize)));
+        if !wanted {
+            return Some(Self { tx: None, remaining: AtomicU64::new(0), har });
+        }
+        let path = dir.join(format!("{}.http", conn_id));
+
+        let (tx, mut rx) = mpsc::channel::<Bytes>(CAPTURE_QUEUE_CHUNKS);
+        tokio::spawn(async move {
+            let result = async {
+                tokio::fs::create_dir_all(&dir).await?;
+                let mut file = tokio::fs::File::create(&path).await?;
+                while let Some(chunk) = rx.recv().await {
+                    file.write_all(&chunk).await?;
+                }
+                file.flush().await
+            }
+            .await;
+            if let Err(e) = result {
+                error!("Failed to write capture {}: {}", path.display(), e);
+            }
+        });
+
+        Some(Self {
+            tx: Some(tx),
+            remaining: AtomicU64::new(config.capture_max_bytes),
+            har,
+        })
+    }
+
+    /// Queue bytes for the capture file, truncating at the size cap
+    pub(crate) fn record(&self, data: &[u8]) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let mut len = 0;
+        let _ = self.remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+            len = (data.len() as u64).min(remaining);
+            (len > 0).then(|| remaining - len)
+        });
+        if len == 0 {
+            return;
+        }
+        if tx.try_send(Bytes::copy_from_slice(&data[..len as usize])).is_err() {
+            debug!("Capture queue full; dropping {} bytes", len);
+        }
+    }
+
+    /// Note a request as it goes upstream: its head, and any of its body sent along with it
+    pub(crate) fn request(&self, message: &[u8]) {
+        if let Some(har) = &self.har {
+            let head_end = message.windows(4).position(|w| w == b"\r\n\r\n").map_or(message.len(), |pos| pos + 4);
+            let mut har = har.lock().unwrap();
+            har.request(&String::from_utf8_lossy(&message[..head_end]));
+            har.request_body(&message[head_end..]);
+        }
+    }
+
+    /// Note more of the body of the latest request
+    fn request_body(&self, data: &[u8]) {
+        if let Some(har) = &self.har {
+            har.lock().unwrap().request_body(data);
+        }
+    }
+
+    /// Note a final response head; it answers the oldest request still waiting
+    pub(crate) fn response(&self, head: &str) {
+        if let Some(har) = &self.har {
+            har.lock().unwrap().response(head);
+        }
+    }
+
+    /// Note more of the body of the latest response
+    fn response_body(&self, data: &[u8]) {
+        if let Some(har) = &self.har {
+            har.lock().unwrap().response_body(data);
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let Some(har) = self.har.take() else {
+            return;
+        };
+        let har = har.into_inner().unwrap();
+        let path = har.path.clone();
+        let document = har.finish();
+        tokio::spawn(async move {
+            let result = async {
+                if let Some(dir) = path.parent() {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+                tokio::fs::write(&path, serde_json::to_vec_pretty(&document)?).await
+            }
+            .await;
+            if let Err(e) = result {
+                error!("Failed to write HAR capture {}: {}", path.display(), e);
+            }
+        });
+    }
+}
+
+/// A request sent upstream that has not been answered yet
+struct HarRequest {
+    started: String,
+    sent: Instant,
+    request: serde_json::Value,
+    body: Vec<u8>,
+}
+
+/// HAR 1.2 log of one connection. Body sizes are taken from Content-Length (-1 when the
+/// length is not declared up front); up to `body_limit` bytes of each body are kept as sent,
+/// chunk framing and content encoding included.
+struct Har {
+    path: PathBuf,
+    host: String,
+    body_limit: usize,
+    pending: VecDeque<HarRequest>,
+    /// Finished entries, each with the response body kept so far
+    entries: Vec<(serde_json::Value, Vec<u8>)>,
+}
+
+impl Har {
+    fn new(path: PathBuf, host: &str, body_limit: usize) -> Self {
+        Self {
+            path,
+            host: host.to_string(),
+            body_limit,
+            pending: VecDeque::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    fn request_body(&mut self, data: &[u8]) {
+        if let Some(pending) = self.pending.back_mut() {
+            keep_body_prefix(&mut pending.body, data, self.body_limit);
+        }
+    }
+
+    fn response_body(&mut self, data: &[u8]) {
+        if let Some((_, body)) = self.entries.last_mut() {
+            keep_body_prefix(body, data, self.body_limit);
+        }
+    }
+
+    fn request(&mut self, head: &str) {
+        let (method, target, version) = {
+            let mut parts = head.lines().next().unwrap_or("").split_whitespace();
+            (parts.next().unwrap_or(""), parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+        };
+        let query: Vec<serde_json::Value> = target
+            .split_once('?')
+            .map(|(_, query)| query)
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                serde_json::json!({ "name": name, "value": value })
+            })
+            .collect();
+        let body_size = match header_value(head, "content-length") {
+            Some(length) => length.trim().parse::<i64>().unwrap_or(-1),
+            None if header_value(head, "transfer-encoding").is_some() => -1,
+            None => 0,
+        };
+        self.pending.push_back(HarRequest {
+            started: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            sent: Instant::now(),
+            request: serde_json::json!({
+                "method": method,
+                "url": format!("https://{}{}", self.host, target),
+                "httpVersion": version,
+                "cookies": [],
+                "headers": har_headers(head),
+                "queryString": query,
+                "headersSize": har_head_size(head),
+                "bodySize": body_size,
+            }),
+            body: Vec::new(),
+        });
+    }
+
+    fn response(&mut self, head: &str) {
+        let Some(pending) = self.pending.pop_front() else {
+            return;
+        };
+        let wait_ms = pending.sent.elapsed().as_secs_f64() * 1000.0;
+        let (version, status, reason) = {
+            let line = head.lines().next().unwrap_or("");
+            let mut parts = line.splitn(3, ' ');
+            (parts.next().unwrap_or(""), parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+        };
+        let body_size = header_value(head, "content-length")
+            .and_then(|length| length.trim().parse::<i64>().ok())
+            .unwrap_or(-1);
+        let response = serde_json::json!({
+            "status": status.parse::<u16>().unwrap_or(0),
+            "statusText": reason,
+            "httpVersion": version,
+            "cookies": [],
+            "headers": har_headers(head),
+            "content": {
+                "size": body_size,
+                "mimeType": header_value(head, "content-type").unwrap_or(""),
+            },
+            "redirectURL": header_value(head, "location").unwrap_or(""),
+            "headersSize": har_head_size(head),
+            "bodySize": body_size,
+        });
+        self.entries.push((har_entry(pending, response, wait_ms), Vec::new()));
+    }
+
+    /// The finished document. Requests that never got a response go in with status 0,
+    /// the way browsers record aborted ones.
+    fn finish(mut self) -> serde_json::Value {
+        for pending in std::mem::take(&mut self.pending) {
+            let response = serde_json::json!({
+                "status": 0,
+                "statusText": "",
+                "httpVersion": "",
+                "cookies": [],
+                "headers": [],
+                "content": { "size": 0, "mimeType": "" },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+            });
+            self.entries.push((har_entry(pending, response, 0.0), Vec::new()));
+        }
+        let entries: Vec<serde_json::Value> = self
+            .entries
+            .into_iter()
+            .map(|(mut entry, body)| {
+                if !body.is_empty() {
+                    let content = &mut entry["response"]["content"];
+                    let (text, encoding) = har_text(&body);
+                    content["text"] = serde_json::json!(text);
+                    if let Some(encoding) = encoding {
+                        content["encoding"] = serde_json::json!(encoding);
+                    }
+                }
+                entry
+            })
+            .collect();
+        serde_json::json!({
+            "log": {
+                "version": "1.2",
+                "creator": { "name": "secure-proxy", "version": env!("CARGO_PKG_VERSION") },
+                "entries": entries,
+            }
+        })
+    }
+}
+
+fn har_entry(pending: HarRequest, response: serde_json::Value, wait_ms: f64) -> serde_json::Value {
+    let mut request = pending.request;
+    if !pending.body.is_empty() {
+        let mime_type = request["headers"]
+            .as_array()
+            .and_then(|headers| headers.iter().find(|h| h["name"].as_str().is_some_and(|n| n.eq_ignore_ascii_case("content-type"))))
+            .and_then(|h| h["value"].as_str())
+            .unwrap_or("")
+            .to_string();
+        // HAR has no encoding field for request bodies, so binary ones are kept lossily
+        request["postData"] = serde_json::json!({
+            "mimeType": mime_type,
+            "text": String::from_utf8_lossy(&pending.body),
+        });
+    }
+    serde_json::json!({
+        "startedDateTime": pending.started,
+        "time": wait_ms,
+        "request": request,
+        "response": response,
+        "cache": {},
+        "timings": { "send": 0, "wait": wait_ms, "receive": 0 },
+    })
+}
+
+fn har_headers(head: &str) -> Vec<serde_json::Value> {
+    head.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| serde_json::json!({ "name": name.trim(), "value": value.trim() }))
+        .collect()
+}
+
+/// Append to a kept body until it reaches `limit` bytes
+fn keep_body_prefix(body: &mut Vec<u8>, data: &[u8], limit: usize) {
+    let room = limit.saturating_sub(body.len());
+    body.extend_from_slice(&data[..data.len().min(room)]);
+}
+
+/// A response body as HAR `content.text`: as-is when it is UTF-8, else base64
+fn har_text(body: &[u8]) -> (String, Option<&'static str>) {
+    match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (base64::engine::general_purpose::STANDARD.encode(body), Some("base64")),
+    }
+}
+
+/// Bytes in the head up to and including the blank line, or -1 if it was cut short
+fn har_head_size(head: &str) -> i64 {
+    head.find("\r\n\r\n").map_or(-1, |end| (end + 4) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::test_support::*;
+    use crate::config::Config;
+    use crate::metrics::{CONNECTION_STATS, ConnectionStats, UPSTREAM_STATUS_COUNTS};
+    use crate::policy::find_host_rule;
+    use crate::http::{gzip_request_body, header_value};
+    use crate::tunnel::{Activity, tunnel};
+    use crate::intercept::{gateway_timeout_response, host_only_session};
+
+    /// Run `relay_responses` over `upstream` as a connection task, for requests `awaiting`.
+    /// Returns its result, what reached the client and the connection's stats.
+    async fn relay_responses_for(
+        config: &Config,
+        host: &str,
+        awaiting: &[(&str, &str)],
+        upstream: &[u8],
+    ) -> (bool, Vec<u8>, ConnectionStats) {
+        let shared = Mutex::new(GateState {
+            awaiting: awaiting.iter().map(|(method, path)| (method.to_string(), path.to_string())).collect(),
+            ..GateState::default()
+        });
+        let stats = Arc::new(Mutex::new(ConnectionStats::default()));
+        let mut client = Vec::new();
+        let blocked = CONNECTION_STATS
+            .scope(stats.clone(), async {
+                let mut reader = upstream;
+                relay_responses(
+                    config,
+                    host,
+                    &mut reader,
+                    &mut client,
+                    MessageState::Head(Vec::new()),
+                    Default::default(),
+                    &shared,
+                    &Notify::new(),
+                    4096,
+                    &Activity::new(),
+                    &AtomicU64::new(0),
+                    None,
+                )
+                .await
+                .unwrap()
+            })
+            .await;
+        let stats = std::mem::take(&mut *stats.lock().unwrap());
+        (blocked, client, stats)
+    }
+
+    const GRPC_OK: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: grpc-status\r\n\r\n5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n";
+    const GRPC_DENIED: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: grpc-status\r\n\r\n5\r\nhello\r\n0\r\ngrpc-status: 7\r\n\r\n";
+
+    /// A gate for `host` on a connection none of whose requests have been read yet
+    fn fresh_gate<'a>(config: &'a Config, host: &'a str, host_only: bool) -> RequestGate<'a> {
+        let rule = find_host_rule(config, host);
+        RequestGate::from_start(RequestChecks { config, hostname: host, rule, client_id: None, host_only })
+    }
+
+    /// Send `requests` through a host-only tunnel to an upstream that answers with `responses`
+    /// and hangs up. Returns what reached the upstream and what reached the client.
+    async fn host_only_exchange(config: &Config, host: &str, requests: &[u8], responses: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let (mut client, proxy_client) = tokio::io::duplex(4096);
+        let (mut upstream, proxy_upstream) = tokio::io::duplex(4096);
+        let proxy = tunnel(proxy_client, proxy_upstream, config, host, None, None, Some(fresh_gate(config, host, true)));
+        let exchange = async {
+            client.write_all(requests).await.unwrap();
+            let mut forwarded = vec![0u8; requests.len()];
+            upstream.read_exact(&mut forwarded).await.unwrap();
+            upstream.write_all(responses).await.unwrap();
+            upstream.shutdown().await.unwrap();
+            let mut answered = vec![0u8; responses.len()];
+            client.read_exact(&mut answered).await.unwrap();
+            (forwarded, answered)
+        };
+        let (result, exchanged) =
+            tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(proxy, exchange) }).await.unwrap();
+        result.unwrap();
+        exchanged
+    }
+
+    #[tokio::test]
+    async fn chunked_trailers_are_logged_and_forwarded() {
+        let config = grpc_config();
+        let (blocked, client, stats) =
+            relay_responses_for(&config, "grpc.example.com", &[("POST", "/svc/Call")], GRPC_OK).await;
+        assert!(!blocked);
+        assert_eq!(client, GRPC_OK);
+        assert_eq!(stats.trailers, vec![("grpc-status".to_string(), "0".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn blocked_trailers_are_checked_on_later_keep_alive_responses() {
+        let config = grpc_config();
+        let upstream = [GRPC_OK, GRPC_DENIED].concat();
+        let (blocked, client, stats) =
+            relay_responses_for(&config, "grpc.example.com", &[("POST", "/svc/A"), ("POST", "/svc/B")], &upstream).await;
+        assert!(blocked);
+        let cut = GRPC_DENIED.len() - b"grpc-status: 7\r\n\r\n".len();
+        assert_eq!(client, [GRPC_OK, &GRPC_DENIED[..cut]].concat());
+        assert_eq!(
+            stats.trailers,
+            vec![("grpc-status".to_string(), "0".to_string()), ("grpc-status".to_string(), "7".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_kept_alive_response_gets_a_gateway_timeout() {
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "first_response_byte_timeout_ms": 50,
+            "allowed_rules": [{ "host": "slow.example.com" }]
+        }));
+        assert!(!host_only_session(&config, "slow.example.com"));
+        let (mut client, proxy_client) = tokio::io::duplex(4096);
+        let (mut upstream, proxy_upstream) = tokio::io::duplex(4096);
+        let proxy = tunnel(proxy_client, proxy_upstream, &config, "slow.example.com", None, None, Some(fresh_gate(&config, "slow.example.com", false)));
+        let exchange = async {
+            client.write_all(b"GET /next HTTP/1.1\r\nHost: slow.example.com\r\n\r\n").await.unwrap();
+            let mut forwarded = [0u8; 64];
+            let n = upstream.read(&mut forwarded).await.unwrap();
+            assert!(forwarded[..n].starts_with(b"GET /next "));
+            let mut answer = Vec::new();
+            client.read_to_end(&mut answer).await.unwrap();
+            answer
+        };
+        let (result, answer) = tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(proxy, exchange) })
+            .await
+            .unwrap();
+        result.unwrap();
+        assert_eq!(answer, gateway_timeout_response().as_bytes());
+    }
+
+    #[tokio::test]
+    async fn host_only_sessions_log_each_response() {
+        let host = "fastpath.example.com";
+        let config = config_from(serde_json::json!({ "mode": "enforce", "allowed_rules": [{ "host": host }] }));
+        assert!(host_only_session(&config, host));
+        let requests: &[u8] = b"GET /one HTTP/1.1\r\nHost: fastpath.example.com\r\n\r\nHEAD /two HTTP/1.1\r\nHost: fastpath.example.com\r\n\r\n";
+        let responses: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhiHTTP/1.1 503 Service Unavailable\r\nContent-Length: 5\r\n\r\n";
+
+        let (forwarded, answered) = host_only_exchange(&config, host, requests, responses).await;
+        assert_eq!(forwarded, requests);
+        assert_eq!(answered, responses);
+
+        let logged: Vec<_> = logged_entries(host)
+            .iter()
+            .map(|entry| (entry["action"].clone(), entry["method"].clone(), entry["path"].clone(), entry["status"].clone()))
+            .collect();
+        assert_eq!(
+            logged,
+            vec![
+                ("RESPONSE".into(), "GET".into(), "/one".into(), 200.into()),
+                ("RESPONSE".into(), "HEAD".into(), "/two".into(), 503.into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn host_only_sessions_count_upstream_statuses() {
+        let host = "counted.example.com";
+        let config = config_from(serde_json::json!({ "mode": "enforce", "allowed_rules": [{ "host": host }] }));
+        assert!(host_only_session(&config, host));
+        let count = |class: &str| UPSTREAM_STATUS_COUNTS.lock().unwrap().get(class).copied().unwrap_or(0);
+        let (ok_before, error_before) = (count("2xx"), count("5xx"));
+
+        let requests: &[u8] = b"GET /ok HTTP/1.1\r\nHost: counted.example.com\r\n\r\nGET /fail HTTP/1.1\r\nHost: counted.example.com\r\n\r\n";
+        let responses: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\nHTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n";
+        host_only_exchange(&config, host, requests, responses).await;
+
+        // Other tests count responses too, so only a lower bound holds
+        assert!(count("2xx") > ok_before);
+        assert!(count("5xx") > error_before);
+    }
+
+    #[tokio::test]
+    async fn connection_closes_at_max_requests_per_connection() {
+        let host = "limited.example.com";
+        let config = config_from(serde_json::json!({
+            "mode": "enforce",
+            "max_requests_per_connection": 2,
+            "allowed_rules": [{ "host": host, "allowed_paths": ["/"] }]
+        }));
+        let request = |path: &str| format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host).into_bytes();
+        let response: &[u8] = b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\nok";
+
+        let (mut client, proxy_client) = tokio::io::duplex(4096);
+        let (mut upstream, proxy_upstream) = tokio::io::duplex(4096);
+        let proxy = tunnel(proxy_client, proxy_upstream, &config, host, None, None, Some(fresh_gate(&config, host, false)));
+        let exchange = async {
+            client.write_all(&[request("/1"), request("/2"), request("/3")].concat()).await.unwrap();
+            let mut forwarded = vec![0u8; 2 * request("/1").len()];
+            upstream.read_exact(&mut forwarded).await.unwrap();
+            upstream.write_all(&[response, response].concat()).await.unwrap();
+            let mut answered = Vec::new();
+            client.read_to_end(&mut answered).await.unwrap();
+            let mut after_limit = Vec::new();
+            upstream.read_to_end(&mut after_limit).await.unwrap();
+            (forwarded, answered, after_limit)
+        };
+        let stats = Arc::new(Mutex::new(ConnectionStats::default()));
+        let (result, (forwarded, answered, after_limit)) = CONNECTION_STATS
+            .scope(stats.clone(), async {
+                tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(proxy, exchange) }).await.unwrap()
+            })
+            .await;
+        result.unwrap();
+
+        assert_eq!(forwarded, [request("/1"), request("/2")].concat());
+        assert!(after_limit.is_empty());
+        let last = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        assert_eq!(answered, [response, last].concat());
+        assert_eq!(stats.lock().unwrap().requests, 2);
+    }
+
+    #[tokio::test]
+    async fn request_body_is_gzipped_with_updated_headers() {
+        let body = "compress me ".repeat(100);
+        let head = format!("POST /upload HTTP/1.1\r\nHost: gzip.example.com\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n", body.len());
+        let (first, rest) = body.as_bytes().split_at(100);
+        let mut request = head.into_bytes();
+        request.extend_from_slice(first);
+        let mut client = [rest, b"GET /next HTTP/1.1\r\n\r\n"].concat();
+
+        let forwarded = gzip_request_body(&mut &client[..], request, Some(Duration::from_secs(5))).await.unwrap();
+        let head_end = forwarded.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&forwarded[..head_end]).into_owned();
+        let compressed_len: usize = header_value(&head, "content-length").unwrap().trim().parse().unwrap();
+        assert_eq!(header_value(&head, "content-encoding"), Some("gzip"));
+        assert!(compressed_len < body.len());
+
+        let compressed = &forwarded[head_end..head_end + compressed_len];
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(compressed), &mut decoded).unwrap();
+        assert_eq!(decoded, body);
+        assert_eq!(&forwarded[head_end + compressed_len..], b"GET /next HTTP/1.1\r\n\r\n");
+
+        // Bodies that aren't compressible text are left alone
+        client = b"\x00\x01".to_vec();
+        let binary = b"POST / HTTP/1.1\r\nContent-Type: image/png\r\nContent-Length: 2\r\n\r\n".to_vec();
+        assert_eq!(gzip_request_body(&mut &client[..], binary.clone(), None).await.unwrap(), binary);
+    }
+
+    #[tokio::test]
+    async fn stalled_request_body_times_out_before_compression() {
+        let (mut client, _stalled) = tokio::io::duplex(64);
+        let request = b"POST / HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 1000\r\n\r\nstart".to_vec();
+        let err = gzip_request_body(&mut client, request, Some(Duration::from_millis(50))).await.unwrap_err();
+        assert!(err.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut));
+    }
+}
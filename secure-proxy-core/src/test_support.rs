@@ -0,0 +1,52 @@
+//! Helpers shared by the unit tests
+
+use std::fs;
+
+use crate::config::{Config, PATHS, Paths, from_config_value, parse_cidrs, rules_snapshot};
+
+/// Paths under the temp dir, so tests never touch a real traffic log or CA
+pub(crate) fn test_paths() -> &'static Paths {
+    PATHS.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("secure-proxy-tests-{}", std::process::id()));
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        Paths {
+            config: path("rules.json"),
+            ca_cert: path("ca.pem"),
+            ca_key: path("ca.private.key"),
+            log: path("traffic.jsonl"),
+        }
+    })
+}
+
+/// Entries the tests have logged for `host`. Tests share one log, so each uses its own hosts.
+pub(crate) fn logged_entries(host: &str) -> Vec<serde_json::Value> {
+    fs::read_to_string(&test_paths().log)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|entry| entry["host"] == host)
+        .collect()
+}
+
+/// Config built from inline JSON the way `load_config` builds it from a file
+pub(crate) fn config_from(value: serde_json::Value) -> Config {
+    test_paths();
+    let mut config: Config = from_config_value("test", value.clone()).unwrap();
+    config.loaded_rules = rules_snapshot(&value);
+    config.allowed_networks = parse_cidrs(&config.allowed_cidrs).unwrap();
+    config
+}
+
+pub(crate) fn grpc_config() -> Config {
+    config_from(serde_json::json!({
+        "mode": "enforce",
+        "allowed_rules": [{ "host": "grpc.example.com", "blocked_trailers": { "grpc-status": ["7"] } }]
+    }))
+}
+
+/// Write `value` to a fresh file under the temp dir and return its path
+pub(crate) fn temp_config_file(name: &str, value: serde_json::Value) -> String {
+    let path = std::env::temp_dir().join(format!("secure-proxy-{}-{}.json", name, std::process::id()));
+    fs::write(&path, value.to_string()).unwrap();
+    path.to_string_lossy().into_owned()
+}
@@ -0,0 +1,516 @@
+//! ClientHello parsing and upstream certificate verification
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, OtherError, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::{fmt, sync::Arc, time::Duration};
+use tokio::net::TcpStream;
+use tracing::debug;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+// ============================================================================
+// Upstream Certificate Verification
+// ============================================================================
+
+/// Error raised by `PinnedCertVerifier` when the upstream leaf matches no configured pin
+#[derive(Debug)]
+struct PinMismatch;
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upstream Pin Mismatch")
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+/// Normal webpki chain verification, plus a check that the leaf certificate (or its SPKI)
+/// has one of the pinned SHA-256 fingerprints
+#[derive(Debug)]
+pub(crate) struct PinnedCertVerifier {
+    pub(crate) inner: Arc<WebPkiServerVerifier>,
+    pub(crate) pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        let cert_fingerprint = hex_sha256(end_entity);
+        let spki_fingerprint = X509Certificate::from_der(end_entity)
+            .ok()
+            .map(|(_, cert)| hex_sha256(cert.tbs_certificate.subject_pki.raw));
+
+        let pinned = self.pins.iter().any(|pin| {
+            *pin == cert_fingerprint || spki_fingerprint.as_ref() == Some(pin)
+        });
+        if !pinned {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+                OtherError(Arc::new(PinMismatch)),
+            )));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Accepts whatever certificate the upstream presents, for `insecure_upstreams`. The
+/// handshake signatures are still checked, so the upstream must hold the certificate's key.
+#[derive(Debug)]
+pub(crate) struct InsecureCertVerifier {
+    pub(crate) algorithms: rustls::crypto::WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for InsecureCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Lowercase hex SHA-256 of some bytes
+pub(crate) fn hex_sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Accept fingerprints in any case, with or without `:` separators
+pub(crate) fn normalize_fingerprint(pin: &str) -> String {
+    pin.chars().filter(|c| *c != ':').collect::<String>().to_ascii_lowercase()
+}
+
+/// Check whether an upstream handshake failed because of a pin mismatch
+pub(crate) fn is_pin_mismatch(error: &std::io::Error) -> bool {
+    let Some(rustls::Error::InvalidCertificate(CertificateError::Other(other))) =
+        error.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>())
+    else {
+        return false;
+    };
+    other.0.downcast_ref::<PinMismatch>().is_some()
+}
+
+/// Short description of why the upstream's certificate failed verification, if that's why the
+/// handshake failed (pin mismatches have their own handling)
+pub(crate) fn upstream_cert_problem(error: &std::io::Error) -> Option<String> {
+    let Some(rustls::Error::InvalidCertificate(cert_error)) =
+        error.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>())
+    else {
+        return None;
+    };
+    let problem = match cert_error {
+        CertificateError::Expired | CertificateError::ExpiredContext { .. } => "expired".to_string(),
+        CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. } => "not yet valid".to_string(),
+        CertificateError::UnknownIssuer => "unknown issuer".to_string(),
+        CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. } => "not valid for name".to_string(),
+        CertificateError::Revoked => "revoked".to_string(),
+        CertificateError::BadSignature => "bad signature".to_string(),
+        CertificateError::Other(other) if other.0.downcast_ref::<PinMismatch>().is_some() => return None,
+        other => format!("{:?}", other).to_lowercase(),
+    };
+    Some(problem)
+}
+
+// ============================================================================
+// SNI Parsing
+// ============================================================================
+
+/// The parts of a TLS ClientHello the proxy routes on or reports with `debug_clienthello`
+#[derive(Debug, Default)]
+struct ClientHello {
+    /// `legacy_version` from the hello body (TLS 1.3 clients still send 0x0303)
+    version: u16,
+    /// Versions offered in the `supported_versions` extension, if any
+    supported_versions: Vec<u16>,
+    sni: Option<String>,
+    alpn: Vec<String>,
+    cipher_suites: usize,
+    extension_types: Vec<u16>,
+}
+
+/// Log a peeked ClientHello under the `clienthello` target, for `debug_clienthello`
+pub fn log_client_hello(buf: &[u8]) {
+    match parse_client_hello(buf) {
+        Some(hello) => debug!(
+            target: "clienthello",
+            "ClientHello version={:#06x} supported_versions={:04x?} sni={:?} alpn={:?} cipher_suites={} extensions={:?}",
+            hello.version,
+            hello.supported_versions,
+            hello.sni,
+            hello.alpn,
+            hello.cipher_suites,
+            hello.extension_types
+        ),
+        None => debug!(target: "clienthello", "Unparseable ClientHello ({} bytes peeked)", buf.len()),
+    }
+}
+
+pub fn parse_sni(buf: &[u8]) -> Option<String> {
+    parse_client_hello(buf)?.sni
+}
+
+/// Request methods recognised when a plaintext HTTP client reaches a TLS listener
+const HTTP_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+/// Whether the first bytes of a connection are an HTTP request line rather than a TLS record
+pub fn is_plaintext_http(buf: &[u8]) -> bool {
+    HTTP_METHODS
+        .iter()
+        .any(|method| buf.strip_prefix(method.as_bytes()).is_some_and(|rest| rest.first() == Some(&b' ')))
+}
+
+/// Most a ClientHello is peeked for: a record header plus the largest record TLS allows
+const MAX_CLIENT_HELLO_BYTES: usize = 5 + 16 * 1024;
+
+/// Pause between peeks while the rest of a fragmented ClientHello is still in flight
+const CLIENT_HELLO_PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Longest a client may take to finish sending its first TLS record
+const CLIENT_HELLO_PEEK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many bytes `buf` must hold before its first TLS record is complete. Anything that
+/// isn't a handshake record is as complete as it will get.
+fn client_hello_wanted(buf: &[u8]) -> usize {
+    match buf {
+        [] => 5,
+        [0x16, ..] if buf.len() < 5 => 5,
+        [0x16, _, _, hi, lo, ..] => (5 + ((*hi as usize) << 8 | *lo as usize)).min(MAX_CLIENT_HELLO_BYTES),
+        _ => buf.len(),
+    }
+}
+
+/// Peek until the first TLS record is fully buffered, so a ClientHello split across TCP
+/// segments still parses. The bytes stay in the socket for whoever reads it next. A client
+/// that stalls mid-record fails with `TimedOut` after `CLIENT_HELLO_PEEK_TIMEOUT`.
+pub async fn peek_client_hello(client: &TcpStream) -> std::io::Result<Vec<u8>> {
+    peek_client_hello_within(client, CLIENT_HELLO_PEEK_TIMEOUT).await
+}
+
+async fn peek_client_hello_within(client: &TcpStream, wait: Duration) -> std::io::Result<Vec<u8>> {
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut buf = vec![0u8; MAX_CLIENT_HELLO_BYTES];
+    loop {
+        let n = tokio::time::timeout_at(deadline, client.peek(&mut buf))
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "ClientHello not received in time"))??;
+        if n == 0 || n >= client_hello_wanted(&buf[..n]) {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+        if tokio::time::Instant::now() + CLIENT_HELLO_PEEK_INTERVAL > deadline {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "ClientHello incomplete"));
+        }
+        tokio::time::sleep(CLIENT_HELLO_PEEK_INTERVAL).await;
+    }
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(((*buf.get(pos)? as u16) << 8) | (*buf.get(pos + 1)? as u16))
+}
+
+fn parse_client_hello(buf: &[u8]) -> Option<ClientHello> {
+    // TLS record: ContentType(1) + Version(2) + Length(2) + Handshake
+    if buf.len() < 5 || buf[0] != 0x16 {
+        return None; // Not a TLS handshake
+    }
+
+    let record_len = ((buf[3] as usize) << 8) | (buf[4] as usize);
+    if buf.len() < 5 + record_len {
+        return None;
+    }
+
+    let handshake = &buf[5..];
+    if handshake.is_empty() || handshake[0] != 0x01 {
+        return None; // Not ClientHello
+    }
+
+    // Skip handshake header (1 + 3 bytes length)
+    if handshake.len() < 4 {
+        return None;
+    }
+    let hello_len = ((handshake[1] as usize) << 16)
+        | ((handshake[2] as usize) << 8)
+        | (handshake[3] as usize);
+    
+    if handshake.len() < 4 + hello_len {
+        return None;
+    }
+
+    let hello = &handshake[4..];
+    
+    // Client version (2) + random (32) = 34 bytes
+    if hello.len() < 34 {
+        return None;
+    }
+    let mut client_hello = ClientHello {
+        version: read_u16(hello, 0)?,
+        ..Default::default()
+    };
+    let mut pos = 34;
+
+    // Skip session ID
+    if pos >= hello.len() {
+        return None;
+    }
+    let session_len = hello[pos] as usize;
+    pos += 1 + session_len;
+
+    // Cipher suites (2 bytes each)
+    let cipher_len = read_u16(hello, pos)? as usize;
+    client_hello.cipher_suites = cipher_len / 2;
+    pos += 2 + cipher_len;
+
+    // Skip compression methods
+    if pos >= hello.len() {
+        return None;
+    }
+    let comp_len = hello[pos] as usize;
+    pos += 1 + comp_len;
+
+    // Extensions
+    let ext_len = read_u16(hello, pos)? as usize;
+    pos += 2;
+
+    let ext_end = pos + ext_len;
+    while pos + 4 <= ext_end && pos + 4 <= hello.len() {
+        let ext_type = read_u16(hello, pos)?;
+        let ext_data_len = read_u16(hello, pos + 2)? as usize;
+        pos += 4;
+        if pos + ext_data_len > hello.len() {
+            break;
+        }
+        let ext_data = &hello[pos..pos + ext_data_len];
+        client_hello.extension_types.push(ext_type);
+
+        match ext_type {
+            0 if ext_data.len() >= 5 => {
+                // SNI list length (2) + type (1) + name length (2) + name
+                let name_len = ((ext_data[3] as usize) << 8) | (ext_data[4] as usize);
+                if let Some(name) = ext_data.get(5..5 + name_len) {
+                    client_hello.sni = String::from_utf8(name.to_vec()).ok();
+                }
+            }
+            16 => {
+                // ALPN list length (2) + repeated (length (1) + protocol)
+                let mut p = 2;
+                while p < ext_data.len() {
+                    let len = ext_data[p] as usize;
+                    let Some(protocol) = ext_data.get(p + 1..p + 1 + len) else {
+                        break;
+                    };
+                    client_hello.alpn.push(String::from_utf8_lossy(protocol).into_owned());
+                    p += 1 + len;
+                }
+            }
+            43 => {
+                // supported_versions: list length (1) + 2 bytes per version
+                client_hello.supported_versions = ext_data
+                    .get(1..)
+                    .unwrap_or_default()
+                    .chunks_exact(2)
+                    .map(|v| ((v[0] as u16) << 8) | (v[1] as u16))
+                    .collect();
+            }
+            _ => {}
+        }
+        pos += ext_data_len;
+    }
+    Some(client_hello)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use crate::test_support::*;
+    use crate::ca::CaAuthority;
+
+    /// A TLS 1.3-style ClientHello record naming `sni` and offering `alpn`
+    fn client_hello_record(sni: &str, alpn: &[&str]) -> Vec<u8> {
+        let with_len16 = |body: Vec<u8>| [(body.len() as u16).to_be_bytes().to_vec(), body].concat();
+        let mut server_name = vec![0u8];
+        server_name.extend(with_len16(sni.as_bytes().to_vec()));
+        let protocols: Vec<u8> = alpn.iter().flat_map(|p| [vec![p.len() as u8], p.as_bytes().to_vec()].concat()).collect();
+        let extensions = [
+            [0u16.to_be_bytes().to_vec(), with_len16(with_len16(server_name))].concat(),
+            [16u16.to_be_bytes().to_vec(), with_len16(with_len16(protocols))].concat(),
+            [43u16.to_be_bytes().to_vec(), with_len16(vec![2, 0x03, 0x04])].concat(),
+        ]
+        .concat();
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0u8; 32]);
+        hello.push(0); // session id
+        hello.extend(with_len16(vec![0x13, 0x01]));
+        hello.extend([1, 0]); // compression: null
+        hello.extend(with_len16(extensions));
+
+        let mut handshake = vec![0x01];
+        handshake.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+        [vec![0x16, 0x03, 0x01], with_len16(handshake)].concat()
+    }
+
+    /// A connected client/server socket pair on loopback
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn parse_client_hello_reads_sni_alpn_and_versions() {
+        let hello = parse_client_hello(&client_hello_record("hello.example.com", &["h2", "http/1.1"])).unwrap();
+        assert_eq!(hello.version, 0x0303);
+        assert_eq!(hello.sni.as_deref(), Some("hello.example.com"));
+        assert_eq!(hello.alpn, ["h2", "http/1.1"]);
+        assert_eq!(hello.supported_versions, [0x0304]);
+        assert_eq!(hello.cipher_suites, 1);
+        assert_eq!(hello.extension_types, [0, 16, 43]);
+    }
+
+    #[test]
+    fn parse_client_hello_rejects_partial_and_non_hello_records() {
+        let record = client_hello_record("partial.example.com", &[]);
+        assert!(parse_client_hello(&record[..record.len() - 1]).is_none());
+        assert!(parse_client_hello(&record[..4]).is_none());
+
+        let mut application_data = record.clone();
+        application_data[0] = 0x17;
+        assert!(parse_client_hello(&application_data).is_none());
+
+        let mut server_hello = record.clone();
+        server_hello[5] = 0x02;
+        assert!(parse_client_hello(&server_hello).is_none());
+        assert!(parse_sni(b"GET / HTTP/1.1\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn client_hello_wanted_covers_the_first_record() {
+        let record = client_hello_record("wanted.example.com", &[]);
+        assert_eq!(client_hello_wanted(&[]), 5);
+        assert_eq!(client_hello_wanted(&record[..3]), 5);
+        assert_eq!(client_hello_wanted(&record[..10]), record.len());
+        assert_eq!(client_hello_wanted(&[0x16, 0x03, 0x01, 0xff, 0xff]), MAX_CLIENT_HELLO_BYTES);
+        assert_eq!(client_hello_wanted(b"GET / HTTP/1.1"), 14);
+    }
+
+    #[tokio::test]
+    async fn peek_client_hello_waits_for_the_rest_of_the_record() {
+        let record = client_hello_record("split.example.com", &["h2"]);
+        let (mut client, server) = tcp_pair().await;
+        let (first, rest) = record.split_at(20);
+        client.write_all(first).await.unwrap();
+        let peek = tokio::spawn(async move { (peek_client_hello(&server).await.unwrap(), server) });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(rest).await.unwrap();
+
+        let (peeked, _server) = peek.await.unwrap();
+        assert_eq!(peeked, record);
+        assert_eq!(parse_sni(&peeked).as_deref(), Some("split.example.com"));
+    }
+
+    #[tokio::test]
+    async fn peek_client_hello_gives_up_on_a_stalled_record() {
+        let record = client_hello_record("stall.example.com", &[]);
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&record[..5]).await.unwrap();
+        let err = peek_client_hello_within(&server, Duration::from_millis(100)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn pinned_upstreams_need_a_matching_certificate_or_key_hash() {
+        let config = config_from(serde_json::json!({}));
+        let ca = CaAuthority::in_memory(&config).unwrap();
+        let (chain, _) = ca.generate_cert_for_host("pinned.example.com").unwrap();
+        let leaf = &chain[0];
+        let (_, parsed) = X509Certificate::from_der(leaf).unwrap();
+        let spki_pin = hex_sha256(parsed.tbs_certificate.subject_pki.raw);
+        let cert_pin = hex_sha256(leaf)
+            .to_ascii_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| String::from_utf8_lossy(pair).into_owned())
+            .collect::<Vec<_>>()
+            .join(":");
+
+        let verify = |pins: &[&str]| {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(ca.ca_der.clone()).unwrap();
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let verifier = PinnedCertVerifier {
+                inner: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build().unwrap(),
+                pins: pins.iter().map(|pin| normalize_fingerprint(pin)).collect(),
+            };
+            let name = ServerName::try_from("pinned.example.com").unwrap();
+            verifier.verify_server_cert(leaf, &[], &name, &[], UnixTime::now()).map(|_| ())
+        };
+
+        verify(&[&cert_pin]).unwrap();
+        verify(&["00", &spki_pin]).unwrap();
+
+        let mismatch = verify(&[&"ab".repeat(32)]).unwrap_err();
+        assert!(is_pin_mismatch(&std::io::Error::new(std::io::ErrorKind::InvalidData, mismatch)));
+    }
+}
@@ -0,0 +1,547 @@
+//! Byte relaying, upstream dialing and idle tunnels
+
+use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tracing::{debug, info, trace};
+
+use crate::config::Config;
+use crate::logging::{log_traffic, log_transfer_alert, print_decision};
+use crate::metrics::{note_connection, note_rollup, record_tunnel_bytes};
+use crate::policy::find_host_rule;
+use crate::http::header_value;
+use crate::session::{Capture, GateState, RequestGate, relay_requests, relay_responses};
+
+/// Check whether a request asks to upgrade the connection to WebSocket
+pub(crate) fn is_websocket_upgrade(request: &str) -> bool {
+    header_value(request, "upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Check whether a response head accepts an upgrade: `101 Switching Protocols` naming the
+/// new protocol in `Upgrade`
+pub(crate) fn switches_protocols(head: &str) -> bool {
+    let status_line = head.lines().next().unwrap_or("");
+    status_line.split_whitespace().nth(1) == Some("101") && header_value(head, "upgrade").is_some()
+}
+
+/// Tracks when a tunnel last moved bytes in either direction
+pub(crate) struct Activity {
+    started: Instant,
+    last_ms: AtomicU64,
+    /// Signalled by the idle reaper to close the tunnel
+    reaped: Notify,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_ms: AtomicU64::new(0),
+            reaped: Notify::new(),
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last_ms.store(now, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+}
+
+/// A tunnel counts as idle for `max_idle_connections` once it has been quiet this long
+const IDLE_REAP_THRESHOLD: Duration = Duration::from_secs(5);
+const IDLE_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Open tunnels by id, so the idle reaper can find the quietest ones
+static OPEN_TUNNELS: Mutex<BTreeMap<u64, (String, Arc<Activity>)>> = Mutex::new(BTreeMap::new());
+static NEXT_TUNNEL_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps a tunnel in `OPEN_TUNNELS` until it is dropped
+struct TunnelRegistration(u64);
+
+impl TunnelRegistration {
+    pub fn new(host: &str, activity: &Arc<Activity>) -> Self {
+        let id = NEXT_TUNNEL_ID.fetch_add(1, Ordering::Relaxed);
+        OPEN_TUNNELS.lock().unwrap().insert(id, (host.to_string(), activity.clone()));
+        Self(id)
+    }
+}
+
+impl Drop for TunnelRegistration {
+    fn drop(&mut self) {
+        OPEN_TUNNELS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Periodically close the longest-idle tunnels while more than `max_idle` are idle
+pub async fn reap_idle_tunnels(config: Arc<Config>, max_idle: usize) {
+    let mut interval = tokio::time::interval(IDLE_REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for (idle_for, host, activity) in idle_tunnels_over(max_idle) {
+            activity.reaped.notify_one();
+            log_traffic(&config, "CLOSE", &host, "/", "CONNECT", "Idle Reaped", &[]);
+            print_decision(&config, format_args!("💤 [{}] {} -> Idle Reaped after {:?}", config.mode, host, idle_for));
+        }
+    }
+}
+
+/// The idle tunnels beyond the first `max_idle`, longest idle first
+fn idle_tunnels_over(max_idle: usize) -> Vec<(Duration, String, Arc<Activity>)> {
+    let mut idle: Vec<(Duration, String, Arc<Activity>)> = OPEN_TUNNELS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|(host, activity)| (activity.idle_for(), host.clone(), activity.clone()))
+        .filter(|(idle_for, _, _)| *idle_for >= IDLE_REAP_THRESHOLD)
+        .collect();
+    if idle.len() <= max_idle {
+        return Vec::new();
+    }
+
+    idle.sort_by_key(|(idle_for, _, _)| std::cmp::Reverse(*idle_for));
+    let excess = idle.len() - max_idle;
+    idle.truncate(excess);
+    idle
+}
+
+/// Copy one direction through a fixed buffer. Each chunk is fully written before the next
+/// read, so a slow reader on the far side holds back the producer instead of growing memory.
+/// Bytes copied are added to `copied` as they go, so the count survives cancellation.
+async fn copy_with_buffer<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buffer_bytes: usize,
+    activity: &Activity,
+    copied: &AtomicU64,
+    capture: Option<&Capture>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; buffer_bytes];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        activity.touch();
+        trace!("Relaying {} bytes: {:?}", n, String::from_utf8_lossy(&buf[..n.min(256)]));
+        writer.write_all(&buf[..n]).await?;
+        copied.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(capture) = capture {
+            capture.record(&buf[..n]);
+        }
+    }
+}
+
+/// Bytes a tunnel may still move under `max_connection_bytes`, shared by both directions
+struct ByteBudget {
+    remaining: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl ByteBudget {
+    fn new(limit: u64) -> Self {
+        Self {
+            remaining: AtomicU64::new(limit),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    /// Set aside up to `wanted` bytes for a read; 0 once the budget is spent
+    fn reserve(&self, wanted: u64) -> u64 {
+        let mut reserved = 0;
+        let _ = self.remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+            reserved = wanted.min(remaining);
+            Some(remaining - reserved)
+        });
+        reserved
+    }
+
+    fn give_back(&self, unused: u64) {
+        self.remaining.fetch_add(unused, Ordering::Relaxed);
+    }
+}
+
+/// Token bucket pacing one direction of a tunnel under `max_bytes_per_sec`. It holds at
+/// most a tenth of a second's worth, and once empty waits for 10ms worth to build up, so
+/// data flows in small steady steps rather than one-second bursts.
+struct Throttle {
+    rate: f64,
+    tokens: f64,
+    capacity: f64,
+    refilled: Instant,
+    wait: Option<std::pin::Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        let capacity = (rate / 10.0).max(1.0);
+        Self {
+            rate,
+            tokens: capacity,
+            capacity,
+            refilled: Instant::now(),
+            wait: None,
+        }
+    }
+
+    /// Bytes the next read may return, or `Pending` with a wakeup set for when there are some
+    fn poll_allowance(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<usize> {
+        loop {
+            if let Some(wait) = &mut self.wait {
+                std::task::ready!(std::future::Future::poll(wait.as_mut(), cx));
+                self.wait = None;
+            }
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+            self.refilled = now;
+            if self.tokens >= 1.0 {
+                return std::task::Poll::Ready(self.tokens as usize);
+            }
+            let step = (self.rate / 100.0).clamp(1.0, self.capacity);
+            let delay = Duration::from_secs_f64((step - self.tokens) / self.rate);
+            self.wait = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+    }
+}
+
+/// Reader for one direction of a tunnel that counts what it hands out against a
+/// `ByteBudget`, and paces it with a `Throttle`. Reads are cut short at the ceiling, and the
+/// read after that fails.
+struct LimitedRead<'a, R> {
+    inner: R,
+    budget: Option<&'a ByteBudget>,
+    throttle: Option<Throttle>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedRead<'_, R> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.budget.is_none() && this.throttle.is_none() {
+            return std::pin::Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        if buf.remaining() == 0 {
+            return std::task::Poll::Ready(Ok(()));
+        }
+        let mut wanted = buf.remaining() as u64;
+        if let Some(throttle) = &mut this.throttle {
+            wanted = wanted.min(std::task::ready!(throttle.poll_allowance(cx)) as u64);
+        }
+        let reserved = match this.budget {
+            Some(budget) => budget.reserve(wanted),
+            None => wanted,
+        };
+        if reserved == 0 {
+            if let Some(budget) = this.budget {
+                budget.exceeded.store(true, Ordering::Relaxed);
+            }
+            return std::task::Poll::Ready(Err(std::io::Error::other("max_connection_bytes exceeded")));
+        }
+        let mut limited = tokio::io::ReadBuf::new(buf.initialize_unfilled_to(reserved as usize));
+        let result = std::pin::Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let n = limited.filled().len();
+        if let Some(budget) = this.budget {
+            budget.give_back(reserved - n as u64);
+        }
+        if let Some(throttle) = &mut this.throttle {
+            throttle.tokens -= n as f64;
+        }
+        buf.advance(n);
+        result
+    }
+}
+
+/// How long to wait for a peer to accept our shutdown (TLS `close_notify`, TCP FIN)
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shut down a write side so TLS peers get a `close_notify` instead of a truncated stream.
+/// Errors are ignored: the peer may already have gone away.
+pub(crate) async fn close_gracefully<W: AsyncWrite + Unpin>(writer: &mut W) {
+    let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, writer.shutdown()).await;
+}
+
+/// Run an I/O step under an optional deadline, failing with `TimedOut` once it passes
+pub async fn with_deadline<T>(
+    limit: Option<Duration>,
+    step: impl std::future::Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, step).await.unwrap_or_else(|_| {
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("timed out after {:?}", limit)))
+        }),
+        None => step.await,
+    }
+}
+
+/// Longest response head accepted from `upstream_proxy` for a CONNECT
+const UPSTREAM_PROXY_HEAD_BYTES: usize = 8192;
+
+/// Open a TCP connection to `addr` (`host:port`), directly or, with `upstream_proxy` set,
+/// through a CONNECT tunnel of the upstream proxy. The proxy resolves the name in that case.
+pub async fn connect_upstream(config: &Config, addr: &str) -> std::io::Result<TcpStream> {
+    let proxy = config.upstream_proxy_addr().map_err(std::io::Error::other)?;
+    let Some(proxy) = proxy else {
+        return TcpStream::connect(addr).await;
+    };
+    let mut stream = TcpStream::connect(&proxy).await?;
+    stream
+        .write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", addr, addr).as_bytes())
+        .await?;
+
+    // Read one byte at a time so nothing after the head (the upstream's first bytes) is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= UPSTREAM_PROXY_HEAD_BYTES {
+            return Err(std::io::Error::other(format!("upstream proxy {} sent an oversized CONNECT response", proxy)));
+        }
+        let mut byte = [0u8; 1];
+        if stream.read(&mut byte).await? == 0 {
+            return Err(std::io::Error::other(format!("upstream proxy {} closed during CONNECT", proxy)));
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or("");
+    if !status_line.split_whitespace().nth(1).is_some_and(|status| status.starts_with('2')) {
+        return Err(std::io::Error::other(format!(
+            "upstream proxy {} refused CONNECT {}: {}",
+            proxy, addr, status_line
+        )));
+    }
+    Ok(stream)
+}
+
+/// How often `client_gone` looks again while the client has unread data waiting
+const CLIENT_GONE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Resolve once the client has closed the connection or it failed. Bytes the client sent
+/// that haven't been read yet (a peeked ClientHello) don't hide the hang-up.
+async fn client_gone(client: &TcpStream) {
+    loop {
+        match client.ready(Interest::READABLE).await {
+            Ok(ready) if ready.is_read_closed() || ready.is_error() => return,
+            // Readable only because of waiting data, which stays readable; check back later
+            Ok(_) => tokio::time::sleep(CLIENT_GONE_POLL_INTERVAL).await,
+            Err(_) => return,
+        }
+    }
+}
+
+/// Run an expensive setup step (upstream connect, certificate generation) unless the client
+/// goes away first. `None` means it did: the step is dropped and the reason is logged at debug.
+pub async fn unless_client_gone<T>(client: &TcpStream, host: &str, step: impl std::future::Future<Output = T>) -> Option<T> {
+    tokio::select! {
+        output = step => Some(output),
+        () = client_gone(client) => {
+            let reason = "Client Gone During Setup";
+            debug!("{} for {}", reason, host);
+            note_connection(|stats| stats.close_reason = Some(reason.to_string()));
+            None
+        }
+    }
+}
+
+/// Resolve once the tunnel has been idle for `idle_timeout` (never, when unset)
+async fn idle_expired(idle_timeout: Option<Duration>, activity: &Activity) {
+    let Some(timeout) = idle_timeout else {
+        return std::future::pending().await;
+    };
+    loop {
+        let idle = activity.idle_for();
+        if idle >= timeout {
+            return;
+        }
+        tokio::time::sleep(timeout - idle).await;
+    }
+}
+
+/// Copy bytes both ways until either side closes, until nothing has moved in either
+/// direction for `idle_timeout`, or until the idle reaper closes it. Any traffic (including
+/// WebSocket pings) resets the timer. Memory is bounded to one `buffer_bytes` buffer per direction.
+/// With a `capture`, both directions are also teed to its file. With a `gate`, every request
+/// the client sends is checked on its way through. Both write sides are shut down on the way
+/// out, so TLS peers see a `close_notify`. Once the tunnel closes, a transfer larger than the
+/// host rule's `alert_bytes_threshold` is logged as an alert. Under `max_connection_bytes`,
+/// the tunnel is closed and logged as a block once that many bytes have been read from
+/// either side. Under `max_bytes_per_sec`, each direction is paced to that rate.
+pub async fn tunnel<C, U>(
+    client: C,
+    upstream: U,
+    config: &Config,
+    host: &str,
+    idle_timeout: Option<Duration>,
+    capture: Option<&Capture>,
+    gate: Option<RequestGate<'_>>,
+) -> Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let buffer_bytes = config.copy_buffer_bytes();
+    let byte_limit = config.max_connection_bytes_for(host);
+    let budget = byte_limit.map(ByteBudget::new);
+    let (client_read, mut client_write) = tokio::io::split(client);
+    let (upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    let bytes_per_sec = config.max_bytes_per_sec_for(host);
+    if let Some(rate) = bytes_per_sec {
+        info!("Throttling tunnel to {} at {} bytes/s each way", host, rate);
+    }
+    let mut client_read = LimitedRead {
+        inner: client_read,
+        budget: budget.as_ref(),
+        throttle: bytes_per_sec.map(Throttle::new),
+    };
+    let mut upstream_read = LimitedRead {
+        inner: upstream_read,
+        budget: budget.as_ref(),
+        throttle: bytes_per_sec.map(Throttle::new),
+    };
+    let activity = Arc::new(Activity::new());
+    let _registration = TunnelRegistration::new(host, &activity);
+
+    let sent = AtomicU64::new(0);
+    let received = AtomicU64::new(0);
+
+    let shared = Mutex::new(GateState::default());
+    let changed = Notify::new();
+    let (request_side, response_side) = match gate {
+        Some(RequestGate { checks, requests, pending, responses, awaiting, responding, forwarded, closing }) => {
+            let mut state = shared.lock().unwrap();
+            state.awaiting = awaiting;
+            state.forwarded = forwarded;
+            state.closing = closing;
+            drop(state);
+            (Some((checks, requests, pending)), Some((responses, responding)))
+        }
+        None => (None, None),
+    };
+    let client_to_upstream = async {
+        match request_side {
+            Some((checks, state, pending)) => {
+                relay_requests(
+                    &mut client_read, &mut upstream_write, &checks, state, pending, &shared, &changed, buffer_bytes,
+                    &activity, &sent, capture,
+                )
+                .await
+            }
+            None => copy_with_buffer(&mut client_read, &mut upstream_write, buffer_bytes, &activity, &sent, capture).await,
+        }
+    };
+    let upstream_to_client = async {
+        match response_side {
+            Some((state, responding)) => {
+                relay_responses(
+                    config, host, &mut upstream_read, &mut client_write, state, responding, &shared, &changed, buffer_bytes,
+                    &activity, &received, capture,
+                )
+                .await
+            }
+            None => copy_with_buffer(&mut upstream_read, &mut client_write, buffer_bytes, &activity, &received, capture)
+                .await
+                .map(|_| false),
+        }
+    };
+
+    let (result, close_reason) = tokio::select! {
+        result = client_to_upstream => (result, "Closed"),
+        result = upstream_to_client => match result {
+            Ok(true) => (Ok(()), "Blocked"),
+            result => (result.map(|_| ()), "Closed"),
+        },
+        _ = idle_expired(idle_timeout, &activity) => {
+            debug!("Closing tunnel after {:?} idle", idle_timeout.unwrap_or_default());
+            (Ok(()), "Idle Timeout")
+        }
+        _ = activity.reaped.notified() => {
+            debug!("Closing tunnel to {} reaped while idle", host);
+            (Ok(()), "Idle Reaped")
+        }
+    };
+
+    let (result, close_reason) = match (&budget, byte_limit) {
+        (Some(budget), Some(limit)) if budget.exceeded.load(Ordering::Relaxed) => {
+            let reason = "Byte Limit Exceeded";
+            log_traffic(config, "BLOCK", host, "/", "CONNECT", reason, &[("max_connection_bytes", &limit.to_string())]);
+            print_decision(config, format_args!("⛔ [{}] {} -> {} ({} bytes)", config.mode, host, reason, limit));
+            (Ok(()), reason)
+        }
+        _ => (result, close_reason),
+    };
+
+    close_gracefully(&mut client_write).await;
+    close_gracefully(&mut upstream_write).await;
+
+    let (sent, received) = (sent.load(Ordering::Relaxed), received.load(Ordering::Relaxed));
+    record_tunnel_bytes(sent, received);
+    if config.metrics_rollup_secs.is_some() {
+        note_rollup(host, |rollup| {
+            rollup.bytes_sent += sent;
+            rollup.bytes_received += received;
+        });
+    }
+    note_connection(|stats| {
+        stats.bytes_sent += sent;
+        stats.bytes_received += received;
+        stats.close_reason = Some(close_reason.to_string());
+    });
+    if let Some(threshold) = find_host_rule(config, host).and_then(|r| r.alert_bytes_threshold) {
+        if sent + received > threshold {
+            log_transfer_alert(config, host, sent, received, threshold);
+            print_decision(config, format_args!("🚨 [{}] {} -> Large Transfer ({} bytes)", config.mode, host, sent + received));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_reaper_picks_the_longest_idle_tunnels_past_the_cap() {
+        let quiet_for = |secs: u64| {
+            Arc::new(Activity {
+                started: Instant::now() - Duration::from_secs(secs),
+                last_ms: AtomicU64::new(0),
+                reaped: Notify::new(),
+            })
+        };
+        let tunnels = [("a.reap.example.com", 600), ("b.reap.example.com", 900), ("c.reap.example.com", 300)];
+        let _registered: Vec<TunnelRegistration> = tunnels
+            .iter()
+            .map(|(host, secs)| TunnelRegistration::new(host, &quiet_for(*secs)))
+            .chain([TunnelRegistration::new("busy.reap.example.com", &Arc::new(Activity::new()))])
+            .collect();
+        let reaped = |max_idle: usize| -> Vec<String> {
+            idle_tunnels_over(max_idle)
+                .into_iter()
+                .map(|(_, host, _)| host)
+                .filter(|host| host.ends_with(".reap.example.com"))
+                .collect()
+        };
+
+        assert_eq!(reaped(1), ["b.reap.example.com", "a.reap.example.com"]);
+        assert_eq!(reaped(2), ["b.reap.example.com"]);
+        assert!(reaped(3).is_empty());
+    }
+}